@@ -85,6 +85,13 @@ def main(args):
     with open("../Cargo.toml", "w") as f:
         f.writelines(lines)
     
+def initramfs():
+    # Included in the kernel with the initramfs feature, the kernel runs /init if there is one
+    if not os.path.isdir("initramfs"):
+        os.mkdir("initramfs")
+        cmd("cp userland initramfs/", "Copying a simple executable file")
+    cmd("cd initramfs && find . | cpio -o -H newc > ../initramfs.cpio", "Packing initramfs in cpio newc format")
+
 DISKS = [
     "25M fat32",
     "30M ext2",
@@ -92,7 +99,9 @@ DISKS = [
 ]
 
 if __name__ == "__main__":
-    if "create-all-disks" in sys.argv:
+    if "initramfs" in sys.argv:
+        initramfs()
+    elif "create-all-disks" in sys.argv:
         for disk in DISKS:
             main(disk.split(" "))
     else:
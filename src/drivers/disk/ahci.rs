@@ -0,0 +1,727 @@
+//! Used https://wiki.osdev.org/AHCI
+//! And mostly https://www.intel.com/content/dam/www/public/us/en/documents/technical-specifications/serial-ata-ahci-spec-rev1-3-1.pdf
+//! Commands are sent one at a time, in the slot 0 of the port, and completed by the interrupt of the controller
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+
+use alloc::{format, string::String, vec::Vec};
+use bit_field::BitField;
+use spin::RwLock;
+use x86_64::{
+    structures::{idt::InterruptStackFrame, paging::PageTableFlags},
+    VirtAddr,
+};
+
+use crate::{
+    interrupts::hardware::{notify_end_of_interrupt, register_interrupt, InterruptIndex, IRQ_COUNTS},
+    mem_map,
+    pci::PciDevice,
+    sync::TimeOutRwLock,
+    task::event::Event,
+};
+
+use super::{
+    dma_page,
+    driver::{Disk, DiskDriver, DiskDriverEnum, GenericDisk, InFlight, SECTOR_SIZE},
+    DiskError, DiskLoc, PAGE_SIZE,
+};
+
+pub static mut AHCI_DRIVER: Option<RwLock<AhciDriver>> = None;
+
+const MAX_CONTROLLERS: usize = 4;
+/// Disks over all of the controllers, the disk indexes after them are the `NVMe` ones
+pub const MAX_DISKS: usize = 32;
+/// Pages the data is copied through, one PRDT entry each, so the most a single command transfers is 128KiB
+const MAX_TRANSFER_PAGES: usize = 32;
+/// Polls of the controller before giving up
+const TIMEOUT: usize = 100_000_000;
+/// Polls of the command issue register after the command is done before we stop waiting for the interrupt
+const IRQ_TIMEOUT: usize = 1_000_000;
+/// Device signatures in PxSIG
+const SATA_SIGNATURE: u32 = 0x0000_0101;
+const SATAPI_SIGNATURE: u32 = 0xEB14_0101;
+
+/// ABAR of each controller, for the interrupt handler which can't take the driver lock
+static ABARS: [AtomicU64; MAX_CONTROLLERS] = [const { AtomicU64::new(0) }; MAX_CONTROLLERS];
+/// PxIS of each port of each controller, gathered by the interrupt handler until the command completes
+static PORT_EVENTS: [AtomicU32; MAX_CONTROLLERS * 32] = [const { AtomicU32::new(0) }; MAX_CONTROLLERS * 32];
+/// Set with `PORT_EVENTS`, wakes the task waiting on the port
+static PORT_DONE: [Event; MAX_CONTROLLERS * 32] = [const { Event::new() }; MAX_CONTROLLERS * 32];
+/// PIC line of the handler, the controllers on other lines are polled
+static IRQ_LINE: AtomicU8 = AtomicU8::new(u8::MAX);
+
+/// Registers of the HBA, offsets from ABAR
+enum HbaReg {
+    Capabilities = 0x00,
+    GlobalHostControl = 0x04,
+    InterruptStatus = 0x08,
+    PortsImplemented = 0x0C,
+    Version = 0x10,
+    Capabilities2 = 0x24,
+    BiosHandoff = 0x28,
+}
+/// Registers of a port, offsets from 0x100 + 0x80 * port
+enum PortReg {
+    CommandListBase = 0x00,
+    CommandListBaseUpper = 0x04,
+    FisBase = 0x08,
+    FisBaseUpper = 0x0C,
+    InterruptStatus = 0x10,
+    InterruptEnable = 0x14,
+    Command = 0x18,
+    TaskFileData = 0x20,
+    Signature = 0x24,
+    SataStatus = 0x28,
+    SataError = 0x30,
+    CommandIssue = 0x38,
+}
+/// Bits of PxCMD
+const PORT_START: usize = 0;
+const PORT_FIS_RECEIVE_ENABLE: usize = 4;
+const PORT_FIS_RECEIVE_RUNNING: usize = 14;
+const PORT_COMMAND_LIST_RUNNING: usize = 15;
+/// Bits of PxIS and PxIE
+const DEVICE_TO_HOST_FIS: usize = 0;
+const TASK_FILE_ERROR: usize = 30;
+
+pub enum AtaCommand {
+    ReadDmaExt = 0x25,
+    WriteDmaExt = 0x35,
+    FlushCacheExt = 0xEA,
+    IdentifyDevice = 0xEC,
+}
+
+unsafe fn read_reg(addr: u64) -> u32 {
+    unsafe { (addr as *const u32).read_volatile() }
+}
+unsafe fn write_reg(addr: u64, value: u32) {
+    unsafe { (addr as *mut u32).write_volatile(value) }
+}
+fn port_base(abar: u64, port: usize) -> u64 {
+    abar + 0x100 + 0x80 * port as u64
+}
+
+#[derive(Debug)]
+pub enum AhciInitError {
+    /// BAR5 is in IO space
+    NoMemoryBar,
+    TooManyControllers,
+    OutOfMemory,
+    /// The BIOS didn't give the controller back
+    BiosHandoff,
+}
+
+/// Initialises the controller and registers its SATA disks in `AHCI_DRIVER`
+pub fn init(ahci_pci: &PciDevice) -> Result<Vec<Disk>, AhciInitError> {
+    let abar = match ahci_pci.raw.determine_mem_base(5).unwrap() {
+        crate::pci::PciMemoryBase::MemorySpace(mem) => mem.as_u64(),
+        crate::pci::PciMemoryBase::IOSpace(_) => return Err(AhciInitError::NoMemoryBar),
+    };
+    // Enable bus mastering & memory space
+    let mut command = ahci_pci.raw.command;
+    command.set_bit(2, true);
+    command.set_bit(1, true);
+    ahci_pci
+        .raw
+        .location
+        .pci_write(crate::pci::PCI_COMMAND, u32::from(command));
+    // The port registers end at 0x1100
+    for i in 0..2 {
+        mem_map!(
+            frame_addr = abar + (0x1000 * i),
+            PageTableFlags::PRESENT
+                | PageTableFlags::WRITABLE
+                | PageTableFlags::NO_CACHE
+                | PageTableFlags::WRITE_THROUGH
+        );
+    }
+    let driver = unsafe { AHCI_DRIVER.get_or_insert_with(|| RwLock::new(AhciDriver::default())) }.get_mut();
+    let index = driver.controllers.len();
+    if index >= MAX_CONTROLLERS {
+        return Err(AhciInitError::TooManyControllers);
+    }
+    let mut controller = AhciController {
+        abar,
+        index,
+        polling: true,
+        irq_seen: false,
+        in_flight: None,
+        buffer: (0..MAX_TRANSFER_PAGES)
+            .map(|_| dma_page())
+            .collect::<Option<_>>()
+            .ok_or(AhciInitError::OutOfMemory)?,
+    };
+    let version = controller.read(HbaReg::Version as u64);
+    log::info!(
+        "[AHCI] Found AHCI controller with version {}.{}, {} ports",
+        version >> 16,
+        version & 0xffff,
+        controller.read(HbaReg::Capabilities as u64).get_bits(0..5) + 1
+    );
+    controller.bios_handoff()?;
+    // AHCI mode
+    let mut ghc = controller.read(HbaReg::GlobalHostControl as u64);
+    controller.write(HbaReg::GlobalHostControl as u64, *ghc.set_bit(31, true));
+
+    let mut disks = Vec::new();
+    let ports_implemented = controller.read(HbaReg::PortsImplemented as u64);
+    for port in (0..32).filter(|port| ports_implemented.get_bit(*port)) {
+        let Some(port) = controller.init_port(port)? else {
+            continue;
+        };
+        let (sector_count, model) = match controller.identify(&port) {
+            Ok(identify) => identify,
+            Err(err) => {
+                log::error!("[AHCI] Failed identifying disk on port {}: {:?}", port.port, err);
+                continue;
+            }
+        };
+        if driver.disks.len() >= MAX_DISKS {
+            log::warn!("[AHCI] Too many disks, skipping port {}", port.port);
+            continue;
+        }
+        let disk = AhciDisk {
+            loc: DiskLoc::Ahci(driver.disks.len() as u8),
+            controller: index,
+            port,
+            sector_count,
+            model,
+        };
+        log::info!("[AHCI] {} \"{}\" has {} sectors", disk, disk.model, sector_count);
+        disks.push(Disk {
+            loc: disk.loc,
+            drv: DiskDriverEnum::Ahci,
+        });
+        driver.disks.push(disk);
+    }
+
+    ABARS[index].store(abar, Ordering::Release);
+    controller.enable_interrupts(ahci_pci.raw.int_line);
+    driver.controllers.push(controller);
+    Ok(disks)
+}
+
+/// Acknowledges the interrupts of every port, and keeps their status for the commands waiting on them
+fn handle_irq() {
+    for (controller, abar) in ABARS.iter().enumerate() {
+        let abar = abar.load(Ordering::Acquire);
+        if abar == 0 {
+            continue;
+        }
+        let pending = unsafe { read_reg(abar + HbaReg::InterruptStatus as u64) };
+        for port in (0..32).filter(|port| pending.get_bit(*port)) {
+            let status_reg = port_base(abar, port) + PortReg::InterruptStatus as u64;
+            let status = unsafe { read_reg(status_reg) };
+            unsafe { write_reg(status_reg, status) };
+            PORT_EVENTS[controller * 32 + port].fetch_or(status, Ordering::AcqRel);
+            PORT_DONE[controller * 32 + port].set();
+        }
+        unsafe { write_reg(abar + HbaReg::InterruptStatus as u64, pending) };
+    }
+}
+extern "x86-interrupt" fn irq(_stack_frame: InterruptStackFrame) {
+    handle_irq();
+    if let Some(idx) = InterruptIndex::from_num_pic(IRQ_LINE.load(Ordering::Acquire)) {
+        IRQ_COUNTS[idx as usize].fetch_add(1, Ordering::Relaxed);
+        notify_end_of_interrupt(idx);
+    }
+}
+
+/// Name of the disk in /dev, sd[letter]
+#[must_use] pub fn disk_name(loc: &DiskLoc) -> Option<String> {
+    match loc {
+        DiskLoc::Ahci(idx) => Some(format!("sd{}", (b'a' + *idx) as char)),
+        _ => None,
+    }
+}
+
+/// Number of disks found, their indexes are below it
+#[must_use] pub fn disk_count() -> usize {
+    unsafe { AHCI_DRIVER.as_ref() }.map_or(0, |driver| driver.read_with_timeout().disks.len())
+}
+
+fn find_disk<'a>(disks: &'a [AhciDisk], loc: &DiskLoc) -> Result<&'a AhciDisk, DiskError> {
+    match loc {
+        DiskLoc::Ahci(idx) => disks.get(usize::from(*idx)).ok_or(DiskError::NotFound),
+        _ => Err(DiskError::NotFound),
+    }
+}
+
+/// Every controller and the SATA disks found on them
+#[derive(Debug, Default)]
+pub struct AhciDriver {
+    controllers: Vec<AhciController>,
+    disks: Vec<AhciDisk>,
+}
+impl AhciDriver {
+    /// Sends one command and sleeps until it's done, the driver is only locked to send it and to collect it
+    /// The command is polled when the task can't sleep on it
+    async fn run_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        command: AtaCommand,
+        lba: u64,
+        len: usize,
+        content: Option<&[u8]>,
+    ) -> Result<Vec<u8>, DiskError> {
+        let read_len = if content.is_some() { 0 } else { len };
+        let (index, port, done) = {
+            let mut guard = lock.write_with_timeout();
+            let drv = &mut *guard;
+            let disk = find_disk(&drv.disks, loc)?;
+            let controller = &mut drv.controllers[disk.controller];
+            controller.collect_in_flight();
+            if let Some(content) = content {
+                controller.copy_to_buffer(content, len);
+            }
+            controller.prepare(&disk.port, command, lba, len);
+            controller.start(disk.port.port)?;
+            // A result is still there when the task of the previous command isn't back yet
+            if !controller.interrupts() || controller.in_flight.is_some() {
+                return controller.finish(disk.port.port, read_len);
+            }
+            controller.in_flight = Some(InFlight::new(disk.port.port, read_len));
+            (disk.controller, disk.port.port, &PORT_DONE[controller.index * 32 + disk.port.port])
+        };
+        done.wait_until(|| {
+            lock.read_with_timeout().controllers[index]
+                .in_flight
+                .as_ref()
+                .is_some_and(InFlight::is_collected)
+        })
+        .await;
+        let mut drv = lock.write_with_timeout();
+        let controller = &mut drv.controllers[index];
+        let command = controller.in_flight.take().expect("Only the task takes its command");
+        command.result.unwrap_or_else(|| controller.finish(port, read_len))
+    }
+}
+impl DiskDriver for AhciDriver {
+    fn read(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let disk = find_disk(&self.disks, loc)?;
+        if start_sector + sector_count > disk.sector_count {
+            return Err(DiskError::SectorTooBig);
+        }
+        let controller = &mut self.controllers[disk.controller];
+        controller.collect_in_flight();
+        let mut data = Vec::with_capacity((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        let mut sector = start_sector;
+        while sector < start_sector + sector_count {
+            let count = (start_sector + sector_count - sector).min(AhciController::max_transfer_sectors());
+            let len = (count * u64::from(SECTOR_SIZE)) as usize;
+            controller.send(&disk.port, AtaCommand::ReadDmaExt, sector, len)?;
+            controller.copy_from_buffer(len, &mut data);
+            sector += count;
+        }
+        Ok(data)
+    }
+
+    /// The last sector is padded with zeroes
+    fn write(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
+        let disk = find_disk(&self.disks, loc)?;
+        let sector_count = (content.len() as u64).div_ceil(u64::from(SECTOR_SIZE));
+        if start_sector + sector_count > disk.sector_count {
+            return Err(DiskError::SectorTooBig);
+        }
+        let controller = &mut self.controllers[disk.controller];
+        controller.collect_in_flight();
+        let max_bytes = (AhciController::max_transfer_sectors() * u64::from(SECTOR_SIZE)) as usize;
+        let mut sector = start_sector;
+        for chunk in content.chunks(max_bytes) {
+            let len = chunk.len().next_multiple_of(usize::from(SECTOR_SIZE));
+            controller.copy_to_buffer(chunk, len);
+            controller.send(&disk.port, AtaCommand::WriteDmaExt, sector, len)?;
+            sector += (len / usize::from(SECTOR_SIZE)) as u64;
+        }
+        controller.send(&disk.port, AtaCommand::FlushCacheExt, 0, 0)
+    }
+
+    async fn read_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let disk_sectors = lock.read_with_timeout().sector_count(loc)?;
+        if start_sector + sector_count > disk_sectors {
+            return Err(DiskError::SectorTooBig);
+        }
+        let mut data = Vec::with_capacity((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        let mut sector = start_sector;
+        while sector < start_sector + sector_count {
+            let count = (start_sector + sector_count - sector).min(AhciController::max_transfer_sectors());
+            let len = (count * u64::from(SECTOR_SIZE)) as usize;
+            data.extend(Self::run_async(lock, loc, AtaCommand::ReadDmaExt, sector, len, None).await?);
+            sector += count;
+        }
+        Ok(data)
+    }
+
+    /// The last sector is padded with zeroes
+    async fn write_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        let sector_count = (content.len() as u64).div_ceil(u64::from(SECTOR_SIZE));
+        if start_sector + sector_count > lock.read_with_timeout().sector_count(loc)? {
+            return Err(DiskError::SectorTooBig);
+        }
+        let max_bytes = (AhciController::max_transfer_sectors() * u64::from(SECTOR_SIZE)) as usize;
+        let mut sector = start_sector;
+        for chunk in content.chunks(max_bytes) {
+            let len = chunk.len().next_multiple_of(usize::from(SECTOR_SIZE));
+            Self::run_async(lock, loc, AtaCommand::WriteDmaExt, sector, len, Some(chunk)).await?;
+            sector += (len / usize::from(SECTOR_SIZE)) as u64;
+        }
+        // No data, so nothing to read back either
+        Self::run_async(lock, loc, AtaCommand::FlushCacheExt, 0, 0, Some(&[])).await?;
+        Ok(())
+    }
+
+    fn sector_count(&self, loc: &DiskLoc) -> Result<u64, DiskError> {
+        Ok(find_disk(&self.disks, loc)?.sector_count)
+    }
+
+    /// Every port can be used at any time
+    fn select_disk(&mut self, _disk: &DiskLoc) {}
+}
+
+#[derive(Debug)]
+pub struct AhciDisk {
+    loc: DiskLoc,
+    /// Index in `AhciDriver::controllers`
+    controller: usize,
+    port: AhciPort,
+    sector_count: u64,
+    model: String,
+}
+impl GenericDisk for AhciDisk {
+    fn loc(&self) -> &DiskLoc {
+        &self.loc
+    }
+}
+impl core::fmt::Display for AhciDisk {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        return f.write_str(format!("AHCI {} port {}", self.controller, self.port.port).as_str())
+    }
+}
+
+#[derive(Debug)]
+struct AhciPort {
+    port: usize,
+    /// The command list is the first KiB, the received FISes are after it
+    command_list: VirtAddr,
+    /// Command table of the slot 0, the command FIS then the PRDT at 0x80
+    command_table: VirtAddr,
+}
+
+#[derive(Debug)]
+struct AhciController {
+    abar: u64,
+    /// Index in `AhciDriver::controllers`, and in the statics shared with the interrupt handler
+    index: usize,
+    /// If the controller doesn't interrupt, completion is then polled
+    polling: bool,
+    /// If an interrupt was seen, only then can a task sleep until the next one
+    irq_seen: bool,
+    /// Identity mapped pages the data is copied through, they aren't contiguous so each one is a PRDT entry
+    buffer: Vec<VirtAddr>,
+    /// The command a task sleeps on, with its port, the buffer is shared by the ports so there is one per controller
+    in_flight: Option<InFlight<usize>>,
+}
+impl AhciController {
+    fn read(&self, offset: u64) -> u32 {
+        unsafe { read_reg(self.abar + offset) }
+    }
+    fn write(&self, offset: u64, value: u32) {
+        unsafe { write_reg(self.abar + offset, value) }
+    }
+    fn read_port(&self, port: usize, reg: PortReg) -> u32 {
+        unsafe { read_reg(port_base(self.abar, port) + reg as u64) }
+    }
+    fn write_port(&self, port: usize, reg: PortReg, value: u32) {
+        unsafe { write_reg(port_base(self.abar, port) + reg as u64, value) }
+    }
+    /// Waits until the bits of the port register are cleared
+    fn wait_port_clear(&self, port: usize, reg: PortReg, mask: u32) -> Result<(), DiskError> {
+        let offset = reg as u64;
+        for _ in 0..TIMEOUT {
+            if unsafe { read_reg(port_base(self.abar, port) + offset) } & mask == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(DiskError::TimeOut)
+    }
+    fn max_transfer_sectors() -> u64 {
+        (MAX_TRANSFER_PAGES * PAGE_SIZE / usize::from(SECTOR_SIZE)) as u64
+    }
+    /// 10.6.3 Takes the ownership of the controller from the BIOS
+    fn bios_handoff(&self) -> Result<(), AhciInitError> {
+        if !self.read(HbaReg::Capabilities2 as u64).get_bit(0) {
+            return Ok(());
+        }
+        let mut handoff = self.read(HbaReg::BiosHandoff as u64);
+        // OS owned semaphore
+        self.write(HbaReg::BiosHandoff as u64, *handoff.set_bit(1, true));
+        for _ in 0..TIMEOUT {
+            // BIOS owned semaphore and BIOS busy
+            let handoff = self.read(HbaReg::BiosHandoff as u64);
+            if !handoff.get_bit(0) && !handoff.get_bit(4) {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(AhciInitError::BiosHandoff)
+    }
+    /// Stops the command list and FIS receive DMA engines of the port
+    fn stop_port(&self, port: usize) -> Result<(), DiskError> {
+        let mut command = self.read_port(port, PortReg::Command);
+        command.set_bit(PORT_START, false);
+        self.write_port(port, PortReg::Command, command);
+        self.wait_port_clear(port, PortReg::Command, 1 << PORT_COMMAND_LIST_RUNNING)?;
+        command.set_bit(PORT_FIS_RECEIVE_ENABLE, false);
+        self.write_port(port, PortReg::Command, command);
+        self.wait_port_clear(port, PortReg::Command, 1 << PORT_FIS_RECEIVE_RUNNING)
+    }
+    /// Starts the engines again, the errors of the port are cleared
+    fn start_port(&self, port: usize) -> Result<(), DiskError> {
+        self.write_port(port, PortReg::SataError, u32::MAX);
+        self.write_port(port, PortReg::InterruptStatus, u32::MAX);
+        let mut command = self.read_port(port, PortReg::Command);
+        command.set_bit(PORT_FIS_RECEIVE_ENABLE, true);
+        self.write_port(port, PortReg::Command, command);
+        // BSY and DRQ
+        self.wait_port_clear(port, PortReg::TaskFileData, 0x88)?;
+        command.set_bit(PORT_START, true);
+        self.write_port(port, PortReg::Command, command);
+        Ok(())
+    }
+    /// 10.1.2 Gives the memory of the port to the controller, None if there is no SATA disk on it
+    fn init_port(&self, port: usize) -> Result<Option<AhciPort>, AhciInitError> {
+        let status = self.read_port(port, PortReg::SataStatus);
+        // Device present with communication established, and in active state
+        if status.get_bits(0..4) != 3 || status.get_bits(8..12) != 1 {
+            return Ok(None);
+        }
+        match self.read_port(port, PortReg::Signature) {
+            SATA_SIGNATURE => {}
+            SATAPI_SIGNATURE => {
+                log::info!("[AHCI] Skipping SATAPI device on port {}", port);
+                return Ok(None);
+            }
+            signature => {
+                log::debug!("[AHCI] Skipping device with signature {:#x} on port {}", signature, port);
+                return Ok(None);
+            }
+        }
+        if self.stop_port(port).is_err() {
+            log::error!("[AHCI] Port {} doesn't stop, skipping it", port);
+            return Ok(None);
+        }
+        let port = AhciPort {
+            port,
+            command_list: dma_page().ok_or(AhciInitError::OutOfMemory)?,
+            command_table: dma_page().ok_or(AhciInitError::OutOfMemory)?,
+        };
+        let command_list = port.command_list.as_u64();
+        self.write_port(port.port, PortReg::CommandListBase, command_list.get_bits(0..32) as u32);
+        self.write_port(port.port, PortReg::CommandListBaseUpper, command_list.get_bits(32..64) as u32);
+        let fis = command_list + 0x400;
+        self.write_port(port.port, PortReg::FisBase, fis.get_bits(0..32) as u32);
+        self.write_port(port.port, PortReg::FisBaseUpper, fis.get_bits(32..64) as u32);
+        // The command header of the slot 0 points to its table
+        let command_table = port.command_table.as_u64();
+        let header = port.command_list.as_mut_ptr::<u32>();
+        unsafe {
+            header.add(2).write_volatile(command_table.get_bits(0..32) as u32);
+            header.add(3).write_volatile(command_table.get_bits(32..64) as u32);
+        }
+        if self.start_port(port.port).is_err() {
+            log::error!("[AHCI] Port {} doesn't start, skipping it", port.port);
+            return Ok(None);
+        }
+        Ok(Some(port))
+    }
+    /// Registers the interrupt handler on the PCI line, the controller stays polled if it's not a PIC line or the handler is on another one
+    fn enable_interrupts(&mut self, line: u8) {
+        if InterruptIndex::from_num_pic(line).is_none() {
+            return;
+        }
+        if IRQ_LINE
+            .compare_exchange(u8::MAX, line, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            register_interrupt(InterruptIndex::from_num_pic(line).unwrap(), irq);
+        } else if IRQ_LINE.load(Ordering::Acquire) != line {
+            return;
+        }
+        let ports_implemented = self.read(HbaReg::PortsImplemented as u64);
+        for port in (0..32).filter(|port| ports_implemented.get_bit(*port)) {
+            self.write_port(port, PortReg::InterruptStatus, u32::MAX);
+            self.write_port(port, PortReg::InterruptEnable, (1 << DEVICE_TO_HOST_FIS) | (1 << TASK_FILE_ERROR));
+        }
+        self.write(HbaReg::InterruptStatus as u64, u32::MAX);
+        let mut ghc = self.read(HbaReg::GlobalHostControl as u64);
+        self.write(HbaReg::GlobalHostControl as u64, *ghc.set_bit(1, true));
+        self.polling = false;
+    }
+    /// Sectors and model of the disk
+    fn identify(&mut self, port: &AhciPort) -> Result<(u64, String), DiskError> {
+        self.send(port, AtaCommand::IdentifyDevice, 0, usize::from(SECTOR_SIZE))?;
+        let identify = unsafe { core::slice::from_raw_parts(self.buffer[0].as_ptr::<u16>(), 256) };
+        let lba48 = identify[100..104]
+            .iter()
+            .rev()
+            .fold(0, |count, word| (count << 16) | u64::from(*word));
+        let lba28 = u64::from(identify[60]) | (u64::from(identify[61]) << 16);
+        // Words 27 to 46, with the bytes of each word swapped
+        let model = identify[27..47]
+            .iter()
+            .flat_map(|word| word.to_be_bytes())
+            .map(char::from)
+            .collect::<String>();
+        // Bit 10 of word 83 is the 48-bit address feature set
+        let sector_count = if identify[83].get_bit(10) { lba48 } else { lba28 };
+        Ok((sector_count, String::from(model.trim())))
+    }
+    fn copy_to_buffer(&mut self, content: &[u8], len: usize) {
+        for (i, page) in self.buffer.iter().enumerate().take(len.div_ceil(PAGE_SIZE)) {
+            let page = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr::<u8>(), PAGE_SIZE) };
+            let data = content.get(i * PAGE_SIZE..).unwrap_or_default();
+            let data = &data[..data.len().min(PAGE_SIZE)];
+            page[..data.len()].copy_from_slice(data);
+            page[data.len()..].fill(0);
+        }
+    }
+    fn copy_from_buffer(&self, len: usize, data: &mut Vec<u8>) {
+        for (i, page) in self.buffer.iter().enumerate().take(len.div_ceil(PAGE_SIZE)) {
+            let page_len = (len - i * PAGE_SIZE).min(PAGE_SIZE);
+            data.extend_from_slice(unsafe { core::slice::from_raw_parts(page.as_ptr::<u8>(), page_len) });
+        }
+    }
+    /// Sends the command with the first `len` bytes of the buffer as its data
+    fn send(&mut self, port: &AhciPort, command: AtaCommand, lba: u64, len: usize) -> Result<(), DiskError> {
+        self.prepare(port, command, lba, len);
+        self.start(port.port)?;
+        self.wait(port.port)
+    }
+    /// Fills the command table and the command header of the slot 0
+    fn prepare(&mut self, port: &AhciPort, command: AtaCommand, lba: u64, len: usize) {
+        let write = matches!(command, AtaCommand::WriteDmaExt);
+        let sector_count = len / usize::from(SECTOR_SIZE);
+        let prdt_len = len.div_ceil(PAGE_SIZE);
+        let table = port.command_table.as_mut_ptr::<u8>();
+        // Register FIS host to device
+        let mut fis = [0_u8; 20];
+        fis[0] = 0x27;
+        fis[1] = 1 << 7; // Command, not control
+        fis[2] = command as u8;
+        fis[4..7].copy_from_slice(&lba.to_le_bytes()[0..3]);
+        fis[7] = 1 << 6; // LBA mode
+        fis[8..11].copy_from_slice(&lba.to_le_bytes()[3..6]);
+        fis[12..14].copy_from_slice(&(sector_count as u16).to_le_bytes());
+        unsafe { core::ptr::copy_nonoverlapping(fis.as_ptr(), table, fis.len()) };
+        // Physical region descriptor table, a page per entry
+        let prdt = unsafe { table.add(0x80) }.cast::<u32>();
+        for (i, page) in self.buffer.iter().enumerate().take(prdt_len) {
+            let page_len = (len - i * PAGE_SIZE).min(PAGE_SIZE);
+            let address = page.as_u64();
+            unsafe {
+                prdt.add(i * 4).write_volatile(address.get_bits(0..32) as u32);
+                prdt.add(i * 4 + 1).write_volatile(address.get_bits(32..64) as u32);
+                prdt.add(i * 4 + 2).write_volatile(0);
+                // 0's based byte count
+                prdt.add(i * 4 + 3).write_volatile((page_len - 1) as u32);
+            }
+        }
+        // Command header of the slot 0, the FIS is 5 dwords long
+        let mut header = 5_u32;
+        header.set_bit(6, write);
+        header.set_bits(16..32, prdt_len as u32);
+        let header_ptr = port.command_list.as_mut_ptr::<u32>();
+        unsafe {
+            header_ptr.write_volatile(header);
+            // Bytes transferred
+            header_ptr.add(1).write_volatile(0);
+        }
+    }
+    /// Issues the slot 0, `wait` then waits for the interrupt that completes it
+    fn start(&self, port: usize) -> Result<(), DiskError> {
+        // BSY and DRQ
+        self.wait_port_clear(port, PortReg::TaskFileData, 0x88)?;
+        PORT_EVENTS[self.index * 32 + port].store(0, Ordering::Release);
+        PORT_DONE[self.index * 32 + port].reset();
+        self.write_port(port, PortReg::CommandIssue, 1);
+        Ok(())
+    }
+    /// The port is restarted if the command failed
+    fn wait(&mut self, port: usize) -> Result<(), DiskError> {
+        let events = &PORT_EVENTS[self.index * 32 + port];
+        let mut done_polls = 0;
+        for _ in 0..TIMEOUT {
+            let mut status = events.load(Ordering::Acquire);
+            if self.polling {
+                status |= self.read_port(port, PortReg::InterruptStatus);
+                self.write_port(port, PortReg::InterruptStatus, status);
+            }
+            if status.get_bit(TASK_FILE_ERROR) {
+                return self.command_failed(port);
+            }
+            if !self.read_port(port, PortReg::CommandIssue).get_bit(0) {
+                if self.polling {
+                    return Ok(());
+                }
+                if status.get_bit(DEVICE_TO_HOST_FIS) {
+                    self.irq_seen = true;
+                    return Ok(());
+                }
+                done_polls += 1;
+                if done_polls > IRQ_TIMEOUT {
+                    log::warn!("[AHCI] No interrupt from the controller, polling it from now");
+                    self.polling = true;
+                    return Ok(());
+                }
+            }
+            core::hint::spin_loop();
+        }
+        log::error!("[AHCI] Command timed out on port {}", port);
+        Err(DiskError::TimeOut)
+    }
+    /// Waits for the command, then copies the first `read_len` bytes of the buffer
+    fn finish(&mut self, port: usize, read_len: usize) -> Result<Vec<u8>, DiskError> {
+        self.wait(port)?;
+        let mut data = Vec::with_capacity(read_len);
+        self.copy_from_buffer(read_len, &mut data);
+        Ok(data)
+    }
+    /// If a task can sleep until the interrupt, only once the controller is known to send it as nothing would wake the task otherwise
+    fn interrupts(&self) -> bool {
+        self.irq_seen && !self.polling
+    }
+    /// Waits for the command a task sleeps on, if there is one, so that the buffer can be used
+    fn collect_in_flight(&mut self) {
+        if let Some(mut command) = self.in_flight.take() {
+            if !command.is_collected() {
+                command.result = Some(self.finish(command.command, command.read_len));
+                // The interrupt may have come before the task slept, or not at all if the controller stopped sending it
+                PORT_DONE[self.index * 32 + command.command].set();
+            }
+            self.in_flight = Some(command);
+        }
+    }
+    /// Restarts the port, which stopped on the error
+    fn command_failed(&self, port: usize) -> Result<(), DiskError> {
+        let task_file = self.read_port(port, PortReg::TaskFileData);
+        log::error!("[AHCI] Command failed on port {} with task file {:#x}", port, task_file);
+        self.stop_port(port)?;
+        self.start_port(port)?;
+        Err(DiskError::CommandFailed(task_file as u16))
+    }
+}
@@ -0,0 +1,210 @@
+//! ATAPI drives, i.e. CD drives, see https://wiki.osdev.org/ATAPI
+//! They take SCSI commands sent in a PACKET command, the data is then transferred by PIO in blocks the drive chooses
+//! The discs are read-only, and exposed in 512 bytes sectors like every other disk
+use alloc::vec::Vec;
+use bit_field::BitField;
+
+use super::{AtaDisk, DiskCommand, DiskError, Reg, SECTOR_SIZE};
+
+/// Polls of the status before giving up, the drive may have to spin up the disc first
+const TIMEOUT: usize = 10_000_000;
+/// Bytes the drive may transfer per DRQ block, the most that fits in LBA mid and high as an even number
+const MAX_BYTE_COUNT: u16 = 0xF800;
+/// Reads with more blocks don't fit in READ(10)
+const MAX_READ10_BLOCKS: u64 = u16::MAX as u64;
+
+/// SCSI commands, see the SCSI Multimedia Commands (MMC)
+enum ScsiCommand {
+    TestUnitReady = 0x00,
+    StartStopUnit = 0x1B,
+    ReadCapacity = 0x25,
+    Read10 = 0x28,
+    Read12 = 0xA8,
+}
+/// Sense keys, in the upper nibble of the error register when a packet fails
+const NOT_READY: u8 = 0x2;
+const UNIT_ATTENTION: u8 = 0x6;
+
+/// The disc in the drive
+#[derive(Debug, Clone, Copy)]
+pub struct Media {
+    /// Bytes per block, 2048 for CDs
+    block_size: u32,
+    block_count: u64,
+}
+impl Media {
+    #[must_use] pub fn sector_count(&self) -> u64 {
+        self.block_count * self.sectors_per_block()
+    }
+    fn sectors_per_block(&self) -> u64 {
+        u64::from(self.block_size / u32::from(SECTOR_SIZE))
+    }
+}
+
+impl AtaDisk {
+    #[must_use] pub fn is_atapi(&self) -> bool {
+        matches!(self.drive_type, Some(super::DriveType::PATAPI | super::DriveType::SATAPI))
+    }
+    /// Called once IDENTIFY PACKET DEVICE succeeded, an empty drive is still a disk with no sectors
+    pub(super) fn init_packet_device(&mut self) {
+        self.dma_supported = false;
+        self.is_hdd = Some(false);
+        match self.refresh_media() {
+            Ok(()) => {}
+            Err(DiskError::NoMedia) => log::info!("No disc in the drive at {}", self.loc),
+            Err(err) => log::warn!("Couldn't read the capacity of the disc at {}: {:?}", self.loc, err),
+        }
+    }
+    /// Reads the capacity of the disc again, i.e. after it was changed
+    pub fn refresh_media(&mut self) -> Result<(), DiskError> {
+        self.media = None;
+        // The first command after a disc change fails with a unit attention, which clears it
+        if let Err(DiskError::MediaChanged) = self.packet(ScsiCommand::TestUnitReady, &[0; 11], 0) {
+            self.packet(ScsiCommand::TestUnitReady, &[0; 11], 0)?;
+        }
+        let capacity = self.packet(ScsiCommand::ReadCapacity, &[0; 11], 8)?;
+        let last_block = u32::from_be_bytes(capacity[0..4].try_into().unwrap());
+        let block_size = u32::from_be_bytes(capacity[4..8].try_into().unwrap());
+        if block_size == 0 || block_size % u32::from(SECTOR_SIZE) != 0 {
+            log::error!("Unsupported block size {} on the disc at {}", block_size, self.loc);
+            return Err(DiskError::NoReadModeAvailable);
+        }
+        let media = Media {
+            block_size,
+            block_count: u64::from(last_block) + 1,
+        };
+        log::info!("Disc at {} has {} blocks of {} bytes", self.loc, media.block_count, block_size);
+        self.media = Some(media);
+        Ok(())
+    }
+    /// Sectors of 512 bytes, the blocks they are in are read with READ(10), or READ(12) when there are too many of them
+    /// Fails with `MediaChanged` once after the disc was changed, the data cached from the previous one has to be dropped
+    pub fn read_packet_sectors(&mut self, start_sector: u64, sector_count: u64) -> Result<Vec<u8>, DiskError> {
+        if self.media.is_none() {
+            self.refresh_media()?;
+        }
+        let media = self.media.ok_or(DiskError::NoMedia)?;
+        if start_sector + sector_count > media.sector_count() {
+            return Err(DiskError::SectorTooBig);
+        }
+        let first_block = start_sector / media.sectors_per_block();
+        let end_block = (start_sector + sector_count).div_ceil(media.sectors_per_block());
+        let block_count = end_block - first_block;
+        let lba = u32::try_from(first_block).or(Err(DiskError::SectorTooBig))?.to_be_bytes();
+        let len = usize::try_from(block_count * u64::from(media.block_size)).or(Err(DiskError::SectorTooBig))?;
+        let result = if block_count <= MAX_READ10_BLOCKS {
+            let count = (block_count as u16).to_be_bytes();
+            let params = [0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0, 0, 0];
+            self.packet(ScsiCommand::Read10, &params, len)
+        } else {
+            let count = u32::try_from(block_count).or(Err(DiskError::SectorTooBig))?.to_be_bytes();
+            let params = [0, lba[0], lba[1], lba[2], lba[3], count[0], count[1], count[2], count[3], 0, 0];
+            self.packet(ScsiCommand::Read12, &params, len)
+        };
+        let mut data = self.media_result(result)?;
+        let offset = (start_sector - first_block * media.sectors_per_block()) * u64::from(SECTOR_SIZE);
+        data.drain(..offset as usize);
+        data.truncate((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        Ok(data)
+    }
+    /// Sends TEST UNIT READY, so that nothing read from a previous disc is used once it was changed or removed
+    /// Fails with `MediaChanged` once after the disc was changed, like `read_packet_sectors`
+    pub fn check_media(&mut self) -> Result<(), DiskError> {
+        let result = self.packet(ScsiCommand::TestUnitReady, &[0; 11], 0);
+        self.media_result(result).map(|_| ())
+    }
+    /// Reads the capacity of the new disc when the command failed because the disc was changed
+    fn media_result<T>(&mut self, result: Result<T, DiskError>) -> Result<T, DiskError> {
+        match result {
+            Err(DiskError::MediaChanged) => {
+                log::warn!("The disc at {} was changed", self.loc);
+                let _ = self.refresh_media();
+            }
+            Err(DiskError::NoMedia) => self.media = None,
+            _ => {}
+        }
+        result
+    }
+    /// Opens the tray, or closes it and reads the new disc when `load` is set
+    pub fn eject(&mut self, load: bool) -> Result<(), DiskError> {
+        // LoEj, then Start to load the disc instead of ejecting it
+        let mut flags = 0_u8;
+        flags.set_bit(1, true);
+        flags.set_bit(0, load);
+        self.packet(ScsiCommand::StartStopUnit, &[0, 0, 0, flags, 0, 0, 0, 0, 0, 0, 0], 0)?;
+        self.media = None;
+        if load {
+            self.refresh_media()?;
+        }
+        Ok(())
+    }
+    /// Sends the 12 bytes SCSI command, the command byte then `params`, and reads the `len` bytes it returns
+    fn packet(&self, command: ScsiCommand, params: &[u8; 11], len: usize) -> Result<Vec<u8>, DiskError> {
+        let mut packet = [0_u8; 12];
+        packet[0] = command as u8;
+        packet[1..].copy_from_slice(params);
+        self.wait_packet_status(false)?;
+        // PIO, no overlap
+        self.write_reg(Reg::Features, 0_u8);
+        self.write_reg(Reg::LbaMi, MAX_BYTE_COUNT as u8);
+        self.write_reg(Reg::LbaHi, (MAX_BYTE_COUNT >> 8) as u8);
+        self.command(DiskCommand::Packet);
+        if !self.wait_packet_status(true)? {
+            log::error!("ATAPI drive at {} didn't ask for the packet", self.loc);
+            return Err(DiskError::DRQRead);
+        }
+        for word in packet.chunks_exact(2) {
+            self.write_reg(Reg::Data, u16::from_le_bytes([word[0], word[1]]));
+        }
+        let mut data = Vec::with_capacity(len);
+        // Each DRQ block has the byte count the drive chose
+        while self.wait_packet_status(false)? {
+            let byte_count =
+                usize::from(self.read_reg::<u8>(Reg::LbaMi)) | (usize::from(self.read_reg::<u8>(Reg::LbaHi)) << 8);
+            for _ in 0..byte_count.div_ceil(2) {
+                let word = self.read_reg::<u16>(Reg::Data);
+                data.extend_from_slice(&word.to_le_bytes());
+            }
+        }
+        data.truncate(len);
+        if data.len() < len {
+            log::error!("ATAPI drive at {} returned {} of {} bytes", self.loc, data.len(), len);
+            return Err(DiskError::ReadDataNotAvailable);
+        }
+        Ok(data)
+    }
+    /// Waits for BSY to clear, returns if DRQ is set, or with `drq` waits for it
+    /// The error of the drive is its sense key
+    fn wait_packet_status(&self, drq: bool) -> Result<bool, DiskError> {
+        // 400ns for the status to be valid
+        for _ in 0..4 {
+            let _ = self.alternate_status();
+        }
+        for _ in 0..TIMEOUT {
+            let status = self.alternate_status();
+            if status.get_bit(7) {
+                core::hint::spin_loop();
+                continue;
+            }
+            if status.get_bit(0) {
+                // Reading the status also acknowledges the interrupt
+                let _ = self.read_reg::<u8>(Reg::Status);
+                return Err(match self.error() >> 4 {
+                    NOT_READY => DiskError::NoMedia,
+                    UNIT_ATTENTION => DiskError::MediaChanged,
+                    sense_key => {
+                        log::error!("ATAPI command failed at {} with sense key {:#x}", self.loc, sense_key);
+                        DiskError::CommandFailed(u16::from(sense_key))
+                    }
+                });
+            }
+            if status.get_bit(3) || !drq {
+                let _ = self.read_reg::<u8>(Reg::Status);
+                return Ok(status.get_bit(3));
+            }
+            core::hint::spin_loop();
+        }
+        log::error!("ATAPI drive at {} timed out", self.loc);
+        Err(DiskError::TimeOut)
+    }
+}
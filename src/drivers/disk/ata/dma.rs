@@ -0,0 +1,190 @@
+//! Bus master IDE, see https://wiki.osdev.org/ATA/ATAPI_using_DMA
+//! The transfers are described by a PRDT, the drive raises IRQ 14 or 15 when done
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use alloc::vec::Vec;
+use bit_field::BitField;
+use x86_64::{
+    instructions::port::{PortRead, PortWrite},
+    VirtAddr,
+};
+
+use crate::disk::{dma_page, PAGE_SIZE};
+
+use super::{irq, Channel, DiskError};
+
+/// Pages the data is copied through, one PRDT entry each, so the most a single command transfers is 128KiB
+const MAX_TRANSFER_PAGES: usize = 32;
+/// Polls of the bus master status before giving up
+const TIMEOUT: usize = 10_000_000;
+/// Polls after the transfer is done before we stop waiting for the interrupt
+const IRQ_TIMEOUT: usize = 1_000_000;
+
+/// Registers of a channel, offsets from its bus master base
+enum BusMasterReg {
+    Command = 0,
+    Status = 2,
+    PrdtAddress = 4,
+}
+/// Bits of the command register
+const START: usize = 0;
+/// Set when the controller writes to memory, so for reads from the disk
+const READ: usize = 3;
+/// Bits of the status register
+const ERROR: usize = 1;
+const INTERRUPT: usize = 2;
+
+/// I/O base of the bus master registers, the secondary channel is 8 ports after the primary one
+#[must_use] pub fn bus_master_base(bar4: u16, channel: Channel) -> u16 {
+    match channel {
+        Channel::Primary => bar4,
+        Channel::Secondary => bar4 + 8,
+    }
+}
+
+#[derive(Debug)]
+pub struct BusMaster {
+    base: u16,
+    channel: Channel,
+    /// The physical region descriptor table, a page so that it doesn't cross a 64KiB boundary
+    prdt: VirtAddr,
+    /// Identity mapped pages the data is copied through, they aren't contiguous so each one is a PRDT entry
+    buffer: Vec<VirtAddr>,
+    /// If the drive doesn't interrupt, completion is then polled
+    polling: AtomicBool,
+    /// If an interrupt was seen, only then can a task sleep until the next one
+    irq_seen: AtomicBool,
+}
+impl BusMaster {
+    /// None if the memory can't be used by the controller, which only takes 32 bits addresses
+    #[must_use] pub fn new(base: u16, channel: Channel) -> Option<Self> {
+        let mut pages = (0..=MAX_TRANSFER_PAGES).map(|_| {
+            let page = dma_page()?;
+            (page.as_u64() + PAGE_SIZE as u64 <= 1 << 32).then_some(page)
+        });
+        let prdt = pages.next()??;
+        let buffer = pages.collect::<Option<Vec<_>>>()?;
+        irq::BUS_MASTER_BASES[channel_index(channel)].store(base, Ordering::Release);
+        Some(Self {
+            base,
+            channel,
+            prdt,
+            buffer,
+            polling: AtomicBool::new(false),
+            irq_seen: AtomicBool::new(false),
+        })
+    }
+    #[must_use] pub fn max_transfer_sectors() -> u16 {
+        (MAX_TRANSFER_PAGES * PAGE_SIZE / usize::from(super::SECTOR_SIZE)) as u16
+    }
+    fn read_reg<T: PortRead>(&self, reg: BusMasterReg) -> T {
+        unsafe { T::read_from_port(self.base + reg as u16) }
+    }
+    fn write_reg<T: PortWrite>(&self, reg: BusMasterReg, value: T) {
+        unsafe { T::write_to_port(self.base + reg as u16, value) }
+    }
+    /// Fills the PRDT with the first `len` bytes of the buffer, the command to the drive is sent after this
+    pub fn prepare(&self, len: usize, read: bool) {
+        let prdt = self.prdt.as_mut_ptr::<u32>();
+        let entries = len.div_ceil(PAGE_SIZE);
+        for (i, page) in self.buffer.iter().enumerate().take(entries) {
+            let page_len = (len - i * PAGE_SIZE).min(PAGE_SIZE) as u32;
+            let mut flags = page_len;
+            // End of table
+            flags.set_bit(31, i == entries - 1);
+            unsafe {
+                prdt.add(i * 2).write_volatile(page.as_u64() as u32);
+                prdt.add(i * 2 + 1).write_volatile(flags);
+            }
+        }
+        self.write_reg(BusMasterReg::Command, 0_u8);
+        self.write_reg(BusMasterReg::PrdtAddress, self.prdt.as_u64() as u32);
+        let mut command = 0_u8;
+        self.write_reg(BusMasterReg::Command, *command.set_bit(READ, read));
+        // Clears the error and interrupt bits
+        let status = self.read_reg::<u8>(BusMasterReg::Status);
+        self.write_reg(BusMasterReg::Status, status | (1 << ERROR) | (1 << INTERRUPT));
+        irq::DMA_STATUS[channel_index(self.channel)].store(0, Ordering::Release);
+        irq::DMA_DONE[channel_index(self.channel)].reset();
+    }
+    /// Starts the transfer, once the drive got the command
+    pub fn start(&self) {
+        let mut command = self.read_reg::<u8>(BusMasterReg::Command);
+        self.write_reg(BusMasterReg::Command, *command.set_bit(START, true));
+    }
+    /// Waits for the interrupt of the drive, then stops the transfer
+    /// The status of the drive should then be checked
+    pub fn wait(&self) -> Result<(), DiskError> {
+        let events = &irq::DMA_STATUS[channel_index(self.channel)];
+        let mut done_polls = 0;
+        let mut result = None;
+        for _ in 0..TIMEOUT {
+            let status = events.load(Ordering::Acquire);
+            if status.get_bit(INTERRUPT) {
+                self.irq_seen.store(true, Ordering::Relaxed);
+                result = Some(status);
+                break;
+            }
+            let status = self.read_reg::<u8>(BusMasterReg::Status);
+            if status.get_bit(INTERRUPT) || status.get_bit(ERROR) {
+                if self.polling.load(Ordering::Relaxed) {
+                    result = Some(status);
+                    break;
+                }
+                done_polls += 1;
+                if done_polls > IRQ_TIMEOUT {
+                    log::warn!("[ATA] No interrupt from the {:?} channel, polling it from now", self.channel);
+                    self.polling.store(true, Ordering::Relaxed);
+                    result = Some(status);
+                    break;
+                }
+            }
+            core::hint::spin_loop();
+        }
+        self.finish(result)
+    }
+    /// If a task can sleep until the interrupt, only once the channel is known to send it as nothing would wake the task otherwise
+    #[must_use] pub fn interrupts(&self) -> bool {
+        self.irq_seen.load(Ordering::Relaxed) && !self.polling.load(Ordering::Relaxed)
+    }
+    /// Stops the transfer and clears the status, `result` being the status it ended with
+    fn finish(&self, result: Option<u8>) -> Result<(), DiskError> {
+        let mut command = self.read_reg::<u8>(BusMasterReg::Command);
+        self.write_reg(BusMasterReg::Command, *command.set_bit(START, false));
+        let status = self.read_reg::<u8>(BusMasterReg::Status);
+        self.write_reg(BusMasterReg::Status, status | (1 << ERROR) | (1 << INTERRUPT));
+        match result {
+            Some(status) if status.get_bit(ERROR) => {
+                log::error!("[ATA] DMA transfer failed on the {:?} channel", self.channel);
+                Err(DiskError::CommandFailed(u16::from(status)))
+            }
+            Some(_) => Ok(()),
+            None => {
+                log::error!("[ATA] DMA transfer timed out on the {:?} channel", self.channel);
+                Err(DiskError::TimeOut)
+            }
+        }
+    }
+    pub fn copy_to_buffer(&self, content: &[u8], len: usize) {
+        for (i, page) in self.buffer.iter().enumerate().take(len.div_ceil(PAGE_SIZE)) {
+            let page = unsafe { core::slice::from_raw_parts_mut(page.as_mut_ptr::<u8>(), PAGE_SIZE) };
+            let data = content.get(i * PAGE_SIZE..).unwrap_or_default();
+            let data = &data[..data.len().min(PAGE_SIZE)];
+            page[..data.len()].copy_from_slice(data);
+            page[data.len()..].fill(0);
+        }
+    }
+    pub fn copy_from_buffer(&self, len: usize, data: &mut Vec<u8>) {
+        for (i, page) in self.buffer.iter().enumerate().take(len.div_ceil(PAGE_SIZE)) {
+            let page_len = (len - i * PAGE_SIZE).min(PAGE_SIZE);
+            data.extend_from_slice(unsafe { core::slice::from_raw_parts(page.as_ptr::<u8>(), page_len) });
+        }
+    }
+}
+
+#[must_use] pub fn channel_index(channel: Channel) -> usize {
+    match channel {
+        Channel::Primary => 0,
+        Channel::Secondary => 1,
+    }
+}
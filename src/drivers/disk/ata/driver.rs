@@ -1,17 +1,80 @@
-use super::{AtaDisk, DiskDriver, DiskError, DiskLoc, SELECTED_DISK, Vec};
+use spin::RwLock;
+
+use crate::{disk::driver::InFlight, sync::TimeOutRwLock};
+
+use super::{dma, irq, AtaDisk, DiskDriver, DiskError, DiskLoc, SECTOR_SIZE, SELECTED_DISK, Vec};
 
 #[derive(Debug)]
 pub struct AtaDriver {
     selected_disk: u8,
     disks: [Option<AtaDisk>; 4],
+    /// The DMA transfer a task sleeps on for each channel, with the index of its disk
+    in_flight: [Option<InFlight<usize>>; 2],
 }
 impl AtaDriver {
     #[must_use] pub fn new(disks: [Option<AtaDisk>; 4]) -> Self {
         Self {
             selected_disk: 0,
             disks,
+            in_flight: [None, None],
         }
     }
+    /// Waits for the transfer a task sleeps on, if there is one on the channel of the disk, so that the channel can be used
+    fn collect_in_flight(&mut self, loc: &DiskLoc) {
+        let channel = dma::channel_index(loc.channel());
+        if let Some(mut command) = self.in_flight[channel].take() {
+            if !command.is_collected() {
+                let disk = self.disks[command.command].as_ref().expect("The disk of a transfer exists");
+                command.result = Some(disk.finish_dma(command.read_len));
+                // The interrupt may have come before the task slept, or not at all if the channel stopped sending it
+                irq::DMA_DONE[channel].set();
+            }
+            self.in_flight[channel] = Some(command);
+        }
+    }
+    /// Sends one DMA transfer and sleeps until it's done, the driver is only locked to send it and to collect it
+    /// The transfer is made synchronously by `fallback` when the task can't sleep on it
+    async fn run_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        lba: u64,
+        sector_count: u16,
+        content: Option<&[u8]>,
+        fallback: impl FnOnce(&mut Self) -> Result<Vec<u8>, DiskError>,
+    ) -> Result<Vec<u8>, DiskError> {
+        let channel = dma::channel_index(loc.channel());
+        {
+            let mut drv = lock.write_with_timeout();
+            drv.collect_in_flight(loc);
+            let disk = drv.disks[loc.as_index()].as_ref().ok_or(DiskError::NotFound)?;
+            // A result is still there when the task of the previous transfer isn't back yet
+            if !disk.sleeps_on_dma() || drv.in_flight[channel].is_some() {
+                return fallback(&mut drv);
+            }
+            drv.select_disk(loc);
+            drv.disks[loc.as_index()].as_ref().unwrap().send_dma(lba, sector_count, content)?;
+            let read_len = match content {
+                Some(_) => 0,
+                None => usize::from(sector_count) * usize::from(SECTOR_SIZE),
+            };
+            drv.in_flight[channel] = Some(InFlight::new(loc.as_index(), read_len));
+        }
+        irq::DMA_DONE[channel]
+            .wait_until(|| {
+                lock.read_with_timeout().in_flight[channel]
+                    .as_ref()
+                    .is_some_and(InFlight::is_collected)
+            })
+            .await;
+        let mut drv = lock.write_with_timeout();
+        let command = drv.in_flight[channel].take().expect("Only the task takes its transfer");
+        command.result.unwrap_or_else(|| {
+            drv.disks[command.command]
+                .as_ref()
+                .expect("The disk of a transfer exists")
+                .finish_dma(command.read_len)
+        })
+    }
 }
 impl super::DiskDriver for AtaDriver {
     fn read(
@@ -20,15 +83,68 @@ impl super::DiskDriver for AtaDriver {
         start_sector: u64,
         sector_count: u64,
     ) -> Result<Vec<u8>, DiskError> {
+        self.collect_in_flight(loc);
         self.select_disk(loc);
-        self.disks[loc.as_index()]
-            .as_mut()
-            .ok_or(DiskError::NotFound)?
-            .read_sectors(start_sector, sector_count.try_into().unwrap())
+        let disk = self.disks[loc.as_index()].as_mut().ok_or(DiskError::NotFound)?;
+        if disk.is_atapi() {
+            return disk.read_packet_sectors(start_sector, sector_count);
+        }
+        disk.read_sectors(start_sector, sector_count.try_into().unwrap())
     }
 
     fn write(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
-        todo!()
+        self.collect_in_flight(loc);
+        self.select_disk(loc);
+        let disk = self.disks[loc.as_index()].as_mut().ok_or(DiskError::NotFound)?;
+        if disk.is_atapi() {
+            return Err(DiskError::ReadOnly);
+        }
+        disk.write_sectors(start_sector, content)
+    }
+
+    fn sector_count(&self, loc: &DiskLoc) -> Result<u64, DiskError> {
+        Ok(self.disks[loc.as_index()]
+            .as_ref()
+            .ok_or(DiskError::NotFound)?
+            .size())
+    }
+
+    /// The transfers are split in the ones the bus master buffer can hold, each one is sent on its own
+    async fn read_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let mut data = Vec::with_capacity((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        let mut sector = start_sector;
+        while sector < start_sector + sector_count {
+            let count = (start_sector + sector_count - sector).min(u64::from(dma::BusMaster::max_transfer_sectors()));
+            let fallback = |drv: &mut Self| drv.read(loc, sector, count);
+            data.extend(Self::run_async(lock, loc, sector, count as u16, None, fallback).await?);
+            sector += count;
+        }
+        Ok(data)
+    }
+
+    async fn write_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        let max_len = usize::from(dma::BusMaster::max_transfer_sectors()) * usize::from(SECTOR_SIZE);
+        let mut sector = start_sector;
+        for chunk in content.chunks(max_len) {
+            let count = chunk.len().div_ceil(usize::from(SECTOR_SIZE));
+            let fallback = |drv: &mut Self| drv.write(loc, sector, chunk).map(|()| Vec::new());
+            Self::run_async(lock, loc, sector, count as u16, Some(chunk), fallback).await?;
+            sector += count as u64;
+        }
+        let mut drv = lock.write_with_timeout();
+        drv.collect_in_flight(loc);
+        drv.select_disk(loc);
+        drv.disks[loc.as_index()].as_ref().ok_or(DiskError::NotFound)?.flush()
     }
 
     fn select_disk(&mut self, loc: &DiskLoc) {
@@ -45,4 +161,28 @@ impl AtaDriver {
     #[must_use] pub fn selected_disk(&self) -> DiskLoc {
         DiskLoc::from_idx(self.selected_disk).unwrap()
     }
+    /// CD drives, the other disks can be written
+    #[must_use] pub fn is_read_only(&self, loc: &DiskLoc) -> bool {
+        self.disks[loc.as_index()].as_ref().is_some_and(AtaDisk::is_atapi)
+    }
+    /// Checks that the disc of a CD drive is still the same, the other disks can't be changed
+    /// See `AtaDisk::check_media`
+    pub fn check_media(&mut self, loc: &DiskLoc) -> Result<(), DiskError> {
+        if !self.disks[loc.as_index()].as_ref().ok_or(DiskError::NotFound)?.is_atapi() {
+            return Ok(());
+        }
+        self.collect_in_flight(loc);
+        self.select_disk(loc);
+        self.disks[loc.as_index()].as_mut().unwrap().check_media()
+    }
+    /// Opens the tray of a CD drive, or closes it with `load`
+    pub fn eject(&mut self, loc: &DiskLoc, load: bool) -> Result<(), DiskError> {
+        self.collect_in_flight(loc);
+        self.select_disk(loc);
+        let disk = self.disks[loc.as_index()].as_mut().ok_or(DiskError::NotFound)?;
+        if !disk.is_atapi() {
+            return Err(DiskError::NotFound);
+        }
+        disk.eject(load)
+    }
 }
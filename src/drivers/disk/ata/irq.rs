@@ -1,14 +1,41 @@
-use super::{DiskLoc, SELECTED_DISK};
+use core::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+
+use bit_field::BitField;
+use x86_64::instructions::port::{PortRead, PortWrite};
+
+use crate::task::event::Event;
+
+use super::Channel;
+
+/// Bus master I/O base of each channel, 0 when the channel only does PIO
+pub static BUS_MASTER_BASES: [AtomicU16; 2] = [const { AtomicU16::new(0) }; 2];
+/// Bus master status of each channel, gathered by the interrupt until the DMA transfer waiting on it takes it
+pub static DMA_STATUS: [AtomicU8; 2] = [const { AtomicU8::new(0) }; 2];
+/// Set with `DMA_STATUS`, wakes the task waiting on the transfer
+pub static DMA_DONE: [Event; 2] = [const { Event::new() }; 2];
 
 pub fn primary_bus_irq() {
-    common();
+    common(Channel::Primary);
 }
 pub fn secondary_bus_irq() {
-    common();
+    common(Channel::Secondary);
 }
 
-pub fn common() {
-    let raw_selected_disk = SELECTED_DISK.load(core::sync::atomic::Ordering::Acquire);
-    let selected_disk = DiskLoc::from_idx(raw_selected_disk).unwrap();
-    // crate::dbg!(selected_disk);
+/// Acknowledges the interrupt of the drive if a DMA transfer ended
+pub fn common(channel: Channel) {
+    let idx = super::dma::channel_index(channel);
+    let base = BUS_MASTER_BASES[idx].load(Ordering::Acquire);
+    if base == 0 {
+        return;
+    }
+    let status = unsafe { u8::read_from_port(base + 2) };
+    if !status.get_bit(2) {
+        return;
+    }
+    // Reading the status register of the drive clears its interrupt
+    let _ = unsafe { u8::read_from_port(channel as u16 + 7) };
+    // The error and interrupt bits are cleared by writing 1 to them
+    unsafe { u8::write_to_port(base + 2, status) };
+    DMA_STATUS[idx].fetch_or(status, Ordering::AcqRel);
+    DMA_DONE[idx].set();
 }
@@ -22,6 +22,8 @@ use crate::{dbg, disk_manager};
 use super::driver::{Disk, DiskDriver, DiskDriverEnum, DiskDriverType, GenericDisk, SECTOR_SIZE};
 use super::{DiskError, DiskLoc};
 
+pub mod atapi;
+pub mod dma;
 pub mod driver;
 pub mod irq;
 
@@ -32,27 +34,29 @@ pub static SELECTED_DISK: core::sync::atomic::AtomicU8 = AtomicU8::new(0);
 /// Primary Channel:   Slave & Master
 /// Secondary Channel: Slave & Master
 /// So the IDE controller only has a max of 4 drives
+/// The disks use DMA when the controller and the drive can, PIO otherwise
 pub fn init(ide: &PciDevice) -> Vec<super::driver::Disk> {
+    let bar4 = bus_master_bar(ide);
     // // set bit 1 to disable interrupts
     // unsafe { u8::write_to_port(0x376, 1 << 2) }
     // unsafe { u8::write_to_port(0x3f6, 0) }
     // unsafe { u8::write_to_port(0x376, 0) }
     let raw_disks = [
         (
-            detect(&DiskLoc(Channel::Primary, Drive::Master)),
-            DiskLoc(Channel::Primary, Drive::Master),
+            detect(&DiskLoc::Ata(Channel::Primary, Drive::Master), bar4),
+            DiskLoc::Ata(Channel::Primary, Drive::Master),
         ),
         (
-            detect(&DiskLoc(Channel::Primary, Drive::Slave)),
-            DiskLoc(Channel::Primary, Drive::Slave),
+            detect(&DiskLoc::Ata(Channel::Primary, Drive::Slave), bar4),
+            DiskLoc::Ata(Channel::Primary, Drive::Slave),
         ),
         (
-            detect(&DiskLoc(Channel::Secondary, Drive::Master)),
-            DiskLoc(Channel::Secondary, Drive::Master),
+            detect(&DiskLoc::Ata(Channel::Secondary, Drive::Master), bar4),
+            DiskLoc::Ata(Channel::Secondary, Drive::Master),
         ),
         (
-            detect(&DiskLoc(Channel::Secondary, Drive::Slave)),
-            DiskLoc(Channel::Secondary, Drive::Slave),
+            detect(&DiskLoc::Ata(Channel::Secondary, Drive::Slave), bar4),
+            DiskLoc::Ata(Channel::Secondary, Drive::Slave),
         ),
     ];
     let mut disks = Vec::with_capacity(4);
@@ -74,9 +78,34 @@ pub fn init(ide: &PciDevice) -> Vec<super::driver::Disk> {
     };
     gen_disks
 }
+/// I/O base of the bus master registers, if the controller can do DMA
+fn bus_master_bar(ide: &PciDevice) -> Option<u16> {
+    // Bit 7 of the programming interface is set for bus master IDE controllers
+    if !ide.raw.prog_if.get_bit(7) {
+        return None;
+    }
+    match ide.raw.determine_mem_base(4).ok()? {
+        PciMemoryBase::IOSpace(io) if io != 0 => {
+            // Enable bus mastering & I/O space
+            let mut command = ide.raw.command;
+            command.set_bit(2, true);
+            command.set_bit(0, true);
+            ide.raw
+                .location
+                .pci_write(crate::pci::PCI_COMMAND, u32::from(command));
+            io.try_into().ok()
+        }
+        _ => None,
+    }
+}
 pub enum DiskCommand {
     Reset = 0x90,
     ReadSectorsExt = 0x24,
+    ReadDmaExt = 0x25,
+    WriteSectorsExt = 0x34,
+    WriteDmaExt = 0x35,
+    Packet = 0xA0,
+    IdentifyPacket = 0xA1,
     CacheFlush = 0xEA,
 }
 #[repr(u8)]
@@ -156,6 +185,12 @@ pub struct AtaDisk {
     drive_type: Option<DriveType>,
     addressing_modes: Option<(bool, u32, u64)>,
     is_hdd: Option<bool>,
+    /// Set by IDENTIFY
+    dma_supported: bool,
+    /// Used for the LBA48 reads and writes when set
+    dma: Option<dma::BusMaster>,
+    /// The disc of ATAPI drives, None if there is none
+    media: Option<atapi::Media>,
 }
 impl AtaDisk {
     #[must_use] pub fn new(loc: DiskLoc, iobase: u16, control_base: u16) -> Self {
@@ -167,9 +202,15 @@ impl AtaDisk {
             drive_type: None,
             addressing_modes: None,
             is_hdd: None,
+            dma_supported: false,
+            dma: None,
+            media: None,
         }
     }
     #[must_use] pub fn size(&self) -> u64 {
+        if self.is_atapi() {
+            return self.media.map_or(0, |media| media.sector_count());
+        }
         if let Some(addressing_modes) = self.addressing_modes {
             if addressing_modes.2 != 0 {
                 addressing_modes.2
@@ -257,8 +298,8 @@ impl AtaDisk {
             self.iobase
         );
         let drive_type = self.drive_type();
-        let identify_command = if drive_type == DriveType::PATAPI {
-            0xA1
+        let identify_command = if matches!(drive_type, DriveType::PATAPI | DriveType::SATAPI) {
+            DiskCommand::IdentifyPacket as u8
         } else {
             0xEC_u8
         };
@@ -281,7 +322,9 @@ impl AtaDisk {
         unsafe {
             bsy(self.iobase);
         };
-        if self.read_reg::<u8>(Reg::LbaMi) != 0 || self.read_reg::<u8>(Reg::LbaHi) != 0 {
+        // IDENTIFY PACKET DEVICE answers with data, the signature of ATAPI drives stays in LBA mid and high
+        let is_packet = identify_command == DiskCommand::IdentifyPacket as u8;
+        if !is_packet && (self.read_reg::<u8>(Reg::LbaMi) != 0 || self.read_reg::<u8>(Reg::LbaHi) != 0) {
             trace!("ATAPI drive detected !");
         } else if unsafe { check_drq_or_err(self.iobase) }.is_err() {
             error!(
@@ -292,6 +335,11 @@ impl AtaDisk {
             return Err(DiskError::DiskNotFound);
         }
         let identify = read_identify(self.iobase);
+        if is_packet {
+            self.drive_type = Some(drive_type);
+            self.init_packet_device();
+            return Ok(());
+        }
         // core::ffi::CStr
         // info!("Serial number: {:?}\tFirmware revision: {:?}\tModel number: {:?}", &char_identify[20..40], &char_identify[46..52], &char_identify[54..92]);
 
@@ -316,6 +364,8 @@ impl AtaDisk {
         self.addressing_modes = Some((chs, lba28, lba48));
         self.is_hdd = Some(is_hardisk);
         self.drive_type = Some(drive_type);
+        // Bit 8 of word 49 is DMA support
+        self.dma_supported = identify[49].get_bit(8);
 
         Ok(())
     }
@@ -354,13 +404,70 @@ impl AtaDisk {
     //48Bit Lba PIO mode
     // 0 for sector_count is equals to u16::MAX
     pub fn read48(&self, lba: u64, sector_count: u16) -> Result<Vec<u8>, DiskError> {
-        if lba + u64::from(sector_count) >= unsafe { self.addressing_modes.unwrap_unchecked().2 } {
+        if lba + u64::from(sector_count) > self.addressing_modes.ok_or(DiskError::Unitialised)?.2 {
             log::error!(
                 "Trying to read sector outside of disk ! {lba}-{}",
                 lba + u64::from(sector_count)
             );
             return Err(DiskError::SectorTooBig);
         }
+        if self.dma.is_some() {
+            return self.read_dma(lba, sector_count);
+        }
+        self.setup_lba48(lba, sector_count);
+        self.command(DiskCommand::ReadSectorsExt); // READ SECTORS EXT
+
+        self.retrieve_read(sector_count)
+    }
+    //48Bit Lba DMA mode, split in transfers the bus master buffer can hold
+    fn read_dma(&self, lba: u64, sector_count: u16) -> Result<Vec<u8>, DiskError> {
+        let mut buffer = Vec::with_capacity(sector_count as usize * 512);
+        let mut sector = 0;
+        while sector < sector_count {
+            let count = (sector_count - sector).min(dma::BusMaster::max_transfer_sectors());
+            self.send_dma(lba + u64::from(sector), count, None)?;
+            buffer.extend(self.finish_dma(usize::from(count) * usize::from(SECTOR_SIZE))?);
+            sector += count;
+        }
+        Ok(buffer)
+    }
+    /// Starts a DMA transfer the buffer can hold, `finish_dma` then waits for it
+    /// The content is written if there is one, else the sectors are read
+    fn send_dma(&self, lba: u64, sector_count: u16, content: Option<&[u8]>) -> Result<(), DiskError> {
+        let dma = self.dma.as_ref().ok_or(DiskError::NoReadModeAvailable)?;
+        if lba + u64::from(sector_count) > self.size() {
+            return Err(DiskError::SectorTooBig);
+        }
+        let len = usize::from(sector_count) * usize::from(SECTOR_SIZE);
+        if let Some(content) = content {
+            dma.copy_to_buffer(content, len);
+        }
+        dma.prepare(len, content.is_none());
+        self.setup_lba48(lba, sector_count);
+        match content {
+            Some(_) => self.command(DiskCommand::WriteDmaExt), // WRITE DMA EXT
+            None => self.command(DiskCommand::ReadDmaExt),     // READ DMA EXT
+        }
+        dma.start();
+        Ok(())
+    }
+    /// Waits for the DMA transfer, then copies the first `read_len` bytes of the buffer
+    fn finish_dma(&self, read_len: usize) -> Result<Vec<u8>, DiskError> {
+        let dma = self.dma.as_ref().ok_or(DiskError::NoReadModeAvailable)?;
+        dma.wait()?;
+        self.wait_not_busy()?;
+        let mut data = Vec::with_capacity(read_len);
+        dma.copy_from_buffer(read_len, &mut data);
+        Ok(data)
+    }
+    /// If a task can sleep on the DMA transfers of the disk, see `BusMaster::interrupts`
+    fn sleeps_on_dma(&self) -> bool {
+        !self.is_atapi()
+            && self.addressing_modes.is_some_and(|modes| modes.2 != 0)
+            && self.dma.as_ref().is_some_and(dma::BusMaster::interrupts)
+    }
+    /// Fills the LBA48 registers, high bytes first
+    fn setup_lba48(&self, lba: u64, sector_count: u16) {
         self.write_reg(Reg::DriveHead, self.loc.drive_lba48_addr());
         self.write_reg(Reg::Data, self.read_reg::<u8>(Reg::Data) | 0x80);
 
@@ -375,9 +482,6 @@ impl AtaDisk {
         self.write_reg(Reg::LbaLo, lba as u8); // LBA1
         self.write_reg(Reg::LbaMi, (lba >> 8) as u8); // LBA2
         self.write_reg(Reg::LbaHi, (lba >> 16) as u8); // LBA3
-        self.command(DiskCommand::ReadSectorsExt); // READ SECTORS EXT
-
-        self.retrieve_read(sector_count)
     }
     fn retrieve_read(&self, sector_count: u16) -> Result<Vec<u8>, DiskError> {
         trace!("Retrieving read !");
@@ -397,8 +501,47 @@ impl AtaDisk {
         Ok(buffer)
     }
 
-    pub fn write48(&self, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
-        todo!()
+    //48Bit Lba PIO mode, content is padded with zeroes to a full sector
+    pub fn write48(&self, lba: u64, content: &[u8]) -> Result<(), DiskError> {
+        if content.is_empty() {
+            return Ok(());
+        }
+        let sector_count: u16 = content
+            .len()
+            .div_ceil(SECTOR_SIZE as usize)
+            .try_into()
+            .or(Err(DiskError::SectorTooBig))?;
+        if lba + u64::from(sector_count) > self.addressing_modes.ok_or(DiskError::Unitialised)?.2 {
+            log::error!(
+                "Trying to write sector outside of disk ! {lba}-{}",
+                lba + u64::from(sector_count)
+            );
+            return Err(DiskError::SectorTooBig);
+        }
+        if self.dma.is_some() {
+            return self.write_dma(lba, content);
+        }
+        self.setup_lba48(lba, sector_count);
+        self.command(DiskCommand::WriteSectorsExt); // WRITE SECTORS EXT
+
+        self.send_write(content)
+    }
+    //48Bit Lba DMA mode, split in transfers the bus master buffer can hold
+    fn write_dma(&self, lba: u64, content: &[u8]) -> Result<(), DiskError> {
+        let max_len = usize::from(dma::BusMaster::max_transfer_sectors()) * usize::from(SECTOR_SIZE);
+        let mut lba = lba;
+        for chunk in content.chunks(max_len) {
+            let count = chunk.len().div_ceil(usize::from(SECTOR_SIZE));
+            self.send_dma(lba, count as u16, Some(chunk))?;
+            self.finish_dma(0)?;
+            lba += count as u64;
+        }
+        self.flush()
+    }
+    /// Cache flush
+    fn flush(&self) -> Result<(), DiskError> {
+        self.command(DiskCommand::CacheFlush);
+        self.wait_not_busy()
     }
     //     let mut sector_count = content.len().div_ceil(512);
     //     debug!("{} {:?}", start_sector, content);
@@ -440,10 +583,7 @@ impl AtaDisk {
     //     self.send_write(content)
     // }
     fn send_write(&self, content: &[u8]) -> Result<(), DiskError> {
-        let mut len = content.len() / 512;
-        if len == 0 {
-            len += 1;
-        }
+        let len = content.len().div_ceil(512);
         for sector in 0..len {
             self.poll()?;
 
@@ -458,8 +598,17 @@ impl AtaDisk {
         }
         // Cache flush
         self.command(DiskCommand::CacheFlush);
-        self.poll()?;
-        Ok(())
+        self.wait_not_busy()
+    }
+    /// Waits for BSY to clear, used for commands that don't transfer data (i.e. cache flush)
+    fn wait_not_busy(&self) -> Result<(), DiskError> {
+        for _ in 0..100_000 {
+            if self.check_status()? & 0x80 == 0 {
+                return Ok(());
+            }
+        }
+        log::error!("Timed out waiting for BSY to clear");
+        Err(DiskError::TimeOut)
     }
 
     /*
@@ -572,7 +721,7 @@ impl GenericDisk for AtaDisk {
 }
 /// Detects a disk at specified channel and drive
 /// Reads identify data & sets it up correctly
-fn detect(loc: &DiskLoc) -> Option<AtaDisk> {
+fn detect(loc: &DiskLoc, bar4: Option<u16>) -> Option<AtaDisk> {
     let control_base = match loc.channel() {
         //TODO Parse pci device to get info
         Channel::Primary => 0x3F6,
@@ -580,6 +729,12 @@ fn detect(loc: &DiskLoc) -> Option<AtaDisk> {
     };
     let mut disk = AtaDisk::new(*loc, loc.base(), control_base);
     disk.init().ok()?;
+    if let Some(bar4) = bar4.filter(|_| disk.dma_supported) {
+        disk.dma = dma::BusMaster::new(dma::bus_master_base(bar4, loc.channel()), loc.channel());
+        if disk.dma.is_none() {
+            log::warn!("No memory the bus master can use, {} stays in PIO", loc);
+        }
+    }
     Some(disk)
 }
 
@@ -0,0 +1,449 @@
+//! LRU sector cache between the filesystems and the disk drivers
+//! Writes are kept in the cache until the sector is evicted or the cache is synced
+use alloc::{
+    collections::BTreeMap,
+    vec,
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+use super::{driver::SECTOR_SIZE, DiskError, DiskLoc};
+
+/// In sectors, so 64Kb, the heap is only 1000Kb
+pub const DEFAULT_CAPACITY: usize = 128;
+/// Maximum amount of sectors written to the disk at once when syncing
+const MAX_SECTORS_PER_WRITE: usize = 128;
+
+/// What the cache reads missing sectors from and writes dirty sectors back to
+pub trait SectorIo {
+    fn read_sectors(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError>;
+    fn write_sectors(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8])
+        -> Result<(), DiskError>;
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty sectors written to disk, when evicted or synced
+    pub write_backs: u64,
+}
+
+#[derive(Debug)]
+struct CachedSector {
+    data: Vec<u8>,
+    dirty: bool,
+    /// Key in `SectorCache::lru`
+    last_use: u64,
+}
+
+#[derive(Debug)]
+pub struct SectorCache {
+    capacity: usize,
+    sectors: HashMap<(DiskLoc, u64), CachedSector>,
+    /// Last use -> sector, the first one is the least recently used
+    lru: BTreeMap<u64, (DiskLoc, u64)>,
+    /// Incremented on each use
+    clock: u64,
+    /// Disks whose dirty sectors were dropped as they couldn't be written back, the next sync of the disk returns the error
+    lost: HashMap<DiskLoc, DiskError>,
+    pub stats: CacheStats,
+}
+impl Default for SectorCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+impl SectorCache {
+    #[must_use] pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            sectors: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            lost: HashMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+    #[must_use] pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+    /// Amount of sectors currently cached
+    #[must_use] pub fn len(&self) -> usize {
+        self.sectors.len()
+    }
+    #[must_use] pub fn is_empty(&self) -> bool {
+        self.sectors.is_empty()
+    }
+    /// Amount of cached sectors that aren't written to disk yet
+    #[must_use] pub fn dirty(&self) -> usize {
+        self.sectors.values().filter(|sector| sector.dirty).count()
+    }
+    /// Evicts sectors if the new capacity is smaller than the amount of cached sectors
+    pub fn set_capacity(&mut self, io: &mut impl SectorIo, capacity: usize) {
+        self.capacity = capacity;
+        self.evict(io, 0);
+    }
+    pub fn read(
+        &mut self,
+        io: &mut impl SectorIo,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let sector_size = SECTOR_SIZE as usize;
+        let mut content = vec![0; sector_count as usize * sector_size];
+        let mut sector = start_sector;
+        while sector < start_sector + sector_count {
+            let offset = (sector - start_sector) as usize * sector_size;
+            if let Some(data) = self.get(loc, sector) {
+                content[offset..offset + sector_size].copy_from_slice(data);
+                self.stats.hits += 1;
+                sector += 1;
+                continue;
+            }
+            // Missing sectors next to each other are read at once
+            let mut run = 1;
+            while sector + run < start_sector + sector_count && !self.sectors.contains_key(&(*loc, sector + run)) {
+                run += 1;
+            }
+            self.stats.misses += run;
+            let data = io.read_sectors(loc, sector, run)?;
+            content[offset..offset + data.len()].copy_from_slice(&data);
+            // Reading more than the cache can hold would only evict what's useful
+            if (run as usize) <= self.capacity {
+                self.evict(io, run as usize);
+                for (i, chunk) in data.chunks(sector_size).enumerate() {
+                    self.insert(*loc, sector + i as u64, chunk.to_vec(), false);
+                }
+            }
+            sector += run;
+        }
+        Ok(content)
+    }
+    /// The last sector is padded with zeroes
+    pub fn write(
+        &mut self,
+        io: &mut impl SectorIo,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        let sector_size = SECTOR_SIZE as usize;
+        let sector_count = content.len().div_ceil(sector_size);
+        if sector_count > self.capacity {
+            // Too big to be kept, so it's written through and the cached copies are updated
+            io.write_sectors(loc, start_sector, content)?;
+            self.refresh(loc, start_sector, content);
+            return Ok(());
+        }
+        let new_sectors = (0..sector_count as u64)
+            .filter(|i| !self.sectors.contains_key(&(*loc, start_sector + i)))
+            .count();
+        self.evict(io, new_sectors);
+        for (i, chunk) in content.chunks(sector_size).enumerate() {
+            let mut data = chunk.to_vec();
+            data.resize(sector_size, 0);
+            self.insert(*loc, start_sector + i as u64, data, true);
+        }
+        Ok(())
+    }
+    /// If this many bytes can be kept in the cache, bigger writes are written through and bigger reads aren't kept
+    #[must_use] pub fn fits(&self, len: usize) -> bool {
+        len.div_ceil(SECTOR_SIZE as usize) <= self.capacity
+    }
+    /// Updates the cached copies of sectors that were written to the disk
+    pub fn refresh(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) {
+        for (i, chunk) in content.chunks(SECTOR_SIZE as usize).enumerate() {
+            if let Some(cached) = self.sectors.get_mut(&(*loc, start_sector + i as u64)) {
+                cached.data.fill(0);
+                cached.data[..chunk.len()].copy_from_slice(chunk);
+                cached.dirty = false;
+            }
+        }
+    }
+    /// Runs of sectors that aren't cached, as (first sector, sector count)
+    #[must_use] pub fn missing(&self, loc: &DiskLoc, start_sector: u64, sector_count: u64) -> Vec<(u64, u64)> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        for sector in start_sector..start_sector + sector_count {
+            if self.sectors.contains_key(&(*loc, sector)) {
+                continue;
+            }
+            match runs.last_mut() {
+                Some((start, count)) if *start + *count == sector => *count += 1,
+                _ => runs.push((sector, 1)),
+            }
+        }
+        runs
+    }
+    /// Caches sectors that were read from the disk, the sectors already cached are kept as they may be dirty
+    pub fn fill(
+        &mut self,
+        io: &mut impl SectorIo,
+        loc: &DiskLoc,
+        start_sector: u64,
+        data: &[u8],
+    ) {
+        let sector_size = SECTOR_SIZE as usize;
+        let new_sectors = (0..data.len().div_ceil(sector_size) as u64)
+            .filter(|i| !self.sectors.contains_key(&(*loc, start_sector + i)))
+            .count();
+        // Like reads, more than the cache can hold would only evict what's useful
+        if new_sectors > self.capacity {
+            return;
+        }
+        self.stats.misses += new_sectors as u64;
+        self.evict(io, new_sectors);
+        for (i, chunk) in data.chunks(sector_size).enumerate() {
+            let sector = start_sector + i as u64;
+            if !self.sectors.contains_key(&(*loc, sector)) {
+                let mut data = chunk.to_vec();
+                data.resize(sector_size, 0);
+                self.insert(*loc, sector, data, false);
+            }
+        }
+    }
+    /// Writes all dirty sectors to their disks
+    /// Fails if some couldn't be written, now or when they were evicted since the last sync
+    pub fn sync(&mut self, io: &mut impl SectorIo) -> Result<(), DiskError> {
+        let keys: Vec<(DiskLoc, u64)> = self
+            .sectors
+            .iter()
+            .filter(|(_, sector)| sector.dirty)
+            .map(|(key, _)| *key)
+            .collect();
+        let failed = self.write_back(io, keys);
+        let lost = core::mem::take(&mut self.lost);
+        failed.into_iter().map(|(_, err)| err).chain(lost.into_values()).next().map_or(Ok(()), Err)
+    }
+    /// Writes the dirty sectors of a disk, see `sync`
+    pub fn sync_disk(&mut self, io: &mut impl SectorIo, loc: &DiskLoc) -> Result<(), DiskError> {
+        let keys: Vec<(DiskLoc, u64)> = self
+            .sectors
+            .iter()
+            .filter(|((sector_loc, _), sector)| sector_loc == loc && sector.dirty)
+            .map(|(key, _)| *key)
+            .collect();
+        let failed = self.write_back(io, keys);
+        let lost = self.lost.remove(loc);
+        failed.into_iter().map(|(_, err)| err).chain(lost).next().map_or(Ok(()), Err)
+    }
+    /// Syncs the disk and forgets all of its sectors, i.e. if the disk was changed by something else than the cache
+    pub fn invalidate(&mut self, io: &mut impl SectorIo, loc: &DiskLoc) -> Result<(), DiskError> {
+        self.sync_disk(io, loc)?;
+        self.discard(loc);
+        Ok(())
+    }
+    /// Forgets all the sectors of a disk without writing them, i.e. if its disc was changed
+    pub fn discard(&mut self, loc: &DiskLoc) {
+        self.lost.remove(loc);
+        let sectors = &mut self.sectors;
+        self.lru.retain(|_, key| {
+            if key.0 == *loc {
+                sectors.remove(key);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn get(&mut self, loc: &DiskLoc, sector: u64) -> Option<&Vec<u8>> {
+        let cached = self.sectors.get_mut(&(*loc, sector))?;
+        self.lru.remove(&cached.last_use);
+        self.clock += 1;
+        cached.last_use = self.clock;
+        self.lru.insert(self.clock, (*loc, sector));
+        Some(&cached.data)
+    }
+    /// There must be room for the sector if it isn't cached already
+    fn insert(&mut self, loc: DiskLoc, sector: u64, data: Vec<u8>, dirty: bool) {
+        self.clock += 1;
+        self.lru.insert(self.clock, (loc, sector));
+        let cached = CachedSector {
+            data,
+            dirty,
+            last_use: self.clock,
+        };
+        if let Some(old) = self.sectors.insert((loc, sector), cached) {
+            self.lru.remove(&old.last_use);
+        }
+    }
+    /// Evicts the least recently used sectors until `needed` sectors can be added
+    /// The dirty ones are written back first, the ones that fail are dropped anyway so that a broken disk doesn't
+    /// fill the cache of all the others, their error is kept for the next sync of their disk
+    fn evict(&mut self, io: &mut impl SectorIo, needed: usize) {
+        let excess = (self.sectors.len() + needed).saturating_sub(self.capacity);
+        let victims: Vec<(DiskLoc, u64)> = self.lru.values().take(excess).copied().collect();
+        let dirty = victims
+            .iter()
+            .filter(|key| self.sectors[*key].dirty)
+            .copied()
+            .collect();
+        for (loc, err) in self.write_back(io, dirty) {
+            log::error!("Dropping sectors of {loc} that couldn't be written back: {err:?}");
+            self.lost.entry(loc).or_insert(err);
+        }
+        for key in victims {
+            // Safe unwrap, lru and sectors always have the same keys
+            let sector = self.sectors.remove(&key).unwrap();
+            self.lru.remove(&sector.last_use);
+            self.stats.evictions += 1;
+        }
+    }
+    /// Writes the sectors in runs, the sectors of a run are clean once it's written
+    /// Returns the disk and the error of the runs that failed, their sectors stay dirty
+    fn write_back(&mut self, io: &mut impl SectorIo, mut keys: Vec<(DiskLoc, u64)>) -> Vec<(DiskLoc, DiskError)> {
+        keys.sort_unstable();
+        let mut failed = Vec::new();
+        for run in runs(&keys) {
+            let (loc, start) = run[0];
+            let content: Vec<u8> = run.iter().flat_map(|key| self.sectors[key].data.iter().copied()).collect();
+            if let Err(err) = io.write_sectors(&loc, start, &content) {
+                failed.push((loc, err));
+                continue;
+            }
+            self.stats.write_backs += run.len() as u64;
+            for key in run {
+                // Safe unwrap, keys come from the cached sectors
+                self.sectors.get_mut(key).unwrap().dirty = false;
+            }
+        }
+        failed
+    }
+}
+
+/// Splits sorted sectors in runs of contiguous sectors on the same disk
+fn runs(sectors: &[(DiskLoc, u64)]) -> Vec<&[(DiskLoc, u64)]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=sectors.len() {
+        let ends_run = i == sectors.len()
+            || i - start == MAX_SECTORS_PER_WRITE
+            || sectors[i].0 != sectors[i - 1].0
+            || sectors[i].1 != sectors[i - 1].1 + 1;
+        if ends_run && start < i {
+            runs.push(&sectors[start..i]);
+            start = i;
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DISK: DiskLoc = DiskLoc::NVMe(0);
+    const BROKEN_DISK: DiskLoc = DiskLoc::NVMe(1);
+
+    /// Disks in memory, writes to `BROKEN_DISK` fail
+    #[derive(Default)]
+    struct MemDisks {
+        disks: HashMap<DiskLoc, Vec<u8>>,
+        /// Disk, first sector and sector count of each write
+        writes: Vec<(DiskLoc, u64, u64)>,
+    }
+    impl MemDisks {
+        fn new(sector_count: usize) -> Self {
+            let mut disks = HashMap::new();
+            for loc in [DISK, BROKEN_DISK] {
+                let mut disk = vec![0; sector_count * SECTOR_SIZE as usize];
+                for (i, sector) in disk.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+                    sector.fill(i as u8);
+                }
+                disks.insert(loc, disk);
+            }
+            Self { disks, writes: Vec::new() }
+        }
+        fn sector(&self, loc: &DiskLoc, sector: u64) -> &[u8] {
+            let start = sector as usize * SECTOR_SIZE as usize;
+            &self.disks[loc][start..start + SECTOR_SIZE as usize]
+        }
+    }
+    impl SectorIo for MemDisks {
+        fn read_sectors(&mut self, loc: &DiskLoc, start_sector: u64, sector_count: u64) -> Result<Vec<u8>, DiskError> {
+            let start = start_sector as usize * SECTOR_SIZE as usize;
+            let end = start + sector_count as usize * SECTOR_SIZE as usize;
+            Ok(self.disks[loc][start..end].to_vec())
+        }
+        fn write_sectors(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
+            if *loc == BROKEN_DISK {
+                return Err(DiskError::TimeOut);
+            }
+            let start = start_sector as usize * SECTOR_SIZE as usize;
+            self.disks.get_mut(loc).unwrap()[start..start + content.len()].copy_from_slice(content);
+            self.writes.push((*loc, start_sector, content.len().div_ceil(SECTOR_SIZE as usize) as u64));
+            Ok(())
+        }
+    }
+
+    #[test_case]
+    fn reads_are_cached() {
+        let mut io = MemDisks::new(16);
+        let mut cache = SectorCache::new(8);
+        let first = cache.read(&mut io, &DISK, 2, 4).unwrap();
+        let second = cache.read(&mut io, &DISK, 2, 4).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first[..SECTOR_SIZE as usize], *io.sector(&DISK, 2));
+        assert_eq!((cache.stats.misses, cache.stats.hits), (4, 4));
+    }
+
+    #[test_case]
+    fn least_recently_used_is_evicted() {
+        let mut io = MemDisks::new(16);
+        let mut cache = SectorCache::new(4);
+        cache.read(&mut io, &DISK, 0, 4).unwrap();
+        cache.read(&mut io, &DISK, 0, 1).unwrap();
+        cache.read(&mut io, &DISK, 4, 1).unwrap();
+        assert_eq!(cache.len(), 4);
+        assert_eq!(cache.missing(&DISK, 0, 5), vec![(1, 1)]);
+    }
+
+    #[test_case]
+    fn dirty_victims_are_written_back() {
+        let mut io = MemDisks::new(16);
+        let mut cache = SectorCache::new(2);
+        cache.write(&mut io, &DISK, 10, &[0xAA; 10]).unwrap();
+        assert!(io.writes.is_empty());
+        cache.read(&mut io, &DISK, 0, 2).unwrap();
+        assert_eq!(io.writes, vec![(DISK, 10, 1)]);
+        assert_eq!(io.sector(&DISK, 10)[..11], [0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0xAA, 0]);
+        assert_eq!(cache.dirty(), 0);
+    }
+
+    #[test_case]
+    fn sync_writes_sorted_runs() {
+        let mut io = MemDisks::new(16);
+        let mut cache = SectorCache::new(8);
+        for sector in [9, 5, 3, 4] {
+            cache.write(&mut io, &DISK, sector, &[sector as u8 + 100; 512]).unwrap();
+        }
+        cache.sync(&mut io).unwrap();
+        assert_eq!(io.writes, vec![(DISK, 3, 3), (DISK, 9, 1)]);
+        assert_eq!(cache.dirty(), 0);
+        assert_eq!(cache.stats.write_backs, 4);
+    }
+
+    #[test_case]
+    fn failed_write_back_is_dropped_and_reported() {
+        let mut io = MemDisks::new(16);
+        let mut cache = SectorCache::new(2);
+        cache.write(&mut io, &BROKEN_DISK, 0, &[1; 512]).unwrap();
+        cache.write(&mut io, &DISK, 0, &[2; 512]).unwrap();
+        // Evicts the sector of the broken disk, it can't be written back so it's dropped
+        cache.read(&mut io, &DISK, 1, 1).unwrap();
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.missing(&BROKEN_DISK, 0, 1), vec![(0, 1)]);
+        assert_eq!(cache.sync_disk(&mut io, &DISK), Ok(()));
+        assert_eq!(cache.sync_disk(&mut io, &BROKEN_DISK), Err(DiskError::TimeOut));
+        // The error is only reported once
+        assert_eq!(cache.sync_disk(&mut io, &BROKEN_DISK), Ok(()));
+    }
+}
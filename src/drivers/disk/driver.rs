@@ -2,10 +2,13 @@ use core::fmt::{Debug, Display};
 
 use alloc::{boxed::Box, vec::Vec};
 use hashbrown::HashMap;
-use spin::Mutex;
+use spin::{Mutex, RwLock};
 
 use super::{
-    ata::{AtaDisk, ATA_DRIVER},
+    ahci::{AhciDriver, AHCI_DRIVER},
+    ata::{driver::AtaDriver, AtaDisk, ATA_DRIVER},
+    cache::{SectorCache, SectorIo},
+    nvme::{NVMeDriver, NVME_DRIVER},
     DiskError, DiskLoc,
 };
 
@@ -33,48 +36,190 @@ pub enum DiskDriverType {
 pub struct DiskManager {
     /// Values are the GenericDisk and the index of the driver to use to read the disk
     pub disks: HashMap<DiskLoc, Disk>,
+    /// Every read and write goes through it, call `sync` to write the cached writes to the disks
+    pub cache: SectorCache,
 }
 #[derive(Debug)]
 pub struct Disk {
     pub loc: DiskLoc,
     pub drv: DiskDriverEnum,
 }
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum DiskDriverEnum {
     Ata,
-    NVMe, // Not supported for now
+    Ahci,
+    NVMe,
 }
 
 impl DiskManager {
+    #[must_use] pub fn new(disks: HashMap<DiskLoc, Disk>) -> Self {
+        Self {
+            disks,
+            cache: SectorCache::default(),
+        }
+    }
     pub fn read_disk(
         &mut self,
         loc: &DiskLoc,
         start_sector: u64,
         sector_count: u64,
     ) -> Result<Vec<u8>, DiskError> {
+        self.check_media(loc)?;
+        let result = self.cache.read(&mut self.disks, loc, start_sector, sector_count);
+        self.media_result(loc, result)
+    }
+    /// Checks that a CD drive still has the disc its cached sectors were read from, before they are used
+    fn check_media(&mut self, loc: &DiskLoc) -> Result<(), DiskError> {
+        let result = match self.disks.get(loc).ok_or(DiskError::NotFound)?.drv {
+            DiskDriverEnum::Ata => unsafe { ATA_DRIVER.as_mut().unwrap().write_with_timeout() }.check_media(loc),
+            DiskDriverEnum::Ahci | DiskDriverEnum::NVMe => Ok(()),
+        };
+        self.media_result(loc, result)
+    }
+    /// Drops the cached sectors when the disc was changed or removed, they are from the previous disc
+    /// They aren't written back, as that disc is gone
+    fn media_result<T>(&mut self, loc: &DiskLoc, result: Result<T, DiskError>) -> Result<T, DiskError> {
+        if let Err(DiskError::MediaChanged | DiskError::NoMedia) = result {
+            self.cache.discard(loc);
+        }
+        result
+    }
+    pub fn write_disk(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        // The cache would take it, and the error would only come once it's written back
+        if self.is_read_only(loc)? {
+            return Err(DiskError::ReadOnly);
+        }
+        self.cache.write(&mut self.disks, loc, start_sector, content)
+    }
+    /// If the disk is a CD drive
+    fn is_read_only(&self, loc: &DiskLoc) -> Result<bool, DiskError> {
+        Ok(match self.disks.get(loc).ok_or(DiskError::NotFound)?.drv {
+            DiskDriverEnum::Ata => unsafe { ATA_DRIVER.as_mut().unwrap().read_with_timeout() }.is_read_only(loc),
+            DiskDriverEnum::Ahci | DiskDriverEnum::NVMe => false,
+        })
+    }
+    /// Size of the disk in sectors
+    pub fn sector_count(&self, loc: &DiskLoc) -> Result<u64, DiskError> {
         match self.disks.get(loc).ok_or(DiskError::NotFound)?.drv {
+            DiskDriverEnum::Ata => {
+                let ata_drv = unsafe { ATA_DRIVER.as_mut().unwrap().read_with_timeout() };
+                ata_drv.sector_count(loc)
+            }
+            DiskDriverEnum::Ahci => {
+                let ahci_drv = unsafe { AHCI_DRIVER.as_mut().unwrap().read_with_timeout() };
+                ahci_drv.sector_count(loc)
+            }
+            DiskDriverEnum::NVMe => {
+                let nvme_drv = unsafe { NVME_DRIVER.as_mut().unwrap().read_with_timeout() };
+                nvme_drv.sector_count(loc)
+            }
+        }
+    }
+    /// Writes the cached writes to the disks
+    pub fn sync(&mut self) -> Result<(), DiskError> {
+        self.cache.sync(&mut self.disks)
+    }
+    /// Writes the cached writes of a single disk
+    pub fn sync_disk(&mut self, loc: &DiskLoc) -> Result<(), DiskError> {
+        self.cache.sync_disk(&mut self.disks, loc)
+    }
+    /// Drops the cached sectors of a disk, after syncing them
+    pub fn invalidate(&mut self, loc: &DiskLoc) -> Result<(), DiskError> {
+        self.cache.invalidate(&mut self.disks, loc)
+    }
+    pub fn set_cache_capacity(&mut self, capacity: usize) {
+        self.cache.set_capacity(&mut self.disks, capacity);
+    }
+    /// Caches sectors read without going through the cache, see `read_from_disk_async`
+    pub fn fill_cache(&mut self, loc: &DiskLoc, start_sector: u64, data: &[u8]) {
+        self.cache.fill(&mut self.disks, loc, start_sector, data);
+    }
+}
+
+/// Same as `SectorIo::read_sectors`, but the task sleeps until the disk is done
+async fn read_sectors_async(
+    drv: DiskDriverEnum,
+    loc: &DiskLoc,
+    start_sector: u64,
+    sector_count: u64,
+) -> Result<Vec<u8>, DiskError> {
+    match drv {
+        DiskDriverEnum::Ata => {
+            AtaDriver::read_async(unsafe { ATA_DRIVER.as_ref().unwrap() }, loc, start_sector, sector_count).await
+        }
+        DiskDriverEnum::Ahci => {
+            AhciDriver::read_async(unsafe { AHCI_DRIVER.as_ref().unwrap() }, loc, start_sector, sector_count).await
+        }
+        DiskDriverEnum::NVMe => {
+            NVMeDriver::read_async(unsafe { NVME_DRIVER.as_ref().unwrap() }, loc, start_sector, sector_count).await
+        }
+    }
+}
+/// Same as `SectorIo::write_sectors`, but the task sleeps until the disk is done
+async fn write_sectors_async(
+    drv: DiskDriverEnum,
+    loc: &DiskLoc,
+    start_sector: u64,
+    content: &[u8],
+) -> Result<(), DiskError> {
+    match drv {
+        DiskDriverEnum::Ata => {
+            AtaDriver::write_async(unsafe { ATA_DRIVER.as_ref().unwrap() }, loc, start_sector, content).await
+        }
+        DiskDriverEnum::Ahci => {
+            AhciDriver::write_async(unsafe { AHCI_DRIVER.as_ref().unwrap() }, loc, start_sector, content).await
+        }
+        DiskDriverEnum::NVMe => {
+            NVMeDriver::write_async(unsafe { NVME_DRIVER.as_ref().unwrap() }, loc, start_sector, content).await
+        }
+    }
+}
+/// Talks to the disk drivers directly, used by the cache
+impl SectorIo for HashMap<DiskLoc, Disk> {
+    fn read_sectors(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        match self.get(loc).ok_or(DiskError::NotFound)?.drv {
             DiskDriverEnum::Ata => {
                 let mut ata_drv = unsafe { ATA_DRIVER.as_mut().unwrap().write_with_timeout() };
                 ata_drv.read(loc, start_sector, sector_count)
             }
+            DiskDriverEnum::Ahci => {
+                let mut ahci_drv = unsafe { AHCI_DRIVER.as_mut().unwrap().write_with_timeout() };
+                ahci_drv.read(loc, start_sector, sector_count)
+            }
             DiskDriverEnum::NVMe => {
-                todo!()
+                let mut nvme_drv = unsafe { NVME_DRIVER.as_mut().unwrap().write_with_timeout() };
+                nvme_drv.read(loc, start_sector, sector_count)
             }
         }
     }
-    pub fn write_disk(
+    fn write_sectors(
         &mut self,
         loc: &DiskLoc,
         start_sector: u64,
         content: &[u8],
     ) -> Result<(), DiskError> {
-        match self.disks.get(loc).ok_or(DiskError::NotFound)?.drv {
+        match self.get(loc).ok_or(DiskError::NotFound)?.drv {
             DiskDriverEnum::Ata => {
                 let mut ata_drv = unsafe { ATA_DRIVER.as_mut().unwrap().write_with_timeout() };
                 ata_drv.write(loc, start_sector, content)
             }
+            DiskDriverEnum::Ahci => {
+                let mut ahci_drv = unsafe { AHCI_DRIVER.as_mut().unwrap().write_with_timeout() };
+                ahci_drv.write(loc, start_sector, content)
+            }
             DiskDriverEnum::NVMe => {
-                todo!()
+                let mut nvme_drv = unsafe { NVME_DRIVER.as_mut().unwrap().write_with_timeout() };
+                nvme_drv.write(loc, start_sector, content)
             }
         }
     }
@@ -87,6 +232,75 @@ pub fn read_from_disk(
 ) -> Result<Vec<u8>, DiskError> {
     disk_manager!().read_disk(addr, start_sector, sector_count)
 }
+/// Same as `read_from_disk`, but the other tasks run while the missing sectors are read
+/// The disk manager isn't locked while the disk works, so the sectors are cached first then read from the cache
+pub async fn read_from_disk_async(
+    addr: &DiskLoc,
+    start_sector: u64,
+    sector_count: u64,
+) -> Result<Vec<u8>, DiskError> {
+    let (drv, missing) = {
+        let mut guard = unsafe { DISK_MANAGER.lock() };
+        let manager = guard.as_mut().unwrap();
+        let drv = manager.disks.get(addr).ok_or(DiskError::NotFound)?.drv;
+        // It wouldn't stay in the cache, so it's read once synchronously instead of twice
+        if !manager.cache.fits((sector_count * u64::from(SECTOR_SIZE)) as usize) {
+            return manager.read_disk(addr, start_sector, sector_count);
+        }
+        manager.check_media(addr)?;
+        (drv, manager.cache.missing(addr, start_sector, sector_count))
+    };
+    for (sector, count) in missing {
+        let data = read_sectors_async(drv, addr, sector, count).await;
+        let mut guard = unsafe { DISK_MANAGER.lock() };
+        let manager = guard.as_mut().unwrap();
+        let data = manager.media_result(addr, data)?;
+        manager.fill_cache(addr, sector, &data);
+    }
+    read_from_disk(addr, start_sector, sector_count)
+}
+/// Same as `write_to_disk`, but the other tasks run while the writes too big for the cache go to the disk
+pub async fn write_to_disk_async(addr: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
+    let (drv, fits) = {
+        let mut guard = unsafe { DISK_MANAGER.lock() };
+        let manager = guard.as_mut().unwrap();
+        let drv = manager.disks.get(addr).ok_or(DiskError::NotFound)?.drv;
+        (drv, manager.cache.fits(content.len()))
+    };
+    if fits {
+        return write_to_disk(addr, start_sector, content);
+    }
+    write_sectors_async(drv, addr, start_sector, content).await?;
+    disk_manager!().cache.refresh(addr, start_sector, content);
+    Ok(())
+}
+/// Reads and writes through the disk manager and its cache, for the code taking any `SectorIo`
+pub struct CachedDisks;
+impl SectorIo for CachedDisks {
+    fn read_sectors(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        read_from_disk(loc, start_sector, sector_count)
+    }
+    fn write_sectors(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        write_to_disk(loc, start_sector, content)
+    }
+}
+pub fn disk_sector_count(addr: &DiskLoc) -> Result<u64, DiskError> {
+    disk_manager!().sector_count(addr)
+}
+/// Writes the cached writes of the disk, for the writes that must reach it before the next ones
+pub fn sync_disk(addr: &DiskLoc) -> Result<(), DiskError> {
+    disk_manager!().sync_disk(addr)
+}
 #[cfg(feature = "fs")]
 use crate::fs::partition::Partition;
 use crate::sync::TimeOutRwLock;
@@ -98,7 +312,7 @@ pub fn read_from_partition(
 ) -> Result<Vec<u8>, DiskError> {
     let start_sector = start_sector + partition.1;
     assert!(
-        (start_sector + sector_count) < partition.1 + partition.2,
+        (start_sector + sector_count) <= partition.end(),
         "Trying to read outside of partition"
     );
     read_from_disk(&partition.0, start_sector, sector_count)
@@ -114,12 +328,14 @@ pub fn write_to_partition(
 ) -> Result<(), DiskError> {
     let start_sector = start_sector + partition.1;
     assert!(
-        (start_sector + content.len() as u64) < partition.1 + partition.2,
+        (start_sector + (content.len() as u64).div_ceil(u64::from(SECTOR_SIZE)))
+            <= partition.end(),
         "Trying to write outside of partition"
     );
     disk_manager!().write_disk(&partition.0, start_sector, content)
 }
 
+#[allow(async_fn_in_trait)]
 pub trait DiskDriver: Debug {
     fn read(
         &mut self,
@@ -128,7 +344,60 @@ pub trait DiskDriver: Debug {
         sector_count: u64,
     ) -> Result<Vec<u8>, DiskError>;
     fn write(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError>;
+    fn sector_count(&self, loc: &DiskLoc) -> Result<u64, DiskError>;
     fn select_disk(&mut self, disk: &DiskLoc);
+    /// Same as `read`, but the task sleeps until the completion interrupt instead of polling the disk
+    /// The driver is only locked to send the commands and to collect them, not while the task sleeps
+    /// Falls back to `read` when the disk doesn't interrupt
+    async fn read_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError>
+    where
+        Self: Sized,
+    {
+        lock.write_with_timeout().read(loc, start_sector, sector_count)
+    }
+    /// Same as `write`, but the task sleeps until the completion interrupt instead of polling the disk
+    /// The driver is only locked to send the commands and to collect them, not while the task sleeps
+    /// Falls back to `write` when the disk doesn't interrupt
+    async fn write_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError>
+    where
+        Self: Sized,
+    {
+        lock.write_with_timeout().write(loc, start_sector, content)
+    }
+}
+
+/// A command a task sleeps on, the driver isn't locked meanwhile
+/// Whoever needs the device before the task is back waits for the command, and keeps its result here for the task
+#[derive(Debug)]
+pub struct InFlight<C> {
+    /// What the driver needs to wait for the command
+    pub command: C,
+    /// Bytes to copy from the buffer once it's done, 0 for writes
+    pub read_len: usize,
+    pub result: Option<Result<Vec<u8>, DiskError>>,
+}
+impl<C> InFlight<C> {
+    #[must_use] pub const fn new(command: C, read_len: usize) -> Self {
+        Self {
+            command,
+            read_len,
+            result: None,
+        }
+    }
+    /// If someone else waited for the command, the task then only has to take the result
+    #[must_use] pub const fn is_collected(&self) -> bool {
+        self.result.is_some()
+    }
 }
 
 pub trait GenericDisk: core::fmt::Debug + Display {
@@ -1,8 +1,14 @@
+pub mod ahci;
 pub mod ata;
+pub mod cache;
 pub mod driver;
 pub mod nvme;
 
-/// Identifies the different ATA disks (and we are working on `NVMe` which is hard asf)
+use x86_64::{structures::paging::PageTableFlags, VirtAddr};
+
+use crate::malloc;
+
+/// Identifies the different ATA, AHCI disks and `NVMe` namespaces
 /// Puts them into a hashmap, for easier use
 pub fn init() {
     let mut disks = hashbrown::HashMap::new();
@@ -12,21 +18,27 @@ pub fn init() {
         }
         if device.subclass() == 0x1 {
             log::info!("Found IDE controller on bus {loc}");
-            for (i, disk) in ata::init(device).into_iter().enumerate() {
-                disks.insert(DiskLoc::from_idx(i.try_into().unwrap()).unwrap(), disk);
+            for disk in ata::init(device) {
+                disks.insert(disk.loc, disk);
+            }
+        } else if device.subclass() == 0x6 {
+            log::info!("Found AHCI controller on bus {loc}");
+            match ahci::init(device) {
+                Ok(ahci_disks) => {
+                    for disk in ahci_disks {
+                        disks.insert(disk.loc, disk);
+                    }
+                }
+                Err(err) => {
+                    log::error!("Failed initialising AHCI driver: {:?}", err);
+                }
             }
         } else if device.subclass() == 0x8 {
             log::info!("Found NVMe controller on bus {loc}");
             match nvme::init(device) {
                 Ok(nvme_disks) => {
-                    for (i, disk) in nvme_disks.into_iter().enumerate() {
-                        disks.insert(
-                            DiskLoc::from_idx(i.try_into().unwrap()).unwrap(),
-                            driver::Disk {
-                                loc: DiskLoc(Channel::Secondary, Drive::Slave),
-                                drv: driver::DiskDriverEnum::NVMe,
-                            },
-                        );
+                    for disk in nvme_disks {
+                        disks.insert(disk.loc, disk);
                     }
                 }
                 Err(err) => {
@@ -35,7 +47,17 @@ pub fn init() {
             }
         }
     }
-    unsafe { DISK_MANAGER.lock().replace(DiskManager { disks }); }
+    unsafe { DISK_MANAGER.lock().replace(DiskManager::new(disks)); }
+}
+
+/// Size of the pages the controllers transfer data through, also the one given to `NVMe` controllers (CC.MPS = 0)
+pub const PAGE_SIZE: usize = 0x1000;
+
+/// Allocates a zeroed page which is identity mapped, so its address can be given to the controller
+#[must_use] pub fn dma_page() -> Option<VirtAddr> {
+    let page = malloc!(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?;
+    unsafe { core::ptr::write_bytes(page.as_mut_ptr::<u8>(), 0, PAGE_SIZE) };
+    Some(page)
 }
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
@@ -49,6 +71,14 @@ pub enum DiskError {
     DiskNotFound,
     TimeOut,
     DRQRead,
+    /// The disk reported an error, with the status it gave
+    CommandFailed(u16),
+    /// Writing to a disk that can only be read, i.e. a CD
+    ReadOnly,
+    /// The drive is empty
+    NoMedia,
+    /// The disc was changed since the last command, the data read before is from the previous one
+    MediaChanged,
     //TODO Handle all errors from the register
     // ErrorRegister {...}
 }
@@ -59,17 +89,34 @@ use crate::disk::{
 
 use self::driver::{DiskManager, DISK_MANAGER};
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
-pub struct DiskLoc(pub Channel, pub Drive);
+pub enum DiskLoc {
+    /// A disk on the IDE controller
+    Ata(Channel, Drive),
+    /// The n-th SATA disk found on the AHCI controllers
+    Ahci(u8),
+    /// The n-th `NVMe` namespace found, over all of the controllers
+    NVMe(u8),
+}
+/// Disk indexes of the AHCI disks start after the 4 ATA disks
+const AHCI_FIRST_INDEX: u8 = 4;
+/// Then come the `NVMe` namespaces
+const NVME_FIRST_INDEX: u8 = AHCI_FIRST_INDEX + ahci::MAX_DISKS as u8;
 impl DiskLoc {
-    fn as_index(&self) -> usize {
-        let mut i = 0;
-        if self.0 == Channel::Secondary {
-            i += 2;
-        }
-        if self.1 == Drive::Slave {
-            i += 1;
+    #[must_use] pub fn as_index(&self) -> usize {
+        match self {
+            Self::Ata(channel, drive) => {
+                let mut i = 0;
+                if *channel == Channel::Secondary {
+                    i += 2;
+                }
+                if *drive == Drive::Slave {
+                    i += 1;
+                }
+                i
+            }
+            Self::Ahci(idx) => usize::from(AHCI_FIRST_INDEX) + usize::from(*idx),
+            Self::NVMe(idx) => usize::from(NVME_FIRST_INDEX) + usize::from(*idx),
         }
-        i
     }
     #[cfg(feature = "fs")]
     fn as_path(
@@ -81,24 +128,18 @@ impl DiskLoc {
         Some(crate::fs::path::FilePath::new("/".to_string(), partition))
     }
     fn as_diskloc(&self) -> DiskLoc {
-        DiskLoc(self.channel(), self.drive())
+        DiskLoc::Ata(self.channel(), self.drive())
     }
     fn channel(&self) -> Channel {
-        match self.as_index() {
-            0 => Channel::Primary,
-            1 => Channel::Primary,
-            2 => Channel::Secondary,
-            3 => Channel::Secondary,
-            _ => panic!("Invalid channel address"),
+        match self {
+            Self::Ata(channel, _) => *channel,
+            Self::Ahci(_) | Self::NVMe(_) => panic!("Invalid channel address"),
         }
     }
     fn drive(&self) -> Drive {
-        match self.as_index() {
-            0 => Drive::Master,
-            1 => Drive::Slave,
-            2 => Drive::Master,
-            3 => Drive::Slave,
-            _ => panic!("Invalid drive address"),
+        match self {
+            Self::Ata(_, drive) => *drive,
+            Self::Ahci(_) | Self::NVMe(_) => panic!("Invalid drive address"),
         }
     }
     fn channel_addr(&self) -> u16 {
@@ -126,18 +167,30 @@ impl DiskLoc {
         self.channel_addr()
     }
 
+    /// None past the AHCI disks and `NVMe` namespaces that were found
     #[must_use] pub fn from_idx(idx: u8) -> Option<Self> {
         Some(match idx {
-            0 => Self(Channel::Primary, Drive::Master),
-            1 => Self(Channel::Primary, Drive::Slave),
-            2 => Self(Channel::Secondary, Drive::Master),
-            3 => Self(Channel::Secondary, Drive::Slave),
-            _ => return None,
+            0 => Self::Ata(Channel::Primary, Drive::Master),
+            1 => Self::Ata(Channel::Primary, Drive::Slave),
+            2 => Self::Ata(Channel::Secondary, Drive::Master),
+            3 => Self::Ata(Channel::Secondary, Drive::Slave),
+            idx if idx < NVME_FIRST_INDEX => {
+                let idx = idx - AHCI_FIRST_INDEX;
+                (usize::from(idx) < ahci::disk_count()).then_some(Self::Ahci(idx))?
+            }
+            idx => {
+                let idx = idx - NVME_FIRST_INDEX;
+                (usize::from(idx) < nvme::disk_count()).then_some(Self::NVMe(idx))?
+            }
         })
     }
 }
 impl core::fmt::Display for DiskLoc {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        return f.write_fmt(format_args!("Drive: {:?} Channel: {:?}", self.1, self.0))
+        return match self {
+            Self::Ata(channel, drive) => f.write_fmt(format_args!("Drive: {:?} Channel: {:?}", drive, channel)),
+            Self::Ahci(idx) => f.write_fmt(format_args!("AHCI disk: {}", idx)),
+            Self::NVMe(idx) => f.write_fmt(format_args!("NVMe namespace: {}", idx)),
+        }
     }
 }
@@ -2,26 +2,55 @@
 //! Used mostly https://nvmexpress.org/wp-content/uploads/NVM-Express-Base-Specification-2.0d-2024.01.11-Ratified.pdf
 //! https://github.com/doug65536/dgos/blob/master/kernel/device/nvme/nvme.cc
 //! https://github.com/LemonOSProject/LemonOS/blob/master/Kernel/include/Storage/NVMe.h#L416
-use core::ptr::addr_of;
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{AtomicU64, AtomicU8, Ordering},
+};
 
-use alloc::vec::Vec;
+use alloc::{format, string::String, vec::Vec};
 use bit_field::BitField;
+use spin::RwLock;
 use x86_64::{
-    structures::paging::{Page, PageTableFlags, PhysFrame},
-    PhysAddr, VirtAddr,
+    structures::{idt::InterruptStackFrame, paging::PageTableFlags},
+    VirtAddr,
 };
 
 use crate::{
-    bit_manipulation::{all_zeroes, any_as_u8_slice},
-    dbg,
-    interrupts::hardware::InterruptIndex,
-    malloc, mem_map,
-    memory::handler::{map, map_frame},
+    interrupts::hardware::{notify_end_of_interrupt, register_interrupt, InterruptIndex, IRQ_COUNTS},
+    mem_map,
     pci::PciDevice,
-    time::mdelay,
+    sync::TimeOutRwLock,
+    task::event::Event,
+};
+
+use super::{
+    dma_page,
+    driver::{Disk, DiskDriver, DiskDriverEnum, GenericDisk, InFlight, SECTOR_SIZE},
+    DiskError, DiskLoc, PAGE_SIZE,
 };
 
-use super::{driver::GenericDisk, DiskLoc};
+pub static mut NVME_DRIVER: Option<RwLock<NVMeDriver>> = None;
+
+/// Entries of every queue, so that a queue fits in a single page (64 bytes per submission entry)
+const QUEUE_ENTRIES: u16 = 64;
+/// Pages the data is copied through, so the most a single command transfers is 128KiB
+const MAX_TRANSFER_PAGES: usize = 32;
+/// Polls of the controller before giving up
+const TIMEOUT: usize = 100_000_000;
+/// Id of the queue pair every read, write and flush goes through
+const IO_QUEUE_ID: u16 = 1;
+/// Controllers that can interrupt, the ones after them are polled
+const MAX_CONTROLLERS: usize = 4;
+/// Polls after the command completed before we stop waiting for the interrupt
+const IRQ_TIMEOUT: usize = 1_000_000;
+
+/// BAR0 of each controller, for the interrupt handler which can't take the driver lock
+static BAR0S: [AtomicU64; MAX_CONTROLLERS] = [const { AtomicU64::new(0) }; MAX_CONTROLLERS];
+/// Set by the interrupt handler, wakes the task waiting on the I/O queue of the controller
+static IO_DONE: [Event; MAX_CONTROLLERS] = [const { Event::new() }; MAX_CONTROLLERS];
+/// PIC line of the handler, the controllers on other lines are polled
+static IRQ_LINE: AtomicU8 = AtomicU8::new(u8::MAX);
+
 impl GenericDisk for NVMeDisk {
     fn loc(&self) -> &super::DiskLoc {
         &self.loc
@@ -29,24 +58,61 @@ impl GenericDisk for NVMeDisk {
 }
 impl core::fmt::Display for NVMeDisk {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        return f.write_str(alloc::format!("NVME {:?}", self.loc).as_str())
+        return f.write_str(format!("NVMe {} namespace {}", self.controller, self.namespace_id).as_str())
     }
 }
-#[derive(Debug)]
+/// A namespace of a controller, each one is a separate disk
+#[derive(Debug, Clone, Copy)]
 pub struct NVMeDisk {
     loc: DiskLoc,
+    /// Index in `NVMeDriver::controllers`
+    controller: usize,
+    namespace_id: u32,
+    /// In bytes, from 512 to 4096
+    lba_size: u64,
+    lba_count: u64,
+}
+impl NVMeDisk {
+    fn sectors_per_lba(&self) -> u64 {
+        self.lba_size / u64::from(SECTOR_SIZE)
+    }
+    /// First block and block count holding the sectors
+    fn lba_range(&self, start_sector: u64, sector_count: u64) -> Result<(u64, u64), DiskError> {
+        let first_lba = start_sector / self.sectors_per_lba();
+        let end_lba = (start_sector + sector_count).div_ceil(self.sectors_per_lba());
+        if end_lba > self.lba_count {
+            return Err(DiskError::SectorTooBig);
+        }
+        Ok((first_lba, end_lba - first_lba))
+    }
+    /// Offset in bytes of the sector in the data of the blocks starting at `first_lba`
+    fn offset_in_lbas(&self, first_lba: u64, start_sector: u64) -> usize {
+        ((start_sector - first_lba * self.sectors_per_lba()) * u64::from(SECTOR_SIZE)) as usize
+    }
 }
 #[derive(Debug)]
 pub enum NVMeControllerInitError {
     FatalError,
+    /// BAR0 is in IO space
+    NoMemoryBar,
+    /// The controller doesn't support 4KiB memory pages
+    UnsupportedPageSize,
+    OutOfMemory,
+    TimeOut,
+    Command(DiskError),
+}
+impl From<DiskError> for NVMeControllerInitError {
+    fn from(err: DiskError) -> Self {
+        Self::Command(err)
+    }
 }
 
 // Following https://nvmexpress.org/wp-content/uploads/NVM-Express-Base-Specification-2.0d-2024.01.11-Ratified.pdf P125
-pub fn init(nvme_pci: &PciDevice) -> Result<Vec<&'static NVMeDisk>, NVMeControllerInitError> {
-    let bar0 = nvme_pci.raw.determine_mem_base(0).unwrap();
-    let bar0 = match bar0 {
+/// Initialises the controller and registers its namespaces in `NVME_DRIVER`
+pub fn init(nvme_pci: &PciDevice) -> Result<Vec<Disk>, NVMeControllerInitError> {
+    let bar0 = match nvme_pci.raw.determine_mem_base(0).unwrap() {
         crate::pci::PciMemoryBase::MemorySpace(mem) => mem.as_u64(),
-        crate::pci::PciMemoryBase::IOSpace(_) => todo!(),
+        crate::pci::PciMemoryBase::IOSpace(_) => return Err(NVMeControllerInitError::NoMemoryBar),
     };
     // Enable bus mastering & memory space
     let mut command = nvme_pci.raw.command;
@@ -66,315 +132,658 @@ pub fn init(nvme_pci: &PciDevice) -> Result<Vec<&'static NVMeDisk>, NVMeControll
                 | PageTableFlags::WRITE_THROUGH
         );
     }
-    let controller = unsafe { NVMeRegisters::new(bar0 as usize) };
+    let registers = unsafe { NVMeRegisters::new(bar0 as usize) };
+    let version = registers.version();
     log::info!(
         "[NVME] Found NVMe device with version {}.{}.{}, maximum queues supported: {}",
-        controller.version >> 16,
-        controller.version >> 8 & 0xff,
-        controller.version & 0xff,
-        controller.get_max_queue_entries()
+        version >> 16,
+        version >> 8 & 0xff,
+        version & 0xff,
+        registers.get_max_queue_entries()
     );
+    let caps = registers.caps();
+    if caps.mpsmin() != 0 {
+        return Err(NVMeControllerInitError::UnsupportedPageSize);
+    }
 
-    // Following DGOS https://github.com/doug65536/dgos/blob/master/kernel/device/nvme/nvme.cc
-    // Disable the controller
-    controller.controller_config.set_enable(0);
+    // 7.6.1 1) Disable the controller
+    let mut cc = registers.config();
+    cc.set_enable(0);
+    registers.set_config(&cc);
+    registers.wait_ready(false)?;
 
-    // let timeout_ms = controller.controller_caps.timeout() * 500;
-    //TODO Make a decrement timer
-    while controller.controller_status.ready() != 0 {}
+    let entries = QUEUE_ENTRIES.min(registers.get_max_queue_entries());
+    let mut controller = NVMeController::new(bar0, caps.dstrd(), entries)
+        .ok_or(NVMeControllerInitError::OutOfMemory)?;
 
-    // dbg!(controller.controller_caps);
+    // 7.6.1 3) The admin queue should be configured
+    let mut attrs = NVMeControllerAdminQueueAttributes(0);
+    attrs.set_admin_completion_queue_size(u32::from(entries - 1));
+    attrs.set_admin_submission_queue_size(u32::from(entries - 1));
+    registers.set_admin_queues(
+        &attrs,
+        controller.admin.submission_base.as_u64(),
+        controller.admin.completion_base.as_u64(),
+    );
+
+    // 7.6.1 4) The controller settings should be configured
+    let mut cc = NVMeControllerConfig(0);
+    cc.set_io_completion_queue_entry_size(4); // 2^4 == 16, size of CompletionEntry
+    cc.set_io_submission_queue_entry_size(6); // 2^6 == 64, size of SubmissionEntry
+    cc.set_mps(0); // 4096 bytes for page size
+    cc.set_io_commandset_selected(0); // NVM command set
+    registers.set_config(&cc);
+    // Set enable with a separate write
+    cc.set_enable(1);
+    registers.set_config(&cc);
 
-    // Attempt to use 64KB/16KB submission/completion queue sizes
-    let mut queue_slots = 1024; // Max 4096
-    let max_queue_slots = controller.controller_caps.mqes() + 1;
+    // 7.6.1 5) Wait for ready
+    registers.wait_ready(true)?;
 
-    if queue_slots > max_queue_slots {
-        queue_slots = max_queue_slots;
+    // 7.6.1 6) Identify the controller
+    let identify = controller.identify(IdentifyType::Controller, 0)?;
+    // MDTS, in units of the minimum page size, 0 means no limit
+    let max_transfer = identify[77];
+    if max_transfer != 0 {
+        controller.max_transfer_pages = controller.max_transfer_pages.min(1 << max_transfer);
     }
+    let namespace_count = u32::from_le_bytes(identify[516..520].try_into().unwrap());
+    controller.volatile_write_cache = identify[525].get_bit(0);
 
-    // Size of one queue, in bytes
-    let mut queue_bytes = queue_slots * core::mem::size_of::<NVMeCommand>() as u64
-        + queue_slots * core::mem::size_of::<NVMeCompletion>() as u64;
+    let driver = unsafe { NVME_DRIVER.get_or_insert_with(|| RwLock::new(NVMeDriver::default())) }.get_mut();
+    let controller_idx = driver.controllers.len();
+    controller.enable_interrupts(controller_idx, nvme_pci.raw.int_line);
 
-    let queue_count = 1;
-    queue_bytes *= queue_count;
+    // 7.6.1 7) Create the I/O queues
+    controller.create_io_queues()?;
 
-    let admin_submission_queue = Vec::<u8>::with_capacity(queue_bytes as usize);
-    let admin_submission_queue_base_addr = admin_submission_queue.leak().as_ptr() as u64;
-    // dbg!(queue_bytes); // 81920 bytes on QEMU
-    // for page_addr in 0..queue_bytes.div_ceil(4096) {
-    //     let page = Page::containing_address(VirtAddr::new(page_addr+admin_submission_queue_base_addr));
-    //     map(page, PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE);
-    // }
+    let mut disks = Vec::new();
+    for namespace_id in controller.namespace_ids(namespace_count) {
+        let Some((lba_size, lba_count)) = controller.identify_namespace(namespace_id)? else {
+            continue;
+        };
+        let Ok(idx) = u8::try_from(driver.disks.len()) else {
+            log::warn!("[NVME] Too many namespaces, skipping namespace {}", namespace_id);
+            continue;
+        };
+        let disk = NVMeDisk {
+            loc: DiskLoc::NVMe(idx),
+            controller: controller_idx,
+            namespace_id,
+            lba_size,
+            lba_count,
+        };
+        log::info!("[NVME] {} has {} blocks of {} bytes", disk, lba_count, lba_size);
+        disks.push(Disk {
+            loc: disk.loc,
+            drv: DiskDriverEnum::NVMe,
+        });
+        driver.disks.push(disk);
+    }
+    driver.controllers.push(controller);
+    Ok(disks)
+}
 
-    // 7.6.1 3) The admin queue should be configured
-    controller
-        .admin_queue_attrs
-        .set_admin_completion_queue_size(queue_slots.try_into().unwrap());
-    controller
-        .admin_queue_attrs
-        .set_admin_submission_queue_size(queue_slots.try_into().unwrap());
+/// Name of the namespace in /dev, nvme[controller]n[namespace id]
+#[must_use] pub fn disk_name(loc: &DiskLoc) -> Option<String> {
+    let driver = unsafe { NVME_DRIVER.as_ref() }?.read_with_timeout();
+    let disk = find_disk(&driver.disks, loc).ok()?;
+    Some(format!("nvme{}n{}", disk.controller, disk.namespace_id))
+}
 
-    // Submission queue address
-    controller.admin_submission_queue_base_addr = admin_submission_queue_base_addr;
+/// The interrupt is pin based, so it's masked until the task waiting on the queue looked at it
+fn handle_irq() {
+    for (controller, bar0) in BAR0S.iter().enumerate() {
+        let bar0 = bar0.load(Ordering::Acquire);
+        if bar0 == 0 {
+            continue;
+        }
+        unsafe { NVMeRegisters::new(bar0 as usize) }.mask_interrupts();
+        IO_DONE[controller].set();
+    }
+}
+extern "x86-interrupt" fn irq(_stack_frame: InterruptStackFrame) {
+    handle_irq();
+    if let Some(idx) = InterruptIndex::from_num_pic(IRQ_LINE.load(Ordering::Acquire)) {
+        IRQ_COUNTS[idx as usize].fetch_add(1, Ordering::Relaxed);
+        notify_end_of_interrupt(idx);
+    }
+}
 
-    // 3.1.10 The vector for the admin queues is always 0
-    // Completion queue address
-    controller.admin_completion_queue_base_addr = admin_submission_queue_base_addr
-        + (queue_count * queue_slots * core::mem::size_of::<NVMeCommand>() as u64);
+/// Number of namespaces found, their indexes are below it
+#[must_use] pub fn disk_count() -> usize {
+    unsafe { NVME_DRIVER.as_ref() }.map_or(0, |driver| driver.read_with_timeout().disks.len())
+}
 
-    // 7.6.1 4) The controller settings should be configured
-    let mut cc = NVMeControllerConfig(0);
-    cc.set_io_completion_queue_entry_size(4); // 2^4 == 16 NVMeCompletion
-    cc.set_io_submission_queue_entry_size(6); // 2^6 == 64
-    cc.set_mps(0); // 4096 bytes for page size
-    cc.set_io_commandset_selected(0);
+fn find_disk<'a>(disks: &'a [NVMeDisk], loc: &DiskLoc) -> Result<&'a NVMeDisk, DiskError> {
+    match loc {
+        DiskLoc::NVMe(idx) => disks.get(usize::from(*idx)).ok_or(DiskError::NotFound),
+        _ => Err(DiskError::NotFound),
+    }
+}
 
-    // Try to enable weighted round robin with urgent if capable
-    if controller.controller_caps.ams() == 1 {
-        cc.set_arbitration_mechanism_selected(1);
+/// Every controller and the namespaces found on them
+#[derive(Debug, Default)]
+pub struct NVMeDriver {
+    controllers: Vec<NVMeController>,
+    disks: Vec<NVMeDisk>,
+}
+impl NVMeDriver {
+    /// Sends the command built by `entry` and sleeps until it's done, the driver is only locked to send it and to collect it
+    /// The command is polled when the task can't sleep on it
+    async fn run_async(
+        lock: &'static RwLock<Self>,
+        index: usize,
+        read_len: usize,
+        entry: impl FnOnce(&mut NVMeController) -> SubmissionEntry,
+    ) -> Result<Vec<u8>, DiskError> {
+        let done = {
+            let mut drv = lock.write_with_timeout();
+            let controller = &mut drv.controllers[index];
+            controller.collect_in_flight();
+            let entry = entry(controller);
+            // A result is still there when the task of the previous command isn't back yet
+            let sleep = controller.io.sleep_event().filter(|_| controller.in_flight.is_none());
+            let opcode = controller.io.submit(entry);
+            let Some(done) = sleep else {
+                return controller.finish(opcode, read_len);
+            };
+            controller.in_flight = Some(InFlight::new(opcode, read_len));
+            done
+        };
+        loop {
+            done.wait_until(|| {
+                lock.read_with_timeout().controllers[index]
+                    .in_flight
+                    .as_ref()
+                    .is_some_and(InFlight::is_collected)
+            })
+            .await;
+            let mut drv = lock.write_with_timeout();
+            let controller = &mut drv.controllers[index];
+            // Reset before looking at the queue, so that an interrupt in between still wakes us
+            done.reset();
+            controller.io.unmask_interrupts();
+            // The interrupt might be the one of another controller on the line
+            if controller.in_flight.as_ref().is_some_and(InFlight::is_collected) || controller.io.has_completion() {
+                let command = controller.in_flight.take().expect("Only the task takes its command");
+                return command.result.unwrap_or_else(|| controller.finish(command.command, command.read_len));
+            }
+        }
     }
-    controller.controller_config = cc;
-    // Set enable with a separate write
-    controller.controller_config.set_enable(1);
-
-    // 7.6.1 4) Wait for ready
-    loop {
-        if controller.controller_status.ready() != 0 {
-            break;
-        } else if controller.controller_status.fatal() != 0 {
-            return Err(NVMeControllerInitError::FatalError);
+    async fn read_lbas_async(
+        lock: &'static RwLock<Self>,
+        disk: &NVMeDisk,
+        mut lba: u64,
+        lba_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let max_lbas = lock.read_with_timeout().controllers[disk.controller].max_transfer_lbas(disk);
+        let mut data = Vec::with_capacity((lba_count * disk.lba_size) as usize);
+        let end_lba = lba + lba_count;
+        while lba < end_lba {
+            let count = (end_lba - lba).min(max_lbas);
+            let len = (count * disk.lba_size) as usize;
+            let entry = |controller: &mut NVMeController| controller.transfer(IOCommand::Read, disk, lba, count, None);
+            data.extend(Self::run_async(lock, disk.controller, len, entry).await?);
+            lba += count;
         }
-    };
+        Ok(data)
+    }
+    /// The content has to be a multiple of the block size
+    async fn write_lbas_async(
+        lock: &'static RwLock<Self>,
+        disk: &NVMeDisk,
+        mut lba: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        let max_lbas = lock.read_with_timeout().controllers[disk.controller].max_transfer_lbas(disk);
+        for chunk in content.chunks((max_lbas * disk.lba_size) as usize) {
+            let count = chunk.len() as u64 / disk.lba_size;
+            let entry =
+                |controller: &mut NVMeController| controller.transfer(IOCommand::Write, disk, lba, count, Some(chunk));
+            Self::run_async(lock, disk.controller, 0, entry).await?;
+            lba += count;
+        }
+        Ok(())
+    }
+}
+impl DiskDriver for NVMeDriver {
+    fn read(
+        &mut self,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let disk = find_disk(&self.disks, loc)?;
+        let (first_lba, lba_count) = disk.lba_range(start_sector, sector_count)?;
+        let controller = &mut self.controllers[disk.controller];
+        controller.collect_in_flight();
+        let mut data = controller.read(disk, first_lba, lba_count)?;
+        data.drain(..disk.offset_in_lbas(first_lba, start_sector));
+        data.truncate((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        Ok(data)
+    }
 
-    // Read the doorbell stride
-    let doorbell_shift = controller.controller_caps.dstrd() + 1;
-
-    // 7.4 Initialize queues
-
-    // Initialize just the admin queue until we are sure about the
-    // number of supported queues
-
-    // let mut config = regs.controller_config;
-    // regs.controller_config = config.set_bit(0, false).clone();
-    // let mut i = 500;
-    // while i > 0 {
-    //     i -= 1;
-    //     let status = regs.controller_status;
-    //     let st = status.get_bit(0);
-    //     if !st {
-    //         break;
-    //     }
-    //     mdelay(10);
-    // }
-    // if i == 0 {
-    //     log::warn!("NVMe device doesn't want to be disabled, skipping");
-    //     return None;
-    // }
-
-    // // Checking page size following https://github.com/LemonOSProject/LemonOS/blob/master/Kernel/include/Storage/NVMe.h#L18
-    // if 0x1000 << ((regs.controller_caps >> 52) & 0xf) < 4096
-    //     || 0x1000 << ((regs.controller_caps >> 48) & 0xf) > 4096
-    // {
-    //     log::warn!("NVMe device doesn't support 4k memory page size, skipping");
-    //     return None;
-    // }
-    // // We can now set page size to 4096
-    // regs.controller_config =
-    //     (regs.controller_config & !NVME_CFG_MPS(NVME_CFG_MPS_MASK as u32)) | NVME_CFG_MPS(0); // 2^12+0 = 4096
-
-    // regs.set_command_set(NVME_CONFIG_CMDSET_NVM);
-
-    // regs.controller_config |= NVME_CFG_DEFAULT_IOCQES | NVME_CFG_DEFAULT_IOSQES;
-
-    // regs.admin_completion_queue_base_addr =
-    //     crate::malloc!(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?.as_u64();
-    // regs.admin_submission_queue_base_addr =
-    //     crate::malloc!(PageTableFlags::PRESENT | PageTableFlags::WRITABLE)?.as_u64();
-
-    // let mut adm_queue = NVMeQueue {
-    //     queue_id: 0, /* admin queue ID is 0 */
-    //     completion_base: VirtAddr::new(regs.admin_completion_queue_base_addr),
-    //     submission_base: VirtAddr::new(regs.admin_submission_queue_base_addr),
-    //     completion_db: regs.get_completion_doorbell(0),
-    //     submission_db: regs.get_submission_doorbell(0),
-    //     completion_queue_size: 4096.min(regs.get_max_queue_entries()),
-    //     submission_queue_size: 4096.min(regs.get_max_queue_entries()),
-    // };
-
-    // regs.admin_queue_attrs = 0;
-
-    // regs.set_admin_completion_queue_size(
-    //     adm_queue.completion_queue_size / core::mem::size_of::<NVMeCompletion>() as u16,
-    // );
-    // regs.set_admin_submission_queue_size(
-    //     adm_queue.submission_queue_size / core::mem::size_of::<NVMeCommand>() as u16,
-    // );
-
-    // log::info!(
-    //     "[NVMe] CQ size: {}, SQ size: {}",
-    //     (regs.admin_queue_attrs >> 16) + 1,
-    //     (regs.admin_queue_attrs & 0xffff) + 1
-    // );
-    // regs.enable();
-    // let mut i = 500;
-    // while i > 0 {
-    //     i -= 1;
-    //     let status = regs.controller_status;
-    //     let st = status.get_bit(0);
-    //     if st {
-    //         break;
-    //     }
-    //     mdelay(10);
-    // }
-    // if i == 0 {
-    //     log::warn!("NVMe device doesn't want to be enabled, skipping");
-    //     return None;
-    // }
-    // if (regs.controller_status & NVME_CSTS_FATAL as u32 != 0) {
-    //     log::warn!("[NVMe] Controller fatal error! (NVME_CSTS_FATAL set)");
-    //     return None;
-    // }
-    // dbg!(adm_queue);
-    // adm_queue.identify();
-    // // loop {
-    // mdelay(100);
-    // dbg!(regs);
-    // let completion = unsafe {
-    //     core::slice::from_raw_parts(
-    //         regs.admin_completion_queue_base_addr as *const NVMeCompletion,
-    //         4,
-    //     )
-    // };
-    // dbg!(completion);
-
-    // // }
-    let mut disks = Vec::new();
+    /// Blocks that are only partly written are read first, as we can't write less than a block
+    fn write(&mut self, loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
+        let disk = find_disk(&self.disks, loc)?;
+        let sector_count = (content.len() as u64).div_ceil(u64::from(SECTOR_SIZE));
+        let (first_lba, lba_count) = disk.lba_range(start_sector, sector_count)?;
+        let controller = &mut self.controllers[disk.controller];
+        controller.collect_in_flight();
+        let offset = disk.offset_in_lbas(first_lba, start_sector);
+        if offset == 0 && content.len() as u64 == lba_count * disk.lba_size {
+            controller.write(disk, first_lba, content)?;
+        } else {
+            let mut data = controller.read(disk, first_lba, lba_count)?;
+            data[offset..offset + content.len()].copy_from_slice(content);
+            controller.write(disk, first_lba, &data)?;
+        }
+        controller.flush(disk.namespace_id)
+    }
 
-    Ok(disks)
-}
-fn bit_log2(n: u64) -> u64 {
-    // if n == 0 {
-    //     return 0; // Logarithm of 0 is undefined, returning 0 as a default
-    // }
+    async fn read_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        sector_count: u64,
+    ) -> Result<Vec<u8>, DiskError> {
+        let disk = *find_disk(&lock.read_with_timeout().disks, loc)?;
+        let (first_lba, lba_count) = disk.lba_range(start_sector, sector_count)?;
+        let mut data = Self::read_lbas_async(lock, &disk, first_lba, lba_count).await?;
+        data.drain(..disk.offset_in_lbas(first_lba, start_sector));
+        data.truncate((sector_count * u64::from(SECTOR_SIZE)) as usize);
+        Ok(data)
+    }
 
-    let mut result = 0;
-    let mut value = n;
+    /// Same as `write`, the controller isn't locked between the read and the write of the blocks only partly written
+    async fn write_async(
+        lock: &'static RwLock<Self>,
+        loc: &DiskLoc,
+        start_sector: u64,
+        content: &[u8],
+    ) -> Result<(), DiskError> {
+        let disk = *find_disk(&lock.read_with_timeout().disks, loc)?;
+        let sector_count = (content.len() as u64).div_ceil(u64::from(SECTOR_SIZE));
+        let (first_lba, lba_count) = disk.lba_range(start_sector, sector_count)?;
+        let offset = disk.offset_in_lbas(first_lba, start_sector);
+        if offset == 0 && content.len() as u64 == lba_count * disk.lba_size {
+            Self::write_lbas_async(lock, &disk, first_lba, content).await?;
+        } else {
+            let mut data = Self::read_lbas_async(lock, &disk, first_lba, lba_count).await?;
+            data[offset..offset + content.len()].copy_from_slice(content);
+            Self::write_lbas_async(lock, &disk, first_lba, &data).await?;
+        }
+        if lock.read_with_timeout().controllers[disk.controller].volatile_write_cache {
+            let flush = |_: &mut NVMeController| NVMeController::flush_entry(disk.namespace_id);
+            Self::run_async(lock, disk.controller, 0, flush).await?;
+        }
+        Ok(())
+    }
 
-    while value > 1 {
-        value >>= 1;
-        result += 1;
+    fn sector_count(&self, loc: &DiskLoc) -> Result<u64, DiskError> {
+        let disk = find_disk(&self.disks, loc)?;
+        Ok(disk.lba_count * disk.sectors_per_lba())
     }
 
-    result
+    /// Every namespace can be used at any time
+    fn select_disk(&mut self, _disk: &DiskLoc) {}
 }
 
+#[derive(Debug)]
+struct NVMeController {
+    bar0: u64,
+    admin: NVMeQueue,
+    io: NVMeQueue,
+    /// Identity mapped pages the data is copied through, they aren't contiguous so each one is given by a PRP entry
+    buffer: Vec<VirtAddr>,
+    /// Holds the PRP entries of the buffer after the first one, when a transfer is more than 2 pages
+    prp_list: VirtAddr,
+    max_transfer_pages: usize,
+    /// If the writes have to be flushed
+    volatile_write_cache: bool,
+    /// The I/O command a task sleeps on, with its opcode
+    in_flight: Option<InFlight<u8>>,
+}
+impl NVMeController {
+    /// Allocates the queues and the buffer, the controller still has to be told about them
+    fn new(bar0: u64, doorbell_stride: u64, queue_entries: u16) -> Option<Self> {
+        Some(Self {
+            bar0,
+            admin: NVMeQueue::new(0, bar0, doorbell_stride, queue_entries)?,
+            io: NVMeQueue::new(IO_QUEUE_ID, bar0, doorbell_stride, queue_entries)?,
+            buffer: (0..MAX_TRANSFER_PAGES).map(|_| dma_page()).collect::<Option<_>>()?,
+            prp_list: dma_page()?,
+            max_transfer_pages: MAX_TRANSFER_PAGES,
+            volatile_write_cache: false,
+            in_flight: None,
+        })
+    }
+    /// Returns the data structure, which is a page
+    fn identify(&mut self, to_identify: IdentifyType, namespace_id: u32) -> Result<Vec<u8>, DiskError> {
+        self.admin
+            .run(SubmissionEntry::new_identify(to_identify, namespace_id, self.buffer[0]))?;
+        Ok(unsafe { core::slice::from_raw_parts(self.buffer[0].as_ptr::<u8>(), PAGE_SIZE) }.to_vec())
+    }
+    /// Block size and block count of the namespace, None if it can't be used as a disk
+    fn identify_namespace(&mut self, namespace_id: u32) -> Result<Option<(u64, u64)>, DiskError> {
+        let identify = self.identify(IdentifyType::Namespace, namespace_id)?;
+        let lba_count = u64::from_le_bytes(identify[0..8].try_into().unwrap());
+        // FLBAS, the LBA format in use
+        let format = 128 + 4 * usize::from(identify[26].get_bits(0..4));
+        let metadata_size = u16::from_le_bytes([identify[format], identify[format + 1]]);
+        // Power of 2
+        let lba_shift = identify[format + 2];
+        if lba_count == 0 || metadata_size != 0 || !(9..=12).contains(&lba_shift) {
+            log::warn!(
+                "[NVME] Skipping namespace {} with {} blocks of 2^{} bytes and {} bytes of metadata",
+                namespace_id,
+                lba_count,
+                lba_shift,
+                metadata_size
+            );
+            return Ok(None);
+        }
+        Ok(Some((1 << lba_shift, lba_count)))
+    }
+    /// Active namespaces, the ids below the namespace count are used if the controller doesn't give the list (before 1.1)
+    fn namespace_ids(&mut self, namespace_count: u32) -> Vec<u32> {
+        match self.identify(IdentifyType::NamespaceList, 0) {
+            Ok(list) => list
+                .chunks_exact(4)
+                .map(|id| u32::from_le_bytes(id.try_into().unwrap()))
+                .take_while(|id| *id != 0)
+                .collect(),
+            Err(_) => (1..=namespace_count).collect(),
+        }
+    }
+    /// Registers the handler of the pin based interrupt, the I/O queue then interrupts on its completions
+    fn enable_interrupts(&mut self, index: usize, line: u8) {
+        if index >= MAX_CONTROLLERS || InterruptIndex::from_num_pic(line).is_none() {
+            return;
+        }
+        if IRQ_LINE
+            .compare_exchange(u8::MAX, line, Ordering::AcqRel, Ordering::Acquire)
+            .is_ok()
+        {
+            register_interrupt(InterruptIndex::from_num_pic(line).unwrap(), irq);
+        } else if IRQ_LINE.load(Ordering::Acquire) != line {
+            return;
+        }
+        BAR0S[index].store(self.bar0, Ordering::Release);
+        self.io.interrupt = Some(index);
+    }
+    /// The completion queue has to be created first, with interrupts on vector 0 if the controller has a line
+    fn create_io_queues(&mut self) -> Result<(), DiskError> {
+        let size_and_id = (u32::from(self.io.entries - 1) << 16) | u32::from(self.io.queue_id);
+        // Bit 0 is physically contiguous, which a single page is, bit 1 enables interrupts
+        let mut flags = 1_u32;
+        flags.set_bit(1, self.io.interrupt.is_some());
+        self.admin.run(SubmissionEntry::new(
+            AdminCommand::CreateIOCompletionQueue as u8,
+            0,
+            [self.io.completion_base.as_u64(), 0],
+            [size_and_id, flags, 0, 0, 0, 0],
+        ))?;
+        self.admin.run(SubmissionEntry::new(
+            AdminCommand::CreateIOSubmissionQueue as u8,
+            0,
+            [self.io.submission_base.as_u64(), 0],
+            [size_and_id, (u32::from(self.io.queue_id) << 16) | 1, 0, 0, 0, 0],
+        ))?;
+        Ok(())
+    }
+    fn max_transfer_lbas(&self, disk: &NVMeDisk) -> u64 {
+        // NLB is 16 bits
+        ((self.max_transfer_pages * PAGE_SIZE) as u64 / disk.lba_size).min(0x1_0000)
+    }
+    /// Builds a read or a write of the blocks, the content is copied to the buffer if there is one
+    /// See https://wiki.osdev.org/NVMe#PRP
+    fn transfer(
+        &mut self,
+        command: IOCommand,
+        disk: &NVMeDisk,
+        lba: u64,
+        lba_count: u64,
+        content: Option<&[u8]>,
+    ) -> SubmissionEntry {
+        if let Some(content) = content {
+            for (page, data) in self.buffer.iter().zip(content.chunks(PAGE_SIZE)) {
+                unsafe { core::ptr::copy_nonoverlapping(data.as_ptr(), page.as_mut_ptr::<u8>(), data.len()) };
+            }
+        }
+        let pages = ((lba_count * disk.lba_size) as usize).div_ceil(PAGE_SIZE);
+        let second_prp = match pages {
+            1 => 0,
+            2 => self.buffer[1].as_u64(),
+            _ => {
+                let list = self.prp_list.as_mut_ptr::<u64>();
+                for (i, page) in self.buffer[1..pages].iter().enumerate() {
+                    unsafe { list.add(i).write_volatile(page.as_u64()) };
+                }
+                self.prp_list.as_u64()
+            }
+        };
+        SubmissionEntry::new(
+            command as u8,
+            disk.namespace_id,
+            [self.buffer[0].as_u64(), second_prp],
+            [
+                lba.get_bits(0..32) as u32,
+                lba.get_bits(32..64) as u32,
+                (lba_count - 1) as u32, // 0's based
+                0,
+                0,
+                0,
+            ],
+        )
+    }
+    /// Commits the writes of the namespace that are in the volatile cache of the controller
+    fn flush_entry(namespace_id: u32) -> SubmissionEntry {
+        SubmissionEntry::new(IOCommand::Flush as u8, namespace_id, [0; 2], [0; 6])
+    }
+    /// Sends the I/O command and polls until it completes, then copies the first `read_len` bytes of the buffer
+    fn send(&mut self, entry: SubmissionEntry, read_len: usize) -> Result<Vec<u8>, DiskError> {
+        let opcode = self.io.submit(entry);
+        self.finish(opcode, read_len)
+    }
+    /// Waits for the I/O command, then copies the first `read_len` bytes of the buffer
+    fn finish(&mut self, opcode: u8, read_len: usize) -> Result<Vec<u8>, DiskError> {
+        self.io.complete(opcode)?;
+        let mut data = Vec::with_capacity(read_len);
+        let mut remaining = read_len;
+        for page in &self.buffer {
+            if remaining == 0 {
+                break;
+            }
+            let len = remaining.min(PAGE_SIZE);
+            data.extend_from_slice(unsafe { core::slice::from_raw_parts(page.as_ptr::<u8>(), len) });
+            remaining -= len;
+        }
+        Ok(data)
+    }
+    /// Waits for the command a task sleeps on, if there is one, so that the queue and the buffer can be used
+    fn collect_in_flight(&mut self) {
+        if let Some(mut command) = self.in_flight.take() {
+            if !command.is_collected() {
+                command.result = Some(self.finish(command.command, command.read_len));
+                // The interrupt may have come before the task slept, or not at all if the controller stopped sending it
+                if let Some(done) = self.io.event() {
+                    done.set();
+                }
+            }
+            self.in_flight = Some(command);
+        }
+    }
+    fn read(&mut self, disk: &NVMeDisk, mut lba: u64, lba_count: u64) -> Result<Vec<u8>, DiskError> {
+        let mut data = Vec::with_capacity((lba_count * disk.lba_size) as usize);
+        let end_lba = lba + lba_count;
+        while lba < end_lba {
+            let count = (end_lba - lba).min(self.max_transfer_lbas(disk));
+            let entry = self.transfer(IOCommand::Read, disk, lba, count, None);
+            data.extend(self.send(entry, (count * disk.lba_size) as usize)?);
+            lba += count;
+        }
+        Ok(data)
+    }
+    /// The content has to be a multiple of the block size
+    fn write(&mut self, disk: &NVMeDisk, mut lba: u64, content: &[u8]) -> Result<(), DiskError> {
+        let max_bytes = (self.max_transfer_lbas(disk) * disk.lba_size) as usize;
+        for chunk in content.chunks(max_bytes) {
+            let count = chunk.len() as u64 / disk.lba_size;
+            let entry = self.transfer(IOCommand::Write, disk, lba, count, Some(chunk));
+            self.send(entry, 0)?;
+            lba += count;
+        }
+        Ok(())
+    }
+    fn flush(&mut self, namespace_id: u32) -> Result<(), DiskError> {
+        if !self.volatile_write_cache {
+            return Ok(());
+        }
+        self.send(Self::flush_entry(namespace_id), 0)?;
+        Ok(())
+    }
+}
+
+/// A submission queue and its completion queue, each one is a page
 #[derive(Debug)]
 pub struct NVMeQueue {
     pub queue_id: u16,
     pub completion_base: VirtAddr,
     pub submission_base: VirtAddr,
-    pub completion_db: u32,
-    pub submission_db: u32,
-    pub completion_queue_size: u16,
-    pub submission_queue_size: u16,
+    /// Takes the new head of the completion queue
+    pub completion_db: u64,
+    /// Takes the new tail of the submission queue
+    pub submission_db: u64,
+    /// Both queues have this many entries
+    pub entries: u16,
+    submission_tail: u16,
+    completion_head: u16,
+    /// Phase tag of the new completion entries, it flips each time the controller wraps around the queue
+    phase: bool,
+    command_id: u16,
+    bar0: u64,
+    /// Index of the controller in the statics shared with the interrupt handler, if the queue interrupts
+    interrupt: Option<usize>,
+    /// If the controller doesn't interrupt, completion is then polled
+    polling: bool,
+    /// If an interrupt was seen, only then can a task sleep until the next one
+    irq_seen: bool,
 }
 impl NVMeQueue {
-    // fn identify(&mut self) {
-    //     let id = malloc!(PageTableFlags::PRESENT | PageTableFlags::WRITABLE).unwrap();
-    //     // TODO Proper command creation
-    //     let command = NVMeSubmission {
-    //         opcode: 0x6,
-    //         fuse_and_psdt: 0,
-    //         command_id: 0,
-    //         ns_id: 0,
-    //         _reserved: 0,
-    //         metadata_ptr: 0,
-    //         prp1: 0,
-    //         prp2: 0,
-    //         command: NVMeCommand {
-    //             raw: [1, 0, 0, 0, 0, 0],
-    //             header: todo!(),
-    //         },
-    //     }; //TODOOOOOOOOOOOOOOOOOOOOOO
-    //     let subs = unsafe {
-    //         core::slice::from_raw_parts_mut(self.submission_base.as_u64() as *mut NVMeSubmission, 4)
-    //     };
-    //     subs[0] = command;
-    //     dbg!((unsafe { *(self.submission_db as *mut u32) }));
-    //     unsafe {
-    //         *(self.submission_db as *mut u32) = 1;
-    //     }
-    // }
-}
-#[repr(C, packed)]
-pub struct NVMeCommand {
-    header: [u32; 10],
-    raw: [u32; 6],
-}
-
-#[repr(C, packed)]
-pub struct NVMeSubmission {
-    opcode: u8,
-    fuse_and_psdt: u8,
-    command_id: u16,
-    ns_id: u32,
-    _reserved: u64,
-    metadata_ptr: u64,
-    prp1: u64,
-    prp2: u64,
-    command: NVMeCommand,
+    fn new(queue_id: u16, bar0: u64, doorbell_stride: u64, entries: u16) -> Option<Self> {
+        // 3.1.4.22 Doorbells start at 0x1000, the submission one then the completion one for each queue
+        let stride = 4 << doorbell_stride;
+        Some(Self {
+            queue_id,
+            completion_base: dma_page()?,
+            submission_base: dma_page()?,
+            completion_db: bar0 + 0x1000 + (2 * u64::from(queue_id) + 1) * stride,
+            submission_db: bar0 + 0x1000 + (2 * u64::from(queue_id)) * stride,
+            entries,
+            submission_tail: 0,
+            completion_head: 0,
+            phase: true,
+            command_id: 0,
+            bar0,
+            interrupt: None,
+            polling: false,
+            irq_seen: false,
+        })
+    }
+    /// Submits the command and polls until it completes, only one command is in the queue at a time
+    /// Returns the command specific dword of the completion
+    fn run(&mut self, entry: SubmissionEntry) -> Result<u32, DiskError> {
+        let opcode = self.submit(entry);
+        self.complete(opcode)
+    }
+    /// Returns the opcode, `complete` then waits for the command
+    fn submit(&mut self, mut entry: SubmissionEntry) -> u8 {
+        if let Some(done) = self.event() {
+            done.reset();
+            self.unmask_interrupts();
+        }
+        self.command_id = self.command_id.wrapping_add(1);
+        entry.command.command_id = self.command_id;
+        let opcode = entry.command.opcode;
+        unsafe {
+            let slot = self.submission_base.as_mut_ptr::<SubmissionEntry>();
+            slot.add(usize::from(self.submission_tail)).write_volatile(entry);
+        }
+        self.submission_tail = (self.submission_tail + 1) % self.entries;
+        unsafe { (self.submission_db as *mut u32).write_volatile(u32::from(self.submission_tail)) };
+        opcode
+    }
+    /// Polls until the command completes, returns the command specific dword of the completion
+    fn complete(&mut self, opcode: u8) -> Result<u32, DiskError> {
+        let slot = unsafe {
+            self.completion_base
+                .as_ptr::<CompletionEntry>()
+                .add(usize::from(self.completion_head))
+        };
+        let mut polls = 0;
+        let completed = loop {
+            let completed = unsafe { slot.read_volatile() };
+            if completed.phase_bit() == self.phase {
+                break completed;
+            }
+            polls += 1;
+            if polls > TIMEOUT {
+                log::error!("[NVME] Command {:#x} on queue {} timed out", opcode, self.queue_id);
+                return Err(DiskError::TimeOut);
+            }
+            core::hint::spin_loop();
+        };
+        if let Some(done) = self.event().filter(|_| !self.irq_seen && !self.polling) {
+            if (0..IRQ_TIMEOUT).any(|_| {
+                core::hint::spin_loop();
+                done.is_set()
+            }) {
+                self.irq_seen = true;
+            } else {
+                log::warn!("[NVME] No interrupt from the controller, polling it from now");
+                self.polling = true;
+            }
+        }
+        self.completion_head = (self.completion_head + 1) % self.entries;
+        if self.completion_head == 0 {
+            self.phase = !self.phase;
+        }
+        unsafe { (self.completion_db as *mut u32).write_volatile(u32::from(self.completion_head)) };
+        if completed.status() != 0 {
+            log::error!(
+                "[NVME] Command {:#x} on queue {} failed with status {:#x}",
+                opcode,
+                self.queue_id,
+                completed.status()
+            );
+            return Err(DiskError::CommandFailed(completed.status()));
+        }
+        Ok(completed.command_specific)
+    }
+    /// If the controller posted the completion of the command
+    fn has_completion(&self) -> bool {
+        let slot = unsafe {
+            self.completion_base
+                .as_ptr::<CompletionEntry>()
+                .add(usize::from(self.completion_head))
+        };
+        unsafe { slot.read_volatile() }.phase_bit() == self.phase
+    }
+    /// Set by the interrupt of the controller, if the queue interrupts
+    fn event(&self) -> Option<&'static Event> {
+        self.interrupt.map(|idx| &IO_DONE[idx])
+    }
+    /// The event a task can sleep on, only once the controller is known to interrupt as nothing would wake the task otherwise
+    fn sleep_event(&self) -> Option<&'static Event> {
+        self.event().filter(|_| self.irq_seen && !self.polling)
+    }
+    /// The interrupt handler masks the interrupt until we looked at the queue
+    fn unmask_interrupts(&self) {
+        unsafe { NVMeRegisters::new(self.bar0 as usize) }.unmask_interrupts();
+    }
 }
-#[derive(Debug)]
-#[repr(C, packed)]
-pub struct NVMeCompletion {
-    dw0: u32,        // DWORD 0, Command specific
-    _reserved: u32,  // DWORD 1, Reserved
-    sq_head: u16,    // DWORD 2, Submission queue (indicated in sqID) head pointer
-    sq_id: u16,      // DWORD 2, Submission queue ID
-    command_id: u16, // Id of the command being completed
-    phase_tag_and_status: u16, // DWORD 3, Changed when a completion queue entry is new
-                     // status: u16,     // DWORD 3, Status of command being completed
-}
-
-// let int = nvme_pci.raw.int_pin;
-// dbg!(int, nvme_pci.raw.command);
-// //TODO The host configures the Admin Queue by setting the Admin Queue Attributes (AQA), Admin
-// // Submission Queue Base Address (ASQ), and Admin Completion Queue Base Address (ACQ) to
-// // appropriate values;
-// let caps = regs.controller_caps;
-// dbg!(regs);
-// // if caps.get_bit(37 + 7) {
-// //     dbg!(config);
-// // }
-// // regs.nvm_subsystem_reset = 0x4E564D65; // Reset NVM
-
-// dbg!(regs.capas_doorbell_stride());
-// let addr = 4096 * 390;
-// //TODO Does the NVMe needs it ?
-// //TODO Get a frame allocator for low memory
-// let buffer = unsafe { core::slice::from_raw_parts_mut(addr as *mut u8, 4096) }; // Rolled a dice, I swear it's random 🤣
-// mem_map!(frame_addr = addr, WRITABLE);
-// let identify = SubmissionEntry::new_identify(IdentifyType::Controller, None, buffer);
-// //
-// let submission_addr = regs.admin_submission_queue;
-// mem_map!(frame_addr = submission_addr, WRITABLE);
-// mem_map!(frame_addr = submission_addr + 0x1000, WRITABLE);
-// regs.add_submission_entry(identify);
-// loop {
-//     let q = regs.completion_queue();
-//     // if q.len()>0 {
-//     //     dbg!(q);
-//     //     break
-//     // }
-//     let buffer = unsafe { core::slice::from_raw_parts(addr as *const u8, 4096) };
-//     // dbg!(q);
-//     if !all_zeroes(buffer) {
-//         break;
-//     }
-// }
-// Some(Vec::new())
 
 bitfield::bitfield! {
     pub struct NVMeControllerCaps(u64);
@@ -448,6 +857,7 @@ impl NVMeControllerSubsystemReset {
         self.0 = 0x4E56_4D65;
     }
 }
+/// Every access goes through the volatile getters and setters, as those are MMIO
 #[repr(C)]
 struct NVMeRegisters {
     /// CAP
@@ -459,7 +869,7 @@ struct NVMeRegisters {
     interrupt_mask_clear: u32,
     /// CC
     controller_config: NVMeControllerConfig,
-    /// Something but idk, following the bad doc wiki.osdev.org/NVMe
+    /// Reserved
     _pad: u32,
     /// CSTS
     controller_status: NVMeControllerStatus,
@@ -479,115 +889,63 @@ struct NVMeRegisters {
     boot_partition_info: u32,
     /// BPRSEL - Boot Partition Read Select . ..
     boot_partition_read_select: u32,
-    // boot_partition_memory_buffer_location: u16,
-    // controller_memory_buffer_memory_space_control: u32,
-    // ...
 }
 impl NVMeRegisters {
-    // fn set_admin_completion_queue_size(&mut self, sz: u16) {
-    //     self.admin_queue_attrs = (self.admin_queue_attrs
-    //         & !NVME_AQA_ACQS(NVME_AQA_AQS_MASK) as u32)
-    //         | NVME_AQA_ACQS(sz as u64 - 1) as u32; // 0's based
-    // }
-
-    // fn set_admin_submission_queue_size(&mut self, sz: u16) {
-    //     self.admin_queue_attrs = (self.admin_queue_attrs
-    //         & !NVME_AQA_ASQS(NVME_AQA_AQS_MASK) as u32)
-    //         | NVME_AQA_ASQS(sz as u64 - 1) as u32; // 0's based
-    // }
-
-    fn get_max_queue_entries(&self) -> u16 {
-        let max = (self.controller_caps.0 & 0xffff) as u16;
-        if max == 0 {
-            u16::MAX
-        } else {
-            max + 1
-        }
-    }
-    // fn set_command_set(&mut self, set: u8) {
-    //     self.controller_config = (self.controller_config & !NVME_CFG_CSS(NVME_CFG_CSS_MASK) as u32)
-    //         | NVME_CFG_CSS(set) as u32;
-    // }
-    // fn get_completion_doorbell(&mut self, queue_id: u16) -> u32 {
-    //     return (addr_of!(*self) as u32)
-    //         + 0x1000
-    //         + (2 * queue_id as u32 + 1) * (4 << self.capas_doorbell_stride());
-    // }
-    // fn get_submission_doorbell(&mut self, queue_id: u16) -> u32 {
-    //     return (addr_of!(*self) as u32)
-    //         + 0x1000
-    //         + (2 * queue_id as u32) * (4 << self.capas_doorbell_stride());
-    // }
-    // fn enable(&mut self) {
-    //     self.controller_config |= NVME_CFG_ENABLE;
-    // }
-
     /// # Safety
     /// Ensure that bar0 address is the proper base for the `NVMe` registers
     pub unsafe fn new(bar0: usize) -> &'static mut Self {
         unsafe { &mut *(bar0 as *mut Self) }
     }
-    ///This bit is set to '1' when the controller is ready to process submission
-    ///queue entries after CC.EN is set to '1'. This bit shall be cleared to '0' when CC.EN is
-    ///cleared to '0' once the controller is ready to be re-enabled. Commands should not be
-    ///submitted to the controller until this bit is set to '1' after the CC.EN bit is set to '1'.
-    ///Failure to follow this recommendation produces undefined results. Refer to the
-    ///definition of CAP.TO, sections 3.5.3, and 3.5.4 for timing information related to this
-    ///field.
-    // pub fn ready(&self) -> bool {
-    //     let status = self.controller_status;
-    //     status.get_bit(0)
-    // }
-    // fn db_stride(&self) -> u64 {
-    //     1 << (((self.controller_caps) >> 32) & 0xf)
-    // }
-    pub fn base(&self) -> usize {
-        core::ptr::addr_of!(self.controller_caps) as usize
-    }
-    // pub fn submission_queue(&self) -> Vec<SubmissionEntry> {
-    //     // Can we know the size of the vec ? If so with_capacity()
-    //     let mut queue = Vec::new();
-    //     for i in 0..1000u64 {
-    //         // Max queues is 64Kib
-    //         let addr = ((self.base() as u64 + 0x1000) + (2 * i) * self.db_stride());
-    //         let v = unsafe { &*(addr as *const SubmissionEntry) }.clone();
-    //         if all_zeroes(any_as_u8_slice(&v)) {
-    //             break;
-    //         }
-    //         queue.push(v);
-    //     }
-    //     queue
-    // }
-    ///((`self.base()` as u64+0x1000)+(2*0+1)*`self.db_stride()`)
-    pub fn add_submission_entry(&mut self, entry: SubmissionEntry) {
-        unsafe { *(self.admin_submission_queue_base_addr as *mut SubmissionEntry) = entry }
-    }
-    pub fn completion_queue(&self) -> Vec<CompletionEntry> {
-        // Can we know the size of the vec ? If so with_capacity()
-        let mut queue = Vec::new();
-        for i in 0..1000_u64 {
-            // Max queues is 64Kib
-            let v = unsafe { &*(self.admin_completion_queue_base_addr as *const CompletionEntry) }
-                .clone();
-            if all_zeroes(any_as_u8_slice(&v)) {
-                break;
+    fn caps(&self) -> NVMeControllerCaps {
+        NVMeControllerCaps(unsafe { addr_of!(self.controller_caps.0).read_volatile() })
+    }
+    fn version(&self) -> u32 {
+        unsafe { addr_of!(self.version).read_volatile() }
+    }
+    fn config(&self) -> NVMeControllerConfig {
+        NVMeControllerConfig(unsafe { addr_of!(self.controller_config.0).read_volatile() })
+    }
+    fn set_config(&mut self, config: &NVMeControllerConfig) {
+        unsafe { addr_of_mut!(self.controller_config.0).write_volatile(config.0) }
+    }
+    fn status(&self) -> NVMeControllerStatus {
+        NVMeControllerStatus(unsafe { addr_of!(self.controller_status.0).read_volatile() })
+    }
+    /// Can only be changed while the controller is disabled
+    fn set_admin_queues(&mut self, attrs: &NVMeControllerAdminQueueAttributes, submission: u64, completion: u64) {
+        unsafe {
+            addr_of_mut!(self.admin_queue_attrs.0).write_volatile(attrs.0);
+            addr_of_mut!(self.admin_submission_queue_base_addr).write_volatile(submission);
+            addr_of_mut!(self.admin_completion_queue_base_addr).write_volatile(completion);
+        }
+    }
+    /// Waits for CSTS.RDY to follow CC.EN, the worst-case time is CAP.TO
+    fn wait_ready(&self, ready: bool) -> Result<(), NVMeControllerInitError> {
+        for _ in 0..TIMEOUT {
+            let status = self.status();
+            if ready && status.fatal() != 0 {
+                return Err(NVMeControllerInitError::FatalError);
             }
-            queue.push(v);
+            if (status.ready() != 0) == ready {
+                return Ok(());
+            }
+            core::hint::spin_loop();
         }
-        queue
-    }
-    // CAP.DSTRD
-    // pub fn capas_doorbell_stride(&self) -> u8 {
-    //     let capas = self.controller_caps;
-    //     capas.get_bits(32..35).try_into().unwrap()
-    // }
-    // /// CAP.CSS
-    // pub fn capas_command_sets_supported(&self) -> u8 {
-    //     let capas = self.controller_caps;
-    //     capas.get_bits(37..44).try_into().unwrap()
-    // }
-}
-#[derive(Clone)]
+        Err(NVMeControllerInitError::TimeOut)
+    }
+    /// Masks the vector 0, the only one of pin based interrupts
+    fn mask_interrupts(&mut self) {
+        unsafe { addr_of_mut!(self.interrupt_mask_set).write_volatile(1) }
+    }
+    fn unmask_interrupts(&mut self) {
+        unsafe { addr_of_mut!(self.interrupt_mask_clear).write_volatile(1) }
+    }
+    fn get_max_queue_entries(&self) -> u16 {
+        // 0's based
+        u16::try_from(self.caps().mqes() + 1).unwrap_or(u16::MAX)
+    }
+}
+#[derive(Clone, Copy)]
 #[repr(C, packed)]
 struct SubmissionEntry {
     command: CommandDword0,
@@ -599,27 +957,26 @@ struct SubmissionEntry {
     command_specific: [u32; 6],
 }
 impl SubmissionEntry {
-    pub fn new_identify(
-        to_identify: IdentifyType,
-        namespace_id: Option<u32>,
-        buffer: &mut [u8],
-    ) -> Self {
-        let addr = addr_of!(*buffer) as *const () as u64;
-        dbg!(addr_of!(*buffer));
-        let (namespace_id, first_command) = match to_identify {
-            IdentifyType::Namespace => (namespace_id.unwrap(), 0),
-            IdentifyType::Controller => (0, 1),
-            IdentifyType::NamespaceList => (0, 2),
-        };
+    /// The command id is set when the entry is submitted
+    pub fn new(opcode: u8, namespace_id: u32, data_ptr: [u64; 2], command_specific: [u32; 6]) -> Self {
         Self {
-            command: CommandDword0::new(0x6, 0, 0, 1),
+            command: CommandDword0::new(opcode, 0, 0, 0),
             namespace_id,
             reserved: [0; 2],
             metadata_ptr: 0,
-            data_ptr: [addr, 0],
-            command_specific: [first_command, 0, 0, 0, 0, 0],
+            data_ptr,
+            command_specific,
         }
     }
+    /// The buffer is a page that receives the data structure
+    pub fn new_identify(to_identify: IdentifyType, namespace_id: u32, buffer: VirtAddr) -> Self {
+        Self::new(
+            AdminCommand::Identify as u8,
+            namespace_id,
+            [buffer.as_u64(), 0],
+            [to_identify as u32, 0, 0, 0, 0, 0],
+        )
+    }
 }
 impl core::fmt::Debug for SubmissionEntry {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -640,6 +997,7 @@ impl core::fmt::Debug for SubmissionEntry {
     }
 }
 #[derive(Debug, Clone, Copy)]
+#[repr(C)]
 struct CommandDword0 {
     pub opcode: u8,
     raw: u8,
@@ -647,13 +1005,14 @@ struct CommandDword0 {
     pub command_id: u16,
 }
 impl CommandDword0 {
-    pub fn new(opcode: u8, fused_op: u8, prp_or_sql_select: u8, command_id: u16) -> Self {
+    pub fn new(opcode: u8, fused_op: u8, prp_or_sgl_select: u8, command_id: u16) -> Self {
+        let mut raw = 0;
+        raw.set_bits(0..2, fused_op);
+        raw.set_bits(6..8, prp_or_sgl_select);
         return Self {
             opcode,
             command_id,
-            raw: *fused_op
-                .get_bits(0..2)
-                .set_bits(6..8, prp_or_sql_select.get_bits(6..8)),
+            raw,
         }
     }
     /// 0 indicates normal operation
@@ -668,7 +1027,8 @@ impl CommandDword0 {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
 struct CompletionEntry {
     pub command_specific: u32,
     _reserved: u32,
@@ -683,65 +1043,28 @@ impl CompletionEntry {
         self._status.get_bit(0)
     }
     /// 0 on success
-    /// 14bits
+    /// 15bits
     pub fn status(&self) -> u16 {
         self._status.get_bits(1..)
     }
 }
 
-enum Commands {
-    Admin(AdminCommand),
-    IO(IOCommand),
-}
+/// Opcodes of the admin command set
 enum AdminCommand {
-    CreateIOSubmissionQueue,
-    CreateIOCompletionQueue,
-    Identify,
+    CreateIOSubmissionQueue = 0x01,
+    CreateIOCompletionQueue = 0x05,
+    Identify = 0x06,
 }
+/// Opcodes of the NVM command set
 enum IOCommand {
-    Read,
-    Write,
+    Flush = 0x00,
+    Write = 0x01,
+    Read = 0x02,
 }
 
+/// CNS of Identify
 enum IdentifyType {
     Namespace = 0,
     Controller = 1,
     NamespaceList = 2,
 }
-
-const NVME_CAP_CMBS: u64 = (1 << 57); // Controller memory buffer supported
-const NVME_CAP_PMRS: u64 = (1 << 56); // Persistent memory region supported
-const NVME_CAP_BPS: u64 = (1 << 45); // Boot partition support
-const NVME_CAP_NVM_CMD_SET: u64 = (1 << 37); // NVM command set supported
-const NVME_CAP_NSSRS: u64 = (1 << 36); // NVM subsystem reset supported
-const NVME_CAP_CQR: u32 = (1 << 16); // Contiguous Queues Required
-
-const NVME_CAP_MPS_MASK: u8 = 0xf;
-const NVME_CAP_MPSMAX: fn(u64) -> u64 = |x| ((x >> 52) & u64::from(NVME_CAP_MPS_MASK)); // Max supported memory page size (2 ^ (12 + MPSMAX))
-const NVME_CAP_MPSMIN: fn(u64) -> u64 = |x| ((x >> 48) & u64::from(NVME_CAP_MPS_MASK)); // Min supported memory page size (2 ^ (12 + MPSMIN))
-
-const NVME_CAP_DSTRD_MASK: u64 = 0xf;
-const NVME_CAP_DSTRD: fn(u64) -> u64 = |x| (((x) >> 32) & NVME_CAP_DSTRD_MASK); // Doorbell stride (2 ^ (2 + DSTRD)) bytes
-
-const NVME_CAP_MQES_MASK: u64 = 0xffff;
-const NVME_CAP_MQES: fn(u64) -> u64 = |x| ((x) & NVME_CAP_MQES_MASK); // Maximum queue entries supported
-
-const NVME_CFG_MPS_MASK: u8 = 0xf;
-const NVME_CFG_MPS: fn(u32) -> u32 = |x| (((x) & u32::from(NVME_CFG_MPS_MASK)) << 7); // Host memory page size (2 ^ (12 + MPSMIN))
-const NVME_CFG_CSS_MASK: u8 = 0b111; // Command set selected
-const NVME_CFG_CSS: fn(u8) -> u8 = |x| (((x) & NVME_CFG_CSS_MASK) << 4);
-const NVME_CFG_ENABLE: u32 = (1 << 0);
-const NVME_CONFIG_CMDSET_NVM: u8 = 0;
-const NVME_CFG_DEFAULT_IOCQES: u32 = (4 << 20); // 16 bytes so log2(16) = 4
-const NVME_CFG_DEFAULT_IOSQES: u32 = (6 << 16); // 64 bytes so log2(64) = 6
-
-const NVME_CSTS_FATAL: u8 = (1 << 1);
-const NVME_CSTS_READY: u8 = (1 << 0); // Set to 1 when the controller is ready to accept submission queue doorbell writes
-const NVME_CSTS_NSSRO: u8 = (1 << 4); // NVM Subsystem reset occurred
-
-const NVME_AQA_AQS_MASK: u64 = 0xfff; // Admin queue size mask
-const NVME_AQA_ACQS: fn(u64) -> u64 = |x| (((x) & NVME_AQA_AQS_MASK) << 16); // Admin completion queue size
-const NVME_AQA_ASQS: fn(u64) -> u64 = |x| ((x) & NVME_AQA_AQS_MASK); // Admin submission queue size
-
-// "NVME", initiates a reset
-const NVME_NSSR_RESET_VALUE: u64 = 0x4E56_4D65;
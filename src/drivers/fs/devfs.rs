@@ -0,0 +1,276 @@
+//! Device files mounted on /dev, backed by the disk, serial and console drivers
+//! Block devices: hd[a-d] for ATA disks and hd[a-d][partition number] for their partitions, nvme0n[1..] and nvme0n[disk]p[partition number] for NVMe ones
+//! Character devices: ttyS0 (COM1), console, null, zero and random
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use rand::RngCore;
+use x86_64::instructions::{interrupts, port::PortRead};
+
+use crate::disk::{
+    driver::{disk_sector_count, read_from_disk, write_to_disk, DISK_MANAGER, SECTOR_SIZE},
+    DiskLoc,
+};
+
+use super::{
+    fs_driver::{
+        Dir, Entry, EntryKind, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, FsWriteError,
+        Metadata, SoftEntry,
+    },
+    partition::{Partition, PartitionKind},
+    path::FilePath,
+    vfs::Volume,
+};
+
+/// Line status register of COM1, bit 0 is set when a byte was received
+const COM1_LINE_STATUS: u16 = 0x3F8 + 5;
+const COM1_DATA: u16 = 0x3F8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Device {
+    Disk(DiskLoc),
+    Partition(Partition),
+    Serial,
+    Console,
+    Null,
+    Zero,
+    Random,
+}
+impl Device {
+    /// Disk, first sector and sector count of block devices, they're read and written through the disk cache
+    /// None for character devices, or if the size of the disk can't be read
+    fn sectors(&self) -> Option<(DiskLoc, u64, u64)> {
+        match self {
+            Self::Disk(loc) => Some((*loc, 0, disk_sector_count(loc).ok()?)),
+            Self::Partition(part) => Some((part.0, part.1, part.2)),
+            _ => None,
+        }
+    }
+}
+
+/// Lists the devices, with the disks and partitions that are currently known
+fn devices() -> Vec<(String, Device)> {
+    let mut devices = Vec::new();
+    let mut disks: Vec<DiskLoc> = unsafe { DISK_MANAGER.lock() }
+        .as_ref()
+        .map(|manager| manager.disks.keys().copied().collect())
+        .unwrap_or_default();
+    disks.sort_by_key(DiskLoc::as_index);
+    for loc in disks {
+        let (name, part_prefix) = match loc {
+            DiskLoc::NVMe(_) => {
+                let Some(name) = crate::disk::nvme::disk_name(&loc) else {
+                    continue;
+                };
+                (name.clone(), format!("{name}p"))
+            }
+            DiskLoc::Ahci(_) => {
+                let Some(name) = crate::disk::ahci::disk_name(&loc) else {
+                    continue;
+                };
+                (name.clone(), name)
+            }
+            DiskLoc::Ata(..) => {
+                let name = format!("hd{}", (b'a' + loc.as_index() as u8) as char);
+                (name.clone(), name)
+            }
+        };
+        devices.push((name, Device::Disk(loc)));
+        let partitions = unsafe { crate::state::FS_DRIVER.as_ref() }
+            .and_then(|manager| manager.partitions.get(&loc));
+        for (part_idx, part) in partitions.into_iter().flatten().enumerate() {
+            if part.3 == PartitionKind::Whole {
+                continue;
+            }
+            devices.push((format!("{}{}", part_prefix, part_idx + 1), Device::Partition(part.clone())));
+        }
+    }
+    devices.push(("ttyS0".to_string(), Device::Serial));
+    devices.push(("console".to_string(), Device::Console));
+    devices.push(("null".to_string(), Device::Null));
+    devices.push(("zero".to_string(), Device::Zero));
+    devices.push(("random".to_string(), Device::Random));
+    devices
+}
+
+#[derive(Debug)]
+pub struct DevfsDriver {
+    volume: Volume,
+}
+impl DevfsDriver {
+    #[must_use] pub fn new(volume: Volume) -> Self {
+        Self { volume }
+    }
+    fn device(path: &FilePath) -> Option<Device> {
+        let name = path.path().trim_start_matches('/');
+        devices()
+            .into_iter()
+            .find(|(device_name, _)| device_name == name)
+            .map(|(_, device)| device)
+    }
+}
+
+impl FsDriverInitialiser for DevfsDriver {
+    /// Devfs isn't stored on partitions, see `FsDriverManager::mount_devfs`
+    fn try_init(_partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl FsDriver for DevfsDriver {
+    fn as_enum(&self) -> FsDriverEnum {
+        FsDriverEnum::Devfs
+    }
+    fn partition(&self) -> Option<&Partition> {
+        None
+    }
+    /// The content of devices isn't loaded, they're read with `read_at`
+    fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
+        if path.path() == "/" {
+            let entries: Vec<SoftEntry> = devices()
+                .into_iter()
+                .map(|(name, device)| SoftEntry {
+                    path: FilePath::new(format!("/{name}"), self.volume.clone()),
+                    size: device.sectors().map_or(0, |(_, _, count)| (count * u64::from(SECTOR_SIZE)) as usize),
+                })
+                .collect();
+            return Ok(Entry::Dir(Dir {
+                path: path.clone(),
+                size: entries.len(),
+                entries,
+            }));
+        }
+        let size = self.file_len(path)? as usize;
+        Ok(Entry::File(File {
+            path: path.clone(),
+            content: Vec::new(),
+            size,
+        }))
+    }
+    /// Disks and partitions are block devices, only root can use them
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        if path.path() == "/" {
+            return Ok(Metadata::new(EntryKind::Dir, devices().len() as u64));
+        }
+        let device = Self::device(path).ok_or(FsReadError::EntryNotFound)?;
+        Ok(match device {
+            Device::Disk(_) | Device::Partition(_) => Metadata {
+                mode: 0o660,
+                ..Metadata::new(EntryKind::BlockDevice, self.file_len(path)?)
+            },
+            _ => Metadata {
+                mode: 0o666,
+                ..Metadata::new(EntryKind::CharDevice, 0)
+            },
+        })
+    }
+    /// Character devices have no length
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        let device = Self::device(path).ok_or(FsReadError::EntryNotFound)?;
+        Ok(device.sectors().map_or(0, |(_, _, count)| count * u64::from(SECTOR_SIZE)))
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let device = Self::device(path).ok_or(FsReadError::EntryNotFound)?;
+        match device {
+            Device::Disk(_) | Device::Partition(_) => {
+                let (loc, start, count) = device.sectors().ok_or(FsReadError::ReadingDiskError)?;
+                let sector_size = u64::from(SECTOR_SIZE);
+                let end = (offset + buf.len() as u64).min(count * sector_size);
+                if offset >= end {
+                    return Ok(0);
+                }
+                let first_sector = offset / sector_size;
+                let sectors = end.div_ceil(sector_size) - first_sector;
+                let data = read_from_disk(&loc, start + first_sector, sectors)
+                    .or(Err(FsReadError::ReadingDiskError))?;
+                let skip = (offset % sector_size) as usize;
+                let read = (end - offset) as usize;
+                buf[..read].copy_from_slice(&data[skip..skip + read]);
+                Ok(read)
+            }
+            // Only returns the bytes that were already received
+            Device::Serial => Ok(interrupts::without_interrupts(|| {
+                let mut read = 0;
+                while read < buf.len() && unsafe { u8::read_from_port(COM1_LINE_STATUS) } & 1 != 0 {
+                    buf[read] = unsafe { u8::read_from_port(COM1_DATA) };
+                    read += 1;
+                }
+                read
+            })),
+            // Waits for a line typed on the keyboard, the end of the line is lost if buf is too small
+            Device::Console => {
+                let mut line = crate::user::prompt::input("").into_bytes();
+                line.push(b'\n');
+                let read = line.len().min(buf.len());
+                buf[..read].copy_from_slice(&line[..read]);
+                Ok(read)
+            }
+            Device::Null => Ok(0),
+            Device::Zero => {
+                buf.fill(0);
+                Ok(buf.len())
+            }
+            Device::Random => {
+                crate::drivers::rand::GENERATOR.lock().fill_bytes(buf);
+                Ok(buf.len())
+            }
+        }
+    }
+    /// Block devices can't grow, the filesystem drivers of a partition aren't told about raw writes
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        let device = Self::device(path).ok_or(FsWriteError::EntryNotFound)?;
+        match device {
+            Device::Disk(_) | Device::Partition(_) => {
+                let (loc, start, count) = device.sectors().ok_or(FsWriteError::ReadingDiskError)?;
+                let sector_size = u64::from(SECTOR_SIZE);
+                let end = offset + data.len() as u64;
+                if end > count * sector_size {
+                    return Err(FsWriteError::NoSpaceLeft);
+                }
+                if data.is_empty() {
+                    return Ok(0);
+                }
+                // The sectors at both ends may only be partly written
+                let first_sector = offset / sector_size;
+                let sectors = end.div_ceil(sector_size) - first_sector;
+                let mut content = if offset % sector_size == 0 && end % sector_size == 0 {
+                    alloc::vec![0; (sectors * sector_size) as usize]
+                } else {
+                    read_from_disk(&loc, start + first_sector, sectors)
+                        .or(Err(FsWriteError::ReadingDiskError))?
+                };
+                let skip = (offset % sector_size) as usize;
+                content[skip..skip + data.len()].copy_from_slice(data);
+                write_to_disk(&loc, start + first_sector, &content).or(Err(FsWriteError::WritingDiskError))?;
+                Ok(data.len())
+            }
+            Device::Serial => {
+                interrupts::without_interrupts(|| {
+                    let mut serial = crate::terminal::serial::SERIAL1.lock();
+                    for byte in data {
+                        serial.send(*byte);
+                    }
+                });
+                Ok(data.len())
+            }
+            Device::Console => {
+                crate::print!("{}", String::from_utf8_lossy(data));
+                Ok(data.len())
+            }
+            // Writing to random doesn't change the generator for now
+            Device::Null | Device::Zero | Device::Random => Ok(data.len()),
+        }
+    }
+    /// Only writes to existing devices, from the start
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        self.write_at(filepath, 0, content)?;
+        Ok(())
+    }
+}
@@ -1,4 +1,4 @@
-use alloc::{string::String, vec::Vec};
+use alloc::{string::String, vec, vec::Vec};
 use bit_field::BitField;
 use x86_64::{
     structures::paging::{Page, PageTableFlags, PhysFrame},
@@ -6,24 +6,38 @@ use x86_64::{
 };
 
 use crate::{dbg, mem_handler, mem_map};
-const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
 
-///TODO Take a file descriptor, not full content
-pub fn execute(content: &[u8]) -> Result<(), ElfError> {
-    let bytes = content;
-    if bytes[0..4] != ELF_MAGIC {
+use super::handle::FileHandle;
+pub const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+/// Size of the header of 64 bits ELFs, the 32 bits one is smaller
+const ELF_HEADER_SIZE: usize = 64;
+
+/// Reads exactly buf.len() bytes of the file
+fn read_exact(file: &FileHandle, offset: u64, buf: &mut [u8]) -> Result<(), ElfError> {
+    let read = file.read_at(offset, buf).or(Err(ElfError::ReadingError))?;
+    if read != buf.len() {
+        // The file is shorter than what the headers say
+        return Err(ElfError::InvalidEntry);
+    }
+    Ok(())
+}
+
+pub fn execute(file: &FileHandle) -> Result<(), ElfError> {
+    let mut bytes = [0; ELF_HEADER_SIZE];
+    let read = file.read_at(0, &mut bytes).or(Err(ElfError::ReadingError))?;
+    if read < ELF_MAGIC.len() || bytes[0..4] != ELF_MAGIC {
         // Simple check of magic number
         return Err(ElfError::InvalidEntry);
     }
-    let elf = ELF::new(bytes)?;
+    let elf = ELF::new(&bytes)?;
     let format = elf.start.format().ok_or(ElfError::InvalidEntry)?;
+    let entry_size = usize::from(elf.end.program_header_table_entry_size);
+    let mut program_header_table =
+        vec![0; usize::from(elf.end.program_header_entries_count) * entry_size];
+    read_exact(file, elf.middle.start_program_header_ptr(), &mut program_header_table)?;
     let mut program_headers = Vec::new();
-    for i in 0..elf.end.program_header_entries_count {
-        //TODO Should we make a mut offset and then increment it on each iteration ?
-        let offset = elf.middle.start_program_header_ptr()
-            + u64::from(i * elf.end.program_header_table_entry_size);
-        let program_header = ElfProgramHeader::new(&bytes[offset as usize..], format)
-            .ok_or(ElfError::InvalidEntry)?;
+    for raw in program_header_table.chunks_exact(entry_size.max(1)) {
+        let program_header = ElfProgramHeader::new(raw, format).ok_or(ElfError::InvalidEntry)?;
         program_headers.push(program_header);
     }
     for ph in program_headers {
@@ -53,15 +67,14 @@ pub fn execute(content: &[u8]) -> Result<(), ElfError> {
                     );
                     // TODO If the p_filesz and p_memsz members differ, this indicates that the segment is padded with zeros. All bytes in memory between the ending offset of the file size, and the segment's virtual memory size are to be cleared with zeros
                     assert_eq!(ph.size_img(), ph.size_mem());
-                    let part = &bytes[ph.offset() as usize..(ph.offset() + ph.size_img()) as usize];
-                    assert_eq!(ph.size_mem(), part.len() as u64);
-                    unsafe {
-                        core::ptr::copy_nonoverlapping(
-                            part.as_ptr(),
+                    // The segment is read from disk straight to where it's mapped
+                    let segment = unsafe {
+                        core::slice::from_raw_parts_mut(
                             ph.virt_addr() as *mut u8,
                             ph.size_mem() as usize,
-                        );
+                        )
                     };
+                    read_exact(file, ph.offset(), segment)?;
                 }
             }
             ElfSegmentType::DYNAMIC => {
@@ -79,20 +92,22 @@ pub fn execute(content: &[u8]) -> Result<(), ElfError> {
     ", in(reg) entry_point_addr);
     }
 
+    let entry_size = usize::from(elf.end.section_header_entry_size);
+    let mut section_header_table =
+        vec![0; usize::from(elf.end.section_header_entries_count) * entry_size];
+    read_exact(file, elf.middle.start_section_header_table_ptr(), &mut section_header_table)?;
     let mut section_headers = Vec::new();
-    for i in 0..elf.end.section_header_entries_count {
-        let offset = elf.middle.start_section_header_table_ptr() as usize
-            + (i as usize) * elf.end.section_header_entry_size as usize;
-        let section_header =
-            ElfSectionHeader::new(&bytes[offset..], format).ok_or(ElfError::InvalidEntry)?;
+    for raw in section_header_table.chunks_exact(entry_size.max(1)) {
+        let section_header = ElfSectionHeader::new(raw, format).ok_or(ElfError::InvalidEntry)?;
         section_headers.push(section_header);
     }
-    let names_entry = &section_headers[elf.end.index_section_header_table_entry as usize];
+    let names_entry = section_headers
+        .get(elf.end.index_section_header_table_entry as usize)
+        .ok_or(ElfError::InvalidEntry)?;
+    let mut raw_names = vec![0; names_entry.img_size() as usize];
+    read_exact(file, names_entry.offset_section_img(), &mut raw_names)?;
 
-    let names = String::from_utf8_lossy(
-        &bytes[names_entry.offset_section_img() as usize
-            ..names_entry.offset_section_img() as usize + names_entry.img_size() as usize],
-    );
+    let names = String::from_utf8_lossy(&raw_names);
     for header in section_headers {
         let mut name = String::new();
         for char in names[header.name() as usize..].chars() {
@@ -541,6 +556,7 @@ impl ElfSegmentType {
 #[derive(Debug)]
 pub enum ElfError {
     InvalidEntry,
+    ReadingError,
 }
 #[derive(Debug)]
 pub struct ELF<'a> {
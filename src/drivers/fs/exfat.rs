@@ -0,0 +1,983 @@
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+use crate::disk::driver::{read_from_partition, write_to_partition, SECTOR_SIZE};
+
+use super::{
+    fs_driver::{
+        Dir, Entry, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, FsWriteError,
+        SoftEntry,
+    },
+    partition::Partition,
+    path::FilePath,
+};
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ExFatBootSector {
+    pub jump_boot: [u8; 3],
+    pub fs_name: [u8; 8],
+    /// Where the BPB of FAT12/16/32 is, so they don't take exFAT for one of them
+    pub must_be_zero: [u8; 53],
+    pub partition_offset: u64,
+    /// In sectors
+    pub volume_length: u64,
+    pub fat_offset: u32,
+    pub fat_length: u32,
+    pub cluster_heap_offset: u32,
+    pub cluster_count: u32,
+    pub first_cluster_of_root: u32,
+    pub volume_serial: u32,
+    pub fs_revision: u16,
+    pub volume_flags: u16,
+    pub bytes_per_sector_shift: u8,
+    pub sectors_per_cluster_shift: u8,
+    pub fats: u8,
+    pub drive_select: u8,
+    pub percent_in_use: u8,
+    pub reserved: [u8; 7],
+}
+
+/// A file entry followed by its stream extension and file name entries, as stored in a directory
+#[derive(Debug, Clone)]
+struct EntrySet {
+    /// Index of the file entry in the directory, in 32 bytes slots
+    slot: usize,
+    raw: Vec<u8>,
+}
+impl EntrySet {
+    /// The first cluster and size of the stream are left to 0, `name` must be valid (see `check_name`)
+    fn new(name: &[u16], name_hash: u16, attributes: u16) -> Self {
+        let name_entries = name.len().div_ceil(NAME_CHARS_PER_ENTRY);
+        let mut raw = vec![0; (2 + name_entries) * 32];
+        raw[0] = ENTRY_FILE;
+        raw[1] = (1 + name_entries) as u8;
+        raw[4..6].copy_from_slice(&attributes.to_le_bytes());
+        raw[32] = ENTRY_STREAM;
+        raw[33] = FLAG_ALLOCATION_POSSIBLE;
+        raw[35] = name.len() as u8;
+        raw[36..38].copy_from_slice(&name_hash.to_le_bytes());
+        for (i, part) in name.chunks(NAME_CHARS_PER_ENTRY).enumerate() {
+            let entry = &mut raw[(2 + i) * 32..(3 + i) * 32];
+            entry[0] = ENTRY_FILE_NAME;
+            for (j, unit) in part.iter().enumerate() {
+                entry[2 + j * 2..4 + j * 2].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        let mut set = Self { slot: 0, raw };
+        set.update_checksum();
+        set
+    }
+    fn slots(&self) -> usize {
+        self.raw.len() / 32
+    }
+    fn attributes(&self) -> u16 {
+        u16::from_le_bytes([self.raw[4], self.raw[5]])
+    }
+    fn is_dir(&self) -> bool {
+        self.attributes() & ATTR_DIRECTORY != 0
+    }
+    fn no_fat_chain(&self) -> bool {
+        self.raw[33] & FLAG_NO_FAT_CHAIN != 0
+    }
+    fn valid_size(&self) -> u64 {
+        read_u64(&self.raw, 40)
+    }
+    fn first_cluster(&self) -> u32 {
+        read_u32(&self.raw, 52)
+    }
+    fn size(&self) -> u64 {
+        read_u64(&self.raw, 56)
+    }
+    fn name_units(&self) -> Vec<u16> {
+        let len = usize::from(self.raw[35]);
+        self.raw[64..]
+            .chunks_exact(32)
+            .filter(|entry| entry[0] == ENTRY_FILE_NAME)
+            .flat_map(|entry| entry[2..].chunks_exact(2).map(|unit| u16::from_le_bytes([unit[0], unit[1]])))
+            .take(len)
+            .collect()
+    }
+    fn name(&self) -> String {
+        String::from_utf16_lossy(&self.name_units())
+    }
+    fn set_stream(&mut self, first_cluster: u32, size: u64, valid_size: u64, no_fat_chain: bool) {
+        let mut flags = FLAG_ALLOCATION_POSSIBLE;
+        if no_fat_chain && first_cluster != 0 {
+            flags |= FLAG_NO_FAT_CHAIN;
+        }
+        self.raw[33] = flags;
+        self.raw[40..48].copy_from_slice(&valid_size.to_le_bytes());
+        self.raw[52..56].copy_from_slice(&first_cluster.to_le_bytes());
+        self.raw[56..64].copy_from_slice(&size.to_le_bytes());
+        self.update_checksum();
+    }
+    fn checksum(&self) -> u16 {
+        self.raw.iter().enumerate().fold(0_u16, |sum, (i, byte)| {
+            // The checksum field itself is skipped
+            if i == 2 || i == 3 {
+                sum
+            } else {
+                sum.rotate_right(1).wrapping_add(u16::from(*byte))
+            }
+        })
+    }
+    fn update_checksum(&mut self) {
+        let checksum = self.checksum();
+        self.raw[2..4].copy_from_slice(&checksum.to_le_bytes());
+    }
+}
+
+/// Reads the entry sets of a directory, the other entries (bitmap, up-case table, label...) are skipped
+fn entry_sets(dir_data: &[u8]) -> Vec<EntrySet> {
+    let mut sets = Vec::new();
+    let mut slot = 0;
+    while slot < dir_data.len() / 32 {
+        let entry_type = dir_data[slot * 32];
+        if entry_type == ENTRY_END_OF_DIR {
+            break;
+        }
+        if entry_type != ENTRY_FILE {
+            slot += 1;
+            continue;
+        }
+        let count = 1 + usize::from(dir_data[slot * 32 + 1]);
+        let end = (slot + count) * 32;
+        if count < 3 || end > dir_data.len() {
+            log::error!("Truncated exFAT entry set at slot {}", slot);
+            break;
+        }
+        let set = EntrySet {
+            slot,
+            raw: dir_data[slot * 32..end].to_vec(),
+        };
+        let valid = set.raw[32] == ENTRY_STREAM
+            && set.raw.chunks_exact(32).all(|entry| entry[0] & ENTRY_IN_USE != 0)
+            && u16::from_le_bytes([set.raw[2], set.raw[3]]) == set.checksum();
+        if valid {
+            sets.push(set);
+        } else {
+            log::error!("Invalid exFAT entry set at slot {}", slot);
+        }
+        slot += count;
+    }
+    sets
+}
+/// Index of the first slot of a run of `needed` unused slots, the run can go past the end of the data
+fn free_slots_start(dir_data: &[u8], needed: usize) -> usize {
+    let mut run = 0;
+    for (i, raw) in dir_data.chunks_exact(32).enumerate() {
+        if raw[0] == ENTRY_END_OF_DIR {
+            // Every slot after the end marker is free
+            return i - run;
+        }
+        if raw[0] & ENTRY_IN_USE == 0 {
+            run += 1;
+            if run == needed {
+                return i + 1 - needed;
+            }
+        } else {
+            run = 0;
+        }
+    }
+    dir_data.len() / 32 - run
+}
+fn check_name(name: &str) -> Result<(), FsWriteError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > MAX_NAME_LEN
+        || name
+            .chars()
+            .any(|c| c.is_control() || "/\\:*?\"<>|".contains(c))
+    {
+        return Err(FsWriteError::InvalidName);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ExFatSoftEntry {
+    pub path: FilePath,
+    pub is_file: bool,
+    /// 0 for empty files
+    pub first_cluster: u32,
+    /// In bytes, directories have one too
+    pub size: u64,
+    /// Bytes after it read as zeroes
+    pub valid_size: u64,
+    /// The clusters are contiguous and the FAT isn't used
+    pub no_fat_chain: bool,
+}
+
+#[derive(Debug)]
+pub struct ExFatDriver {
+    partition: Partition,
+    boot: ExFatBootSector,
+    files: HashMap<FilePath, ExFatSoftEntry>,
+    /// Allocation bitmap, one bit per cluster starting from cluster 2
+    bitmap: Vec<u8>,
+    /// Clusters holding the allocation bitmap on disk
+    bitmap_clusters: Vec<u32>,
+    /// Up-case table, used to compare and hash names
+    upcase: Vec<u16>,
+    /// Where to start looking for free clusters
+    next_free_cluster: u32,
+    /// Directories found but not read yet, see `index_step`
+    unindexed: Vec<ExFatSoftEntry>,
+}
+impl ExFatDriver {
+    #[must_use] pub fn new(partition: &Partition) -> Option<Self> {
+        let mut driver = Self::open(partition)?;
+        while driver.index_step().ok()? {}
+        Some(driver)
+    }
+    /// Reads the metadata and adds the root, the directories are then read by `index_step`
+    fn open(partition: &Partition) -> Option<Self> {
+        // The backup boot region is used if the main one is damaged
+        let boot = Self::read_boot_region(partition, 0)
+            .or_else(|| Self::read_boot_region(partition, BACKUP_BOOT_REGION))?;
+        let root = FilePath::new("/".to_string(), partition.clone());
+        let mut driver = Self {
+            partition: partition.clone(),
+            boot,
+            files: HashMap::new(),
+            bitmap: Vec::new(),
+            bitmap_clusters: Vec::new(),
+            upcase: Vec::new(),
+            next_free_cluster: 2,
+            unindexed: Vec::new(),
+        };
+        let root_chain = driver.cluster_chain(boot.first_cluster_of_root).ok()?;
+        let root_entry = ExFatSoftEntry {
+            path: root.clone(),
+            is_file: false,
+            first_cluster: boot.first_cluster_of_root,
+            size: (root_chain.len() * driver.cluster_size()) as u64,
+            valid_size: (root_chain.len() * driver.cluster_size()) as u64,
+            no_fat_chain: false,
+        };
+        let root_data = driver.read_clusters(&root_chain).ok()?;
+        driver.load_metadata(&root_data)?;
+        driver.files.insert(root.clone(), root_entry.clone());
+        driver.unindexed.push(root_entry);
+        Some(driver)
+    }
+    /// Reads and checks the boot sector and the checksum of a boot region
+    fn read_boot_region(partition: &Partition, start: u64) -> Option<ExFatBootSector> {
+        let region = read_from_partition(partition, start, BOOT_REGION_SECTORS).ok()?;
+        let boot: ExFatBootSector =
+            bytemuck::pod_read_unaligned(&region[..core::mem::size_of::<ExFatBootSector>()]);
+        let sector_size = usize::from(SECTOR_SIZE);
+        if boot.fs_name != *EXFAT_SIGNATURE
+            || boot.must_be_zero.iter().any(|byte| *byte != 0)
+            || region[510..512] != [0x55, 0xAA]
+            // Only 512 bytes sectors are supported by the disk drivers
+            || 1_usize << boot.bytes_per_sector_shift != sector_size
+            || boot.sectors_per_cluster_shift > MAX_CLUSTER_SHIFT
+            || boot.fats == 0
+            || boot.fats > 2
+        {
+            return None;
+        }
+        // Volume flags and percent in use can change without updating the checksum
+        let checksum = region[..11 * sector_size]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| ![106, 107, 112].contains(i))
+            .fold(0_u32, |sum, (_, byte)| sum.rotate_right(1).wrapping_add(u32::from(*byte)));
+        if region[11 * sector_size..]
+            .chunks_exact(4)
+            .any(|chunk| read_u32(chunk, 0) != checksum)
+        {
+            log::error!("Invalid exFAT boot region checksum on {:?}", partition);
+            return None;
+        }
+        Some(boot)
+    }
+    /// Loads the allocation bitmap and up-case table described in the root directory
+    fn load_metadata(&mut self, root_data: &[u8]) -> Option<()> {
+        let active_fat = (self.boot.volume_flags & VOLUME_FLAG_ACTIVE_FAT) as u8;
+        for raw in root_data.chunks_exact(32) {
+            match raw[0] {
+                ENTRY_END_OF_DIR => break,
+                // With two FATs, each one has its own bitmap
+                ENTRY_BITMAP if raw[1] & 1 == active_fat => {
+                    let clusters = self.cluster_chain(read_u32(raw, 20)).ok()?;
+                    let mut bitmap = self.read_clusters(&clusters).ok()?;
+                    bitmap.truncate(read_u64(raw, 24) as usize);
+                    self.bitmap = bitmap;
+                    self.bitmap_clusters = clusters;
+                }
+                ENTRY_UPCASE => {
+                    let clusters = self.cluster_chain(read_u32(raw, 20)).ok()?;
+                    let mut table = self.read_clusters(&clusters).ok()?;
+                    table.truncate(read_u64(raw, 24) as usize);
+                    self.upcase = decompress_upcase(&table);
+                }
+                _ => {}
+            }
+        }
+        if self.bitmap.len() * 8 < self.boot.cluster_count as usize {
+            log::error!("exFAT allocation bitmap is missing or too small on {:?}", self.partition);
+            return None;
+        }
+        Some(())
+    }
+    #[must_use] pub fn cluster_size(&self) -> usize {
+        usize::from(SECTOR_SIZE) << self.boot.sectors_per_cluster_shift
+    }
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        u64::from(self.boot.cluster_heap_offset)
+            + (u64::from(cluster - 2) << self.boot.sectors_per_cluster_shift)
+    }
+    /// First sector of the FAT in use, the second FAT is only used by TexFAT
+    fn fat_sector(&self) -> u64 {
+        let active_fat = u64::from(self.boot.volume_flags & VOLUME_FLAG_ACTIVE_FAT);
+        u64::from(self.boot.fat_offset) + active_fat * u64::from(self.boot.fat_length)
+    }
+    /// Follows the FAT from the first cluster of an entry
+    fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>, FsWriteError> {
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+        // Chains are mostly contiguous, so the FAT sector is often the same as before
+        let mut loaded_sector = u64::MAX;
+        let mut content = Vec::new();
+        while (2..BAD_CLUSTER).contains(&cluster) {
+            if chain.len() > self.boot.cluster_count as usize {
+                log::error!("Loop in cluster chain starting at {}", first_cluster);
+                return Err(FsWriteError::ReadingDiskError);
+            }
+            chain.push(cluster);
+            let fat_offset = u64::from(cluster) * 4;
+            if fat_offset / 512 != loaded_sector {
+                loaded_sector = fat_offset / 512;
+                content = read_from_partition(&self.partition, self.fat_sector() + loaded_sector, 1)
+                    .or(Err(FsWriteError::ReadingDiskError))?;
+            }
+            cluster = read_u32(&content, (fat_offset % 512) as usize);
+        }
+        Ok(chain)
+    }
+    /// Clusters of an entry, contiguous ones don't use the FAT
+    fn entry_clusters(&self, entry: &ExFatSoftEntry) -> Result<Vec<u32>, FsWriteError> {
+        if entry.first_cluster == 0 {
+            return Ok(Vec::new());
+        }
+        if entry.no_fat_chain {
+            let count = entry.size.div_ceil(self.cluster_size() as u64) as u32;
+            return Ok((entry.first_cluster..entry.first_cluster + count).collect());
+        }
+        self.cluster_chain(entry.first_cluster)
+    }
+    /// Sets (cluster, value) entries in the FAT, each FAT sector is only written once
+    fn set_fat_entries(&self, entries: &[(u32, u32)]) -> Result<(), FsWriteError> {
+        let mut by_sector: HashMap<u64, Vec<(usize, u32)>> = HashMap::new();
+        for (cluster, value) in entries {
+            let fat_offset = u64::from(*cluster) * 4;
+            by_sector
+                .entry(fat_offset / 512)
+                .or_default()
+                .push(((fat_offset % 512) as usize, *value));
+        }
+        for (sector, values) in by_sector {
+            let mut content = read_from_partition(&self.partition, self.fat_sector() + sector, 1)
+                .or(Err(FsWriteError::ReadingDiskError))?;
+            for (offset, value) in values {
+                content[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+            write_to_partition(&self.partition, self.fat_sector() + sector, &content)
+                .or(Err(FsWriteError::WritingDiskError))?;
+        }
+        Ok(())
+    }
+    /// Links the clusters together in the FAT, the last one is marked as end of chain
+    fn link_chain(&self, chain: &[u32]) -> Result<(), FsWriteError> {
+        let entries: Vec<(u32, u32)> = chain
+            .iter()
+            .enumerate()
+            .map(|(i, cluster)| (*cluster, chain.get(i + 1).copied().unwrap_or(END_OF_CHAIN)))
+            .collect();
+        self.set_fat_entries(&entries)
+    }
+    fn is_free(&self, cluster: u32) -> bool {
+        let index = (cluster - 2) as usize;
+        self.bitmap[index / 8] & (1 << (index % 8)) == 0
+    }
+    /// Marks the clusters as used or free in the bitmap, and writes the sectors of the bitmap that changed
+    fn set_allocated(&mut self, clusters: &[u32], used: bool) -> Result<(), FsWriteError> {
+        let mut sectors = Vec::new();
+        for cluster in clusters {
+            let index = (cluster - 2) as usize;
+            if used {
+                self.bitmap[index / 8] |= 1 << (index % 8);
+            } else {
+                self.bitmap[index / 8] &= !(1 << (index % 8));
+            }
+            let sector = index / 8 / usize::from(SECTOR_SIZE);
+            if !sectors.contains(&sector) {
+                sectors.push(sector);
+            }
+        }
+        let sector_size = usize::from(SECTOR_SIZE);
+        let sectors_per_cluster = self.cluster_size() / sector_size;
+        for sector in sectors {
+            let cluster = self.bitmap_clusters[sector / sectors_per_cluster];
+            let disk_sector = self.cluster_sector(cluster) + (sector % sectors_per_cluster) as u64;
+            let mut content = self.bitmap[sector * sector_size..].to_vec();
+            content.resize(sector_size, 0);
+            write_to_partition(&self.partition, disk_sector, &content)
+                .or(Err(FsWriteError::WritingDiskError))?;
+        }
+        Ok(())
+    }
+    /// Finds `count` free clusters from `hint` and marks them as used
+    /// A contiguous run is preferred, so the entry doesn't need a FAT chain
+    fn allocate_clusters(&mut self, count: usize, hint: u32) -> Result<Vec<u32>, FsWriteError> {
+        let max_cluster = self.boot.cluster_count + 2;
+        let start = hint.clamp(2, max_cluster - 1);
+        let mut run = Vec::with_capacity(count);
+        let mut scattered = Vec::with_capacity(count);
+        for cluster in (start..max_cluster).chain(2..start) {
+            if !self.is_free(cluster) {
+                run.clear();
+                continue;
+            }
+            if run.last().is_some_and(|last| *last + 1 != cluster) {
+                run.clear();
+            }
+            run.push(cluster);
+            if scattered.len() < count {
+                scattered.push(cluster);
+            }
+            if run.len() == count {
+                break;
+            }
+        }
+        let clusters = if run.len() == count { run } else { scattered };
+        if clusters.len() < count {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        self.set_allocated(&clusters, true)?;
+        if let Some(last) = clusters.last() {
+            self.next_free_cluster = last + 1;
+        }
+        Ok(clusters)
+    }
+    /// Adds `count` clusters at the end of the clusters of an entry
+    /// The entry switches to a FAT chain if they can't stay contiguous
+    fn extend_clusters(
+        &mut self,
+        chain: &mut Vec<u32>,
+        no_fat_chain: &mut bool,
+        count: usize,
+    ) -> Result<(), FsWriteError> {
+        let hint = chain.last().map_or(self.next_free_cluster, |last| last + 1);
+        let new_clusters = self.allocate_clusters(count, hint)?;
+        let old_len = chain.len();
+        chain.extend(new_clusters);
+        let contiguous = chain.windows(2).all(|pair| pair[1] == pair[0] + 1);
+        if old_len == 0 {
+            *no_fat_chain = contiguous;
+        }
+        if !*no_fat_chain {
+            self.link_chain(&chain[old_len.saturating_sub(1)..])?;
+        } else if !contiguous {
+            *no_fat_chain = false;
+            self.link_chain(chain)?;
+        }
+        Ok(())
+    }
+    fn free_clusters(&mut self, chain: &[u32], no_fat_chain: bool) -> Result<(), FsWriteError> {
+        if !no_fat_chain {
+            let entries: Vec<(u32, u32)> = chain.iter().map(|cluster| (*cluster, 0)).collect();
+            self.set_fat_entries(&entries)?;
+        }
+        self.set_allocated(chain, false)
+    }
+    fn read_clusters(&self, chain: &[u32]) -> Result<Vec<u8>, FsWriteError> {
+        let sectors_per_cluster = 1 << self.boot.sectors_per_cluster_shift;
+        let mut data = Vec::with_capacity(chain.len() * self.cluster_size());
+        // Contiguous clusters are read at once
+        let mut i = 0;
+        while i < chain.len() {
+            let mut run = 1;
+            while i + run < chain.len() && chain[i + run] == chain[i] + run as u32 {
+                run += 1;
+            }
+            data.extend(
+                read_from_partition(&self.partition, self.cluster_sector(chain[i]), run as u64 * sectors_per_cluster)
+                    .or(Err(FsWriteError::ReadingDiskError))?,
+            );
+            i += run;
+        }
+        Ok(data)
+    }
+    /// Writes data on the clusters of the chain, the last cluster is padded with zeroes
+    fn write_clusters(&self, chain: &[u32], data: &[u8]) -> Result<(), FsWriteError> {
+        for (cluster, chunk) in chain.iter().zip(data.chunks(self.cluster_size())) {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(self.cluster_size(), 0);
+            write_to_partition(&self.partition, self.cluster_sector(*cluster), &chunk)
+                .or(Err(FsWriteError::WritingDiskError))?;
+        }
+        Ok(())
+    }
+    /// Returns the clusters and the raw entries of a directory
+    fn read_dir_data(&self, dir: &ExFatSoftEntry) -> Result<(Vec<u32>, Vec<u8>), FsWriteError> {
+        let chain = self.entry_clusters(dir)?;
+        let mut data = self.read_clusters(&chain)?;
+        data.truncate(dir.size as usize);
+        Ok((chain, data))
+    }
+    fn upcase_char(&self, unit: u16) -> u16 {
+        self.upcase.get(usize::from(unit)).copied().unwrap_or(unit)
+    }
+    fn upcase_name(&self, name: &str) -> Vec<u16> {
+        name.encode_utf16().map(|unit| self.upcase_char(unit)).collect()
+    }
+    /// Hash of the up-cased name stored in the stream extension, to skip most entries when looking for a name
+    fn name_hash(upcased: &[u16]) -> u16 {
+        upcased
+            .iter()
+            .flat_map(|unit| unit.to_le_bytes())
+            .fold(0_u16, |hash, byte| hash.rotate_right(1).wrapping_add(u16::from(byte)))
+    }
+    fn new_set(&self, name: &str, attributes: u16) -> EntrySet {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        EntrySet::new(&units, Self::name_hash(&self.upcase_name(name)), attributes)
+    }
+    /// Looks for an entry set by name, case insensitive like exFAT is
+    fn find_set(&self, dir_data: &[u8], name: &str) -> Option<EntrySet> {
+        let upcased = self.upcase_name(name);
+        let hash = Self::name_hash(&upcased);
+        entry_sets(dir_data).into_iter().find(|set| {
+            u16::from_le_bytes([set.raw[36], set.raw[37]]) == hash
+                && set
+                    .name_units()
+                    .iter()
+                    .map(|unit| self.upcase_char(*unit))
+                    .eq(upcased.iter().copied())
+        })
+    }
+    fn dir_entry(&self, path: &FilePath) -> Result<ExFatSoftEntry, FsWriteError> {
+        let entry = self.files.get(path).ok_or(FsWriteError::ParentNotFound)?;
+        if entry.is_file {
+            return Err(FsWriteError::NotADir);
+        }
+        Ok(entry.clone())
+    }
+    fn file_entry(&self, path: &FilePath) -> Result<&ExFatSoftEntry, FsReadError> {
+        self.files
+            .get(path)
+            .filter(|entry| entry.is_file)
+            .ok_or(FsReadError::EntryNotFound)
+    }
+    /// Writes the new location and size of an entry in its parent directory and in the index
+    fn update_entry(&mut self, entry: ExFatSoftEntry) -> Result<(), FsWriteError> {
+        if !entry.path.name().is_empty() {
+            let parent = self.dir_entry(&entry.path.parent())?;
+            let (chain, mut data) = self.read_dir_data(&parent)?;
+            let mut set = self
+                .find_set(&data, entry.path.name())
+                .ok_or(FsWriteError::EntryNotFound)?;
+            set.set_stream(entry.first_cluster, entry.size, entry.valid_size, entry.no_fat_chain);
+            data[set.slot * 32..(set.slot + set.slots()) * 32].copy_from_slice(&set.raw);
+            self.write_clusters(&chain, &data)?;
+        }
+        self.files.insert(entry.path.clone(), entry);
+        Ok(())
+    }
+    /// Writes an entry set in a directory, and grows it if it's full
+    fn insert_set(&mut self, dir_path: &FilePath, mut set: EntrySet) -> Result<(), FsWriteError> {
+        let mut dir = self.dir_entry(dir_path)?;
+        let (mut chain, mut data) = self.read_dir_data(&dir)?;
+        if self.find_set(&data, &set.name()).is_some() {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        let start = free_slots_start(&data, set.slots());
+        let total_slots = data.len() / 32;
+        if start + set.slots() > total_slots {
+            let missing = (start + set.slots() - total_slots) * 32;
+            let count = missing.div_ceil(self.cluster_size());
+            self.extend_clusters(&mut chain, &mut dir.no_fat_chain, count)?;
+            data.resize(chain.len() * self.cluster_size(), 0);
+            dir.size = data.len() as u64;
+            dir.valid_size = dir.size;
+            self.update_entry(dir)?;
+        }
+        set.slot = start;
+        data[start * 32..(start + set.slots()) * 32].copy_from_slice(&set.raw);
+        self.write_clusters(&chain, &data)
+    }
+}
+
+impl FsDriverInitialiser for ExFatDriver {
+    fn try_init(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        Some(Box::new(Self::new(partition)?))
+    }
+    fn try_open(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        Some(Box::new(Self::open(partition)?))
+    }
+    fn index_step(&mut self) -> Result<bool, FsReadError> {
+        let Some(dir) = self.unindexed.pop() else {
+            return Ok(false);
+        };
+        match self.read_dir_data(&dir) {
+            Ok((_, data)) => {
+                for set in entry_sets(&data) {
+                    let entry = ExFatSoftEntry {
+                        path: dir.path.join_str(set.name()),
+                        is_file: !set.is_dir(),
+                        first_cluster: set.first_cluster(),
+                        size: set.size(),
+                        valid_size: set.valid_size(),
+                        no_fat_chain: set.no_fat_chain(),
+                    };
+                    if !entry.is_file {
+                        self.unindexed.push(entry.clone());
+                    }
+                    self.files.insert(entry.path.clone(), entry);
+                }
+            }
+            Err(err) => log::error!("Couldn't read directory {}: {:?}", dir.path, err),
+        }
+        Ok(!self.unindexed.is_empty())
+    }
+}
+
+impl FsDriver for ExFatDriver {
+    fn as_enum(&self) -> FsDriverEnum {
+        FsDriverEnum::ExFat
+    }
+    fn partition(&self) -> Option<&Partition> {
+        Some(&self.partition)
+    }
+    fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        if entry.is_file {
+            let mut content = vec![0; entry.size as usize];
+            let size = self.read_at(path, 0, &mut content)?;
+            return Ok(Entry::File(File {
+                path: entry.path.clone(),
+                content,
+                size,
+            }));
+        }
+        let (_, data) = self
+            .read_dir_data(entry)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let entries: Vec<SoftEntry> = entry_sets(&data)
+            .iter()
+            .map(|set| SoftEntry {
+                path: path.join_str(set.name()),
+                size: 0,
+            })
+            .collect();
+        Ok(Entry::Dir(Dir {
+            path: entry.path.clone(),
+            size: entries.len(),
+            entries,
+        }))
+    }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        Ok(self.file_entry(path)?.size)
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let entry = self.file_entry(path)?;
+        let end = (offset + buf.len() as u64).min(entry.size);
+        if offset >= end {
+            return Ok(0);
+        }
+        let read = (end - offset) as usize;
+        buf[..read].fill(0);
+        // The bytes after the valid data length are zeroes, whatever is on the disk
+        let valid_end = end.min(entry.valid_size);
+        if offset >= valid_end {
+            return Ok(read);
+        }
+        let cluster_size = self.cluster_size() as u64;
+        let first_index = (offset / cluster_size) as usize;
+        let last_index = valid_end.div_ceil(cluster_size) as usize;
+        let chain = self
+            .entry_clusters(entry)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        if chain.len() < last_index {
+            log::error!("Cluster chain of {} is shorter than its size", path);
+            return Err(FsReadError::ParsingError);
+        }
+        let data = self
+            .read_clusters(&chain[first_index..last_index])
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let start = (offset - first_index as u64 * cluster_size) as usize;
+        let valid = (valid_end - offset) as usize;
+        buf[..valid].copy_from_slice(&data[start..start + valid]);
+        Ok(read)
+    }
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        let mut entry = self.file_entry(path)?.clone();
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset + data.len() as u64;
+        let new_size = end.max(entry.size);
+        let cluster_size = self.cluster_size() as u64;
+        let mut chain = self.entry_clusters(&entry)?;
+        let needed = new_size.div_ceil(cluster_size) as usize;
+        if needed > chain.len() {
+            let count = needed - chain.len();
+            self.extend_clusters(&mut chain, &mut entry.no_fat_chain, count)?;
+        }
+        // Everything after the valid data length is zeroed, so it can be made valid
+        let old_valid = entry.valid_size;
+        let start = offset.min(old_valid);
+        for index in (start / cluster_size) as usize..end.div_ceil(cluster_size) as usize {
+            let cluster_start = index as u64 * cluster_size;
+            let mut content = if cluster_start >= old_valid {
+                vec![0; cluster_size as usize]
+            } else {
+                let mut content = self.read_clusters(&chain[index..=index])?;
+                if old_valid < cluster_start + cluster_size {
+                    content[(old_valid - cluster_start) as usize..].fill(0);
+                }
+                content
+            };
+            let copy_start = offset.max(cluster_start);
+            let copy_end = end.min(cluster_start + cluster_size);
+            if copy_start < copy_end {
+                content[(copy_start - cluster_start) as usize..(copy_end - cluster_start) as usize]
+                    .copy_from_slice(&data[(copy_start - offset) as usize..(copy_end - offset) as usize]);
+            }
+            self.write_clusters(&chain[index..=index], &content)?;
+        }
+        entry.first_cluster = chain[0];
+        entry.size = new_size;
+        entry.valid_size = old_valid.max(end);
+        self.update_entry(entry)?;
+        Ok(data.len())
+    }
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        let name = filepath.name().to_string();
+        check_name(&name)?;
+        let old = self.files.get(filepath).cloned();
+        if old.as_ref().is_some_and(|entry| !entry.is_file) {
+            return Err(FsWriteError::IsADir);
+        }
+        self.dir_entry(&filepath.parent())?;
+        let mut chain = Vec::new();
+        let mut no_fat_chain = true;
+        if !content.is_empty() {
+            let count = content.len().div_ceil(self.cluster_size());
+            self.extend_clusters(&mut chain, &mut no_fat_chain, count)?;
+        }
+        self.write_clusters(&chain, content)?;
+        let entry = ExFatSoftEntry {
+            path: filepath.clone(),
+            is_file: true,
+            first_cluster: chain.first().copied().unwrap_or(0),
+            size: content.len() as u64,
+            valid_size: content.len() as u64,
+            no_fat_chain,
+        };
+        let result = match &old {
+            Some(_) => self.update_entry(entry.clone()),
+            None => {
+                let mut set = self.new_set(&name, ATTR_ARCHIVE);
+                set.set_stream(entry.first_cluster, entry.size, entry.valid_size, entry.no_fat_chain);
+                self.insert_set(&filepath.parent(), set)
+            }
+        };
+        if let Err(err) = result {
+            self.free_clusters(&chain, no_fat_chain)?;
+            return Err(err);
+        }
+        if let Some(old) = old {
+            // Overwrite, the old content is freed after the entry points to the new one
+            let old_chain = self.entry_clusters(&old)?;
+            self.free_clusters(&old_chain, old.no_fat_chain)?;
+        }
+        self.files.insert(filepath.clone(), entry);
+        Ok(())
+    }
+    fn create_dir(&mut self, dirpath: &FilePath) -> Result<(), FsWriteError> {
+        let name = dirpath.name().to_string();
+        check_name(&name)?;
+        if self.files.contains_key(dirpath) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        self.dir_entry(&dirpath.parent())?;
+        let mut chain = Vec::new();
+        let mut no_fat_chain = true;
+        self.extend_clusters(&mut chain, &mut no_fat_chain, 1)?;
+        // exFAT directories have no "." and ".." entries, the cluster is zeroed so it has no entry at all
+        self.write_clusters(&chain, &vec![0; self.cluster_size()])?;
+        let entry = ExFatSoftEntry {
+            path: dirpath.clone(),
+            is_file: false,
+            first_cluster: chain[0],
+            size: self.cluster_size() as u64,
+            valid_size: self.cluster_size() as u64,
+            no_fat_chain,
+        };
+        let mut set = self.new_set(&name, ATTR_DIRECTORY);
+        set.set_stream(entry.first_cluster, entry.size, entry.valid_size, entry.no_fat_chain);
+        if let Err(err) = self.insert_set(&dirpath.parent(), set) {
+            self.free_clusters(&chain, no_fat_chain)?;
+            return Err(err);
+        }
+        self.files.insert(dirpath.clone(), entry);
+        Ok(())
+    }
+    fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        if path.name().is_empty() {
+            // Can't remove root
+            return Err(FsWriteError::InvalidName);
+        }
+        let entry = self
+            .files
+            .get(path)
+            .cloned()
+            .ok_or(FsWriteError::EntryNotFound)?;
+        if !entry.is_file {
+            let (_, data) = self.read_dir_data(&entry)?;
+            if !entry_sets(&data).is_empty() {
+                return Err(FsWriteError::DirNotEmpty);
+            }
+        }
+        let parent = self.dir_entry(&path.parent())?;
+        let (chain, mut data) = self.read_dir_data(&parent)?;
+        let set = self
+            .find_set(&data, path.name())
+            .ok_or(FsWriteError::EntryNotFound)?;
+        for slot in set.slot..set.slot + set.slots() {
+            data[slot * 32] &= !ENTRY_IN_USE;
+        }
+        self.write_clusters(&chain, &data)?;
+        let clusters = self.entry_clusters(&entry)?;
+        self.free_clusters(&clusters, entry.no_fat_chain)?;
+        self.files.remove(path);
+        Ok(())
+    }
+    fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        let new_name = to.name().to_string();
+        check_name(&new_name)?;
+        if from.name().is_empty() || to.path().starts_with(&format!("{}/", from.path())) {
+            // Root can't be moved, and a dir can't be moved in itself
+            return Err(FsWriteError::InvalidName);
+        }
+        if !self.files.contains_key(from) {
+            return Err(FsWriteError::EntryNotFound);
+        }
+        if self.files.contains_key(to) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        self.dir_entry(&to.parent())?;
+        let old_parent = self.dir_entry(&from.parent())?;
+        let (chain, mut data) = self.read_dir_data(&old_parent)?;
+        let old_set = self
+            .find_set(&data, from.name())
+            .ok_or(FsWriteError::EntryNotFound)?;
+        // Same attributes and timestamps, with the new name
+        let mut set = self.new_set(&new_name, old_set.attributes());
+        set.raw[4..32].copy_from_slice(&old_set.raw[4..32]);
+        set.set_stream(
+            old_set.first_cluster(),
+            old_set.size(),
+            old_set.valid_size(),
+            old_set.no_fat_chain(),
+        );
+        let original = data.clone();
+        for slot in old_set.slot..old_set.slot + old_set.slots() {
+            data[slot * 32] &= !ENTRY_IN_USE;
+        }
+        self.write_clusters(&chain, &data)?;
+        if let Err(err) = self.insert_set(&to.parent(), set) {
+            // Put back the old entry
+            self.write_clusters(&chain, &original)?;
+            return Err(err);
+        }
+        // Move the entry and all of its children in the index
+        let old_prefix = format!("{}/", from.path());
+        let moved: Vec<FilePath> = self
+            .files
+            .keys()
+            .filter(|path| *path == from || path.path().starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        for old_path in moved {
+            // Safe unwrap, key comes from the map
+            let mut entry = self.files.remove(&old_path).unwrap();
+            let new_path = FilePath::new(
+                format!("{}{}", to.path(), &old_path.path()[from.path().len()..]),
+                self.partition.clone(),
+            );
+            entry.path = new_path.clone();
+            self.files.insert(new_path, entry);
+        }
+        Ok(())
+    }
+}
+
+/// The table is made of the up-case value of every character, runs of characters mapped to themselves
+/// are stored as 0xFFFF followed by their length
+fn decompress_upcase(raw: &[u8]) -> Vec<u16> {
+    let mut table = Vec::new();
+    let mut units = raw
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]));
+    while let Some(unit) = units.next() {
+        if table.len() > usize::from(u16::MAX) {
+            break;
+        }
+        if unit == UPCASE_IDENTITY_RUN {
+            let count = units.next().unwrap_or(0);
+            let start = table.len();
+            table.extend((start..start + usize::from(count)).map(|unit| unit as u16));
+        } else {
+            table.push(unit);
+        }
+    }
+    table
+}
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from(read_u32(data, offset)) | (u64::from(read_u32(data, offset + 4)) << 32)
+}
+
+pub const EXFAT_SIGNATURE: &[u8; 8] = b"EXFAT   ";
+/// Boot sector, extended boot sectors, OEM parameters, a reserved sector and the checksum sector
+pub const BOOT_REGION_SECTORS: u64 = 12;
+pub const BACKUP_BOOT_REGION: u64 = 12;
+/// Clusters can't be bigger than 32MiB
+const MAX_CLUSTER_SHIFT: u8 = 16;
+pub const VOLUME_FLAG_ACTIVE_FAT: u16 = 1;
+pub const END_OF_CHAIN: u32 = 0xFFFF_FFFF;
+pub const BAD_CLUSTER: u32 = 0xFFFF_FFF7;
+pub const ENTRY_END_OF_DIR: u8 = 0x00;
+pub const ENTRY_IN_USE: u8 = 0x80;
+pub const ENTRY_BITMAP: u8 = 0x81;
+pub const ENTRY_UPCASE: u8 = 0x82;
+pub const ENTRY_FILE: u8 = 0x85;
+pub const ENTRY_STREAM: u8 = 0xC0;
+pub const ENTRY_FILE_NAME: u8 = 0xC1;
+pub const FLAG_ALLOCATION_POSSIBLE: u8 = 0x01;
+pub const FLAG_NO_FAT_CHAIN: u8 = 0x02;
+pub const ATTR_DIRECTORY: u16 = 0x10;
+pub const ATTR_ARCHIVE: u16 = 0x20;
+const NAME_CHARS_PER_ENTRY: usize = 15;
+const MAX_NAME_LEN: usize = 255;
+const UPCASE_IDENTITY_RUN: u16 = 0xFFFF;
@@ -2,22 +2,29 @@ use core::str::FromStr;
 
 use alloc::{
     boxed::Box,
+    collections::BTreeMap,
+    format,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
+use bit_field::BitField;
 use bytemuck::Zeroable;
 use hashbrown::HashMap;
 
 use crate::{
     bit_manipulation::{all_zeroes, any_as_u8_slice},
     dbg,
-    disk::driver::read_from_partition,
+    disk::driver::{read_from_partition, write_to_partition, SECTOR_SIZE},
+    time::unix_time,
 };
 
 use super::{
     fs_driver::{
-        Dir, Entry, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, SoftEntry,
+        Dir, Entry, EntryKind, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError,
+        FsWriteError, Metadata, SoftEntry,
     },
+    fsck::{FsckProblem, FsckReport},
     partition::Partition,
     path::FilePath,
 };
@@ -32,6 +39,8 @@ pub struct ExtDriver {
     blk_grp_desc_table: Vec<BlockGroupDescriptor>,
     //TODO Hold a cached root info
     files: HashMap<FilePath, ExtEntryDescriptor>,
+    /// Directories in `files` that weren't read yet, see `index_step`
+    unindexed: Vec<FilePath>,
 }
 impl ExtDriver {
     fn extsuperblock(&self) -> &ExtendedExtSuperblock {
@@ -41,14 +50,30 @@ impl ExtDriver {
         return self.superblock.as_super_block()
     }
     fn block_size(&self) -> u32 {
-        return self.superblock().block_size() / 256
+        return self.superblock().block_size()
     }
-    fn walk_dir(
-        &self,
-        dir: &ExtEntryDescriptor,
-    ) -> Result<HashMap<FilePath, ExtEntryDescriptor>, FsReadError> {
-        let mut files = HashMap::new();
-
+    fn sectors_per_block(&self) -> u32 {
+        return self.block_size() / u32::from(SECTOR_SIZE)
+    }
+    fn block_sector(&self, block: u32) -> u64 {
+        u64::from(block) * u64::from(self.sectors_per_block())
+    }
+    /// Blocks outside of the filesystem are refused, the block pointers of a corrupted inode can point anywhere
+    fn read_block(&self, block: u32) -> Result<Vec<u8>, FsReadError> {
+        if block >= self.superblock().total_blocks {
+            log::error!("Block {} is outside of the filesystem", block);
+            return Err(FsReadError::ParsingError);
+        }
+        read_from_partition(
+            &self.partition,
+            self.block_sector(block),
+            self.sectors_per_block().into(),
+        )
+        .or(Err(FsReadError::ReadingDiskError))
+    }
+    /// Reads a directory of `files` and adds its entries, the directories among them are added to `unindexed`
+    fn index_dir(&mut self, path: &FilePath) -> Result<(), FsReadError> {
+        let dir = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
         let inode = self
             .get_inode(dir.inner.inode)
             .ok_or(FsReadError::EntryNotFound)?;
@@ -57,111 +82,1370 @@ impl ExtDriver {
             ExtEntry::Dir(d) => d.entries,
         };
         for entry in entries {
-            files.insert(
-                FilePath::new(entry.name.clone(), self.partition.clone()),
-                entry,
-            );
+            if entry.name.ends_with("/.") || entry.name.ends_with("/..") {
+                continue;
+            }
+            let is_dir = match entry.type_indicator() {
+                // Directory entries don't contain the type
+                ExtInodeType::Unknown => self
+                    .get_inode(entry.inner.inode)
+                    .is_some_and(|inode| inode.is_dir()),
+                type_indicator => type_indicator == ExtInodeType::Dir,
+            };
+            let path = FilePath::new(entry.name.clone(), self.partition.clone());
+            if is_dir {
+                self.unindexed.push(path.clone());
+            }
+            self.files.insert(path, entry);
         }
-
-        Ok(files)
+        Ok(())
     }
-    fn index_disk(&mut self) -> Result<(), FsReadError> {
-        let root = ExtEntryDescriptor::new_raw(2, "/".to_string(), false);
-        self.files = self.walk_dir(&root)?;
+    /// Adds the root, the directories are then read by `index_step`
+    fn start_indexing(&mut self) {
+        let root = FilePath::new("/".to_string(), self.partition.clone());
         self.files
-            .insert(FilePath::new("/".to_string(), self.partition.clone()), root);
-        Ok(())
+            .insert(root.clone(), ExtEntryDescriptor::new_raw(ROOT_INODE, "/".to_string(), false));
+        self.unindexed.push(root);
     }
     fn dir_entries_contain_type(&self) -> bool {
         return self.extsuperblock().required_feat_present & 0x2 != 0
     }
     fn read_inode(&self, inode: Inode) -> Option<ExtEntryDescriptor> {
-        let block_size = self.block_size();
-        let data_blk = read_from_partition(
-            &self.partition,
-            u64::from(inode.direct_blk_ptr_0 * block_size),
-            block_size.into(),
-        )
-        .ok()?;
+        let data_blk = self.read_block(inode.direct_blk_ptr_0).ok()?;
         let inode_entry = ExtEntryDescriptor::new(&data_blk, self.dir_entries_contain_type());
         Some(inode_entry)
     }
+    fn inode_size(&self) -> u64 {
+        u64::from(self.extsuperblock().size_inode_struct)
+    }
+    /// Offset in bytes of an inode from the start of the partition
+    fn inode_offset(&self, inode_number: u32) -> Option<u64> {
+        let inodes_per_group = self.superblock().inodes_per_group;
+        let blk_grp_number = block_group_of_inode(u64::from(inode_number), inodes_per_group);
+        let blk_grp = &self.blk_grp_desc_table.get(blk_grp_number as usize)?;
+        let index = (u64::from(inode_number) - 1) % u64::from(inodes_per_group);
+        Some(
+            u64::from(blk_grp.lo_block_addr_of_inode_start) * u64::from(self.block_size())
+                + index * self.inode_size(),
+        )
+    }
     /// Takes a inode number and returns the inode
     fn get_inode(&self, inode_number: u32) -> Option<Inode> {
-        let blk_grp_number =
-            block_group_of_inode(u64::from(inode_number), self.superblock().inodes_per_group);
-        let blk_grp = &self.blk_grp_desc_table.get(blk_grp_number as usize)?;
-        let block_size = self.block_size();
-        let inode_table_start_sector = u64::from(blk_grp.lo_block_addr_of_inode_start * block_size);
-        let inode_size = self.extsuperblock().size_inode_struct as usize;
-        let inode_table_start_sector =
-            inode_table_start_sector + ((u64::from(inode_number) - 1) / (512 / inode_size as u64));
-        let tables = get_inode_table(&self.partition, inode_table_start_sector, inode_size)?;
-        let inode = tables.get((inode_number as usize - 1) % (512 / inode_size))?;
-        Some(inode.clone())
+        if inode_number == 0 {
+            return None;
+        }
+        let offset = self.inode_offset(inode_number)?;
+        // Inodes are 128 or 256 bytes, so they never cross a sector
+        let sector = read_from_partition(&self.partition, offset / u64::from(SECTOR_SIZE), 1).ok()?;
+        let start = (offset % u64::from(SECTOR_SIZE)) as usize;
+        Some(bytemuck::pod_read_unaligned(
+            &sector[start..start + core::mem::size_of::<Inode>()],
+        ))
+    }
+    /// Extended attributes blocks are counted in the sectors of the inode
+    fn xattr_sectors(&self, inode: &Inode) -> u32 {
+        if inode.ext_attr_blk == 0 {
+            0
+        } else {
+            self.sectors_per_block()
+        }
+    }
+    /// Symlinks with a target shorter than 60 bytes store it in the block pointers, they have no data block
+    /// Sparse files have no data block either, so the type is checked too
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        inode.type_n_perms & INODE_TYPE_MASK == INODE_TYPE_SYMLINK
+            && inode.n_disk_sectors == self.xattr_sectors(inode)
+    }
+    fn pointers_per_block(&self) -> usize {
+        self.block_size() as usize / 4
+    }
+    /// Returns the data blocks of an inode (0 for holes) and the blocks used to map them (indirect blocks or extent tree nodes)
+    fn block_map(&self, inode: &Inode) -> Result<(Vec<u32>, Vec<u32>), FsReadError> {
+        let count = inode.size().div_ceil(u64::from(self.block_size())) as usize;
+        self.block_map_start(inode, count)
+    }
+    /// Same as `block_map`, but stops after the first `count` data blocks
+    fn block_map_start(&self, inode: &Inode, count: usize) -> Result<(Vec<u32>, Vec<u32>), FsReadError> {
+        let mut data = Vec::new();
+        let mut indirect = Vec::new();
+        if inode.flags & EXT4_EXTENTS_FL != 0 {
+            let i_block = &bytemuck::bytes_of(inode)[40..100];
+            self.map_extents(i_block, count, &mut data, &mut indirect)?;
+            data.resize(count, 0);
+            return Ok((data, indirect));
+        }
+        if self.is_fast_symlink(inode) {
+            return Ok((data, indirect));
+        }
+        let ptrs = inode.block_ptrs();
+        data.extend(ptrs[..DIRECT_BLOCKS].iter().take(count));
+        for (level, ptr) in ptrs[DIRECT_BLOCKS..].iter().enumerate() {
+            self.map_indirect(*ptr, level as u32 + 1, count, &mut data, &mut indirect)?;
+        }
+        // Blocks past the ones mapped are holes
+        data.resize(count, 0);
+        Ok((data, indirect))
+    }
+    fn map_indirect(
+        &self,
+        block: u32,
+        level: u32,
+        count: usize,
+        data: &mut Vec<u32>,
+        indirect: &mut Vec<u32>,
+    ) -> Result<(), FsReadError> {
+        if data.len() >= count {
+            return Ok(());
+        }
+        if block == 0 {
+            let hole = self.pointers_per_block().pow(level);
+            data.resize((data.len() + hole).min(count), 0);
+            return Ok(());
+        }
+        indirect.push(block);
+        let raw = self.read_block(block)?;
+        for ptr in raw.chunks_exact(4).map(|ptr| read_u32(ptr, 0)) {
+            if level == 1 {
+                if data.len() >= count {
+                    break;
+                }
+                data.push(ptr);
+            } else {
+                self.map_indirect(ptr, level - 1, count, data, indirect)?;
+            }
+        }
+        Ok(())
+    }
+    /// Walks an extent tree node (the header followed by the entries)
+    fn map_extents(
+        &self,
+        node: &[u8],
+        count: usize,
+        data: &mut Vec<u32>,
+        indirect: &mut Vec<u32>,
+    ) -> Result<(), FsReadError> {
+        let header: ExtentHeader = bytemuck::pod_read_unaligned(&node[..12]);
+        if header.magic != EXTENT_HEADER_MAGIC {
+            log::error!("Invalid extent header magic: {:#x}", { header.magic });
+            return Err(FsReadError::ParsingError);
+        }
+        let entries = node[12..]
+            .chunks_exact(12)
+            .take(header.entries as usize);
+        for raw in entries {
+            if header.depth == 0 {
+                let extent: Extent = bytemuck::pod_read_unaligned(raw);
+                let first_logical = extent.first_block as usize;
+                if first_logical >= count {
+                    continue;
+                }
+                let len = extent.len().min(count - first_logical);
+                if data.len() < first_logical + len {
+                    data.resize(first_logical + len, 0);
+                }
+                // Uninitialized extents are read as zeroes
+                if !extent.is_initialized() {
+                    continue;
+                }
+                for i in 0..len {
+                    data[first_logical + i] = block_u32(extent.start() + i as u64)?;
+                }
+            } else {
+                let index: ExtentIndex = bytemuck::pod_read_unaligned(raw);
+                let leaf = block_u32(index.leaf())?;
+                indirect.push(leaf);
+                let node = self.read_block(leaf)?;
+                self.map_extents(&node, count, data, indirect)?;
+            }
+        }
+        Ok(())
+    }
+    /// Inode number and inode of a regular file in the index
+    fn file_inode(&self, path: &FilePath) -> Result<(u32, Inode), FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        let inode = self
+            .get_inode(entry.inner.inode)
+            .ok_or(FsReadError::ReadingDiskError)?;
+        if inode.type_n_perms & INODE_TYPE_MASK != INODE_TYPE_FILE {
+            return Err(FsReadError::EntryNotFound);
+        }
+        Ok((entry.inner.inode, inode))
+    }
+    /// Reads the data blocks, holes are filled with zeroes
+    fn read_data_blocks(&self, blocks: &[u32]) -> Result<Vec<u8>, FsReadError> {
+        let block_size = self.block_size() as usize;
+        let max_run = (MAX_SECTORS_PER_READ / self.sectors_per_block()).max(1) as usize;
+        let mut content = Vec::with_capacity(blocks.len() * block_size);
+        let mut i = 0;
+        while i < blocks.len() {
+            if blocks[i] == 0 {
+                content.resize(content.len() + block_size, 0);
+                i += 1;
+                continue;
+            }
+            // Contiguous blocks are read at once
+            let mut run = 1;
+            while run < max_run
+                && i + run < blocks.len()
+                && blocks[i + run] == blocks[i] + run as u32
+            {
+                run += 1;
+            }
+            content.extend(
+                read_from_partition(
+                    &self.partition,
+                    self.block_sector(blocks[i]),
+                    run as u64 * u64::from(self.sectors_per_block()),
+                )
+                .or(Err(FsReadError::ReadingDiskError))?,
+            );
+            i += run;
+        }
+        Ok(content)
+    }
+    /// Fast symlinks store their target in the block pointers, the others in data blocks like a file
+    fn link_target(&self, inode: &Inode) -> Result<Vec<u8>, FsReadError> {
+        let size = inode.size() as usize;
+        if self.is_fast_symlink(inode) {
+            let i_block = &bytemuck::bytes_of(inode)[40..100];
+            return Ok(i_block[..size.min(i_block.len())].to_vec());
+        }
+        let (blocks, _) = self.block_map(inode)?;
+        let mut target = self.read_data_blocks(&blocks)?;
+        target.truncate(size);
+        Ok(target)
     }
     fn read_inode_block(
         &self,
         inode: Inode,
         entry: &ExtEntryDescriptor,
     ) -> Result<ExtEntry, super::fs_driver::FsReadError> {
-        let data_blk = read_from_partition(
-            self.partition(),
-            (inode.direct_blk_ptr_0 * self.block_size())
-                .into(),
-            self.block_size().into(),
-        )
-        .or(Err(FsReadError::ReadingDiskError))?;
-        if inode.type_n_perms & 0x4000 == 0x4000 {
+        let (blocks, _) = self.block_map(&inode)?;
+        let mut data_blk = self.read_data_blocks(&blocks)?;
+        if inode.is_dir() {
             //DIR
-            let mut idx = 0; // usize cuz slice indexing
             let mut entries = Vec::new();
-            loop {
-                let sl = &data_blk[idx..];
-                if sl.len() <= 12 || all_zeroes(&sl[..12]) {
-                    break;
-                };
-                let ext_entry = ExtEntryDescriptor::new(sl, self.dir_entries_contain_type());
-                idx += ext_entry.inner.entry_size as usize;
-                // let entry = match ext_entry.type_indicator() {
-                //     ExtInodeType::File => ExtEntryDescriptor { inner: (), name: () },
-                //     ExtInodeType::Dir => ExtEntryDescriptor { inner: (), name: () },
-                //     _ => todo!(),
-                // };
-                entries.push(ext_entry);
+            let dir_path = entry.name.trim_end_matches('/');
+            // Entries never cross a block
+            for block in data_blk.chunks_exact(self.block_size() as usize) {
+                let mut idx = 0; // usize cuz slice indexing
+                while idx + 8 <= block.len() {
+                    let sl = &block[idx..];
+                    let mut ext_entry = ExtEntryDescriptor::new(sl, self.dir_entries_contain_type());
+                    if ext_entry.inner.entry_size < 8 {
+                        log::error!("Corrupted directory entry in {}", entry.name);
+                        return Err(FsReadError::ParsingError);
+                    }
+                    idx += ext_entry.inner.entry_size as usize;
+                    // Unused entry
+                    if ext_entry.inner.inode == 0 {
+                        continue;
+                    }
+                    ext_entry.name = format!("{}/{}", dir_path, ext_entry.name);
+                    entries.push(ext_entry);
+                }
             }
             Ok(ExtEntry::Dir(ExtDir {
                 path: FilePath::new(entry.name.clone(), self.partition.clone()),
                 inode: entry.inner.inode,
-                size: u64::from(inode.lo_32b_size),
+                size: inode.size(),
                 type_indicator: entry.type_indicator(),
                 entries,
             }))
-        } else if inode.type_n_perms & 0x8000 == 0x8000 {
+        } else if inode.type_n_perms & INODE_TYPE_MASK == INODE_TYPE_FILE {
             //FILE
-            let content = String::from_utf8_lossy(&data_blk).to_string();
-            // Ok(Entry::File(File {
-            //     // inner: inode_entry,
-            //     content,
-            //     path: FilePath::new(entry.name.clone(), self.partition),
-            //     size: inode.lo_32b_size as usize, //TODO Combine with hi_32b_size if 64bit feature ste
-            // }))
+            data_blk.truncate(inode.size() as usize); // Last block is padded
             return Ok(ExtEntry::File(ExtFile {
                 path: FilePath::new(entry.name.clone(), self.partition.clone()),
                 inode: entry.inner.inode,
-                size: u64::from(inode.lo_32b_size),
+                size: inode.size(),
                 type_indicator: entry.type_indicator(),
                 content: data_blk,
             }))
+        } else if inode.type_n_perms & INODE_TYPE_MASK == INODE_TYPE_SYMLINK {
+            // Read like a file containing the target, the VFS follows it with `read_link`
+            let content = self.link_target(&inode)?;
+            Ok(ExtEntry::File(ExtFile {
+                path: FilePath::new(entry.name.clone(), self.partition.clone()),
+                inode: entry.inner.inode,
+                size: content.len() as u64,
+                type_indicator: entry.type_indicator(),
+                content,
+            }))
+        } else if INODE_TYPES_SPECIAL.contains(&(inode.type_n_perms & INODE_TYPE_MASK)) {
+            // Read as empty files, so the directories holding them can still be indexed
+            Ok(ExtEntry::File(ExtFile {
+                path: FilePath::new(entry.name.clone(), self.partition.clone()),
+                inode: entry.inner.inode,
+                size: 0,
+                type_indicator: entry.type_indicator(),
+                content: Vec::new(),
+            }))
         } else {
             let typ = inode.type_n_perms;
             log::error!("Unknown inode type: {:b}", typ);
-            dbg!(inode);
             return Err(FsReadError::ParsingError)
         }
     }
 }
 
+// Writing
+impl ExtDriver {
+    fn write_block(&self, block: u32, data: &[u8]) -> Result<(), FsWriteError> {
+        write_to_partition(&self.partition, self.block_sector(block), data)
+            .or(Err(FsWriteError::WritingDiskError))
+    }
+    /// Read-modify-write of the sectors containing `data`
+    fn write_bytes(&self, offset: u64, data: &[u8]) -> Result<(), FsWriteError> {
+        let sector_size = u64::from(SECTOR_SIZE);
+        let first_sector = offset / sector_size;
+        let start = (offset % sector_size) as usize;
+        let sector_count = (start + data.len()).div_ceil(SECTOR_SIZE as usize) as u64;
+        let mut content = read_from_partition(&self.partition, first_sector, sector_count)
+            .or(Err(FsWriteError::ReadingDiskError))?;
+        content[start..start + data.len()].copy_from_slice(data);
+        write_to_partition(&self.partition, first_sector, &content)
+            .or(Err(FsWriteError::WritingDiskError))
+    }
+    /// We only write on filesystems where we know every feature that changes the on-disk layout
+    fn check_writable(&self) -> Result<(), FsWriteError> {
+        let extsuperblock = self.extsuperblock();
+        let required = extsuperblock.required_feat_present;
+        let read_only = extsuperblock.feat_read_only_not_supported;
+        if required & !WRITE_SUPPORTED_REQUIRED_FEATURES != 0
+            || read_only & !WRITE_SUPPORTED_READ_ONLY_FEATURES != 0
+        {
+            log::error!(
+                "Can't write on {:?}, unsupported features: {:#x} {:#x}",
+                self.partition,
+                required,
+                read_only
+            );
+            return Err(FsWriteError::NotSupported);
+        }
+        Ok(())
+    }
+    fn write_superblock(&self) -> Result<(), FsWriteError> {
+        write_to_partition(&self.partition, 2, &self.superblock.data)
+            .or(Err(FsWriteError::WritingDiskError))
+    }
+    fn write_group_descriptor(&self, group: usize) -> Result<(), FsWriteError> {
+        let bgdt_block = self.superblock().superblock_block_number + 1;
+        let offset = u64::from(bgdt_block) * u64::from(self.block_size())
+            + (group * core::mem::size_of::<BlockGroupDescriptor>()) as u64;
+        self.write_bytes(offset, bytemuck::bytes_of(&self.blk_grp_desc_table[group]))
+    }
+    fn group_first_block(&self, group: usize) -> u32 {
+        let superblock = self.superblock();
+        superblock.superblock_block_number + group as u32 * superblock.blocks_per_group
+    }
+    /// The last group can be smaller than the others
+    fn blocks_in_group(&self, group: usize) -> u32 {
+        let superblock = self.superblock();
+        (superblock.total_blocks - self.group_first_block(group)).min(superblock.blocks_per_group)
+    }
+    fn group_of_block(&self, block: u32) -> usize {
+        let superblock = self.superblock();
+        ((block - superblock.superblock_block_number) / superblock.blocks_per_group) as usize
+    }
+    fn group_of_inode(&self, inode_number: u32) -> usize {
+        block_group_of_inode(u64::from(inode_number), self.superblock().inodes_per_group) as usize
+    }
+    /// Finds free blocks in the block bitmaps, starting from `goal_group`
+    fn allocate_blocks(&mut self, count: usize, goal_group: usize) -> Result<Vec<u32>, FsWriteError> {
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        if (self.superblock().unallocated_blocks as usize) < count {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let groups = self.blk_grp_desc_table.len();
+        let goal_group = goal_group.min(groups - 1);
+        let mut blocks = Vec::with_capacity(count);
+        for group in (goal_group..groups).chain(0..goal_group) {
+            if blocks.len() == count {
+                break;
+            }
+            if self.blk_grp_desc_table[group].lo_unallocated_blocks_in_group == 0 {
+                continue;
+            }
+            let bitmap_block = self.blk_grp_desc_table[group].lo_block_addr_block;
+            let mut bitmap = self.read_block(bitmap_block)?;
+            let allocated_before = blocks.len();
+            for bit in 0..self.blocks_in_group(group) as usize {
+                if blocks.len() == count {
+                    break;
+                }
+                if !bitmap[bit / 8].get_bit(bit % 8) {
+                    bitmap[bit / 8].set_bit(bit % 8, true);
+                    blocks.push(self.group_first_block(group) + bit as u32);
+                }
+            }
+            let allocated = (blocks.len() - allocated_before) as u16;
+            if allocated != 0 {
+                self.write_block(bitmap_block, &bitmap)?;
+                self.blk_grp_desc_table[group].lo_unallocated_blocks_in_group -= allocated;
+                self.write_group_descriptor(group)?;
+                self.superblock.as_super_block_mut().unallocated_blocks -= u32::from(allocated);
+            }
+        }
+        self.write_superblock()?;
+        if blocks.len() < count {
+            log::error!("Free blocks count doesn't match the bitmaps on {:?}", self.partition);
+            self.free_blocks(&blocks)?;
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        Ok(blocks)
+    }
+    fn free_blocks(&mut self, blocks: &[u32]) -> Result<(), FsWriteError> {
+        let mut by_group: BTreeMap<usize, Vec<u32>> = BTreeMap::new();
+        for block in blocks.iter().filter(|block| **block != 0) {
+            by_group.entry(self.group_of_block(*block)).or_default().push(*block);
+        }
+        for (group, blocks) in by_group {
+            let bitmap_block = self.blk_grp_desc_table[group].lo_block_addr_block;
+            let mut bitmap = self.read_block(bitmap_block)?;
+            let first_block = self.group_first_block(group);
+            for block in &blocks {
+                let bit = (block - first_block) as usize;
+                bitmap[bit / 8].set_bit(bit % 8, false);
+            }
+            self.write_block(bitmap_block, &bitmap)?;
+            self.blk_grp_desc_table[group].lo_unallocated_blocks_in_group += blocks.len() as u16;
+            self.write_group_descriptor(group)?;
+            self.superblock.as_super_block_mut().unallocated_blocks += blocks.len() as u32;
+        }
+        self.write_superblock()
+    }
+    fn allocate_inode(&mut self, is_dir: bool, goal_group: usize) -> Result<u32, FsWriteError> {
+        if self.superblock().unallocated_inodes == 0 {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let inodes_per_group = self.superblock().inodes_per_group;
+        let first_non_reserved = self.extsuperblock().fst_non_reserved_inode;
+        let groups = self.blk_grp_desc_table.len();
+        let goal_group = goal_group.min(groups - 1);
+        for group in (goal_group..groups).chain(0..goal_group) {
+            if self.blk_grp_desc_table[group].lo_unallocated_inodes_in_group == 0 {
+                continue;
+            }
+            let bitmap_block = self.blk_grp_desc_table[group].lo_block_addr_inode;
+            let mut bitmap = self.read_block(bitmap_block)?;
+            let first_inode = group as u32 * inodes_per_group + 1;
+            let free_bit = (0..inodes_per_group as usize).find(|bit| {
+                !bitmap[bit / 8].get_bit(bit % 8) && first_inode + *bit as u32 >= first_non_reserved
+            });
+            let Some(bit) = free_bit else { continue };
+            bitmap[bit / 8].set_bit(bit % 8, true);
+            self.write_block(bitmap_block, &bitmap)?;
+            let bgd = &mut self.blk_grp_desc_table[group];
+            bgd.lo_unallocated_inodes_in_group -= 1;
+            if is_dir {
+                bgd.lo_n_dirs_in_grp += 1;
+            }
+            self.write_group_descriptor(group)?;
+            self.superblock.as_super_block_mut().unallocated_inodes -= 1;
+            self.write_superblock()?;
+            return Ok(first_inode + bit as u32);
+        }
+        log::error!("Free inodes count doesn't match the bitmaps on {:?}", self.partition);
+        Err(FsWriteError::NoSpaceLeft)
+    }
+    /// Marks the inode as free and clears it on disk
+    fn free_inode(&mut self, inode_number: u32, is_dir: bool) -> Result<(), FsWriteError> {
+        let group = self.group_of_inode(inode_number);
+        let bitmap_block = self.blk_grp_desc_table[group].lo_block_addr_inode;
+        let mut bitmap = self.read_block(bitmap_block)?;
+        let bit = ((inode_number - 1) % self.superblock().inodes_per_group) as usize;
+        bitmap[bit / 8].set_bit(bit % 8, false);
+        self.write_block(bitmap_block, &bitmap)?;
+        let bgd = &mut self.blk_grp_desc_table[group];
+        bgd.lo_unallocated_inodes_in_group += 1;
+        if is_dir {
+            bgd.lo_n_dirs_in_grp -= 1;
+        }
+        self.write_group_descriptor(group)?;
+        self.superblock.as_super_block_mut().unallocated_inodes += 1;
+        self.write_superblock()?;
+        self.write_new_inode(inode_number, &Inode::zeroed())
+    }
+    /// Only writes the first 128 bytes, so the extra fields of bigger inodes are kept
+    fn write_inode(&self, inode_number: u32, inode: &Inode) -> Result<(), FsWriteError> {
+        let offset = self.inode_offset(inode_number).ok_or(FsWriteError::EntryNotFound)?;
+        self.write_bytes(offset, bytemuck::bytes_of(inode))
+    }
+    /// Writes the whole inode slot, clearing the extra fields
+    fn write_new_inode(&self, inode_number: u32, inode: &Inode) -> Result<(), FsWriteError> {
+        let offset = self.inode_offset(inode_number).ok_or(FsWriteError::EntryNotFound)?;
+        let mut raw = vec![0; self.inode_size() as usize];
+        raw[..core::mem::size_of::<Inode>()].copy_from_slice(bytemuck::bytes_of(inode));
+        self.write_bytes(offset, &raw)
+    }
+    /// Amount of indirect blocks needed to map `data_blocks` blocks
+    fn indirect_blocks_needed(&self, data_blocks: usize) -> Result<usize, FsWriteError> {
+        let ptrs_per_block = self.pointers_per_block();
+        let mut remaining = data_blocks.saturating_sub(DIRECT_BLOCKS);
+        let mut needed = 0;
+        for level in 1..=3 {
+            let mapped = remaining.min(ptrs_per_block.pow(level));
+            let mut blocks_at_level = mapped;
+            for _ in 0..level {
+                blocks_at_level = blocks_at_level.div_ceil(ptrs_per_block);
+                needed += blocks_at_level;
+            }
+            remaining -= mapped;
+        }
+        if remaining != 0 {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        Ok(needed)
+    }
+    /// Writes the indirect block mapping `data`, taking its blocks from `indirect`
+    fn build_indirect(
+        &self,
+        data: &[u32],
+        level: u32,
+        indirect: &mut impl Iterator<Item = u32>,
+    ) -> Result<u32, FsWriteError> {
+        // Safe unwrap, we allocated exactly indirect_blocks_needed blocks
+        let block = indirect.next().unwrap();
+        let mut raw = vec![0; self.block_size() as usize];
+        let per_ptr = self.pointers_per_block().pow(level - 1);
+        for (i, chunk) in data.chunks(per_ptr).enumerate() {
+            let ptr = if level == 1 {
+                chunk[0]
+            } else {
+                self.build_indirect(chunk, level - 1, indirect)?
+            };
+            raw[i * 4..i * 4 + 4].copy_from_slice(&ptr.to_le_bytes());
+        }
+        self.write_block(block, &raw)?;
+        Ok(block)
+    }
+    /// Points the inode to `data`, allocating new indirect blocks and freeing the old ones
+    fn set_block_map(
+        &mut self,
+        inode: &mut Inode,
+        data: &[u32],
+        goal_group: usize,
+    ) -> Result<(), FsWriteError> {
+        let (_, old_indirect) = self.block_map(inode)?;
+        self.free_blocks(&old_indirect)?;
+        let needed = self.indirect_blocks_needed(data.len())?;
+        let indirect = self.allocate_blocks(needed, goal_group)?;
+        let mut indirect_iter = indirect.iter().copied();
+        let mut ptrs = [0; 15];
+        let (direct, mut rest) = data.split_at(data.len().min(DIRECT_BLOCKS));
+        ptrs[..direct.len()].copy_from_slice(direct);
+        for level in 1..=3 {
+            if rest.is_empty() {
+                break;
+            }
+            let (mapped, remaining) = rest.split_at(rest.len().min(self.pointers_per_block().pow(level)));
+            ptrs[DIRECT_BLOCKS + level as usize - 1] =
+                self.build_indirect(mapped, level, &mut indirect_iter)?;
+            rest = remaining;
+        }
+        inode.set_block_ptrs(&ptrs);
+        let used_blocks = data.iter().filter(|block| **block != 0).count() + indirect.len();
+        inode.n_disk_sectors =
+            (used_blocks * self.sectors_per_block() as usize) as u32 + self.xattr_sectors(inode);
+        Ok(())
+    }
+    /// Frees the content of the inode and replaces it, doesn't write the inode
+    fn set_content(
+        &mut self,
+        inode: &mut Inode,
+        content: &[u8],
+        goal_group: usize,
+    ) -> Result<(), FsWriteError> {
+        let size: u32 = content.len().try_into().or(Err(FsWriteError::NoSpaceLeft))?;
+        let (old_data, old_indirect) = self.block_map(inode)?;
+        let block_size = self.block_size() as usize;
+        let data_blocks = content.len().div_ceil(block_size);
+        let needed = data_blocks + self.indirect_blocks_needed(data_blocks)?;
+        // Checked before freeing anything, so the old content is kept if the new one doesn't fit
+        let old_blocks = old_data.iter().chain(&old_indirect).filter(|block| **block != 0).count();
+        if needed > self.superblock().unallocated_blocks as usize + old_blocks {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        self.free_blocks(&old_data)?;
+        self.free_blocks(&old_indirect)?;
+        inode.set_block_ptrs(&[0; 15]);
+        inode.n_disk_sectors = self.xattr_sectors(inode);
+        inode.lo_32b_size = 0;
+
+        let data = self.allocate_blocks(data_blocks, goal_group)?;
+        for (block, chunk) in data.iter().zip(content.chunks(block_size)) {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(block_size, 0);
+            self.write_block(*block, &chunk)?;
+        }
+        self.set_block_map(inode, &data, goal_group)?;
+        inode.lo_32b_size = size;
+        inode.hi_32b_size = 0;
+        Ok(())
+    }
+    /// Frees the blocks, the extended attributes and the inode itself
+    fn delete_inode(&mut self, inode_number: u32, inode: &Inode) -> Result<(), FsWriteError> {
+        let (data, indirect) = self.block_map(inode)?;
+        self.free_blocks(&data)?;
+        self.free_blocks(&indirect)?;
+        let xattr_block = inode.ext_attr_blk;
+        if xattr_block != 0 {
+            // Extended attribute blocks can be shared, the refcount is after the magic
+            let mut raw = self.read_block(xattr_block)?;
+            let refcount = read_u32(&raw, 4);
+            if refcount > 1 {
+                raw[4..8].copy_from_slice(&(refcount - 1).to_le_bytes());
+                self.write_block(xattr_block, &raw)?;
+            } else {
+                self.free_blocks(&[xattr_block])?;
+            }
+        }
+        self.free_inode(inode_number, inode.is_dir())
+    }
+    /// Returns the data blocks of a directory with their content
+    fn read_dir_blocks(&self, inode: &Inode) -> Result<Vec<(u32, Vec<u8>)>, FsReadError> {
+        let (data, _) = self.block_map(inode)?;
+        let mut blocks = Vec::with_capacity(data.len());
+        for block in data.into_iter().filter(|block| *block != 0) {
+            blocks.push((block, self.read_block(block)?));
+        }
+        Ok(blocks)
+    }
+    /// Returns the inode number of the entry called `name` in the directory
+    fn find_in_dir(&self, dir_inode: &Inode, name: &str) -> Result<Option<u32>, FsWriteError> {
+        for (_, raw) in self.read_dir_blocks(dir_inode)? {
+            for entry in self.raw_dir_entries(&raw)? {
+                if entry.inode != 0 && entry.name == name.as_bytes() {
+                    return Ok(Some(entry.inode));
+                }
+            }
+        }
+        Ok(None)
+    }
+    fn raw_dir_entries<'a>(&self, raw: &'a [u8]) -> Result<Vec<RawDirEntry<'a>>, FsWriteError> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= raw.len() {
+            let entry_size = u16::from_le_bytes([raw[offset + 4], raw[offset + 5]]) as usize;
+            let mut name_length = raw[offset + 6] as usize;
+            if !self.dir_entries_contain_type() {
+                name_length |= (raw[offset + 7] as usize) << 8;
+            }
+            if entry_size < 8 || offset + entry_size > raw.len() || 8 + name_length > entry_size {
+                log::error!("Corrupted directory entry at offset {}", offset);
+                return Err(FsWriteError::ReadingDiskError);
+            }
+            entries.push(RawDirEntry {
+                offset,
+                inode: read_u32(raw, offset),
+                entry_size,
+                name: &raw[offset + 8..offset + 8 + name_length],
+            });
+            offset += entry_size;
+        }
+        Ok(entries)
+    }
+    /// Returns the inode number and the inode, following the path from the root
+    fn lookup(&self, path: &FilePath) -> Result<(u32, Inode), FsWriteError> {
+        let mut inode_number = ROOT_INODE;
+        let mut inode = self.get_inode(ROOT_INODE).ok_or(FsWriteError::ReadingDiskError)?;
+        for name in path.path().split('/').filter(|name| !name.is_empty()) {
+            if !inode.is_dir() {
+                return Err(FsWriteError::NotADir);
+            }
+            inode_number = self
+                .find_in_dir(&inode, name)?
+                .ok_or(FsWriteError::EntryNotFound)?;
+            inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+        }
+        Ok((inode_number, inode))
+    }
+    fn lookup_parent(&self, path: &FilePath) -> Result<(u32, Inode), FsWriteError> {
+        let (parent_number, parent) = match self.lookup(&path.parent()) {
+            Err(FsWriteError::EntryNotFound) => return Err(FsWriteError::ParentNotFound),
+            res => res?,
+        };
+        if !parent.is_dir() {
+            return Err(FsWriteError::NotADir);
+        }
+        Ok((parent_number, parent))
+    }
+    fn new_dir_entry(&self, inode: u32, name: &str, type_indicator: ExtInodeType, entry_size: usize) -> Vec<u8> {
+        let mut raw = vec![0; entry_size];
+        raw[..4].copy_from_slice(&inode.to_le_bytes());
+        raw[4..6].copy_from_slice(&(entry_size as u16).to_le_bytes());
+        raw[6] = name.len() as u8;
+        if self.dir_entries_contain_type() {
+            raw[7] = type_indicator as u8;
+        }
+        raw[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        raw
+    }
+    /// Adds an entry in a directory, splitting an existing entry or appending a block
+    /// The directory inode is written
+    fn insert_dir_entry(
+        &mut self,
+        dir_number: u32,
+        dir: &mut Inode,
+        name: &str,
+        inode: u32,
+        type_indicator: ExtInodeType,
+    ) -> Result<(), FsWriteError> {
+        let needed = dir_entry_size(name.len());
+        let blocks = self.read_dir_blocks(dir)?;
+        let mut free_space = None;
+        for (block, raw) in &blocks {
+            for entry in self.raw_dir_entries(raw)? {
+                if entry.inode != 0 && entry.name == name.as_bytes() {
+                    return Err(FsWriteError::AlreadyExists);
+                }
+                let used = if entry.inode == 0 { 0 } else { dir_entry_size(entry.name.len()) };
+                if free_space.is_none() && entry.entry_size - used >= needed {
+                    free_space = Some((*block, entry.offset, used, entry.entry_size));
+                }
+            }
+        }
+        // The hash tree would be outdated, the directory can still be read linearly without it
+        dir.flags &= !EXT2_INDEX_FL;
+        if let Some((block, offset, used, entry_size)) = free_space {
+            // Safe unwrap, we found the block above
+            let mut raw = blocks.into_iter().find(|(b, _)| *b == block).unwrap().1;
+            if used != 0 {
+                raw[offset + 4..offset + 6].copy_from_slice(&(used as u16).to_le_bytes());
+            }
+            let new_entry = self.new_dir_entry(inode, name, type_indicator, entry_size - used);
+            raw[offset + used..offset + entry_size].copy_from_slice(&new_entry);
+            self.write_block(block, &raw)?;
+        } else {
+            let goal_group = self.group_of_inode(dir_number);
+            let new_block = self.allocate_blocks(1, goal_group)?[0];
+            let raw = self.new_dir_entry(inode, name, type_indicator, self.block_size() as usize);
+            self.write_block(new_block, &raw)?;
+            let (mut data, _) = self.block_map(dir)?;
+            data.push(new_block);
+            if let Err(err) = self.set_block_map(dir, &data, goal_group) {
+                self.free_blocks(&[new_block])?;
+                return Err(err);
+            }
+            dir.lo_32b_size += self.block_size();
+        }
+        self.write_inode(dir_number, dir)
+    }
+    /// Removes the entry from the directory by merging it with the previous one
+    fn remove_dir_entry(&mut self, dir: &Inode, name: &str) -> Result<(), FsWriteError> {
+        for (block, mut raw) in self.read_dir_blocks(dir)? {
+            let entries = self.raw_dir_entries(&raw)?;
+            let Some(idx) = entries
+                .iter()
+                .position(|entry| entry.inode != 0 && entry.name == name.as_bytes())
+            else {
+                continue;
+            };
+            let (offset, entry_size) = (entries[idx].offset, entries[idx].entry_size);
+            if idx == 0 {
+                // The first entry of a block can't be merged, so it's only marked as unused
+                raw[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+            } else {
+                let previous = &entries[idx - 1];
+                let merged = (previous.entry_size + entry_size) as u16;
+                let previous_offset = previous.offset;
+                raw[previous_offset + 4..previous_offset + 6].copy_from_slice(&merged.to_le_bytes());
+            }
+            return self.write_block(block, &raw);
+        }
+        Err(FsWriteError::EntryNotFound)
+    }
+    fn dir_is_empty(&self, dir: &Inode) -> Result<bool, FsWriteError> {
+        for (_, raw) in self.read_dir_blocks(dir)? {
+            for entry in self.raw_dir_entries(&raw)? {
+                if entry.inode != 0 && entry.name != b"." && entry.name != b".." {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+    /// Points the ".." entry of a directory to a new parent
+    fn set_dotdot(&self, dir: &Inode, parent: u32) -> Result<(), FsWriteError> {
+        for (block, mut raw) in self.read_dir_blocks(dir)? {
+            let entries = self.raw_dir_entries(&raw)?;
+            if let Some(entry) = entries.iter().find(|entry| entry.name == b"..") {
+                let offset = entry.offset;
+                raw[offset..offset + 4].copy_from_slice(&parent.to_le_bytes());
+                return self.write_block(block, &raw);
+            }
+        }
+        Err(FsWriteError::EntryNotFound)
+    }
+}
+
+// Checking
+impl ExtDriver {
+    /// Rebuilds the block and inode usage from the directory tree, and compares it with the bitmaps and counts
+    /// When repairing, the bitmaps, counts and link counts are rewritten, bad entries are cleared and orphaned inodes are moved to /lost+found
+    /// Blocks used twice or outside of the filesystem are only reported
+    pub fn check(&mut self, repair: bool) -> Result<FsckReport, FsWriteError> {
+        if self.superblock().ext2_signature != EXT2_SIGNATURE {
+            return Err(FsWriteError::NotSupported);
+        }
+        self.check_writable()?;
+        let superblock = self.superblock();
+        let mut check = ExtCheck {
+            blocks: vec![0; superblock.total_blocks as usize / 8 + 1],
+            reached: vec![0; superblock.total_inodes as usize / 8 + 1],
+            linked: vec![0; superblock.total_inodes as usize / 8 + 1],
+            dirs: vec![0; self.blk_grp_desc_table.len()],
+            inodes_per_group: superblock.inodes_per_group,
+            links: HashMap::new(),
+            report: FsckReport::default(),
+            repair,
+        };
+        self.mark_metadata_blocks(&mut check);
+        self.mark_reserved_inodes(&mut check)?;
+        check.set_inode(ROOT_INODE, true, true);
+        self.check_dir_tree(&mut check, ROOT_INODE, String::new(), Some(ROOT_INODE))?;
+        let orphans = self.find_orphans(&mut check)?;
+        // The bitmaps are repaired before new entries are allocated in /lost+found
+        self.check_bitmaps(&mut check)?;
+        if repair {
+            self.reconnect_orphans(&mut check, &orphans)?;
+        } else {
+            for inode_number in &orphans {
+                check.links.remove(inode_number);
+            }
+        }
+        self.check_link_counts(&mut check)?;
+        let first_orphan = self.extsuperblock().head_orphan_inode_list;
+        if first_orphan != 0 {
+            // The inodes of the list aren't linked, so the bitmaps were already repaired
+            if repair {
+                self.superblock.as_ext_super_block_mut().head_orphan_inode_list = 0;
+                self.write_superblock()?;
+            }
+            check.report.add(FsckProblem::OrphanList { first_inode: first_orphan }, repair);
+        }
+        Ok(check.report)
+    }
+    /// Superblock and descriptors copies, bitmaps and inode tables
+    fn mark_metadata_blocks(&self, check: &mut ExtCheck) {
+        let block_size = self.block_size() as usize;
+        let sparse = self.extsuperblock().feat_read_only_not_supported
+            & ReadOnlyFeaturesFlagsExt2::SparseSuperblocksNGroupDescriptorTables as u32
+            != 0;
+        // Reserved descriptor blocks are 0 without the resize inode
+        let descriptor_blocks = (self.blk_grp_desc_table.len() * core::mem::size_of::<BlockGroupDescriptor>())
+            .div_ceil(block_size) as u32
+            + u32::from(self.extsuperblock().reserved_gdt_blocks);
+        let inode_table_blocks =
+            (u64::from(self.superblock().inodes_per_group) * self.inode_size()).div_ceil(block_size as u64) as u32;
+        for (group, bgd) in self.blk_grp_desc_table.iter().enumerate() {
+            if !sparse || has_superblock_backup(group as u32) {
+                let first_block = self.group_first_block(group);
+                for block in first_block..=first_block + descriptor_blocks {
+                    check.set_block(block);
+                }
+            }
+            check.set_block(bgd.lo_block_addr_block);
+            check.set_block(bgd.lo_block_addr_inode);
+            let inode_table = bgd.lo_block_addr_of_inode_start;
+            for block in inode_table..inode_table + inode_table_blocks {
+                check.set_block(block);
+            }
+        }
+    }
+    /// Reserved inodes are always in use, their blocks are marked but they aren't linked
+    fn mark_reserved_inodes(&self, check: &mut ExtCheck) -> Result<(), FsWriteError> {
+        for inode_number in 1..self.extsuperblock().fst_non_reserved_inode {
+            if inode_number == ROOT_INODE {
+                continue;
+            }
+            check.set_inode(inode_number, false, false);
+            let inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+            if inode_number == RESIZE_INODE {
+                // Its doubly indirect block lists the reserved descriptor blocks, which are already marked
+                check.set_block(inode.double_indirect_blk_ptr);
+            } else if inode.type_n_perms != 0 {
+                self.check_inode_blocks(check, inode_number, &inode, &format!("<inode {inode_number}>"))?;
+            }
+        }
+        Ok(())
+    }
+    /// Marks the data, indirect and extended attributes blocks of the inode as used
+    fn check_inode_blocks(
+        &self,
+        check: &mut ExtCheck,
+        inode_number: u32,
+        inode: &Inode,
+        path: &str,
+    ) -> Result<(), FsWriteError> {
+        let Ok((data, indirect)) = self.block_map(inode) else {
+            check.report.add(FsckProblem::BadEntry { reason: "unreadable block map", path: path.to_string() }, false);
+            return Ok(());
+        };
+        for block in data.into_iter().chain(indirect).filter(|block| *block != 0) {
+            if block >= self.superblock().total_blocks {
+                check.report.add(FsckProblem::BadBlock { block, inode: inode_number }, false);
+            } else if check.is_block_used(block) {
+                check.report.add(FsckProblem::DuplicateBlock { block, inode: inode_number }, false);
+            } else {
+                check.set_block(block);
+            }
+        }
+        // Extended attributes blocks can be shared between inodes
+        let xattr_block = inode.ext_attr_blk;
+        if xattr_block != 0 && xattr_block < self.superblock().total_blocks {
+            check.set_block(xattr_block);
+        }
+        Ok(())
+    }
+    /// Walks the directories from `dir_number`, counting the links to each inode and clearing the bad entries
+    /// The ".." entries are checked against `parent`, an orphaned directory has no known parent
+    fn check_dir_tree(
+        &mut self,
+        check: &mut ExtCheck,
+        dir_number: u32,
+        path: String,
+        parent: Option<u32>,
+    ) -> Result<(), FsWriteError> {
+        let mut dirs = vec![(dir_number, path, parent)];
+        while let Some((dir_number, path, parent)) = dirs.pop() {
+            let dir = self.get_inode(dir_number).ok_or(FsWriteError::ReadingDiskError)?;
+            let dir_path = if path.is_empty() { "/" } else { &path };
+            self.check_inode_blocks(check, dir_number, &dir, dir_path)?;
+            let Ok(blocks) = self.read_dir_blocks(&dir) else {
+                check.report.add(FsckProblem::BadEntry { reason: "unreadable directory", path: dir_path.to_string() }, false);
+                continue;
+            };
+            for (block, mut raw) in blocks {
+                let Ok(entries) = self.raw_dir_entries(&raw) else {
+                    check.report.add(FsckProblem::BadEntry { reason: "corrupted directory block", path: dir_path.to_string() }, false);
+                    continue;
+                };
+                let entries: Vec<(usize, u32, String)> = entries
+                    .iter()
+                    .filter(|entry| entry.inode != 0)
+                    .map(|entry| (entry.offset, entry.inode, String::from_utf8_lossy(entry.name).to_string()))
+                    .collect();
+                let mut changed = false;
+                for (offset, inode_number, name) in entries {
+                    let entry_path = format!("{path}/{name}");
+                    let expected = match name.as_str() {
+                        "." => Some(dir_number),
+                        ".." => match parent {
+                            Some(parent) => Some(parent),
+                            None => continue,
+                        },
+                        _ => None,
+                    };
+                    if let Some(expected) = expected {
+                        if inode_number != expected {
+                            let reason = if name == "." { "\".\" points to the wrong inode" } else { "\"..\" points to the wrong inode" };
+                            check.report.add(FsckProblem::BadEntry { reason, path: dir_path.to_string() }, check.repair);
+                            raw[offset..offset + 4].copy_from_slice(&expected.to_le_bytes());
+                            changed = true;
+                        }
+                        *check.links.entry(expected).or_default() += 1;
+                        continue;
+                    }
+                    let inode = (inode_number <= self.superblock().total_inodes)
+                        .then(|| self.get_inode(inode_number))
+                        .flatten();
+                    let reason = match &inode {
+                        None => Some("invalid inode number"),
+                        Some(_) if inode_number < self.extsuperblock().fst_non_reserved_inode => Some("links to a reserved inode"),
+                        Some(inode) if inode.type_n_perms == 0 || inode.deletion_time != 0 => Some("links to a deleted inode"),
+                        Some(inode) if inode.is_dir() && check.is_linked(inode_number) => Some("directory linked twice"),
+                        Some(_) => None,
+                    };
+                    if let Some(reason) = reason {
+                        check.report.add(FsckProblem::BadEntry { reason, path: entry_path }, check.repair);
+                        raw[offset..offset + 4].copy_from_slice(&0u32.to_le_bytes());
+                        changed = true;
+                        continue;
+                    }
+                    // Safe unwrap, no reason means the inode was read
+                    let inode = inode.unwrap();
+                    let type_indicator = inode.type_indicator() as u8;
+                    if self.dir_entries_contain_type() && raw[offset + 7] != type_indicator {
+                        check.report.add(FsckProblem::BadEntry { reason: "wrong file type", path: entry_path.clone() }, check.repair);
+                        raw[offset + 7] = type_indicator;
+                        changed = true;
+                    }
+                    *check.links.entry(inode_number).or_default() += 1;
+                    let reached = check.is_reached(inode_number);
+                    check.set_inode(inode_number, inode.is_dir(), true);
+                    if reached {
+                        continue;
+                    }
+                    if inode.is_dir() {
+                        dirs.push((inode_number, entry_path, Some(dir_number)));
+                    } else {
+                        self.check_inode_blocks(check, inode_number, &inode, &entry_path)?;
+                    }
+                }
+                if changed && check.repair {
+                    self.write_block(block, &raw)?;
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Inodes in use that aren't reachable from the root, their directories are walked too
+    /// Returns the first inode of each orphaned tree
+    fn find_orphans(&mut self, check: &mut ExtCheck) -> Result<Vec<u32>, FsWriteError> {
+        let inodes_per_group = self.superblock().inodes_per_group;
+        let first_non_reserved = self.extsuperblock().fst_non_reserved_inode;
+        let mut candidates = Vec::new();
+        for group in 0..self.blk_grp_desc_table.len() {
+            let bitmap = self.read_block(self.blk_grp_desc_table[group].lo_block_addr_inode)?;
+            let first_inode = group as u32 * inodes_per_group + 1;
+            for bit in 0..inodes_per_group as usize {
+                let inode_number = first_inode + bit as u32;
+                if !bitmap[bit / 8].get_bit(bit % 8) || inode_number < first_non_reserved || check.is_reached(inode_number) {
+                    continue;
+                }
+                let inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+                // Deleted inodes are only marked in the bitmap
+                if inode.type_n_perms != 0 && inode.n_hardlinks_to_inode != 0 && inode.deletion_time == 0 {
+                    candidates.push((inode_number, inode));
+                }
+            }
+        }
+        // Directories first, so the files they contain aren't orphans themselves
+        for (inode_number, inode) in candidates.iter().filter(|(_, inode)| inode.is_dir()) {
+            if !check.is_reached(*inode_number) {
+                check.set_inode(*inode_number, true, false);
+                self.check_dir_tree(check, *inode_number, format!("#{inode_number}"), None)?;
+            }
+        }
+        for (inode_number, inode) in candidates.iter().filter(|(_, inode)| !inode.is_dir()) {
+            if !check.is_reached(*inode_number) {
+                check.set_inode(*inode_number, false, false);
+                self.check_inode_blocks(check, *inode_number, inode, &format!("#{inode_number}"))?;
+            }
+        }
+        let orphans: Vec<u32> = candidates
+            .iter()
+            .map(|(inode_number, _)| *inode_number)
+            .filter(|inode_number| !check.is_linked(*inode_number))
+            .collect();
+        for (inode_number, inode) in &candidates {
+            if orphans.contains(inode_number) {
+                check.report.add(
+                    FsckProblem::OrphanedInode { inode: *inode_number, is_dir: inode.is_dir() },
+                    check.repair,
+                );
+            }
+        }
+        Ok(orphans)
+    }
+    /// Compares the bitmaps and the free counts of each group and of the superblock with the walk
+    fn check_bitmaps(&mut self, check: &mut ExtCheck) -> Result<(), FsWriteError> {
+        let inodes_per_group = self.superblock().inodes_per_group;
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..self.blk_grp_desc_table.len() {
+            let bgd = self.blk_grp_desc_table[group];
+            let first_block = self.group_first_block(group);
+            let mut bitmap = self.read_block(bgd.lo_block_addr_block)?;
+            let (mut marked_free, mut marked_used, mut group_free_blocks) = (0, 0, 0);
+            for bit in 0..self.blocks_in_group(group) as usize {
+                let used = check.is_block_used(first_block + bit as u32);
+                match (bitmap[bit / 8].get_bit(bit % 8), used) {
+                    (false, true) => marked_free += 1,
+                    (true, false) => marked_used += 1,
+                    _ => {}
+                }
+                if !used {
+                    group_free_blocks += 1;
+                }
+                bitmap[bit / 8].set_bit(bit % 8, used);
+            }
+            if marked_free != 0 || marked_used != 0 {
+                if check.repair {
+                    self.write_block(bgd.lo_block_addr_block, &bitmap)?;
+                }
+                check.report.add(FsckProblem::BlockBitmap { group, marked_free, marked_used }, check.repair);
+            }
+            let mut bitmap = self.read_block(bgd.lo_block_addr_inode)?;
+            let first_inode = group as u32 * inodes_per_group + 1;
+            let (mut marked_free, mut marked_used, mut group_free_inodes) = (0, 0, 0);
+            for bit in 0..inodes_per_group as usize {
+                let used = check.is_reached(first_inode + bit as u32);
+                match (bitmap[bit / 8].get_bit(bit % 8), used) {
+                    (false, true) => marked_free += 1,
+                    (true, false) => marked_used += 1,
+                    _ => {}
+                }
+                if !used {
+                    group_free_inodes += 1;
+                }
+                bitmap[bit / 8].set_bit(bit % 8, used);
+            }
+            if marked_free != 0 || marked_used != 0 {
+                if check.repair {
+                    self.write_block(bgd.lo_block_addr_inode, &bitmap)?;
+                }
+                check.report.add(FsckProblem::InodeBitmap { group, marked_free, marked_used }, check.repair);
+            }
+            let counts = [
+                ("free blocks", u32::from(bgd.lo_unallocated_blocks_in_group), group_free_blocks),
+                ("free inodes", u32::from(bgd.lo_unallocated_inodes_in_group), group_free_inodes),
+                ("directories", u32::from(bgd.lo_n_dirs_in_grp), check.dirs[group]),
+            ];
+            let mut changed = false;
+            for (what, stored, counted) in counts {
+                if stored != counted {
+                    check.report.add(FsckProblem::GroupCount { group, what, stored, counted }, check.repair);
+                    changed = true;
+                }
+            }
+            if changed && check.repair {
+                let bgd = &mut self.blk_grp_desc_table[group];
+                bgd.lo_unallocated_blocks_in_group = group_free_blocks as u16;
+                bgd.lo_unallocated_inodes_in_group = group_free_inodes as u16;
+                bgd.lo_n_dirs_in_grp = check.dirs[group] as u16;
+                self.write_group_descriptor(group)?;
+            }
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+        }
+        let superblock = self.superblock();
+        let counts = [
+            ("free blocks", superblock.unallocated_blocks, free_blocks),
+            ("free inodes", superblock.unallocated_inodes, free_inodes),
+        ];
+        let mut changed = false;
+        for (what, stored, counted) in counts {
+            if stored != counted {
+                check.report.add(FsckProblem::FreeCount { what, stored, counted }, check.repair);
+                changed = true;
+            }
+        }
+        if changed && check.repair {
+            let superblock = self.superblock.as_super_block_mut();
+            superblock.unallocated_blocks = free_blocks;
+            superblock.unallocated_inodes = free_inodes;
+            self.write_superblock()?;
+        }
+        Ok(())
+    }
+    /// Links the orphaned inodes in /lost+found, which is created if needed, as "#[inode number]"
+    fn reconnect_orphans(&mut self, check: &mut ExtCheck, orphans: &[u32]) -> Result<(), FsWriteError> {
+        if orphans.is_empty() {
+            return Ok(());
+        }
+        let lost_found = FilePath::new("/lost+found".to_string(), self.partition.clone());
+        let (lost_found_number, mut lost_found_dir) = match self.lookup(&lost_found) {
+            Err(FsWriteError::EntryNotFound) => {
+                self.create_dir(&lost_found)?;
+                let (number, dir) = self.lookup(&lost_found)?;
+                // Its "." and ".." entries, and its entry in the root
+                *check.links.entry(number).or_default() += 2;
+                *check.links.entry(ROOT_INODE).or_default() += 1;
+                (number, dir)
+            }
+            Ok((_, dir)) if !dir.is_dir() => return Err(FsWriteError::NotADir),
+            res => res?,
+        };
+        for inode_number in orphans {
+            let inode = self.get_inode(*inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+            let name = format!("#{inode_number}");
+            self.insert_dir_entry(lost_found_number, &mut lost_found_dir, &name, *inode_number, inode.type_indicator())?;
+            *check.links.entry(*inode_number).or_default() += 1;
+            if inode.is_dir() {
+                self.set_dotdot(&inode, lost_found_number)?;
+                *check.links.entry(lost_found_number).or_default() += 1;
+                lost_found_dir.n_hardlinks_to_inode += 1;
+            }
+        }
+        self.write_inode(lost_found_number, &lost_found_dir)
+    }
+    /// Compares the link count of each inode that was reached with the entries that point to it
+    fn check_link_counts(&mut self, check: &mut ExtCheck) -> Result<(), FsWriteError> {
+        let mut links: Vec<(u32, u32)> = check.links.iter().map(|(inode, links)| (*inode, *links)).collect();
+        links.sort_unstable();
+        for (inode_number, counted) in links {
+            let mut inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+            let stored = inode.n_hardlinks_to_inode;
+            if u32::from(stored) == counted {
+                continue;
+            }
+            if check.repair {
+                inode.n_hardlinks_to_inode = counted.min(u32::from(u16::MAX)) as u16;
+                self.write_inode(inode_number, &inode)?;
+            }
+            check.report.add(FsckProblem::LinkCount { inode: inode_number, stored, counted }, check.repair);
+        }
+        Ok(())
+    }
+}
+
+// Formatting
+impl ExtDriver {
+    /// Writes an empty ext2 filesystem with a lost+found directory on the partition, and returns its driver
+    pub fn format(partition: &Partition, label: &str) -> Result<Box<Self>, FsWriteError> {
+        let size = partition.2 * u64::from(SECTOR_SIZE);
+        let block_size: u32 = if size > FORMAT_SMALL_FS_SIZE { 4096 } else { 1024 };
+        // On 1KiB blocks the first block holds the boot sector
+        let first_data_block = u32::from(block_size == 1024);
+        let blocks_per_group = block_size * 8;
+        let mut total_blocks =
+            u32::try_from(size / u64::from(block_size)).or(Err(FsWriteError::NotSupported))?;
+        if total_blocks <= first_data_block {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let mut groups = (total_blocks - first_data_block).div_ceil(blocks_per_group);
+        let inodes_per_block = block_size / u32::from(FORMAT_INODE_SIZE);
+        let inodes_per_group = ((size / FORMAT_BYTES_PER_INODE) as u32)
+            .div_ceil(groups)
+            .max(FIRST_NON_RESERVED_INODE)
+            .next_multiple_of(inodes_per_block)
+            .min(blocks_per_group);
+        let inode_table_blocks = inodes_per_group / inodes_per_block;
+        let gdt_blocks = |groups: u32| (groups * 32).div_ceil(block_size);
+        let metadata_blocks = |group: u32, groups: u32| {
+            u32::from(has_superblock_backup(group)) * (1 + gdt_blocks(groups)) + 2 + inode_table_blocks
+        };
+        // A small last group isn't worth its metadata
+        let last_group_blocks = total_blocks - first_data_block - (groups - 1) * blocks_per_group;
+        if groups > 1 && last_group_blocks < metadata_blocks(groups - 1, groups) + FORMAT_MIN_GROUP_DATA_BLOCKS {
+            groups -= 1;
+            total_blocks = first_data_block + groups * blocks_per_group;
+        }
+        // Room for the root directory block
+        if total_blocks - first_data_block <= metadata_blocks(0, groups) {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let gdt_blocks = gdt_blocks(groups);
+        let root_block = first_data_block + 1 + gdt_blocks + 2 + inode_table_blocks;
+
+        let bitmap = |used: u32, len: u32| {
+            // Bits after the end of the group are set, like mke2fs does
+            let mut bitmap = vec![0u8; block_size as usize];
+            for bit in (0..used).chain(len..block_size * 8) {
+                bitmap[bit as usize / 8].set_bit(bit as usize % 8, true);
+            }
+            bitmap
+        };
+        let mut bgds = Vec::new();
+        for group in 0..groups {
+            let first_block = first_data_block + group * blocks_per_group;
+            let blocks = (total_blocks - first_block).min(blocks_per_group);
+            let block_bitmap = first_block + metadata_blocks(group, groups) - 2 - inode_table_blocks;
+            let inode_table = block_bitmap + 2;
+            let mut used_blocks = metadata_blocks(group, groups);
+            let mut used_inodes = 0;
+            if group == 0 {
+                used_blocks += 1;
+                used_inodes = FIRST_NON_RESERVED_INODE - 1;
+            }
+            let write = |block: u32, data: &[u8]| {
+                write_to_partition(partition, u64::from(block) * u64::from(block_size / u32::from(SECTOR_SIZE)), data)
+                    .or(Err(FsWriteError::WritingDiskError))
+            };
+            write(block_bitmap, &bitmap(used_blocks, blocks))?;
+            write(block_bitmap + 1, &bitmap(used_inodes, inodes_per_group))?;
+            let zeroes = vec![0; (MAX_SECTORS_PER_READ * u32::from(SECTOR_SIZE)) as usize];
+            let inode_table_size = (inode_table_blocks * block_size) as usize;
+            for offset in (0..inode_table_size).step_by(zeroes.len()) {
+                let len = zeroes.len().min(inode_table_size - offset);
+                write(inode_table + (offset / block_size as usize) as u32, &zeroes[..len])?;
+            }
+            bgds.push(BlockGroupDescriptor {
+                lo_block_addr_block: block_bitmap,
+                lo_block_addr_inode: block_bitmap + 1,
+                lo_block_addr_of_inode_start: inode_table,
+                lo_unallocated_blocks_in_group: (blocks - used_blocks) as u16,
+                lo_unallocated_inodes_in_group: (inodes_per_group - used_inodes) as u16,
+                lo_n_dirs_in_grp: u16::from(group == 0),
+                ..BlockGroupDescriptor::zeroed()
+            });
+        }
+
+        let mut superblock = ExtendedExtSuperblock::zeroed();
+        let base = &mut superblock.super_block;
+        base.total_inodes = inodes_per_group * groups;
+        base.total_blocks = total_blocks;
+        base.reserved_blocks = total_blocks / 20;
+        base.unallocated_blocks = bgds.iter().map(|bgd| u32::from(bgd.lo_unallocated_blocks_in_group)).sum();
+        base.unallocated_inodes = bgds.iter().map(|bgd| u32::from(bgd.lo_unallocated_inodes_in_group)).sum();
+        base.superblock_block_number = first_data_block;
+        base.block_size_shift = block_size.trailing_zeros() - 10;
+        base.fragment_size_shift = base.block_size_shift;
+        base.blocks_per_group = blocks_per_group;
+        base.fragments_per_group = blocks_per_group;
+        base.inodes_per_group = inodes_per_group;
+        base.mounts_allowed_before_consistency_check = u16::MAX;
+        base.ext2_signature = EXT2_SIGNATURE;
+        base.fs_state = 1; // Clean
+        base.to_do_when_error = 1; // Ignore
+        base.major_portion_version = 1;
+        superblock.fst_non_reserved_inode = FIRST_NON_RESERVED_INODE;
+        superblock.size_inode_struct = FORMAT_INODE_SIZE;
+        superblock.required_feat_present = WRITE_SUPPORTED_REQUIRED_FEATURES;
+        superblock.feat_read_only_not_supported = ReadOnlyFeaturesFlagsExt2::SparseSuperblocksNGroupDescriptorTables as u32
+            | ReadOnlyFeaturesFlagsExt2::Fs64bitFileSize as u32;
+        superblock.fs_id = (u128::from(crate::drivers::rand::rand()) << 64) | u128::from(crate::drivers::rand::rand());
+        for (dst, src) in superblock.volume_name.iter_mut().zip(label.bytes()) {
+            *dst = src;
+        }
+        let mut gdt = vec![0; (gdt_blocks * block_size) as usize];
+        for (raw, bgd) in gdt.chunks_exact_mut(32).zip(&bgds) {
+            raw.copy_from_slice(bytemuck::bytes_of(bgd));
+        }
+        for group in (0..groups).filter(|group| has_superblock_backup(*group)) {
+            let first_block = first_data_block + group * blocks_per_group;
+            superblock.block_group_superblock_part_of = group as u16;
+            let mut raw_superblock = vec![0; 1024];
+            raw_superblock[..core::mem::size_of::<ExtendedExtSuperblock>()]
+                .copy_from_slice(bytemuck::bytes_of(&superblock));
+            // The primary superblock is always 1024 bytes after the start of the partition
+            let superblock_offset = if group == 0 { 1024 } else { u64::from(first_block) * u64::from(block_size) };
+            write_to_partition(partition, superblock_offset / u64::from(SECTOR_SIZE), &raw_superblock)
+                .or(Err(FsWriteError::WritingDiskError))?;
+            write_to_partition(
+                partition,
+                u64::from(first_block + 1) * u64::from(block_size / u32::from(SECTOR_SIZE)),
+                &gdt,
+            )
+            .or(Err(FsWriteError::WritingDiskError))?;
+        }
+
+        // Root directory, lost+found is created by the driver
+        let mut root = Inode::new(INODE_TYPE_DIR | 0o755, 2);
+        root.lo_32b_size = block_size;
+        root.n_disk_sectors = block_size / u32::from(SECTOR_SIZE);
+        root.direct_blk_ptr_0 = root_block;
+        let mut root_content = vec![0; block_size as usize];
+        for (offset, name, entry_size) in [(0, ".", 12), (12, "..", block_size as usize - 12)] {
+            let entry = &mut root_content[offset..offset + entry_size];
+            entry[..4].copy_from_slice(&ROOT_INODE.to_le_bytes());
+            entry[4..6].copy_from_slice(&(entry_size as u16).to_le_bytes());
+            entry[6] = name.len() as u8;
+            entry[7] = ExtInodeType::Dir as u8;
+            entry[8..8 + name.len()].copy_from_slice(name.as_bytes());
+        }
+        let sectors_per_block = u64::from(block_size / u32::from(SECTOR_SIZE));
+        write_to_partition(partition, u64::from(root_block) * sectors_per_block, &root_content)
+            .or(Err(FsWriteError::WritingDiskError))?;
+        let root_offset = u64::from(bgds[0].lo_block_addr_of_inode_start) * u64::from(block_size)
+            + u64::from(ROOT_INODE - 1) * u64::from(FORMAT_INODE_SIZE);
+        let mut inode_sector = vec![0; usize::from(SECTOR_SIZE)];
+        let inode_start = (root_offset % u64::from(SECTOR_SIZE)) as usize;
+        inode_sector[inode_start..inode_start + core::mem::size_of::<Inode>()]
+            .copy_from_slice(bytemuck::bytes_of(&root));
+        write_to_partition(partition, root_offset / u64::from(SECTOR_SIZE), &inode_sector)
+            .or(Err(FsWriteError::WritingDiskError))?;
+
+        let mut driver = Self::try_init(partition).ok_or(FsWriteError::ReadingDiskError)?;
+        driver.create_dir(&FilePath::new("/lost+found".to_string(), partition.clone()))?;
+        Ok(driver)
+    }
+}
+
 impl FsDriver for ExtDriver {
     fn read(
         &self,
@@ -176,7 +1460,7 @@ impl FsDriver for ExtDriver {
                 let mut entries = Vec::with_capacity(d.entries.len());
                 for entry in d.entries {
                     entries.push(SoftEntry {
-                        path: FilePath::new(entry.name, self.partition().clone()),
+                        path: FilePath::new(entry.name, self.partition.clone()),
                         size: 0,
                     });
                 }
@@ -193,11 +1477,288 @@ impl FsDriver for ExtDriver {
             })),
         }
     }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        Ok(self.file_inode(path)?.1.size())
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let (_, inode) = self.file_inode(path)?;
+        let end = (offset + buf.len() as u64).min(inode.size());
+        if offset >= end {
+            return Ok(0);
+        }
+        let block_size = u64::from(self.block_size());
+        let first_index = (offset / block_size) as usize;
+        let (blocks, _) = self.block_map_start(&inode, end.div_ceil(block_size) as usize)?;
+        let data = self.read_data_blocks(&blocks[first_index..])?;
+        let start = (offset - first_index as u64 * block_size) as usize;
+        let read = (end - offset) as usize;
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        self.check_writable()?;
+        let (inode_number, mut inode) = self.file_inode(path)?;
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let old_size = inode.size();
+        let end = offset + data.len() as u64;
+        let new_size: u32 = end
+            .max(old_size)
+            .try_into()
+            .or(Err(FsWriteError::NoSpaceLeft))?;
+        let block_size = u64::from(self.block_size());
+        let goal_group = self.group_of_inode(inode_number);
+        let (mut blocks, old_indirect) = self.block_map(&inode)?;
+        blocks.resize(u64::from(new_size).div_ceil(block_size) as usize, 0);
+        let mut to_write: Vec<usize> =
+            ((offset / block_size) as usize..end.div_ceil(block_size) as usize).collect();
+        // The old last block is zeroed past the old end, the blocks between it and offset stay holes
+        let old_last = (old_size / block_size) as usize;
+        if offset > old_size && old_size % block_size != 0 && old_last < to_write[0] {
+            to_write.insert(0, old_last);
+        }
+        let holes = to_write.iter().filter(|index| blocks[**index] == 0).count();
+        let needed = holes + self.indirect_blocks_needed(blocks.len())?;
+        if needed > self.superblock().unallocated_blocks as usize + old_indirect.len() {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let mut new_blocks = self.allocate_blocks(holes, goal_group)?.into_iter();
+        for index in to_write {
+            let block_start = index as u64 * block_size;
+            let mut content = if blocks[index] == 0 {
+                // Safe unwrap, we allocated a block for each hole
+                blocks[index] = new_blocks.next().unwrap();
+                vec![0; block_size as usize]
+            } else {
+                let mut content = self.read_block(blocks[index])?;
+                if old_size < block_start + block_size {
+                    content[old_size.saturating_sub(block_start) as usize..].fill(0);
+                }
+                content
+            };
+            let copy_start = offset.max(block_start);
+            let copy_end = end.min(block_start + block_size);
+            if copy_start < copy_end {
+                content[(copy_start - block_start) as usize..(copy_end - block_start) as usize]
+                    .copy_from_slice(&data[(copy_start - offset) as usize..(copy_end - offset) as usize]);
+            }
+            self.write_block(blocks[index], &content)?;
+        }
+        if holes != 0 {
+            self.set_block_map(&mut inode, &blocks, goal_group)?;
+        }
+        inode.lo_32b_size = new_size;
+        inode.hi_32b_size = 0;
+        inode.last_modif_time = unix_time() as u32;
+        self.write_inode(inode_number, &inode)?;
+        Ok(data.len())
+    }
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        self.check_writable()?;
+        let name = filepath.name().to_string();
+        check_name(&name)?;
+        let (parent_number, mut parent) = self.lookup_parent(filepath)?;
+        let goal_group = self.group_of_inode(parent_number);
+        let inode_number = match self.find_in_dir(&parent, &name)? {
+            Some(inode_number) => {
+                let mut inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+                if inode.is_dir() {
+                    return Err(FsWriteError::IsADir);
+                }
+                if inode.type_n_perms & INODE_TYPE_MASK != INODE_TYPE_FILE {
+                    return Err(FsWriteError::NotSupported);
+                }
+                let res = self.set_content(&mut inode, content, goal_group);
+                inode.last_modif_time = unix_time() as u32;
+                // Written even on error, a full disk keeps the old content but a failed write may have freed it
+                self.write_inode(inode_number, &inode)?;
+                res?;
+                inode_number
+            }
+            None => {
+                let inode_number = self.allocate_inode(false, goal_group)?;
+                let mut inode = Inode::new(INODE_TYPE_FILE | 0o644, 1);
+                if let Err(err) = self.set_content(&mut inode, content, goal_group) {
+                    self.delete_inode(inode_number, &inode)?;
+                    return Err(err);
+                }
+                self.write_new_inode(inode_number, &inode)?;
+                if let Err(err) = self.insert_dir_entry(
+                    parent_number,
+                    &mut parent,
+                    &name,
+                    inode_number,
+                    ExtInodeType::File,
+                ) {
+                    self.delete_inode(inode_number, &inode)?;
+                    return Err(err);
+                }
+                inode_number
+            }
+        };
+        self.files.insert(
+            filepath.clone(),
+            ExtEntryDescriptor::new_raw(inode_number, filepath.path().clone(), true),
+        );
+        Ok(())
+    }
+    fn create_dir(&mut self, dirpath: &FilePath) -> Result<(), FsWriteError> {
+        self.check_writable()?;
+        let name = dirpath.name().to_string();
+        check_name(&name)?;
+        let (parent_number, mut parent) = self.lookup_parent(dirpath)?;
+        if self.find_in_dir(&parent, &name)?.is_some() {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        let goal_group = self.group_of_inode(parent_number);
+        let inode_number = self.allocate_inode(true, goal_group)?;
+        let mut inode = Inode::new(INODE_TYPE_DIR | 0o755, 2);
+        let block_size = self.block_size() as usize;
+        let mut content = self.new_dir_entry(inode_number, ".", ExtInodeType::Dir, 12);
+        content.extend(self.new_dir_entry(parent_number, "..", ExtInodeType::Dir, block_size - 12));
+        if let Err(err) = self.set_content(&mut inode, &content, goal_group) {
+            self.delete_inode(inode_number, &inode)?;
+            return Err(err);
+        }
+        self.write_new_inode(inode_number, &inode)?;
+        if let Err(err) =
+            self.insert_dir_entry(parent_number, &mut parent, &name, inode_number, ExtInodeType::Dir)
+        {
+            self.delete_inode(inode_number, &inode)?;
+            return Err(err);
+        }
+        // ".." of the new directory links to the parent
+        parent.n_hardlinks_to_inode += 1;
+        self.write_inode(parent_number, &parent)?;
+        self.files.insert(
+            dirpath.clone(),
+            ExtEntryDescriptor::new_raw(inode_number, dirpath.path().clone(), false),
+        );
+        Ok(())
+    }
+    fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        self.check_writable()?;
+        let name = path.name().to_string();
+        if name.is_empty() {
+            return Err(FsWriteError::InvalidName);
+        }
+        let (parent_number, mut parent) = self.lookup_parent(path)?;
+        let inode_number = self
+            .find_in_dir(&parent, &name)?
+            .ok_or(FsWriteError::EntryNotFound)?;
+        let mut inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+        if inode.is_dir() && !self.dir_is_empty(&inode)? {
+            return Err(FsWriteError::DirNotEmpty);
+        }
+        self.remove_dir_entry(&parent, &name)?;
+        if inode.is_dir() {
+            parent.n_hardlinks_to_inode -= 1;
+            self.write_inode(parent_number, &parent)?;
+            self.delete_inode(inode_number, &inode)?;
+        } else {
+            inode.n_hardlinks_to_inode = inode.n_hardlinks_to_inode.saturating_sub(1);
+            if inode.n_hardlinks_to_inode == 0 {
+                self.delete_inode(inode_number, &inode)?;
+            } else {
+                self.write_inode(inode_number, &inode)?;
+            }
+        }
+        self.files.remove(path);
+        Ok(())
+    }
+    fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        self.check_writable()?;
+        let old_name = from.name().to_string();
+        let new_name = to.name().to_string();
+        if old_name.is_empty() || to.path().starts_with(&format!("{}/", from.path())) {
+            return Err(FsWriteError::InvalidName);
+        }
+        check_name(&new_name)?;
+        let (old_parent_number, mut old_parent) = self.lookup_parent(from)?;
+        let inode_number = self
+            .find_in_dir(&old_parent, &old_name)?
+            .ok_or(FsWriteError::EntryNotFound)?;
+        let inode = self.get_inode(inode_number).ok_or(FsWriteError::ReadingDiskError)?;
+        let (new_parent_number, mut new_parent) = self.lookup_parent(to)?;
+        let type_indicator = inode.type_indicator();
+        self.insert_dir_entry(
+            new_parent_number,
+            &mut new_parent,
+            &new_name,
+            inode_number,
+            type_indicator,
+        )?;
+        if old_parent_number == new_parent_number {
+            // The parent was written when inserting the new entry
+            old_parent = new_parent;
+        }
+        self.remove_dir_entry(&old_parent, &old_name)?;
+        if inode.is_dir() && old_parent_number != new_parent_number {
+            self.set_dotdot(&inode, new_parent_number)?;
+            old_parent.n_hardlinks_to_inode -= 1;
+            self.write_inode(old_parent_number, &old_parent)?;
+            new_parent.n_hardlinks_to_inode += 1;
+            self.write_inode(new_parent_number, &new_parent)?;
+        }
+        let prefix = format!("{}/", from.path());
+        let moved: Vec<FilePath> = self
+            .files
+            .keys()
+            .filter(|path| *path == from || path.path().starts_with(&prefix))
+            .cloned()
+            .collect();
+        for path in moved {
+            // Safe unwrap, we just got the key from the map
+            let entry = self.files.remove(&path).unwrap();
+            let new_path = format!("{}{}", to.path(), &path.path()[from.path().len()..]);
+            let is_file = entry.type_indicator() != ExtInodeType::Dir;
+            self.files.insert(
+                FilePath::new(new_path.clone(), self.partition.clone()),
+                ExtEntryDescriptor::new_raw(entry.inner.inode, new_path, is_file),
+            );
+        }
+        Ok(())
+    }
+    fn read_link(&self, path: &FilePath) -> Option<String> {
+        let entry = self.files.get(path)?;
+        let inode = self.get_inode(entry.inner.inode)?;
+        if inode.type_n_perms & INODE_TYPE_MASK != INODE_TYPE_SYMLINK {
+            return None;
+        }
+        let target = self.link_target(&inode).ok()?;
+        Some(String::from_utf8_lossy(&target).to_string())
+    }
+    /// ext2 only stores when the inode was last changed, it's used as the creation time
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        let inode = self
+            .get_inode(entry.inner.inode)
+            .ok_or(FsReadError::ReadingDiskError)?;
+        let kind = match inode.type_indicator() {
+            ExtInodeType::Dir => EntryKind::Dir,
+            ExtInodeType::SoftLink => EntryKind::Symlink,
+            ExtInodeType::BlockDevice => EntryKind::BlockDevice,
+            ExtInodeType::ChrDevice => EntryKind::CharDevice,
+            _ => EntryKind::File,
+        };
+        Ok(Metadata {
+            size: inode.size(),
+            kind,
+            mode: inode.type_n_perms & !INODE_TYPE_MASK,
+            uid: inode.uid(),
+            gid: inode.gid(),
+            created: u64::from(inode.creation_time),
+            modified: u64::from(inode.last_modif_time),
+            accessed: u64::from(inode.last_access_time),
+            links: u32::from(inode.n_hardlinks_to_inode),
+        })
+    }
     fn as_enum(&self) -> FsDriverEnum {
         FsDriverEnum::Ext
     }
-    fn partition(&self) -> &Partition {
-        &self.partition
+    fn partition(&self) -> Option<&Partition> {
+        Some(&self.partition)
     }
 }
 impl FsDriverInitialiser for ExtDriver {
@@ -205,6 +1766,29 @@ impl FsDriverInitialiser for ExtDriver {
     where
         Self: Sized,
     {
+        let mut driver = Self::try_open(partition)?;
+        while driver.index_step().ok()? {}
+        Some(driver)
+    }
+    fn try_open(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        let mut driver = Self::open(partition)?;
+        driver.start_indexing();
+        Some(Box::new(driver))
+    }
+    fn index_step(&mut self) -> Result<bool, FsReadError> {
+        let Some(path) = self.unindexed.pop() else {
+            return Ok(false);
+        };
+        self.index_dir(&path)?;
+        Ok(!self.unindexed.is_empty())
+    }
+}
+impl ExtDriver {
+    /// Reads the superblock and the group descriptors without indexing the files, i.e. to check a filesystem whose directories can't be trusted
+    pub(super) fn open(partition: &Partition) -> Option<Self> {
         let superblock = read_superblock(partition)?;
         let extsuperblock = match superblock.as_super_block().major_portion_version {
             0 => {
@@ -222,34 +1806,182 @@ impl FsDriverInitialiser for ExtDriver {
             return None;
         }
         let bg_size = extsuperblock.block_descriptor_group_size() as usize;
-        if bg_size == 64 {
-            log::error!("Ext4 not currently supported sry !");
-            return None;
-        }
-        let block_size = extsuperblock.super_block.block_size() / 256;
-        let raw_bgdt = read_from_partition(partition, ((block_size) * 2).into(), 1)
-            .expect("Failed reading Block Group Descriptor");
+        let sectors_per_block = extsuperblock.super_block.block_size() / u32::from(SECTOR_SIZE);
+        let groups = extsuperblock.super_block.block_groups() as usize;
+        // The descriptor table is in the block following the superblock
+        let bgdt_block = extsuperblock.super_block.superblock_block_number + 1;
+        let raw_bgdt = read_from_partition(
+            partition,
+            u64::from(bgdt_block * sectors_per_block),
+            (groups * bg_size).div_ceil(SECTOR_SIZE as usize) as u64,
+        )
+        .expect("Failed reading Block Group Descriptor");
         let mut bgds = Vec::new();
-        //TODO Fix ext2 & NTFS drivers
-        // dbg!(raw_bgdt);
-        for raw_bgd in raw_bgdt.chunks_exact(32) {
-            //TODO Support 64 bit mode for ext4 i.e.
-            if raw_bgd.iter().all(|x| *x == 0) {
-                break;
+        //TODO Fix NTFS driver
+        for raw_bgd in raw_bgdt.chunks_exact(bg_size).take(groups) {
+            // The high halves of the bitmaps and inode table addresses of 64 bytes descriptors
+            if bg_size >= 64 && raw_bgd[32..44].iter().any(|byte| *byte != 0) {
+                log::error!("Block groups above 2^32 blocks aren't supported");
+                return None;
             }
-            let bgd = unsafe { &*raw_bgd.as_ptr().cast::<BlockGroupDescriptor>() }.clone();
-            bgds.push(bgd);
+            bgds.push(bytemuck::pod_read_unaligned::<BlockGroupDescriptor>(&raw_bgd[..32]));
         }
-        let mut _self = Self {
+        Some(Self {
             partition: partition.clone(),
             superblock,
             blk_grp_desc_table: bgds,
             files: HashMap::new(),
-        };
-        // dbg!(_self);
-        _self.index_disk().ok()?;
-        Some(Box::new(_self))
+            unindexed: Vec::new(),
+        })
+    }
+}
+
+const ROOT_INODE: u32 = 2;
+/// Reserves blocks to grow the group descriptor table
+const RESIZE_INODE: u32 = 7;
+/// Inodes below are reserved
+const FIRST_NON_RESERVED_INODE: u32 = 11;
+const EXT2_SIGNATURE: u16 = 0xEF53;
+/// Like mke2fs, for small filesystems
+const FORMAT_BYTES_PER_INODE: u64 = 4096;
+const FORMAT_INODE_SIZE: u16 = 128;
+/// Bigger partitions are formatted with 4KiB blocks
+const FORMAT_SMALL_FS_SIZE: u64 = 512 * 1024 * 1024;
+/// The last group is dropped if it has less free blocks than this
+const FORMAT_MIN_GROUP_DATA_BLOCKS: u32 = 64;
+const DIRECT_BLOCKS: usize = 12;
+const INODE_TYPE_MASK: u16 = 0xF000;
+const INODE_TYPE_FILE: u16 = 0x8000;
+const INODE_TYPE_DIR: u16 = 0x4000;
+const INODE_TYPE_SYMLINK: u16 = 0xA000;
+/// Char and block devices, fifos and sockets, they don't have any content
+const INODE_TYPES_SPECIAL: [u16; 4] = [0x2000, 0x6000, 0x1000, 0xC000];
+/// Directory is indexed with a hash tree
+const EXT2_INDEX_FL: u32 = 0x1000;
+/// Inode uses an extent tree instead of block pointers
+const EXT4_EXTENTS_FL: u32 = 0x80000;
+const EXTENT_HEADER_MAGIC: u16 = 0xF30A;
+/// Bigger reads are split, so that we don't hold the disk for too long
+const MAX_SECTORS_PER_READ: u32 = 128;
+const WRITE_SUPPORTED_REQUIRED_FEATURES: u32 = RequiredFeaturesFlagsExt2::DirsContainTypeField as u32;
+const WRITE_SUPPORTED_READ_ONLY_FEATURES: u32 =
+    ReadOnlyFeaturesFlagsExt2::SparseSuperblocksNGroupDescriptorTables as u32
+        | ReadOnlyFeaturesFlagsExt2::Fs64bitFileSize as u32
+        | ReadOnlyFeaturesFlagsExt2::DirContentBinaryTree as u32;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+/// Block numbers are stored on 32 bits, see the 64 bytes block group descriptors
+fn block_u32(block: u64) -> Result<u32, FsReadError> {
+    block.try_into().or(Err(FsReadError::ParsingError))
+}
+/// Entries are aligned on 4 bytes
+fn dir_entry_size(name_length: usize) -> usize {
+    (8 + name_length + 3) & !3
+}
+fn check_name(name: &str) -> Result<(), FsWriteError> {
+    if name.is_empty() || name.len() > 255 || name == "." || name == ".." || name.contains('\0') {
+        return Err(FsWriteError::InvalidName);
     }
+    Ok(())
+}
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ExtentHeader {
+    magic: u16,
+    entries: u16,
+    max_entries: u16,
+    /// 0 if the entries are leaves (extents)
+    depth: u16,
+    generation: u32,
+}
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct ExtentIndex {
+    /// First logical block covered by the node
+    first_block: u32,
+    lo_leaf: u32,
+    hi_leaf: u16,
+    unused: u16,
+}
+impl ExtentIndex {
+    fn leaf(&self) -> u64 {
+        u64::from(self.lo_leaf) | (u64::from(self.hi_leaf) << 32)
+    }
+}
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+struct Extent {
+    first_block: u32,
+    /// Above 32768 the extent is uninitialized, and the real length is len-32768
+    raw_len: u16,
+    hi_start: u16,
+    lo_start: u32,
+}
+impl Extent {
+    fn is_initialized(&self) -> bool {
+        self.raw_len <= 32768
+    }
+    fn len(&self) -> usize {
+        if self.is_initialized() {
+            self.raw_len as usize
+        } else {
+            self.raw_len as usize - 32768
+        }
+    }
+    fn start(&self) -> u64 {
+        u64::from(self.lo_start) | (u64::from(self.hi_start) << 32)
+    }
+}
+/// State of `ExtDriver::check`, the bitmaps have one bit per block or inode
+struct ExtCheck {
+    /// Blocks used by the metadata or by an inode
+    blocks: Vec<u8>,
+    /// Inodes in use, reserved ones included
+    reached: Vec<u8>,
+    /// Inodes that a directory entry points to
+    linked: Vec<u8>,
+    /// Directories of each group
+    dirs: Vec<u32>,
+    inodes_per_group: u32,
+    /// Directory entries pointing to each inode, "." and ".." included
+    links: HashMap<u32, u32>,
+    report: FsckReport,
+    repair: bool,
+}
+impl ExtCheck {
+    fn is_block_used(&self, block: u32) -> bool {
+        self.blocks[block as usize / 8].get_bit(block as usize % 8)
+    }
+    /// Blocks outside of the filesystem are ignored
+    fn set_block(&mut self, block: u32) {
+        if let Some(byte) = self.blocks.get_mut(block as usize / 8) {
+            byte.set_bit(block as usize % 8, true);
+        }
+    }
+    fn is_reached(&self, inode_number: u32) -> bool {
+        self.reached[inode_number as usize / 8].get_bit(inode_number as usize % 8)
+    }
+    fn is_linked(&self, inode_number: u32) -> bool {
+        self.linked[inode_number as usize / 8].get_bit(inode_number as usize % 8)
+    }
+    /// Marks the inode as reached, counting it in the directories of its group the first time
+    fn set_inode(&mut self, inode_number: u32, is_dir: bool, linked: bool) {
+        if is_dir && !self.is_reached(inode_number) {
+            self.dirs[block_group_of_inode(u64::from(inode_number), self.inodes_per_group) as usize] += 1;
+        }
+        self.reached[inode_number as usize / 8].set_bit(inode_number as usize % 8, true);
+        if linked {
+            self.linked[inode_number as usize / 8].set_bit(inode_number as usize % 8, true);
+        }
+    }
+}
+struct RawDirEntry<'a> {
+    offset: usize,
+    inode: u32,
+    entry_size: usize,
+    name: &'a [u8],
 }
 
 pub enum ExtEntry {
@@ -270,23 +2002,6 @@ pub struct ExtFile {
     type_indicator: ExtInodeType,
     content: Vec<u8>,
 }
-//TODO Not use vec but [Inode; 4]
-/// `inode_size` should be u16
-fn get_inode_table(
-    partition: &Partition,
-    inode_table_start_sector: u64,
-    inode_size: usize,
-) -> Option<Vec<Inode>> {
-    let raw_inode_table = read_from_partition(partition, inode_table_start_sector, 1).unwrap();
-    let mut tables = Vec::new();
-    for i in 0..raw_inode_table.len() / inode_size {
-        let inode_table =
-            unsafe { &*raw_inode_table[i * inode_size..].as_ptr().cast::<Inode>() }.clone();
-        tables.push(inode_table);
-    }
-    Some(tables)
-}
-
 fn read_superblock(partition: &Partition) -> Option<Superblock> {
     let mut rawsuper_block =
         read_from_partition(partition, 2, 2).expect("Failed reading partition on disk");
@@ -313,6 +2028,12 @@ impl Superblock {
     #[must_use] pub fn as_ext_super_block(&self) -> &ExtendedExtSuperblock {
         return bytemuck::from_bytes(&self.data[..core::mem::size_of::<ExtendedExtSuperblock>()])
     }
+    pub fn as_super_block_mut(&mut self) -> &mut ExtSuperBlock {
+        return bytemuck::from_bytes_mut(&mut self.data[..core::mem::size_of::<ExtSuperBlock>()])
+    }
+    pub fn as_ext_super_block_mut(&mut self) -> &mut ExtendedExtSuperblock {
+        return bytemuck::from_bytes_mut(&mut self.data[..core::mem::size_of::<ExtendedExtSuperblock>()])
+    }
 }
 impl core::fmt::Debug for Superblock {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -331,10 +2052,7 @@ impl core::fmt::Debug for Superblock {
 // Returns true if error in superblock (i.e. smth not supported)
 //TODO Return Result<()
 fn check_superblock(extsuperblock: &ExtendedExtSuperblock) -> bool {
-    let n_b_gs = extsuperblock
-        .super_block
-        .total_blocks
-        .div_ceil(extsuperblock.super_block.blocks_per_group);
+    let n_b_gs = extsuperblock.super_block.block_groups();
     let n_b_gs_i = extsuperblock
         .super_block
         .total_inodes
@@ -348,6 +2066,17 @@ fn check_superblock(extsuperblock: &ExtendedExtSuperblock) -> bool {
     }
     false
 }
+/// With sparse superblocks, only groups 0, 1 and powers of 3, 5 and 7 have a copy of the superblock and descriptors
+fn has_superblock_backup(group: u32) -> bool {
+    let is_power_of = |base: u32| {
+        let mut n = group;
+        while n > 1 && n % base == 0 {
+            n /= base;
+        }
+        n == 1
+    };
+    group <= 1 || is_power_of(3) || is_power_of(5) || is_power_of(7)
+}
 fn block_group_of_inode(inode_number: u64, inodes_per_group: u32) -> u64 {
     (inode_number - 1) / u64::from(inodes_per_group)
 }
@@ -409,10 +2138,14 @@ pub struct ExtSuperBlock {
 }
 impl ExtSuperBlock {
     #[must_use] pub fn block_size(&self) -> u32 {
-        self.block_size_shift << 10 // Shifts it 1024
+        1024 << self.block_size_shift
     }
     #[must_use] pub fn fragment_size(&self) -> u32 {
-        self.fragment_size_shift << 10
+        1024 << self.fragment_size_shift
+    }
+    /// Block 0 is the boot block on 1KiB block filesystems, so the groups start at block 1
+    #[must_use] pub fn block_groups(&self) -> u32 {
+        (self.total_blocks - self.superblock_block_number).div_ceil(self.blocks_per_group)
     }
 }
 
@@ -432,7 +2165,7 @@ pub struct ExtendedExtSuperblock {
     pub compress_algo_used: u32,     // See required features
     pub n_blocks_prealloc_for_files: u8,
     pub n_blocks_prealloc_for_dirs: u8,
-    pub unused: u16,
+    pub reserved_gdt_blocks: u16, // For online resizing, see the resize inode
     pub journal_id: u128,
     pub journal_inode: u32,
     pub journal_device: u32,
@@ -468,10 +2201,10 @@ pub struct ExtendedExtSuperblock {
     //TODO rest of fields
 }
 impl ExtendedExtSuperblock {
-    #[must_use] pub fn block_descriptor_group_size(&self) -> u8 {
+    #[must_use] pub fn block_descriptor_group_size(&self) -> u16 {
         if self.required_feat_present & 0x80 == 0x80 {
             // Fs uses 64 bit features
-            64
+            self.size_group_descriptors_bytes_in_64bit_mode.max(64)
         } else {
             32
         }
@@ -501,7 +2234,7 @@ impl core::fmt::Debug for ExtendedExtSuperblock {
         let compress_algo_used = self.compress_algo_used;
         let n_blocks_prealloc_for_files = self.n_blocks_prealloc_for_files;
         let n_blocks_prealloc_for_dirs = self.n_blocks_prealloc_for_dirs;
-        let unused = self.unused;
+        let reserved_gdt_blocks = self.reserved_gdt_blocks;
         let journal_id = self.journal_id;
         let journal_inode = self.journal_inode;
         let journal_device = self.journal_device;
@@ -526,7 +2259,7 @@ impl core::fmt::Debug for ExtendedExtSuperblock {
             .field("compress_algo_used", &compress_algo_used)
             .field("n_blocks_prealloc_for_files", &n_blocks_prealloc_for_files)
             .field("n_blocks_prealloc_for_dirs", &n_blocks_prealloc_for_dirs)
-            .field("unused", &unused)
+            .field("reserved_gdt_blocks", &reserved_gdt_blocks)
             .field("journal_id", &journal_id)
             .field("journal_inode", &journal_inode)
             .field("journal_device", &journal_device)
@@ -631,7 +2364,7 @@ pub enum ReadOnlyFeaturesFlagsExt2 {
     DirContentBinaryTree = 4,
 }
 #[repr(C, packed)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct BlockGroupDescriptor {
     pub lo_block_addr_block: u32,
     pub lo_block_addr_inode: u32,
@@ -713,7 +2446,7 @@ impl core::fmt::Debug for BlockGroupDescriptor64 {
 }
 
 #[repr(C, packed)]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Inode {
     pub type_n_perms: u16, // https://wiki.osdev.org/Ext2#Inode_Type_and_Permissions
     pub user_id: u16,
@@ -748,3 +2481,55 @@ pub struct Inode {
     pub blk_addr_frag: u32,
     pub os_spec_2: [u8; 12], // https://wiki.osdev.org/Ext2#OS_Specific_Value_2
 }
+impl Inode {
+    /// The times are set to now
+    #[must_use] pub fn new(type_n_perms: u16, n_hardlinks_to_inode: u16) -> Self {
+        let now = unix_time() as u32;
+        Self {
+            type_n_perms,
+            n_hardlinks_to_inode,
+            last_access_time: now,
+            creation_time: now,
+            last_modif_time: now,
+            ..Self::zeroed()
+        }
+    }
+    /// The high half is in the Linux specific value 2
+    #[must_use] pub fn uid(&self) -> u32 {
+        u32::from(self.user_id) | (u32::from(u16::from_le_bytes([self.os_spec_2[4], self.os_spec_2[5]])) << 16)
+    }
+    #[must_use] pub fn gid(&self) -> u32 {
+        u32::from(self.group_id) | (u32::from(u16::from_le_bytes([self.os_spec_2[6], self.os_spec_2[7]])) << 16)
+    }
+    #[must_use] pub fn is_dir(&self) -> bool {
+        self.type_n_perms & INODE_TYPE_MASK == INODE_TYPE_DIR
+    }
+    /// On directories the high half is the directory ACL
+    #[must_use] pub fn size(&self) -> u64 {
+        if self.is_dir() {
+            u64::from(self.lo_32b_size)
+        } else {
+            u64::from(self.lo_32b_size) | (u64::from(self.hi_32b_size) << 32)
+        }
+    }
+    #[must_use] pub fn type_indicator(&self) -> ExtInodeType {
+        match self.type_n_perms & INODE_TYPE_MASK {
+            0x1000 => ExtInodeType::FIFO,
+            0x2000 => ExtInodeType::ChrDevice,
+            INODE_TYPE_DIR => ExtInodeType::Dir,
+            0x6000 => ExtInodeType::BlockDevice,
+            INODE_TYPE_FILE => ExtInodeType::File,
+            INODE_TYPE_SYMLINK => ExtInodeType::SoftLink,
+            0xC000 => ExtInodeType::Socket,
+            _ => ExtInodeType::Unknown,
+        }
+    }
+    /// The 12 direct pointers followed by the singly, doubly and triply indirect ones
+    #[must_use] pub fn block_ptrs(&self) -> [u32; 15] {
+        let raw = &bytemuck::bytes_of(self)[40..100];
+        core::array::from_fn(|i| read_u32(raw, i * 4))
+    }
+    pub fn set_block_ptrs(&mut self, ptrs: &[u32; 15]) {
+        bytemuck::bytes_of_mut(self)[40..100].copy_from_slice(bytemuck::cast_slice(ptrs));
+    }
+}
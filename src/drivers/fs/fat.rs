@@ -1,26 +1,32 @@
 use alloc::{
     boxed::Box,
+    collections::BTreeMap,
     format,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
+use bit_field::BitField;
 use hashbrown::HashMap;
 
 use crate::{
     bit_manipulation::any_as_u8_slice,
     dbg,
     disk::{
-        driver::{read_from_partition, write_to_partition},
+        driver::{read_from_partition, write_to_partition, SECTOR_SIZE},
         DiskError,
     },
     fs::path::FileSystemError,
-    serial_print, serial_println,
+    serial_println,
+    time::{civil_from_days, days_from_civil, unix_time},
 };
 
 use super::{
     fs_driver::{
-        Dir, Entry, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, SoftEntry,
+        Dir, Entry, EntryKind, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError,
+        FsWriteError, Metadata, SoftEntry,
     },
+    fsck::{FsckProblem, FsckReport},
     partition::Partition,
     path::FilePath,
     userland::FatAttributes,
@@ -31,45 +37,46 @@ pub struct Fat32Driver {
     files: HashMap<FilePath, Fat32SoftEntry>,
     pub fat_info: FatInfo,
     pub partition: Partition,
-    fat_table: FatTable,
+    /// Where to start looking for free clusters
+    next_free_cluster: u32,
+    /// Directories found but not read yet, with their first sector, see `index_step`
+    unindexed: Vec<(FilePath, u64)>,
 }
 impl Fat32Driver {
+    /// Also handles FAT12 and FAT16, the type is found from the BPB
     #[must_use] pub fn new(partition: &Partition) -> Option<Self> {
-        let fat_info = Self::get_fat_boot(partition).unwrap();
-        if fat_info.0.fs_type_label[0..5] != [70, 65, 84, 51, 50] {
-            // log::error!("Error reading fat info in {:?} {}",partition, crate::bit_manipulation::as_chars(&fat_info.0.fs_type_label));
-            return None;
-        }
-        let first_fat_sector = fat_info.first_fat_sector();
-        let first_data_sector = fat_info.get_first_data_sector();
-        let fat_table = Self::read_fat(
-            partition,
-            fat_info.get_fat_size(),
-            first_fat_sector,
-            first_data_sector,
-        );
-        let root = FilePath::new("/".to_string(), partition.clone());
-        let root_sector = fat_info.first_sector_of_cluster();
-        let mut files = Self::walk_dir(
-            root.clone(),
-            partition,
-            root_sector,
-            first_data_sector,
-            u64::from(fat_info.first_fat_sector()),
-        );
-        files.insert(
+        let mut driver = Self::open(partition)?;
+        driver.start_indexing();
+        while driver.index_step().ok()? {}
+        Some(driver)
+    }
+    /// Adds the root, the directories are then read by `index_step`
+    fn start_indexing(&mut self) {
+        let root = FilePath::new("/".to_string(), self.partition.clone());
+        let root_sector = self.fat_info.root_dir_sector();
+        self.files.insert(
             root.clone(),
             Fat32SoftEntry {
-                path: root,
+                path: root.clone(),
                 is_file: false,
                 sector: root_sector,
+                size: 0,
             },
         );
+        self.unindexed.push((root, root_sector));
+    }
+    /// Reads the BPB without indexing the files, i.e. to check a filesystem whose directories can't be trusted
+    pub(super) fn open(partition: &Partition) -> Option<Self> {
+        let fat_info = Self::get_fat_boot(partition).ok()?;
+        if !fat_info.is_valid() {
+            return None;
+        }
         Some(Self {
-            files,
+            files: HashMap::new(),
             fat_info,
             partition: partition.clone(),
-            fat_table,
+            next_free_cluster: 2,
+            unindexed: Vec::new(),
         })
     }
     #[must_use] pub fn get_sector(&self, path: &FilePath) -> Option<u64> {
@@ -77,186 +84,26 @@ impl Fat32Driver {
     }
     #[must_use] pub fn read_file(&self, path: &FilePath) -> Option<Vec<u8>> {
         let sector = self.get_sector(path)?;
-        let raw = Self::read_and_follow_clusters(
-            &self.partition,
-            sector,
-            self.fat_info.get_first_data_sector(),
-            u64::from(self.fat_info.first_fat_sector()),
-        )?;
-        Some(raw)
+        if sector == 0 {
+            // Empty files don't have any cluster
+            return Some(Vec::new());
+        }
+        let chain = self.cluster_chain(self.sector_cluster(sector)).ok()?;
+        self.read_clusters(&chain).ok()
     }
     #[must_use] pub fn read_dir(&self, path: &FilePath) -> Option<Vec<Fat32SoftEntry>> {
-        let sector = self.get_sector(path)?;
-        let sectors = Self::read_and_follow_clusters(
-            &self.partition,
-            sector,
-            self.fat_info.get_first_data_sector(),
-            u64::from(self.fat_info.first_fat_sector()),
-        )?;
-        let raw_entries_part = Self::get_raw_entries(&sectors);
-        let entries_part = Self::parse_entries(
-            &raw_entries_part,
-            self.fat_info.get_first_data_sector(),
-            path,
-            &self.partition,
-        );
-        Some(entries_part)
-    }
-    /// Reads a fat32 entry and follow clusters from fat table
-    #[must_use] pub fn read_and_follow_clusters(
-        partition: &Partition,
-        start_sector: u64,
-        first_data_sector: u64,
-        first_fat_sector: u64,
-    ) -> Option<Vec<u8>> {
-        let mut res = Vec::new();
-        let mut reading = true;
-        let mut next_sector = start_sector;
-        while reading {
-            let mut sector = read_from_partition(partition, next_sector, 1).unwrap();
-            // if *sector.last().unwrap()==0 {
-            if let Some(cluster) =
-                Self::read_fat_cluster(partition, next_sector, first_fat_sector, first_data_sector)
-            {
-                match cluster {
-                    ClusterEnum::EndOfChain => reading = false,
-                    ClusterEnum::BadCluster => reading = false,
-                    ClusterEnum::Cluster(cluster) => {
-                        next_sector = cluster_to_sector(u64::from(cluster), first_data_sector);
-                    }
-                };
-            } else {
-                log::error!("Sector too small ?!");
-                dbg!(Self::read_fat_cluster(
-                    partition,
-                    next_sector,
-                    first_fat_sector,
-                    first_data_sector
-                ));
-            }
-
-            res.extend(sector);
-            // next_sector+=1;
-        }
-        Some(res)
+        self.dir_entries(path, self.get_sector(path)?)
     }
-
-    fn read_fat(
-        partition: &Partition,
-        fat_size: u32,
-        first_fat_sector: u16,
-        first_data_sector: u64,
-    ) -> FatTable {
-        let (mut last_sector, mut last_offset) = (0, 0);
-        let mut last_used_sector = 0;
-        for i in 0..fat_size {
-            if last_sector != 0 {
-                break;
-            }
-            let content =
-                read_from_partition(partition, u64::from(first_fat_sector) + u64::from(i), 1).unwrap();
-            for offset in 0..content.len() / 4 {
-                let table_value = &content[offset * 4..offset * 4 + 4];
-                let mut table_value = (u32::from(table_value[3]) << 24)
-                    | (u32::from(table_value[2]) << 16)
-                    | (u32::from(table_value[1]) << 8)
-                    | u32::from(table_value[0]);
-                table_value &= 0x0FFF_FFFF;
-                if table_value < 0x0FFF_FFF8 && table_value != 0x0FFF_FFF7 {
-                    if table_value == 0 {
-                        last_sector = first_fat_sector + i as u16;
-                        last_offset = offset as u16;
-                        // Useless to change table_value cuz never read after
-                        // let table_value = &content[(offset - 1) * 4..(offset - 1) * 4 + 4];
-                        // let mut table_value: u32 = ((table_value[3] as u32) << 24)
-                        //     | ((table_value[2] as u32) << 16)
-                        //     | ((table_value[1] as u32) << 8)
-                        //     | (table_value[0] as u32);
-                        // table_value &= 0x0FFFFFFF;
-                        break;
-                    }
-                    last_used_sector =
-                        cluster_to_sector(u64::from(table_value), first_data_sector) as u32;
-                }
-            }
-        }
-        FatTable {
-            size: fat_size,
-            first_sector: first_fat_sector,
-            last_sector,
-            last_offset,
-            last_used_sector,
-        }
-    }
-    // Current sector should be u32
-    // Reads the fat table to know where is the next cluster to follow
-    #[must_use] pub fn read_fat_cluster(
-        partition: &Partition,
-        current_sector: u64,
-        first_fat_sector: u64,
-        first_data_sector: u64,
-    ) -> Option<ClusterEnum> {
-        if current_sector < first_data_sector {
-            // Should do current_sector-2 but we could have buffer underflows...
-            log::error!(
-                "Can't read cluster if sector is to small ! {}<{}",
-                current_sector,
-                first_data_sector
-            );
-            return None;
-        }
-        let fat_offset = ((current_sector - first_data_sector) + 2) * 4;
-        let fat_sector = (fat_offset / 512) + first_fat_sector;
-        let ent_offset = (fat_offset % 512) as usize;
-        let content = read_from_partition(partition, fat_sector, 1).unwrap();
-        let table_value = &content[ent_offset..ent_offset + 4];
-        let mut table_value = (u32::from(table_value[3]) << 24)
-            | (u32::from(table_value[2]) << 16)
-            | (u32::from(table_value[1]) << 8)
-            | u32::from(table_value[0]);
-        table_value &= 0x0FFF_FFFF;
-        if table_value >= 0x0FFF_FFF8 || table_value == 0 {
-            Some(ClusterEnum::EndOfChain)
-        } else if table_value == 0x0FFF_FFF7 {
-            return Some(ClusterEnum::BadCluster)
-        } else {
-            return Some(ClusterEnum::Cluster(table_value))
-        }
+    fn dir_entries(&self, path: &FilePath, sector: u64) -> Option<Vec<Fat32SoftEntry>> {
+        let (_, data) = self.read_dir_data(self.sector_cluster(sector)).ok()?;
+        let raw_entries = Self::get_raw_entries(&data);
+        Some(self.parse_entries(&raw_entries, path))
     }
     fn get_fat_boot(partition: &Partition) -> Result<FatInfo, DiskError> {
         let raw_fat_boot = read_from_partition(partition, 0, 2)?;
         let fat_boot = unsafe { &*raw_fat_boot.as_ptr().cast::<BiosParameterBlock>() };
         Ok(FatInfo(fat_boot.clone()))
     }
-    //TODO Change prefix to String/&str ?
-    fn walk_dir(
-        prefix: FilePath,
-        partition: &Partition,
-        sector: u64,
-        first_data_sector: u64,
-        first_fat_sector: u64,
-    ) -> HashMap<FilePath, Fat32SoftEntry> {
-        let mut files = HashMap::new();
-        let raw_sector =
-            Self::read_and_follow_clusters(partition, sector, first_data_sector, first_fat_sector)
-                .unwrap();
-        let raw_entries = Self::get_raw_entries(&raw_sector);
-        for entry in Self::parse_entries(&raw_entries, first_data_sector, &prefix, partition) {
-            //TODO is_dir
-            if !entry.is_file {
-                let inner_entries = Self::walk_dir(
-                    prefix.clone().join_str(entry.path.name().to_string()),
-                    partition,
-                    entry.sector,
-                    first_data_sector,
-                    first_fat_sector,
-                );
-                files.extend(inner_entries);
-            }
-            files.insert(entry.path.clone(), entry);
-        }
-        files
-    }
     fn get_raw_entries(sector: &[u8]) -> Vec<RawFat32Entry> {
         let mut entries = Vec::new();
         for i in 0..sector.len() / 32 {
@@ -295,95 +142,764 @@ impl Fat32Driver {
         }
         entries
     }
-    fn parse_entries(
-        entries: &[RawFat32Entry],
-        first_data_sector: u64,
-        prefix: &FilePath,
-        partition: &Partition,
-    ) -> Vec<Fat32SoftEntry> {
+    fn parse_entries(&self, entries: &[RawFat32Entry], prefix: &FilePath) -> Vec<Fat32SoftEntry> {
         let mut files = Vec::new();
-        let mut i = 0;
-        while i < entries.len() {
-            let entry = &entries[i];
-            i += 1;
-            match entry {
-                RawFat32Entry::LFN(file) => {
-                    let mut name = file.name();
-                    let mut nentry = None;
-                    loop {
-                        if i >= entries.len() {
-                            dbg!(file);
-                            dbg!(entries[i - 1]);
-                            break;
-                        } else {
-                            match &entries[i] {
-                                RawFat32Entry::LFN(lfn) => {
-                                    let mut new_name = lfn.name();
-                                    new_name.push_str(&name);
-                                    name = new_name;
-                                    i += 1;
-                                }
-                                RawFat32Entry::Standard(file) => {
-                                    nentry = Some(file);
-                                    break;
-                                }
-                            };
-                        }
+        let mut long_name: Option<String> = None;
+        for entry in entries {
+            let file = match entry {
+                RawFat32Entry::LFN(lfn) => {
+                    // The last part of the name comes first
+                    let mut name = lfn.name();
+                    if lfn.order & 0x40 == 0 {
+                        name.push_str(&long_name.unwrap_or_default());
                     }
-                    if nentry.is_none() {
+                    long_name = Some(name);
+                    continue;
+                }
+                RawFat32Entry::Standard(file) => file,
+            };
+            // Entries without a long name (i.e. made by DOS) only have their 8.3 name
+            let name = long_name.take().unwrap_or_else(|| file.short_name());
+            if name == "." || name == ".." || file.attributes & ATTR_VOLUME_ID != 0 {
+                continue;
+            }
+            let path = prefix.clone().join(FilePath::new(name, self.partition.clone()));
+            let sector = match file.cluster() {
+                // File is empty
+                0 => 0,
+                1 => {
+                    log::error!("Cluster is too low ! (1) for {}", path);
+                    continue;
+                }
+                cluster => self.cluster_sector(cluster),
+            };
+            files.push(Fat32SoftEntry {
+                path,
+                sector,
+                is_file: file.attributes & ATTR_DIRECTORY == 0,
+                size: file.size,
+            });
+        }
+        files
+    }
+}
+// Writing
+impl Fat32Driver {
+    #[must_use] pub fn cluster_size(&self) -> usize {
+        SECTOR_SIZE as usize * self.fat_info.0.sectors_per_cluster as usize
+    }
+    fn cluster_sector(&self, cluster: u32) -> u64 {
+        cluster_to_sector(
+            u64::from(cluster),
+            self.fat_info.get_first_data_sector(),
+            self.fat_info.0.sectors_per_cluster,
+        )
+    }
+    /// Sectors before the data region are the fixed root directory of FAT12/16, which is cluster 0
+    fn sector_cluster(&self, sector: u64) -> u32 {
+        let first_data_sector = self.fat_info.get_first_data_sector();
+        if sector < first_data_sector {
+            return 0;
+        }
+        sector_to_cluster(sector, first_data_sector, self.fat_info.0.sectors_per_cluster) as u32
+    }
+    /// Reads the FAT entry of a cluster, `loaded` holds the last FAT sectors read so they aren't read again
+    fn read_fat_entry(&self, cluster: u32, loaded: &mut (u64, Vec<u8>)) -> Result<u32, FsWriteError> {
+        let fat_offset = self.fat_info.fat_entry_offset(cluster);
+        if fat_offset / 512 != loaded.0 {
+            let sector = u64::from(self.fat_info.first_fat_sector()) + fat_offset / 512;
+            loaded.1 = read_from_partition(&self.partition, sector, self.fat_info.fat_entry_sectors())
+                .or(Err(FsWriteError::ReadingDiskError))?;
+            loaded.0 = fat_offset / 512;
+        }
+        Ok(self.fat_info.fat_value(&loaded.1, (fat_offset % 512) as usize, cluster))
+    }
+    /// Reads the value of the FAT entry of a cluster (i.e. the next cluster in the chain)
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsWriteError> {
+        self.read_fat_entry(cluster, &mut (u64::MAX, Vec::new()))
+    }
+    /// Sets (cluster, value) entries in every copy of the FAT, each FAT sector is only written once
+    fn set_fat_entries(&self, entries: &[(u32, u32)]) -> Result<(), FsWriteError> {
+        let mut by_sector: BTreeMap<u64, Vec<(usize, u32, u32)>> = BTreeMap::new();
+        for (cluster, value) in entries {
+            let fat_offset = self.fat_info.fat_entry_offset(*cluster);
+            by_sector
+                .entry(fat_offset / 512)
+                .or_default()
+                .push(((fat_offset % 512) as usize, *cluster, *value));
+        }
+        for fat in 0..u64::from(self.fat_info.0.fats) {
+            let fat_start = u64::from(self.fat_info.first_fat_sector())
+                + fat * u64::from(self.fat_info.get_fat_size());
+            for (sector, values) in &by_sector {
+                let mut content = read_from_partition(
+                    &self.partition,
+                    fat_start + sector,
+                    self.fat_info.fat_entry_sectors(),
+                )
+                .or(Err(FsWriteError::ReadingDiskError))?;
+                for (offset, cluster, value) in values {
+                    self.fat_info.set_fat_value(&mut content, *offset, *cluster, *value);
+                }
+                write_to_partition(&self.partition, fat_start + sector, &content)
+                    .or(Err(FsWriteError::WritingDiskError))?;
+            }
+        }
+        Ok(())
+    }
+    /// Follows the FAT from the first cluster of an entry
+    fn cluster_chain(&self, first_cluster: u32) -> Result<Vec<u32>, FsWriteError> {
+        self.cluster_chain_start(first_cluster, usize::MAX)
+    }
+    /// Same as `cluster_chain`, but stops after `max_len` clusters
+    fn cluster_chain_start(&self, first_cluster: u32, max_len: usize) -> Result<Vec<u32>, FsWriteError> {
+        let bad_cluster = BAD_CLUSTER & self.fat_info.fat_entry_mask();
+        let mut chain = Vec::new();
+        let mut cluster = first_cluster;
+        // Chains are mostly contiguous, so the FAT sector is often the same as before
+        let mut loaded = (u64::MAX, Vec::new());
+        while (2..bad_cluster).contains(&cluster) && chain.len() < max_len {
+            if chain.len() as u64 > self.fat_info.get_total_clusters() {
+                log::error!("Loop in cluster chain starting at {}", first_cluster);
+                return Err(FsWriteError::ReadingDiskError);
+            }
+            chain.push(cluster);
+            cluster = self.read_fat_entry(cluster, &mut loaded)?;
+        }
+        Ok(chain)
+    }
+    /// Entry of a file in the index
+    fn file_entry(&self, path: &FilePath) -> Result<&Fat32SoftEntry, FsReadError> {
+        self.files
+            .get(path)
+            .filter(|entry| entry.is_file)
+            .ok_or(FsReadError::EntryNotFound)
+    }
+    /// Finds `count` free clusters, links them together and marks the last one as end of chain
+    fn allocate_clusters(&mut self, count: usize) -> Result<Vec<u32>, FsWriteError> {
+        let max_cluster = self.fat_info.get_total_clusters() as u32 + 2;
+        let start = self.next_free_cluster.clamp(2, max_cluster - 1);
+        let mut free = Vec::with_capacity(count);
+        let mut loaded = (u64::MAX, Vec::new());
+        for cluster in (start..max_cluster).chain(2..start) {
+            if free.len() == count {
+                break;
+            }
+            if self.read_fat_entry(cluster, &mut loaded)? == 0 {
+                free.push(cluster);
+            }
+        }
+        if free.len() < count {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let mut entries = Vec::with_capacity(count);
+        for (i, cluster) in free.iter().enumerate() {
+            entries.push((*cluster, free.get(i + 1).copied().unwrap_or(END_OF_CHAIN)));
+        }
+        self.set_fat_entries(&entries)?;
+        self.update_fs_info(-(count as i64), free.last().copied())?;
+        Ok(free)
+    }
+    fn free_chain(&mut self, first_cluster: u32) -> Result<(), FsWriteError> {
+        let chain = self.cluster_chain(first_cluster)?;
+        let entries: Vec<(u32, u32)> = chain.iter().map(|cluster| (*cluster, 0)).collect();
+        self.set_fat_entries(&entries)?;
+        self.update_fs_info(chain.len() as i64, None)
+    }
+    /// Keeps the free cluster count and the next free cluster hint of the FSInfo sector up to date
+    fn update_fs_info(
+        &mut self,
+        free_delta: i64,
+        last_allocated: Option<u32>,
+    ) -> Result<(), FsWriteError> {
+        if let Some(last) = last_allocated {
+            self.next_free_cluster = last + 1;
+        }
+        let Some((sector, mut content)) = self.fs_info()? else {
+            return Ok(());
+        };
+        let free = read_u32(&content, 488);
+        if free != 0xFFFF_FFFF {
+            // 0xFFFFFFFF means unknown, so we leave it as is
+            let free = (i64::from(free) + free_delta).max(0) as u32;
+            content[488..492].copy_from_slice(&free.to_le_bytes());
+        }
+        if let Some(last) = last_allocated {
+            content[492..496].copy_from_slice(&(last + 1).to_le_bytes());
+        }
+        write_to_partition(&self.partition, sector, &content)
+            .or(Err(FsWriteError::WritingDiskError))
+    }
+    /// Sector and content of the FSInfo sector, None if there's none or if it's invalid
+    fn fs_info(&self) -> Result<Option<(u64, Vec<u8>)>, FsWriteError> {
+        let sector = u64::from(self.fat_info.0.fs_info_sector);
+        // Only FAT32 has a FSInfo sector, the field is part of the volume label on FAT12/16
+        if self.fat_info.fat_type() != FatType::Fat32 || sector == 0 || sector == 0xFFFF {
+            return Ok(None);
+        }
+        let content = read_from_partition(&self.partition, sector, 1)
+            .or(Err(FsWriteError::ReadingDiskError))?;
+        if read_u32(&content, 0) != FSINFO_LEAD_SIGNATURE
+            || read_u32(&content, 484) != FSINFO_STRUCT_SIGNATURE
+        {
+            log::warn!("Invalid FSInfo sector on {:?}", self.partition);
+            return Ok(None);
+        }
+        Ok(Some((sector, content)))
+    }
+    fn read_clusters(&self, chain: &[u32]) -> Result<Vec<u8>, FsWriteError> {
+        let sectors_per_cluster = u64::from(self.fat_info.0.sectors_per_cluster);
+        let mut data = Vec::with_capacity(chain.len() * self.cluster_size());
+        for cluster in chain {
+            data.extend(
+                read_from_partition(&self.partition, self.cluster_sector(*cluster), sectors_per_cluster)
+                    .or(Err(FsWriteError::ReadingDiskError))?,
+            );
+        }
+        Ok(data)
+    }
+    /// Writes data on the clusters of the chain, the last cluster is padded with zeroes
+    fn write_clusters(&self, chain: &[u32], data: &[u8]) -> Result<(), FsWriteError> {
+        for (cluster, chunk) in chain.iter().zip(data.chunks(self.cluster_size())) {
+            let mut chunk = chunk.to_vec();
+            chunk.resize(self.cluster_size(), 0);
+            write_to_partition(&self.partition, self.cluster_sector(*cluster), &chunk)
+                .or(Err(FsWriteError::WritingDiskError))?;
+        }
+        Ok(())
+    }
+    /// Returns the cluster chain and the raw entries of a directory
+    /// Cluster 0 is the fixed root directory of FAT12/16, its chain is empty
+    fn read_dir_data(&self, dir_cluster: u32) -> Result<(Vec<u32>, Vec<u8>), FsWriteError> {
+        if dir_cluster == 0 {
+            let data = read_from_partition(
+                &self.partition,
+                self.fat_info.root_dir_sector(),
+                self.fat_info.get_root_dir_sectors(),
+            )
+            .or(Err(FsWriteError::ReadingDiskError))?;
+            return Ok((Vec::new(), data));
+        }
+        let chain = self.cluster_chain(dir_cluster)?;
+        let data = self.read_clusters(&chain)?;
+        Ok((chain, data))
+    }
+    /// Writes back the raw entries returned by `read_dir_data`
+    fn write_dir_data(&self, chain: &[u32], data: &[u8]) -> Result<(), FsWriteError> {
+        if chain.is_empty() {
+            return write_to_partition(&self.partition, self.fat_info.root_dir_sector(), data)
+                .or(Err(FsWriteError::WritingDiskError));
+        }
+        self.write_clusters(chain, data)
+    }
+    /// First cluster of the directory containing path
+    fn parent_cluster(&self, path: &FilePath) -> Result<u32, FsWriteError> {
+        let parent = self
+            .files
+            .get(&path.parent())
+            .ok_or(FsWriteError::ParentNotFound)?;
+        if parent.is_file {
+            return Err(FsWriteError::NotADir);
+        }
+        Ok(self.sector_cluster(parent.sector))
+    }
+    /// Writes the LFN entries followed by the 8.3 entry in a directory, and grows it if it's full
+    /// The name fields of entry are overwritten
+    fn insert_dir_entry(
+        &mut self,
+        dir_cluster: u32,
+        name: &str,
+        mut entry: Standard32,
+    ) -> Result<(), FsWriteError> {
+        let (mut chain, mut data) = self.read_dir_data(dir_cluster)?;
+        if find_dir_slot(&data, name).is_some() {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        let short_name = short_name_for(name, &data);
+        entry.name.copy_from_slice(&short_name[..8]);
+        entry.extension.copy_from_slice(&short_name[8..]);
+        let lfns = lfn_entries(name, lfn_checksum(&short_name));
+
+        let needed = lfns.len() + 1;
+        let start = free_slots_start(&data, needed);
+        let total_slots = data.len() / 32;
+        if start + needed > total_slots {
+            if chain.is_empty() {
+                // The fixed root directory of FAT12/16 can't grow
+                return Err(FsWriteError::NoSpaceLeft);
+            }
+            let missing = (start + needed - total_slots) * 32;
+            let new_clusters = self.allocate_clusters(missing.div_ceil(self.cluster_size()))?;
+            // Safe unwrap, a directory always has at least one cluster
+            self.set_fat_entries(&[(*chain.last().unwrap(), new_clusters[0])])?;
+            data.resize(data.len() + new_clusters.len() * self.cluster_size(), 0);
+            chain.extend(new_clusters);
+        }
+        for (i, lfn) in lfns.iter().enumerate() {
+            data[(start + i) * 32..(start + i + 1) * 32].copy_from_slice(any_as_u8_slice(lfn));
+        }
+        data[(start + lfns.len()) * 32..(start + needed) * 32]
+            .copy_from_slice(any_as_u8_slice(&entry));
+        self.write_dir_data(&chain, &data)
+    }
+}
+// Checking
+impl Fat32Driver {
+    /// Walks the directories from the root, then looks for lost clusters and compares the FAT copies
+    /// When repairing, broken chains are cut, entries that can't be fixed are deleted and lost clusters are freed
+    pub fn check(&mut self, repair: bool) -> Result<FsckReport, FsWriteError> {
+        let mut check = FatCheck {
+            used: vec![0; self.cluster_end() as usize / 8 + 1],
+            report: FsckReport::default(),
+            repair,
+        };
+        self.check_dirs(&mut check)?;
+        let free = self.check_lost_chains(&mut check)?;
+        self.check_free_count(&mut check, free)?;
+        self.check_fat_copies(&mut check)?;
+        Ok(check.report)
+    }
+    /// Clusters go from 2 to this one (excluded)
+    fn cluster_end(&self) -> u32 {
+        self.fat_info.get_total_clusters() as u32 + 2
+    }
+    /// Follows the chain of a valid first cluster and marks its clusters as used
+    /// Stops before the first cluster that is free, reserved, out of range or already used, which is returned
+    fn check_chain(&self, check: &mut FatCheck, first_cluster: u32) -> Result<(Vec<u32>, Option<u32>), FsWriteError> {
+        let end_of_chain = (END_OF_CHAIN & self.fat_info.fat_entry_mask()) - 7;
+        let mut chain = vec![first_cluster];
+        check.set_used(first_cluster, true);
+        let mut loaded = (u64::MAX, Vec::new());
+        loop {
+            // Safe unwrap, the chain isn't empty
+            let next = self.read_fat_entry(*chain.last().unwrap(), &mut loaded)?;
+            if next >= end_of_chain {
+                return Ok((chain, None));
+            }
+            if !(2..self.cluster_end()).contains(&next) || check.is_used(next) {
+                return Ok((chain, Some(next)));
+            }
+            check.set_used(next, true);
+            chain.push(next);
+        }
+    }
+    fn check_dirs(&mut self, check: &mut FatCheck) -> Result<(), FsWriteError> {
+        let root_cluster = self.fat_info.root_dir_cluster();
+        let mut root_chain = Vec::new();
+        if root_cluster != 0 {
+            if root_cluster >= self.cluster_end() {
+                log::error!("Root directory cluster {} is out of range", root_cluster);
+                return Err(FsWriteError::ReadingDiskError);
+            }
+            let (chain, stop) = self.check_chain(check, root_cluster)?;
+            if stop.is_some() {
+                // Safe unwrap, the chain isn't empty
+                self.cut_chain(check, *chain.last().unwrap(), "/".to_string())?;
+            }
+            root_chain = chain;
+        }
+        // Path, cluster chain and the cluster ".." should point to
+        let mut dirs = vec![(String::new(), root_chain, 0)];
+        while let Some((path, chain, parent_cluster)) = dirs.pop() {
+            self.check_dir_entries(check, &mut dirs, &path, &chain, parent_cluster)?;
+        }
+        Ok(())
+    }
+    /// Marks the cluster as the end of its chain, after a problem was found on the next one
+    fn cut_chain(&self, check: &mut FatCheck, last_cluster: u32, path: String) -> Result<(), FsWriteError> {
+        if check.repair {
+            self.set_fat_entries(&[(last_cluster, END_OF_CHAIN)])?;
+        }
+        check.report.add(FsckProblem::BadChain { cluster: last_cluster, path }, check.repair);
+        Ok(())
+    }
+    /// Checks the long names, the "." and ".." entries and the chain of every entry of a directory
+    /// Subdirectories are added to `dirs`
+    fn check_dir_entries(
+        &self,
+        check: &mut FatCheck,
+        dirs: &mut Vec<(String, Vec<u32>, u32)>,
+        path: &str,
+        chain: &[u32],
+        parent_cluster: u32,
+    ) -> Result<(), FsWriteError> {
+        let mut data = if chain.is_empty() {
+            self.read_dir_data(0)?.1
+        } else {
+            self.read_clusters(chain)?
+        };
+        let dir_cluster = chain.first().copied().unwrap_or(0);
+        let is_root = dir_cluster == self.fat_info.root_dir_cluster();
+        let mut changed = false;
+        if !is_root {
+            for (slot, name, cluster) in [(0, *b".          ", dir_cluster), (1, *b"..         ", parent_cluster)] {
+                let entry = unsafe { &*data[slot * 32..].as_ptr().cast::<Standard32>() }.clone();
+                if data[slot * 32..slot * 32 + 11] != name {
+                    check.report.add(FsckProblem::BadEntry { reason: "missing \".\" or \"..\" entry", path: path.to_string() }, false);
+                } else if entry.cluster() != cluster {
+                    let reason = if slot == 0 { "\".\" points to the wrong cluster" } else { "\"..\" points to the wrong cluster" };
+                    check.report.add(FsckProblem::BadEntry { reason, path: path.to_string() }, check.repair);
+                    let mut entry = entry;
+                    entry.set_cluster(cluster);
+                    data[slot * 32..(slot + 1) * 32].copy_from_slice(any_as_u8_slice(&entry));
+                    changed = true;
+                }
+            }
+        }
+        // First slot and checksum of the long name being read, and the order of its next part
+        let mut long_name: Option<(usize, u8, u8)> = None;
+        let mut name = String::new();
+        for slot in 0..data.len() / 32 {
+            // Safe unwrap, a slot is 32 bytes long
+            let raw: [u8; 32] = data[slot * 32..(slot + 1) * 32].try_into().unwrap();
+            if raw[0] == 0 {
+                break;
+            }
+            if raw[0] == DELETED_ENTRY || raw[11] == ATTR_LFN {
+                let (order, checksum) = (raw[0], raw[13]);
+                let part = unsafe { &*raw.as_ptr().cast::<LFN32>() }.name();
+                match &mut long_name {
+                    Some((_, sum, next)) if raw[0] != DELETED_ENTRY && order == *next && checksum == *sum => {
+                        *next -= 1;
+                        name.insert_str(0, &part);
                         continue;
                     }
-                    let nentry = nentry.unwrap();
-                    let next_name = nentry.name();
-                    if next_name.starts_with('.') || next_name.starts_with("..") {
-                        continue;
-                    } // Pass on "." and ".." folders
-                    let is_file = nentry.attributes & 0x20 == 0x20;
-                    let path = prefix.clone().join(FilePath::new(name, partition.clone()));
-
-                    let fst_cluster = (u32::from(nentry.high_u16_1st_cluster) << 16)
-                        | u32::from(nentry.low_u16_1st_cluster);
-                    if fst_cluster > 1_000_000 {
-                        serial_print!(
-                            "Cluster to big ! {} {}",
-                            fst_cluster,
-                            nentry.printable(first_data_sector)
-                        );
+                    _ => {}
+                }
+                if let Some((start, ..)) = long_name.take() {
+                    changed |= Self::orphan_long_name(check, &mut data, start..slot, &format!("{path}/{name}"));
+                }
+                if raw[0] == DELETED_ENTRY {
+                    continue;
+                }
+                if order & 0x40 != 0 && order & 0x1F != 0 {
+                    long_name = Some((slot, checksum, (order & 0x1F) - 1));
+                    name = part;
+                } else {
+                    changed |= Self::orphan_long_name(check, &mut data, slot..slot + 1, &format!("{path}/{part}"));
+                }
+                continue;
+            }
+            let mut entry = unsafe { &*raw.as_ptr().cast::<Standard32>() }.clone();
+            let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+            let mut first_slot = slot;
+            match long_name.take() {
+                Some((start, checksum, 0)) if checksum == lfn_checksum(&short_name) => first_slot = start,
+                Some((start, ..)) => {
+                    changed |= Self::orphan_long_name(check, &mut data, start..slot, &format!("{path}/{name}"));
+                    name = entry.short_name();
+                }
+                None => name = entry.short_name(),
+            }
+            if entry.attributes & ATTR_VOLUME_ID != 0 || name == "." || name == ".." {
+                continue;
+            }
+            let entry_path = format!("{path}/{name}");
+            if short_name[0] == b' ' || short_name.iter().enumerate().any(|(i, c)| *c < 0x20 && !(i == 0 && *c == 0x05)) {
+                check.report.add(FsckProblem::BadEntry { reason: "invalid short name", path: entry_path.clone() }, false);
+            }
+            let is_dir = entry.attributes & ATTR_DIRECTORY != 0;
+            let size = entry.size;
+            let cluster = entry.cluster();
+            let problem = if cluster == 0 {
+                if is_dir {
+                    Some(FsckProblem::BadEntry { reason: "directory without clusters", path: entry_path })
+                } else if size != 0 {
+                    Some(FsckProblem::BadSize { size: u64::from(size), clusters: 0, path: entry_path })
+                } else {
+                    None
+                }
+            } else if cluster == 1 || cluster >= self.cluster_end() {
+                Some(FsckProblem::BadEntry { reason: "invalid first cluster", path: entry_path })
+            } else if check.is_used(cluster) {
+                Some(FsckProblem::CrossLinked { cluster, path: entry_path })
+            } else {
+                let (mut entry_chain, stop) = self.check_chain(check, cluster)?;
+                // Safe unwrap, the chain isn't empty
+                let last = *entry_chain.last().unwrap();
+                match stop {
+                    Some(next) if (2..self.cluster_end()).contains(&next) && !entry_chain.contains(&next) => {
+                        if check.repair {
+                            self.set_fat_entries(&[(last, END_OF_CHAIN)])?;
+                        }
+                        check.report.add(FsckProblem::CrossLinked { cluster: next, path: entry_path.clone() }, check.repair);
                     }
-                    let sector = if fst_cluster > 2 {
-                        cluster_to_sector(u64::from(fst_cluster), first_data_sector)
-                    } else if fst_cluster == 0 {
-                        // File is empty
-                        0
-                    } else {
-                        log::error!("Cluster is too low ! ({})", fst_cluster);
-                        dbg!(path);
-                        i += 1;
-                        continue;
-                    } as u32;
-
-                    let parsed_entry = Fat32SoftEntry {
-                        path,
-                        sector: u64::from(sector),
-                        is_file,
-                    };
-                    files.push(parsed_entry);
-
-                    i += 1; // Skip next entry cuz it's related to the current LFN
+                    Some(_) => self.cut_chain(check, last, entry_path.clone())?,
+                    None => {}
+                }
+                if is_dir {
+                    // ".." points to cluster 0 when the parent is the root directory
+                    let dotdot_cluster = if is_root { 0 } else { dir_cluster };
+                    dirs.push((entry_path, entry_chain, dotdot_cluster));
+                    continue;
                 }
-                RawFat32Entry::Standard(file) => {
-                    // From my tests only "." and ".." folders
-                    //EDIT Also CACHEDIRTAG file
-                    if !file.name().starts_with('.') && !file.name().contains("CACHEDIRTAG") {
-                        log::debug!("What is this file ?"); // If this prints one day we need to do investigations
-                        dbg!(file);
+                let needed = u64::from(size).div_ceil(self.cluster_size() as u64) as usize;
+                if needed == entry_chain.len() {
+                    continue;
+                }
+                check.report.add(
+                    FsckProblem::BadSize { size: u64::from(size), clusters: entry_chain.len(), path: entry_path },
+                    check.repair,
+                );
+                if !check.repair {
+                    continue;
+                }
+                if needed > entry_chain.len() {
+                    entry.size = (entry_chain.len() * self.cluster_size()) as u32;
+                } else {
+                    // The clusters after the size are freed
+                    let extra = entry_chain.split_off(needed);
+                    let mut entries: Vec<(u32, u32)> = extra.iter().map(|cluster| (*cluster, 0)).collect();
+                    match entry_chain.last() {
+                        Some(last) => entries.push((*last, END_OF_CHAIN)),
+                        None => entry.set_cluster(0),
+                    }
+                    self.set_fat_entries(&entries)?;
+                    for cluster in extra {
+                        check.set_used(cluster, false);
                     }
                 }
+                data[slot * 32..(slot + 1) * 32].copy_from_slice(any_as_u8_slice(&entry));
+                changed = true;
+                continue;
+            };
+            let Some(problem) = problem else { continue };
+            check.report.add(problem, check.repair);
+            if is_dir {
+                for i in first_slot..=slot {
+                    data[i * 32] = DELETED_ENTRY;
+                }
+            } else {
+                // Files without a valid chain are emptied
+                entry.set_cluster(0);
+                entry.size = 0;
+                data[slot * 32..(slot + 1) * 32].copy_from_slice(any_as_u8_slice(&entry));
             }
+            changed = true;
         }
-        files
+        if changed && check.repair {
+            self.write_dir_data(chain, &data)?;
+        }
+        Ok(())
+    }
+    /// Deletes the long name entries that aren't followed by their 8.3 entry, returns true
+    fn orphan_long_name(check: &mut FatCheck, data: &mut [u8], slots: core::ops::Range<usize>, path: &str) -> bool {
+        check.report.add(
+            FsckProblem::BadEntry { reason: "long name without its 8.3 entry", path: path.to_string() },
+            check.repair,
+        );
+        for slot in slots {
+            data[slot * 32] = DELETED_ENTRY;
+        }
+        true
+    }
+    /// Allocated clusters that aren't used are freed, chains are reported from their first cluster
+    /// Returns the amount of free clusters
+    fn check_lost_chains(&mut self, check: &mut FatCheck) -> Result<u32, FsWriteError> {
+        let bad_cluster = BAD_CLUSTER & self.fat_info.fat_entry_mask();
+        let is_lost = |check: &FatCheck, cluster: u32, value: u32| {
+            value != 0 && value != bad_cluster && !check.is_used(cluster)
+        };
+        // Lost clusters that another lost cluster points to aren't the start of a chain
+        let mut pointed = vec![0u8; check.used.len()];
+        let mut free = 0;
+        let mut loaded = (u64::MAX, Vec::new());
+        for cluster in 2..self.cluster_end() {
+            let value = self.read_fat_entry(cluster, &mut loaded)?;
+            if value == 0 {
+                free += 1;
+            } else if is_lost(check, cluster, value) && (2..self.cluster_end()).contains(&value) {
+                pointed[value as usize / 8].set_bit(value as usize % 8, true);
+            }
+        }
+        // Then the chains that loop without a first cluster
+        for from_start in [true, false] {
+            let mut loaded = (u64::MAX, Vec::new());
+            for cluster in 2..self.cluster_end() {
+                let value = self.read_fat_entry(cluster, &mut loaded)?;
+                if is_lost(check, cluster, value) && (!from_start || !pointed[cluster as usize / 8].get_bit(cluster as usize % 8)) {
+                    let clusters = self.free_lost_chain(check, cluster)?;
+                    if check.repair {
+                        free += clusters;
+                    }
+                }
+            }
+        }
+        Ok(free)
+    }
+    /// Marks the lost chain as used so it's only reported once, and frees it when repairing
+    fn free_lost_chain(&mut self, check: &mut FatCheck, first_cluster: u32) -> Result<u32, FsWriteError> {
+        let mut loaded = (u64::MAX, Vec::new());
+        let mut freed = Vec::new();
+        let mut clusters = 0;
+        let mut cluster = first_cluster;
+        loop {
+            let next = self.read_fat_entry(cluster, &mut loaded)?;
+            check.set_used(cluster, true);
+            clusters += 1;
+            if check.repair {
+                freed.push((cluster, 0));
+                if freed.len() == FSCK_FREE_BATCH {
+                    self.set_fat_entries(&freed)?;
+                    freed.clear();
+                }
+            }
+            if !(2..self.cluster_end()).contains(&next) || check.is_used(next) {
+                break;
+            }
+            // The chain stops before free and bad clusters
+            let next_value = self.read_fat_entry(next, &mut loaded)?;
+            if next_value == 0 || next_value == BAD_CLUSTER & self.fat_info.fat_entry_mask() {
+                break;
+            }
+            cluster = next;
+        }
+        self.set_fat_entries(&freed)?;
+        check.report.add(FsckProblem::LostChain { first_cluster, clusters: clusters as usize }, check.repair);
+        Ok(clusters)
+    }
+    /// Compares the free cluster count of the FSInfo sector with the FAT
+    fn check_free_count(&mut self, check: &mut FatCheck, free: u32) -> Result<(), FsWriteError> {
+        let Some((_, content)) = self.fs_info()? else {
+            return Ok(());
+        };
+        let stored = read_u32(&content, 488);
+        // 0xFFFFFFFF means unknown
+        if stored == 0xFFFF_FFFF || stored == free {
+            return Ok(());
+        }
+        if check.repair {
+            self.update_fs_info(i64::from(free) - i64::from(stored), None)?;
+        }
+        check.report.add(FsckProblem::FreeCount { what: "free clusters", stored, counted: free }, check.repair);
+        Ok(())
+    }
+    /// The other copies of the FAT should be the same as the first one, which is the one we use
+    fn check_fat_copies(&self, check: &mut FatCheck) -> Result<(), FsWriteError> {
+        let extended_flags = self.fat_info.0.extended_flags;
+        // Bit 7 of the FAT32 flags disables the mirroring, only one FAT is used
+        if self.fat_info.fat_type() == FatType::Fat32 && extended_flags & 0x80 != 0 {
+            return Ok(());
+        }
+        let fat_size = u64::from(self.fat_info.get_fat_size());
+        let first_fat = u64::from(self.fat_info.first_fat_sector());
+        for copy in 1..self.fat_info.0.fats {
+            let copy_start = first_fat + u64::from(copy) * fat_size;
+            let mut sectors = 0;
+            for offset in (0..fat_size).step_by(FSCK_FAT_SECTORS_PER_READ) {
+                let count = (fat_size - offset).min(FSCK_FAT_SECTORS_PER_READ as u64);
+                let read = |sector: u64| {
+                    read_from_partition(&self.partition, sector, count).or(Err(FsWriteError::ReadingDiskError))
+                };
+                let first = read(first_fat + offset)?;
+                let other = read(copy_start + offset)?;
+                let differing = first
+                    .chunks(SECTOR_SIZE as usize)
+                    .zip(other.chunks(SECTOR_SIZE as usize))
+                    .filter(|(first, other)| first != other)
+                    .count();
+                if differing != 0 && check.repair {
+                    write_to_partition(&self.partition, copy_start + offset, &first)
+                        .or(Err(FsWriteError::WritingDiskError))?;
+                }
+                sectors += differing;
+            }
+            if sectors != 0 {
+                check.report.add(FsckProblem::FatCopyMismatch { copy, sectors }, check.repair);
+            }
+        }
+        Ok(())
     }
 }
+// Formatting
+impl Fat32Driver {
+    /// Writes an empty FAT32 filesystem on the partition, and returns its driver
+    /// FAT32 needs at least 65525 clusters, so partitions under about 32MiB are refused
+    pub fn format(partition: &Partition, label: &str) -> Result<Box<Self>, FsWriteError> {
+        let total_sectors = u32::try_from(partition.2).or(Err(FsWriteError::NotSupported))?;
+        // Default FAT32 cluster sizes of Windows
+        let sectors_per_cluster = match total_sectors {
+            0..=532_480 => 1,
+            532_481..=16_777_216 => 8,
+            16_777_217..=33_554_432 => 16,
+            33_554_433..=67_108_864 => 32,
+            _ => 64,
+        };
+        // https://academy.cba.mit.edu/classes/networking_communications/SD/FAT.pdf, page 21
+        let fat_size = (total_sectors.saturating_sub(FORMAT_RESERVED_SECTORS.into()))
+            .div_ceil((256 * sectors_per_cluster + u32::from(FORMAT_FATS)) / 2);
+        let first_data_sector = u32::from(FORMAT_RESERVED_SECTORS) + u32::from(FORMAT_FATS) * fat_size;
+        if total_sectors < first_data_sector + FORMAT_MIN_CLUSTERS * sectors_per_cluster {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        let clusters = (total_sectors - first_data_sector) / sectors_per_cluster;
+        let mut volume_label = [b' '; 11];
+        for (dst, src) in volume_label.iter_mut().zip(label.bytes()) {
+            *dst = src.to_ascii_uppercase();
+        }
+        let bpb = BiosParameterBlock {
+            bootjmp: [0xEB, 0x58, 0x90],
+            oem_name: *b"GLUOS   ",
+            bytes_per_sector: SECTOR_SIZE,
+            sectors_per_cluster: sectors_per_cluster as u8,
+            reserved_sectors: FORMAT_RESERVED_SECTORS,
+            fats: FORMAT_FATS,
+            media: FORMAT_MEDIA,
+            sectors_per_track: 32,
+            heads: 64,
+            hidden_sectors: partition.1 as u32,
+            total_sectors_32: total_sectors,
+            sectors_per_fat_32: fat_size,
+            root_dir_first_cluster: 2,
+            fs_info_sector: 1,
+            backup_boot_sector: FORMAT_BACKUP_BOOT_SECTOR,
+            drive_num: 0x80,
+            ext_sig: 0x29,
+            volume_id: crate::drivers::rand::rand() as u32,
+            volume_label,
+            fs_type_label: *b"FAT32   ",
+            ..Default::default()
+        };
+        let mut boot_sector = vec![0; SECTOR_SIZE as usize];
+        boot_sector[..core::mem::size_of::<BiosParameterBlock>()].copy_from_slice(any_as_u8_slice(&bpb));
+        boot_sector[510..].copy_from_slice(&[0x55, 0xAA]);
+        let mut fs_info = vec![0; SECTOR_SIZE as usize];
+        fs_info[0..4].copy_from_slice(&FSINFO_LEAD_SIGNATURE.to_le_bytes());
+        fs_info[484..488].copy_from_slice(&FSINFO_STRUCT_SIGNATURE.to_le_bytes());
+        // The root directory uses the first cluster
+        fs_info[488..492].copy_from_slice(&(clusters - 1).to_le_bytes());
+        fs_info[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fs_info[508..512].copy_from_slice(&FSINFO_TRAIL_SIGNATURE.to_le_bytes());
+
+        let write = |sector: u32, data: &[u8]| {
+            write_to_partition(partition, u64::from(sector), data).or(Err(FsWriteError::WritingDiskError))
+        };
+        let mut reserved = vec![0; usize::from(FORMAT_RESERVED_SECTORS) * SECTOR_SIZE as usize];
+        for start in [0, usize::from(FORMAT_BACKUP_BOOT_SECTOR)] {
+            let offset = start * SECTOR_SIZE as usize;
+            reserved[offset..offset + SECTOR_SIZE as usize].copy_from_slice(&boot_sector);
+            reserved[offset + SECTOR_SIZE as usize..offset + 2 * SECTOR_SIZE as usize].copy_from_slice(&fs_info);
+        }
+        write(0, &reserved)?;
+        let zeroes = vec![0; FORMAT_ZEROES_SECTORS * SECTOR_SIZE as usize];
+        for fat in 0..u32::from(FORMAT_FATS) {
+            let fat_start = u32::from(FORMAT_RESERVED_SECTORS) + fat * fat_size;
+            for sector in (0..fat_size).step_by(FORMAT_ZEROES_SECTORS) {
+                let len = (fat_size - sector).min(FORMAT_ZEROES_SECTORS as u32) as usize;
+                write(fat_start + sector, &zeroes[..len * SECTOR_SIZE as usize])?;
+            }
+            // Media descriptor, reserved entry and the root directory
+            let mut first_entries = vec![0; SECTOR_SIZE as usize];
+            first_entries[0..4].copy_from_slice(&(0x0FFF_FF00 | u32::from(FORMAT_MEDIA)).to_le_bytes());
+            first_entries[4..8].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+            first_entries[8..12].copy_from_slice(&END_OF_CHAIN.to_le_bytes());
+            write(fat_start, &first_entries)?;
+        }
+        write(first_data_sector, &zeroes[..(sectors_per_cluster * u32::from(SECTOR_SIZE)) as usize])?;
+        Self::try_init(partition).ok_or(FsWriteError::ReadingDiskError)
+    }
+}
+
 impl FsDriver for Fat32Driver {
     fn as_enum(&self) -> FsDriverEnum {
         FsDriverEnum::Fat32
@@ -392,7 +908,8 @@ impl FsDriver for Fat32Driver {
         let soft_entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
         let entry = match soft_entry.is_file {
             true => {
-                let content = self.read_file(path).unwrap(); // Safe unwrap cuz we know file exists from above
+                let mut content = self.read_file(path).unwrap(); // Safe unwrap cuz we know file exists from above
+                content.truncate(soft_entry.size as usize); // Last cluster is padded
                 let size = content.len();
                 Entry::File(File {
                     path: soft_entry.path.clone(),
@@ -419,8 +936,326 @@ impl FsDriver for Fat32Driver {
         };
         Ok(entry)
     }
-    fn partition(&self) -> &Partition {
-        &self.partition
+    fn partition(&self) -> Option<&Partition> {
+        Some(&self.partition)
+    }
+    /// FAT has no owners, read only entries don't have the write permissions
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        let soft_entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        let kind = if soft_entry.is_file {
+            EntryKind::File
+        } else {
+            EntryKind::Dir
+        };
+        let mut metadata = Metadata::new(kind, u64::from(soft_entry.size));
+        if path.name().is_empty() {
+            // The root directory doesn't have an entry
+            return Ok(metadata);
+        }
+        let (_, dir_data) = self
+            .parent_cluster(path)
+            .and_then(|cluster| self.read_dir_data(cluster))
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let entry = find_dir_slot(&dir_data, path.name())
+            .ok_or(FsReadError::EntryNotFound)?
+            .entry;
+        if FatAttributes(entry.attributes).read_only() {
+            metadata.mode &= !0o222;
+        }
+        metadata.created = entry.created();
+        metadata.modified = entry.modified();
+        metadata.accessed = entry.accessed();
+        Ok(metadata)
+    }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        Ok(u64::from(self.file_entry(path)?.size))
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let entry = self.file_entry(path)?;
+        let end = (offset + buf.len() as u64).min(u64::from(entry.size));
+        if offset >= end {
+            return Ok(0);
+        }
+        let cluster_size = self.cluster_size() as u64;
+        let first_index = (offset / cluster_size) as usize;
+        let last_index = end.div_ceil(cluster_size) as usize;
+        let chain = self
+            .cluster_chain_start(self.sector_cluster(entry.sector), last_index)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        if chain.len() < last_index {
+            log::error!("Cluster chain of {} is shorter than its size", path);
+            return Err(FsReadError::ParsingError);
+        }
+        let data = self
+            .read_clusters(&chain[first_index..])
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let start = (offset - first_index as u64 * cluster_size) as usize;
+        let read = (end - offset) as usize;
+        buf[..read].copy_from_slice(&data[start..start + read]);
+        Ok(read)
+    }
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        let entry = self.file_entry(path)?;
+        let old_size = u64::from(entry.size);
+        let old_first_cluster = if entry.sector == 0 {
+            0
+        } else {
+            self.sector_cluster(entry.sector)
+        };
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset + data.len() as u64;
+        let new_size: u32 = end
+            .max(old_size)
+            .try_into()
+            .or(Err(FsWriteError::NoSpaceLeft))?;
+        let cluster_size = self.cluster_size() as u64;
+        let mut chain = self.cluster_chain(old_first_cluster)?;
+        let needed = u64::from(new_size).div_ceil(cluster_size) as usize;
+        if needed > chain.len() {
+            let new_clusters = self.allocate_clusters(needed - chain.len())?;
+            if let Some(last) = chain.last() {
+                self.set_fat_entries(&[(*last, new_clusters[0])])?;
+            }
+            chain.extend(new_clusters);
+        }
+        // There are no holes in FAT, the clusters between the old end and offset are zeroed
+        let start = offset.min(old_size);
+        for index in (start / cluster_size) as usize..end.div_ceil(cluster_size) as usize {
+            let cluster_start = index as u64 * cluster_size;
+            let mut content = if cluster_start >= old_size {
+                vec![0; cluster_size as usize]
+            } else {
+                let mut content = self.read_clusters(&chain[index..=index])?;
+                // The end of the last cluster isn't part of the file, it can be garbage
+                if old_size < cluster_start + cluster_size {
+                    content[(old_size - cluster_start) as usize..].fill(0);
+                }
+                content
+            };
+            let copy_start = offset.max(cluster_start);
+            let copy_end = end.min(cluster_start + cluster_size);
+            if copy_start < copy_end {
+                content[(copy_start - cluster_start) as usize..(copy_end - cluster_start) as usize]
+                    .copy_from_slice(&data[(copy_start - offset) as usize..(copy_end - offset) as usize]);
+            }
+            self.write_clusters(&chain[index..=index], &content)?;
+        }
+
+        let first_cluster = chain[0];
+        let parent_cluster = self.parent_cluster(path)?;
+        let (dir_chain, mut dir_data) = self.read_dir_data(parent_cluster)?;
+        let slot = find_dir_slot(&dir_data, path.name()).ok_or(FsWriteError::EntryNotFound)?;
+        let mut dir_entry = slot.entry;
+        dir_entry.set_cluster(first_cluster);
+        dir_entry.size = new_size;
+        dir_entry.set_modified(unix_time());
+        dir_data[slot.short * 32..(slot.short + 1) * 32].copy_from_slice(any_as_u8_slice(&dir_entry));
+        self.write_dir_data(&dir_chain, &dir_data)?;
+        self.files.insert(
+            path.clone(),
+            Fat32SoftEntry {
+                path: path.clone(),
+                sector: self.cluster_sector(first_cluster),
+                is_file: true,
+                size: new_size,
+            },
+        );
+        Ok(data.len())
+    }
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        let name = filepath.name().to_string();
+        check_name(&name)?;
+        if self.files.get(filepath).is_some_and(|entry| !entry.is_file) {
+            return Err(FsWriteError::IsADir);
+        }
+        let parent_cluster = self.parent_cluster(filepath)?;
+        let size: u32 = content
+            .len()
+            .try_into()
+            .or(Err(FsWriteError::NoSpaceLeft))?;
+        let new_chain = if content.is_empty() {
+            Vec::new()
+        } else {
+            self.allocate_clusters(content.len().div_ceil(self.cluster_size()))?
+        };
+        self.write_clusters(&new_chain, content)?;
+        let first_cluster = new_chain.first().copied().unwrap_or(0);
+
+        let (chain, mut data) = self.read_dir_data(parent_cluster)?;
+        if let Some(slot) = find_dir_slot(&data, &name) {
+            // Overwrite, the old content is freed after the entry points to the new one
+            let mut entry = slot.entry;
+            let old_cluster = entry.cluster();
+            entry.set_cluster(first_cluster);
+            entry.size = size;
+            entry.set_modified(unix_time());
+            data[slot.short * 32..(slot.short + 1) * 32].copy_from_slice(any_as_u8_slice(&entry));
+            self.write_dir_data(&chain, &data)?;
+            if old_cluster >= 2 {
+                self.free_chain(old_cluster)?;
+            }
+        } else {
+            let entry = Standard32::new(ATTR_ARCHIVE, first_cluster, size);
+            if let Err(err) = self.insert_dir_entry(parent_cluster, &name, entry) {
+                if first_cluster != 0 {
+                    self.free_chain(first_cluster)?;
+                }
+                return Err(err);
+            }
+        }
+        let sector = if first_cluster == 0 {
+            0
+        } else {
+            self.cluster_sector(first_cluster)
+        };
+        self.files.insert(
+            filepath.clone(),
+            Fat32SoftEntry {
+                path: filepath.clone(),
+                sector,
+                is_file: true,
+                size,
+            },
+        );
+        Ok(())
+    }
+    fn create_dir(&mut self, dirpath: &FilePath) -> Result<(), FsWriteError> {
+        let name = dirpath.name().to_string();
+        check_name(&name)?;
+        if self.files.contains_key(dirpath) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        let parent_cluster = self.parent_cluster(dirpath)?;
+        let cluster = self.allocate_clusters(1)?[0];
+        // ".." points to cluster 0 when the parent is the root directory
+        let dotdot_cluster = if parent_cluster == self.fat_info.root_dir_cluster() {
+            0
+        } else {
+            parent_cluster
+        };
+        let mut dot = Standard32::new(ATTR_DIRECTORY, cluster, 0);
+        dot.name = *b".       ";
+        dot.extension = *b"   ";
+        let mut dotdot = Standard32::new(ATTR_DIRECTORY, dotdot_cluster, 0);
+        dotdot.name = *b"..      ";
+        dotdot.extension = *b"   ";
+        let mut data = vec![0; self.cluster_size()];
+        data[0..32].copy_from_slice(any_as_u8_slice(&dot));
+        data[32..64].copy_from_slice(any_as_u8_slice(&dotdot));
+        self.write_clusters(&[cluster], &data)?;
+
+        let entry = Standard32::new(ATTR_DIRECTORY, cluster, 0);
+        if let Err(err) = self.insert_dir_entry(parent_cluster, &name, entry) {
+            self.free_chain(cluster)?;
+            return Err(err);
+        }
+        self.files.insert(
+            dirpath.clone(),
+            Fat32SoftEntry {
+                path: dirpath.clone(),
+                sector: self.cluster_sector(cluster),
+                is_file: false,
+                size: 0,
+            },
+        );
+        Ok(())
+    }
+    fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        if path.name().is_empty() {
+            // Can't remove root
+            return Err(FsWriteError::InvalidName);
+        }
+        if !self.files.contains_key(path) {
+            return Err(FsWriteError::EntryNotFound);
+        }
+        let parent_cluster = self.parent_cluster(path)?;
+        let (chain, mut data) = self.read_dir_data(parent_cluster)?;
+        let slot = find_dir_slot(&data, path.name()).ok_or(FsWriteError::EntryNotFound)?;
+        let cluster = slot.entry.cluster();
+        if slot.entry.attributes & ATTR_DIRECTORY != 0 && cluster >= 2 {
+            let (_, dir_data) = self.read_dir_data(cluster)?;
+            if !dir_is_empty(&dir_data) {
+                return Err(FsWriteError::DirNotEmpty);
+            }
+        }
+        for i in slot.first..=slot.short {
+            data[i * 32] = DELETED_ENTRY;
+        }
+        self.write_dir_data(&chain, &data)?;
+        if cluster >= 2 {
+            self.free_chain(cluster)?;
+        }
+        self.files.remove(path);
+        Ok(())
+    }
+    fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        let new_name = to.name().to_string();
+        check_name(&new_name)?;
+        if from.name().is_empty() || to.path().starts_with(&format!("{}/", from.path())) {
+            // Root can't be moved, and a dir can't be moved in itself
+            return Err(FsWriteError::InvalidName);
+        }
+        if !self.files.contains_key(from) {
+            return Err(FsWriteError::EntryNotFound);
+        }
+        if self.files.contains_key(to) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        let old_parent = self.parent_cluster(from)?;
+        let new_parent = self.parent_cluster(to)?;
+        let (chain, mut data) = self.read_dir_data(old_parent)?;
+        let slot = find_dir_slot(&data, from.name()).ok_or(FsWriteError::EntryNotFound)?;
+        let original = data.clone();
+        for i in slot.first..=slot.short {
+            data[i * 32] = DELETED_ENTRY;
+        }
+        self.write_dir_data(&chain, &data)?;
+        if let Err(err) = self.insert_dir_entry(new_parent, &new_name, slot.entry.clone()) {
+            // Put back the old entry
+            self.write_dir_data(&chain, &original)?;
+            return Err(err);
+        }
+        let cluster = slot.entry.cluster();
+        if slot.entry.attributes & ATTR_DIRECTORY != 0 && old_parent != new_parent && cluster >= 2 {
+            // Update ".." of the moved directory
+            let dotdot_cluster = if new_parent == self.fat_info.root_dir_cluster() {
+                0
+            } else {
+                new_parent
+            };
+            let mut dir_data = self.read_clusters(&[cluster])?;
+            if let Some(raw) = dir_data
+                .chunks_exact_mut(32)
+                .take(2)
+                .find(|raw| raw[0..2] == *b"..")
+            {
+                let mut dotdot = unsafe { &*raw.as_ptr().cast::<Standard32>() }.clone();
+                dotdot.set_cluster(dotdot_cluster);
+                raw.copy_from_slice(any_as_u8_slice(&dotdot));
+            }
+            self.write_clusters(&[cluster], &dir_data)?;
+        }
+        // Move the entry and all of its children in the index
+        let old_prefix = format!("{}/", from.path());
+        let moved: Vec<FilePath> = self
+            .files
+            .keys()
+            .filter(|path| *path == from || path.path().starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        for old_path in moved {
+            // Safe unwrap, key comes from the map
+            let mut entry = self.files.remove(&old_path).unwrap();
+            let new_path = FilePath::new(
+                format!("{}{}", to.path(), &old_path.path()[from.path().len()..]),
+                self.partition.clone(),
+            );
+            entry.path = new_path.clone();
+            self.files.insert(new_path, entry);
+        }
+        Ok(())
     }
 }
 impl FsDriverInitialiser for Fat32Driver {
@@ -430,6 +1265,31 @@ impl FsDriverInitialiser for Fat32Driver {
     {
         Some(Box::new(Self::new(partition)?))
     }
+    fn try_open(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        let mut driver = Self::open(partition)?;
+        driver.start_indexing();
+        Some(Box::new(driver))
+    }
+    fn index_step(&mut self) -> Result<bool, FsReadError> {
+        let Some((path, sector)) = self.unindexed.pop() else {
+            return Ok(false);
+        };
+        match self.dir_entries(&path, sector) {
+            Some(entries) => {
+                for entry in entries {
+                    if !entry.is_file {
+                        self.unindexed.push((entry.path.clone(), entry.sector));
+                    }
+                    self.files.insert(entry.path.clone(), entry);
+                }
+            }
+            None => log::error!("Couldn't read directory {}", path),
+        }
+        Ok(!self.unindexed.is_empty())
+    }
 }
 
 #[derive(Debug)]
@@ -437,6 +1297,8 @@ pub struct Fat32SoftEntry {
     pub path: FilePath,
     pub sector: u64,
     pub is_file: bool,
+    /// In bytes, 0 for directories
+    pub size: u32,
 }
 
 #[derive(Debug, Clone)]
@@ -482,13 +1344,41 @@ impl Fat32Dir {
     // }
 }
 
-//TODO Mult by sectors_per_cluster
+/// Unix time of a FAT date and time, 0 if the date isn't set
+/// The date holds the day (bits 0-4), month (5-8) and years since 1980 (9-15)
+/// The time holds the seconds / 2 (bits 0-4), minutes (5-10) and hours (11-15)
+#[must_use] pub fn fat_time_to_unix(date: u16, time: u16) -> u64 {
+    if date == 0 {
+        return 0;
+    }
+    let days = days_from_civil(
+        1980 + u64::from(date >> 9),
+        u64::from((date >> 5) & 0xF),
+        u64::from(date & 0x1F),
+    );
+    days * 86400
+        + u64::from(time >> 11) * 3600
+        + u64::from((time >> 5) & 0x3F) * 60
+        + u64::from(time & 0x1F) * 2
+}
+/// FAT date and time of a Unix time, see `fat_time_to_unix`, times before 1980 are clamped
+#[must_use] pub fn unix_to_fat_time(time: u64) -> (u16, u16) {
+    if time < FAT_EPOCH {
+        return unix_to_fat_time(FAT_EPOCH);
+    }
+    let (year, month, day) = civil_from_days(time / 86400);
+    let seconds = time % 86400;
+    let date = ((year - 1980).min(127) << 9) | (month << 5) | day;
+    let time = ((seconds / 3600) << 11) | ((seconds % 3600 / 60) << 5) | (seconds % 60 / 2);
+    (date as u16, time as u16)
+}
+
 // All safely to u32
-#[must_use] pub fn cluster_to_sector(cluster_number: u64, first_data_sector: u64) -> u64 {
-    (cluster_number - 2) + first_data_sector
+#[must_use] pub fn cluster_to_sector(cluster_number: u64, first_data_sector: u64, sectors_per_cluster: u8) -> u64 {
+    (cluster_number - 2) * u64::from(sectors_per_cluster) + first_data_sector
 }
-#[must_use] pub fn sector_to_cluster(sector_number: u64, first_data_sector: u64) -> u64 {
-    (sector_number - first_data_sector) + 2
+#[must_use] pub fn sector_to_cluster(sector_number: u64, first_data_sector: u64, sectors_per_cluster: u8) -> u64 {
+    (sector_number - first_data_sector) / u64::from(sectors_per_cluster) + 2
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
@@ -525,6 +1415,7 @@ pub struct BiosParameterBlock {
     pub fs_type_label: [u8; 8],
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FatType {
     ExFat,
     Fat12,
@@ -534,10 +1425,37 @@ pub enum FatType {
 #[derive(Default, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct FatInfo(pub BiosParameterBlock);
 impl FatInfo {
-    #[must_use] pub fn first_sector_of_cluster(&self) -> u64 {
-        let first_data_sector = self.get_first_data_sector();
-        cluster_to_sector(u64::from(self.0.root_dir_first_cluster), first_data_sector)
-        // , self.0.sectors_per_cluster as u64
+    /// Checks the BPB fields shared by every FAT version, so other filesystems aren't taken for FAT
+    #[must_use] pub fn is_valid(&self) -> bool {
+        let bytes_per_sector = self.0.bytes_per_sector;
+        let root_dir_first_cluster = self.0.root_dir_first_cluster;
+        bytes_per_sector == SECTOR_SIZE
+            && self.0.sectors_per_cluster.is_power_of_two()
+            && self.0.reserved_sectors != 0
+            && self.0.fats != 0
+            && self.get_fat_size() != 0
+            && u64::from(self.get_total_sectors()) > self.get_first_data_sector()
+            && (self.fat_type() != FatType::Fat32 || root_dir_first_cluster >= 2)
+    }
+    /// First sector of the root directory, FAT12/16 have it in a fixed region right after the FATs
+    #[must_use] pub fn root_dir_sector(&self) -> u64 {
+        if self.fat_type() == FatType::Fat32 {
+            cluster_to_sector(
+                u64::from(self.0.root_dir_first_cluster),
+                self.get_first_data_sector(),
+                self.0.sectors_per_cluster,
+            )
+        } else {
+            u64::from(self.0.reserved_sectors) + u64::from(self.0.fats) * u64::from(self.get_fat_size())
+        }
+    }
+    /// Cluster of the root directory, 0 for the fixed root directory of FAT12/16
+    #[must_use] pub fn root_dir_cluster(&self) -> u32 {
+        if self.fat_type() == FatType::Fat32 {
+            self.0.root_dir_first_cluster
+        } else {
+            0
+        }
     }
     #[must_use] pub fn get_first_data_sector(&self) -> u64 {
         let fat_size = self.get_fat_size();
@@ -545,9 +1463,12 @@ impl FatInfo {
         let reserved_sector_count = self.0.reserved_sectors;
         u64::from(reserved_sector_count) + (u64::from(self.0.fats) * u64::from(fat_size)) + root_dir_sectors
     }
+    /// Like Linux, a BPB without a FAT16 size and root directory is FAT32 whatever its cluster count is
     #[must_use] pub fn fat_type(&self) -> FatType {
         let total_clusters = self.get_total_clusters();
-        if total_clusters < 4085 {
+        if self.0.sectors_per_fat_16 == 0 && self.0.root_entries == 0 {
+            FatType::Fat32
+        } else if total_clusters < 4085 {
             FatType::Fat12
         } else if total_clusters < 0xFFF5 {
             return FatType::Fat16
@@ -586,21 +1507,63 @@ impl FatInfo {
     #[must_use] pub fn first_fat_sector(&self) -> u16 {
         self.0.reserved_sectors
     }
-}
-
-#[derive(Debug, Default)]
-pub struct FatTable {
-    pub size: u32,
-    pub first_sector: u16,
-    pub last_sector: u16,
-    pub last_offset: u16, // u16 even though in range 0..512
-    pub last_used_sector: u32,
-}
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone, Copy)]
-pub enum ClusterEnum {
-    EndOfChain,
-    BadCluster,
-    Cluster(u32),
+    /// FAT entries are 12, 16 or 28 bits long (the 4 high bits of FAT32 entries are reserved)
+    #[must_use] pub fn fat_entry_mask(&self) -> u32 {
+        match self.fat_type() {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            _ => 0x0FFF_FFFF,
+        }
+    }
+    /// Offset in bytes of the entry of a cluster from the start of the FAT
+    #[must_use] pub fn fat_entry_offset(&self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self.fat_type() {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            _ => cluster * 4,
+        }
+    }
+    /// Sectors to read to get a whole FAT entry, FAT12 entries can be split across two sectors
+    #[must_use] pub fn fat_entry_sectors(&self) -> u64 {
+        if self.fat_type() == FatType::Fat12 {
+            2
+        } else {
+            1
+        }
+    }
+    /// Value of the entry of a cluster, which starts at `offset` in the FAT data
+    #[must_use] pub fn fat_value(&self, data: &[u8], offset: usize, cluster: u32) -> u32 {
+        let low = u32::from(u16::from_le_bytes([data[offset], data[offset + 1]]));
+        match self.fat_type() {
+            // Odd entries use the high nibble of their first byte
+            FatType::Fat12 if cluster % 2 == 1 => low >> 4,
+            FatType::Fat12 => low & 0xFFF,
+            FatType::Fat16 => low,
+            _ => read_u32(data, offset) & 0x0FFF_FFFF,
+        }
+    }
+    /// Sets the entry of a cluster in the FAT data, keeping the bits that belong to the neighbour or are reserved
+    pub fn set_fat_value(&self, data: &mut [u8], offset: usize, cluster: u32, value: u32) {
+        match self.fat_type() {
+            FatType::Fat12 => {
+                let old = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                let value = (value & 0xFFF) as u16;
+                let new = if cluster % 2 == 1 {
+                    (old & 0x000F) | (value << 4)
+                } else {
+                    (old & 0xF000) | value
+                };
+                data[offset..offset + 2].copy_from_slice(&new.to_le_bytes());
+            }
+            FatType::Fat16 => data[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes()),
+            _ => {
+                // The 4 high bits are reserved and must be kept
+                let value = (read_u32(data, offset) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    }
 }
 
 #[derive(Default, Clone)]
@@ -622,6 +1585,56 @@ pub struct Standard32 {
     pub size: u32,
 }
 impl Standard32 {
+    /// Name and extension fields are left blank, the times are set to now
+    #[must_use] pub fn new(attributes: u8, cluster: u32, size: u32) -> Self {
+        let mut entry = Self {
+            name: [b' '; 8],
+            extension: [b' '; 3],
+            attributes,
+            size,
+            ..Default::default()
+        };
+        entry.set_cluster(cluster);
+        let now = unix_time();
+        (entry.creation_date, entry.creation_time) = unix_to_fat_time(now);
+        entry.duration_creation_time = (now % 2 * 100) as u8;
+        entry.set_modified(now);
+        entry
+    }
+    #[must_use] pub fn created(&self) -> u64 {
+        // Hundredths of seconds, up to 199 for the odd seconds that the time can't hold
+        fat_time_to_unix(self.creation_date, self.creation_time)
+            + u64::from(self.duration_creation_time / 100)
+    }
+    #[must_use] pub fn modified(&self) -> u64 {
+        fat_time_to_unix(self.last_modif_date, self.last_modif_time)
+    }
+    /// Only the day is stored
+    #[must_use] pub fn accessed(&self) -> u64 {
+        fat_time_to_unix(self.last_accessed_date, 0)
+    }
+    /// Also sets the access date
+    pub fn set_modified(&mut self, time: u64) {
+        (self.last_modif_date, self.last_modif_time) = unix_to_fat_time(time);
+        self.last_accessed_date = self.last_modif_date;
+    }
+    #[must_use] pub fn cluster(&self) -> u32 {
+        (u32::from(self.high_u16_1st_cluster) << 16) | u32::from(self.low_u16_1st_cluster)
+    }
+    pub fn set_cluster(&mut self, cluster: u32) {
+        self.high_u16_1st_cluster = (cluster >> 16) as u16;
+        self.low_u16_1st_cluster = cluster as u16;
+    }
+    /// Name as it would be displayed (i.e. "HELLO.TXT")
+    #[must_use] pub fn short_name(&self) -> String {
+        let name = String::from_utf8_lossy(&self.name).trim_end().to_string();
+        let extension = String::from_utf8_lossy(&self.extension).trim_end().to_string();
+        if extension.is_empty() {
+            name
+        } else {
+            format!("{name}.{extension}")
+        }
+    }
     #[must_use] pub fn name(&self) -> String {
         return String::from_utf8_lossy(
             [self.name.to_vec(), self.extension.to_vec()]
@@ -682,7 +1695,7 @@ impl LFN32 {
 
         let mut name = String::new();
         for chr in raw_name {
-            if chr == 0 || chr == 255 {
+            if chr == 0 || chr == 0xFFFF {
                 continue;
             }
             name.push_str(String::from_utf16_lossy(&[chr]).to_string().as_str());
@@ -696,6 +1709,228 @@ impl core::fmt::Debug for LFN32 {
         return f.write_str(format!("LFN32({})", self.name()).as_str())
     }
 }
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LFN: u8 = 0x0F;
+pub const DELETED_ENTRY: u8 = 0xE5;
+/// 1980-01-01, the first date FAT can store
+const FAT_EPOCH: u64 = 315_532_800;
+pub const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+pub const BAD_CLUSTER: u32 = 0x0FFF_FFF7;
+pub const FSINFO_LEAD_SIGNATURE: u32 = 0x4161_5252;
+pub const FSINFO_STRUCT_SIGNATURE: u32 = 0x6141_7272;
+pub const FSINFO_TRAIL_SIGNATURE: u32 = 0xAA55_0000;
+const FORMAT_RESERVED_SECTORS: u16 = 32;
+const FORMAT_FATS: u8 = 2;
+const FORMAT_BACKUP_BOOT_SECTOR: u16 = 6;
+/// Fixed disk
+const FORMAT_MEDIA: u8 = 0xF8;
+/// Below this the spec says it's FAT16, whatever the BPB says, so formatting is refused
+const FORMAT_MIN_CLUSTERS: u32 = 65525;
+/// Zeroes written at once when clearing the FATs
+const FORMAT_ZEROES_SECTORS: usize = 128;
+/// FAT entries freed at once when repairing lost chains
+const FSCK_FREE_BATCH: usize = 512;
+/// Sectors of each FAT copy read at once when comparing them
+const FSCK_FAT_SECTORS_PER_READ: usize = 64;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+/// State of `Fat32Driver::check`
+struct FatCheck {
+    /// One bit per cluster, set for the clusters of the entries that were checked
+    used: Vec<u8>,
+    report: FsckReport,
+    repair: bool,
+}
+impl FatCheck {
+    fn is_used(&self, cluster: u32) -> bool {
+        self.used[cluster as usize / 8].get_bit(cluster as usize % 8)
+    }
+    fn set_used(&mut self, cluster: u32, used: bool) {
+        self.used[cluster as usize / 8].set_bit(cluster as usize % 8, used);
+    }
+}
+
+/// Place of an entry in the raw data of a directory, in 32 bytes slots
+#[derive(Debug, Clone)]
+struct DirSlot {
+    /// First LFN slot, or the 8.3 slot if the entry has no long name
+    first: usize,
+    short: usize,
+    entry: Standard32,
+}
+/// Looks for an entry by its long or 8.3 name, case insensitive like FAT is
+fn find_dir_slot(dir_data: &[u8], name: &str) -> Option<DirSlot> {
+    let mut lfn_start = None;
+    let mut lfn_name = String::new();
+    for (i, raw) in dir_data.chunks_exact(32).enumerate() {
+        if raw[0] == 0 {
+            break;
+        }
+        if raw[0] == DELETED_ENTRY {
+            lfn_start = None;
+            continue;
+        }
+        if raw[11] == ATTR_LFN {
+            let lfn = unsafe { &*raw.as_ptr().cast::<LFN32>() };
+            if lfn.order & 0x40 != 0 {
+                lfn_start = Some(i);
+                lfn_name.clear();
+            }
+            let mut part = lfn.name().replace('\u{ffff}', "");
+            part.push_str(&lfn_name);
+            lfn_name = part;
+            continue;
+        }
+        let entry = unsafe { &*raw.as_ptr().cast::<Standard32>() }.clone();
+        let long_match = lfn_start.is_some() && lfn_name.eq_ignore_ascii_case(name);
+        if long_match || entry.short_name().eq_ignore_ascii_case(name) {
+            return Some(DirSlot {
+                first: lfn_start.unwrap_or(i),
+                short: i,
+                entry,
+            });
+        }
+        lfn_start = None;
+    }
+    None
+}
+/// Index of the first slot of a run of `needed` free slots, the run can go past the end of the data
+fn free_slots_start(dir_data: &[u8], needed: usize) -> usize {
+    let mut run = 0;
+    for (i, raw) in dir_data.chunks_exact(32).enumerate() {
+        if raw[0] == 0 {
+            // Every slot after the end marker is free
+            return i - run;
+        }
+        if raw[0] == DELETED_ENTRY {
+            run += 1;
+            if run == needed {
+                return i + 1 - needed;
+            }
+        } else {
+            run = 0;
+        }
+    }
+    dir_data.len() / 32 - run
+}
+/// Only "." and ".." are left
+fn dir_is_empty(dir_data: &[u8]) -> bool {
+    dir_data
+        .chunks_exact(32)
+        .take_while(|raw| raw[0] != 0)
+        .all(|raw| raw[0] == DELETED_ENTRY || raw[0] == b'.')
+}
+fn check_name(name: &str) -> Result<(), FsWriteError> {
+    if name.is_empty()
+        || name == "."
+        || name == ".."
+        || name.encode_utf16().count() > 255
+        || name
+            .chars()
+            .any(|c| c.is_control() || "/\\:*?\"<>|".contains(c))
+    {
+        return Err(FsWriteError::InvalidName);
+    }
+    Ok(())
+}
+/// Creates a 8.3 name that isn't used in the directory yet (i.e. "LONGFI~1TXT")
+fn short_name_for(name: &str, dir_data: &[u8]) -> [u8; 11] {
+    let upper = name.to_uppercase();
+    let (base, ext) = match upper.rfind('.') {
+        Some(idx) if idx > 0 => (&upper[..idx], &upper[idx + 1..]),
+        _ => (upper.as_str(), ""),
+    };
+    let clean = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c) {
+                    c as u8
+                } else {
+                    b'_'
+                }
+            })
+            .collect()
+    };
+    let mut clean_base = clean(base);
+    let clean_ext = clean(ext);
+    let lossless = clean_base.len() <= 8
+        && clean_ext.len() <= 3
+        && clean_base == base.as_bytes()
+        && clean_ext == ext.as_bytes();
+    if clean_base.is_empty() {
+        clean_base.push(b'_');
+    }
+    let existing: Vec<&[u8]> = dir_data
+        .chunks_exact(32)
+        .take_while(|raw| raw[0] != 0)
+        .filter(|raw| raw[0] != DELETED_ENTRY && raw[11] != ATTR_LFN)
+        .map(|raw| &raw[..11])
+        .collect();
+    let mut short = [b' '; 11];
+    let ext_len = clean_ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&clean_ext[..ext_len]);
+    if lossless {
+        short[..clean_base.len()].copy_from_slice(&clean_base);
+        if !existing.contains(&&short[..]) {
+            return short;
+        }
+    }
+    for n in 1..1_000_000 {
+        let tail = format!("~{n}");
+        let base_len = clean_base.len().min(8 - tail.len());
+        short[..8].copy_from_slice(b"        ");
+        short[..base_len].copy_from_slice(&clean_base[..base_len]);
+        short[base_len..base_len + tail.len()].copy_from_slice(tail.as_bytes());
+        if !existing.contains(&&short[..]) {
+            break;
+        }
+    }
+    short
+}
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0_u8, |sum, c| {
+        ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(*c)
+    })
+}
+/// Splits the name in LFN entries, in the order they are stored on disk (last part first)
+fn lfn_entries(name: &str, checksum: u8) -> Vec<LFN32> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    if chars.len() % 13 != 0 {
+        chars.push(0);
+    }
+    while chars.len() % 13 != 0 {
+        chars.push(0xFFFF);
+    }
+    let count = chars.len() / 13;
+    let mut entries = Vec::with_capacity(count);
+    for (i, part) in chars.chunks_exact(13).enumerate().rev() {
+        let mut order = (i + 1) as u8;
+        if i + 1 == count {
+            order |= 0x40; // Last LFN entry
+        }
+        entries.push(LFN32 {
+            order,
+            fst_chars: [part[0], part[1], part[2], part[3], part[4]],
+            attribute: ATTR_LFN,
+            long_entry_type: 0,
+            chksum: checksum,
+            scd_chars: [part[5], part[6], part[7], part[8], part[9], part[10]],
+            zeroes: 0,
+            fin_chars: [part[11], part[12]],
+        });
+    }
+    entries
+}
+
 #[derive(Debug, Clone)]
 pub enum RawFat32Entry {
     LFN(LFN32),
@@ -774,3 +2009,35 @@ pub enum RawFat32Entry {
 //     }
 //     Ok(())
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-02-29 13:45:58
+    const LEAP_DAY: u64 = 1_709_214_358;
+    const LEAP_DAY_FAT: (u16, u16) = ((44 << 9) | (2 << 5) | 29, (13 << 11) | (45 << 5) | 29);
+
+    #[test_case]
+    fn fat_time_to_unix_time() {
+        assert_eq!(fat_time_to_unix((20 << 9) | (1 << 5) | 1, 0), 946_684_800);
+        assert_eq!(fat_time_to_unix(LEAP_DAY_FAT.0, LEAP_DAY_FAT.1), LEAP_DAY);
+        // Unset date
+        assert_eq!(fat_time_to_unix(0, LEAP_DAY_FAT.1), 0);
+    }
+
+    #[test_case]
+    fn unix_time_to_fat_time() {
+        assert_eq!(unix_to_fat_time(LEAP_DAY), LEAP_DAY_FAT);
+        // FAT times have a 2 seconds resolution
+        assert_eq!(unix_to_fat_time(LEAP_DAY + 1), LEAP_DAY_FAT);
+        assert_eq!(unix_to_fat_time(FAT_EPOCH), ((1 << 5) | 1, 0));
+    }
+
+    #[test_case]
+    fn times_before_1980_are_clamped() {
+        assert_eq!(unix_to_fat_time(0), unix_to_fat_time(FAT_EPOCH));
+        let (date, time) = unix_to_fat_time(0);
+        assert_eq!(fat_time_to_unix(date, time), FAT_EPOCH);
+    }
+}
@@ -37,11 +37,64 @@ pub trait FsDriver: core::fmt::Debug + FsDriverInitialiser {
             Entry::Dir(d) => Ok(d),
         }
     }
-    //TODO fn write(&self, path: &FilePath) -> Result<Entry, FsWriteError>;
-    //TODO fn write_file(&self,filepath: &FilePath) -> Result<FileEntry, FsWriteError>;
-    //TODO fn write_dir (&self, dirpath: &FilePath) -> Result<DirEntry,  FsWriteError>;
+    /// Size of the file in bytes
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        Ok(self.read_file(path)?.size as u64)
+    }
+    /// Reads the file starting at offset, returns the amount of bytes read (0 at the end of the file)
+    /// Drivers should only read the blocks that are needed, this default reads the whole file
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let file = self.read_file(path)?;
+        let start = (offset as usize).min(file.content.len());
+        let read = buf.len().min(file.content.len() - start);
+        buf[..read].copy_from_slice(&file.content[start..start + read]);
+        Ok(read)
+    }
+    /// Writes data at offset in an existing file, growing it if needed
+    /// Drivers should only write the blocks that are needed, this default rewrites the whole file
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        let mut content = self.read_file(path)?.content;
+        let end = offset as usize + data.len();
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset as usize..end].copy_from_slice(data);
+        self.write_file(path, &content)?;
+        Ok(data.len())
+    }
+    /// Returns the target if the entry is a symbolic link
+    fn read_link(&self, path: &FilePath) -> Option<String> {
+        None
+    }
+    /// Size, type, permissions, owner and times of an entry, without following symlinks
+    /// This default only knows the size and type, drivers that store more should override it
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        if let Some(target) = self.read_link(path) {
+            return Ok(Metadata::new(EntryKind::Symlink, target.len() as u64));
+        }
+        Ok(match self.read(path)? {
+            Entry::File(f) => Metadata::new(EntryKind::File, f.size as u64),
+            Entry::Dir(d) => Metadata::new(EntryKind::Dir, d.size as u64),
+        })
+    }
+    /// Creates the file if it doesn't exist, else replaces its content
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        Err(FsWriteError::NotSupported)
+    }
+    fn create_dir(&mut self, dirpath: &FilePath) -> Result<(), FsWriteError> {
+        Err(FsWriteError::NotSupported)
+    }
+    /// Removes a file or an empty directory
+    fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        Err(FsWriteError::NotSupported)
+    }
+    /// Moves an entry, both paths must be on the same partition
+    fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        Err(FsWriteError::NotSupported)
+    }
     fn as_enum(&self) -> FsDriverEnum;
-    fn partition(&self) -> &Partition;
+    /// None for filesystems that aren't on a disk
+    fn partition(&self) -> Option<&Partition>;
 }
 
 pub trait FsDriverInitialiser {
@@ -49,6 +102,18 @@ pub trait FsDriverInitialiser {
     fn try_init(partition: &Partition) -> Option<Box<Self>>
     where
         Self: Sized;
+    /// Same as `try_init`, but the directories are left to `index_step`
+    fn try_open(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        Self::try_init(partition)
+    }
+    /// Indexes the next directory of a driver from `try_open`, returns false once all of them are
+    /// So the init can let the other tasks run in between, as the drivers read the disks synchronously
+    fn index_step(&mut self) -> Result<bool, FsReadError> {
+        Ok(false)
+    }
     // fn index_disk(&mut self) {
     //     self.mut_files().extend(self.walk_dir("/"))
     // }
@@ -83,8 +148,13 @@ pub struct SoftEntry {
 //TODO Hold a driver Fat32(Fat32Driver)
 pub enum FsDriverEnum {
     Fat32,
+    ExFat,
     Ext,
     NTFS,
+    Iso9660,
+    Tmpfs,
+    Procfs,
+    Devfs,
     //NOT SUPPORTED
     BTRFS,
     TFS,
@@ -93,8 +163,13 @@ impl core::fmt::Display for FsDriverEnum {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         let repr = match self {
             Self::Fat32 => "Fat32",
+            Self::ExFat => "exFAT",
             Self::Ext => "Ext",
             Self::NTFS => "Ntfs",
+            Self::Iso9660 => "ISO 9660",
+            Self::Tmpfs => "tmpfs",
+            Self::Procfs => "procfs",
+            Self::Devfs => "devfs",
             Self::BTRFS => "Btrfs",
             Self::TFS => "Tfs (redox)",
         };
@@ -106,6 +181,97 @@ pub enum FsReadError {
     EntryNotFound,
     ReadingDiskError, //TODO This error should come from the ATA errors (see issue better error handling)
     ParsingError,
+    /// Seeking before the start of a file
+    InvalidSeek,
+}
+#[derive(Debug)]
+pub enum FsWriteError {
+    EntryNotFound,
+    ParentNotFound,
+    AlreadyExists,
+    NotADir,
+    IsADir,
+    DirNotEmpty,
+    InvalidName,
+    NoSpaceLeft,
+    CrossPartition,
+    ReadingDiskError,
+    WritingDiskError,
+    NotSupported,
+}
+impl From<FsReadError> for FsWriteError {
+    fn from(value: FsReadError) -> Self {
+        match value {
+            FsReadError::EntryNotFound => Self::EntryNotFound,
+            FsReadError::ReadingDiskError | FsReadError::ParsingError => Self::ReadingDiskError,
+            FsReadError::InvalidSeek => Self::NotSupported,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    BlockDevice,
+    CharDevice,
+}
+
+/// Infos about an entry returned by `FsDriver::stat`, what the filesystem doesn't store is left to 0
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    /// In bytes
+    pub size: u64,
+    pub kind: EntryKind,
+    /// Permission bits, without the type (i.e. 0o755)
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Unix times
+    pub created: u64,
+    pub modified: u64,
+    pub accessed: u64,
+    /// Amount of directory entries pointing to it
+    pub links: u32,
+}
+impl Metadata {
+    /// With the usual permissions (rwxr-xr-x for dirs, rw-r--r-- for the rest), owned by root
+    #[must_use] pub fn new(kind: EntryKind, size: u64) -> Self {
+        let mode = match kind {
+            EntryKind::Dir | EntryKind::Symlink => 0o755,
+            _ => 0o644,
+        };
+        Self {
+            size,
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            created: 0,
+            modified: 0,
+            accessed: 0,
+            links: 1,
+        }
+    }
+    /// Like the first column of ls -l, i.e. "drwxr-xr-x"
+    #[must_use] pub fn mode_string(&self) -> String {
+        let mut repr = String::with_capacity(10);
+        repr.push(match self.kind {
+            EntryKind::File => '-',
+            EntryKind::Dir => 'd',
+            EntryKind::Symlink => 'l',
+            EntryKind::BlockDevice => 'b',
+            EntryKind::CharDevice => 'c',
+        });
+        for shift in [6, 3, 0] {
+            let bits = (self.mode >> shift) & 0o7;
+            repr.push(if bits & 0o4 != 0 { 'r' } else { '-' });
+            repr.push(if bits & 0o2 != 0 { 'w' } else { '-' });
+            repr.push(if bits & 0o1 != 0 { 'x' } else { '-' });
+        }
+        repr
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,108 @@
+//! Offline consistency checks of FAT and ext2 filesystems, with an optional repair
+//! The partition is read again from the disk, so the driver bound to it isn't trusted
+//! The checks themselves walk the driver structures, see `Fat32Driver::check` and `ExtDriver::check`
+use alloc::{string::String, vec::Vec};
+
+use super::{ext::ExtDriver, fat::Fat32Driver, fs_driver::FsWriteError, partition::Partition};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsckProblem {
+    /// Clusters allocated in the FAT that no entry uses
+    LostChain { first_cluster: u32, clusters: usize },
+    /// The chain of an entry reaches a cluster that is already used, it's cut before it
+    CrossLinked { cluster: u32, path: String },
+    /// The chain of an entry goes to a free, reserved or out of range cluster, or loops
+    BadChain { cluster: u32, path: String },
+    /// The size of a file doesn't match the length of its cluster chain
+    BadSize { size: u64, clusters: usize, path: String },
+    /// A copy of the FAT differs from the first one, which is the one that is used
+    FatCopyMismatch { copy: u8, sectors: usize },
+    /// An entry that can't be used as it is, i.e. it points to a deleted inode
+    BadEntry { reason: &'static str, path: String },
+    /// Blocks used but marked as free in the bitmap, and unused blocks marked as used
+    BlockBitmap { group: usize, marked_free: usize, marked_used: usize },
+    InodeBitmap { group: usize, marked_free: usize, marked_used: usize },
+    /// Free blocks, free inodes or directories count of a block group
+    GroupCount { group: usize, what: &'static str, stored: u32, counted: u32 },
+    /// Free clusters, blocks or inodes count of the whole filesystem
+    FreeCount { what: &'static str, stored: u32, counted: u32 },
+    LinkCount { inode: u32, stored: u16, counted: u32 },
+    /// An inode in use that no directory links to, it's moved to /lost+found
+    OrphanedInode { inode: u32, is_dir: bool },
+    /// Inodes deleted while they were open, the list is cleared and they are freed
+    OrphanList { first_inode: u32 },
+    /// A block used twice, it can't be repaired
+    DuplicateBlock { block: u32, inode: u32 },
+    /// A block outside of the filesystem, it can't be repaired
+    BadBlock { block: u32, inode: u32 },
+}
+impl core::fmt::Display for FsckProblem {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Self::LostChain { first_cluster, clusters } => {
+                write!(f, "Lost chain of {clusters} clusters starting at cluster {first_cluster}")
+            }
+            Self::CrossLinked { cluster, path } => write!(f, "{path} is cross-linked on cluster {cluster}"),
+            Self::BadChain { cluster, path } => write!(f, "Cluster chain of {path} is broken after cluster {cluster}"),
+            Self::BadSize { size, clusters, path } => {
+                write!(f, "{path} has a size of {size} bytes but {clusters} clusters")
+            }
+            Self::FatCopyMismatch { copy, sectors } => {
+                write!(f, "FAT copy {copy} differs from the first one on {sectors} sectors")
+            }
+            Self::BadEntry { reason, path } => write!(f, "{path}: {reason}"),
+            Self::BlockBitmap { group, marked_free, marked_used } => write!(
+                f,
+                "Block bitmap of group {group}: {marked_free} used blocks marked free, {marked_used} free blocks marked used"
+            ),
+            Self::InodeBitmap { group, marked_free, marked_used } => write!(
+                f,
+                "Inode bitmap of group {group}: {marked_free} used inodes marked free, {marked_used} free inodes marked used"
+            ),
+            Self::GroupCount { group, what, stored, counted } => {
+                write!(f, "Group {group} has {stored} {what} instead of {counted}")
+            }
+            Self::FreeCount { what, stored, counted } => write!(f, "{stored} {what} instead of {counted}"),
+            Self::LinkCount { inode, stored, counted } => {
+                write!(f, "Inode {inode} has a link count of {stored} instead of {counted}")
+            }
+            Self::OrphanedInode { inode, is_dir } => {
+                let kind = if *is_dir { "Directory" } else { "File" };
+                write!(f, "{kind} inode {inode} isn't linked to any directory")
+            }
+            Self::OrphanList { first_inode } => write!(f, "Orphan inode list isn't empty (inode {first_inode})"),
+            Self::DuplicateBlock { block, inode } => write!(f, "Block {block} of inode {inode} is already used"),
+            Self::BadBlock { block, inode } => write!(f, "Block {block} of inode {inode} is outside of the filesystem"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct FsckReport {
+    /// Each problem found, and whether it was repaired
+    pub problems: Vec<(FsckProblem, bool)>,
+}
+impl FsckReport {
+    #[must_use] pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+    #[must_use] pub fn repaired(&self) -> usize {
+        self.problems.iter().filter(|(_, repaired)| *repaired).count()
+    }
+    pub(super) fn add(&mut self, problem: FsckProblem, repaired: bool) {
+        log::warn!("fsck: {}", problem);
+        self.problems.push((problem, repaired));
+    }
+}
+
+/// Checks the FAT or ext2 filesystem of the partition, and fixes what can be fixed if `repair` is set
+/// Other filesystems, and ext ones with features the driver can't write, aren't supported
+pub fn check(partition: &Partition, repair: bool) -> Result<FsckReport, FsWriteError> {
+    if let Some(mut driver) = Fat32Driver::open(partition) {
+        return driver.check(repair);
+    }
+    if let Some(mut driver) = ExtDriver::open(partition) {
+        return driver.check(repair);
+    }
+    Err(FsWriteError::NotSupported)
+}
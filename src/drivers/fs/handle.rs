@@ -0,0 +1,78 @@
+//! Open files, reading or writing only loads the blocks that are touched
+use crate::fs_driver;
+
+use super::{
+    fs_driver::{FsReadError, FsWriteError},
+    path::FilePath,
+};
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    End(i64),
+    Current(i64),
+}
+
+/// Returned by `FsDriverManager::open`
+#[derive(Debug)]
+pub struct FileHandle {
+    path: FilePath,
+    /// Where `read` and `write` start, in bytes
+    pos: u64,
+}
+impl FileHandle {
+    pub(super) fn new(path: FilePath) -> Self {
+        Self { path, pos: 0 }
+    }
+    #[must_use] pub fn path(&self) -> &FilePath {
+        &self.path
+    }
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> Result<u64, FsReadError> {
+        fs_driver!()
+            .drivers
+            .get(&self.path.volume)
+            .ok_or(FsReadError::EntryNotFound)?
+            .file_len(&self.path)
+    }
+    /// Returns the amount of bytes read, which is smaller than buf only at the end of the file
+    pub fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        fs_driver!()
+            .drivers
+            .get(&self.path.volume)
+            .ok_or(FsReadError::EntryNotFound)?
+            .read_at(&self.path, offset, buf)
+    }
+    /// Writing past the end of the file grows it, the gap is filled with zeroes
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        fs_driver!()
+            .drivers
+            .get_mut(&self.path.volume)
+            .ok_or(FsWriteError::EntryNotFound)?
+            .write_at(&self.path, offset, data)
+    }
+    /// Reads at the current position and moves it forward
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let read = self.read_at(self.pos, buf)?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+    /// Writes at the current position and moves it forward
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsWriteError> {
+        let written = self.write_at(self.pos, data)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+    /// Returns the new position, it can be past the end of the file but not before its start
+    /// Seeking before the start fails and leaves the position unchanged
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsReadError> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.len()?.checked_add_signed(offset).ok_or(FsReadError::InvalidSeek)?,
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset).ok_or(FsReadError::InvalidSeek)?,
+        };
+        Ok(self.pos)
+    }
+    /// Nothing is cached in the handle for now, so this only drops it
+    pub fn close(self) {}
+}
@@ -0,0 +1,425 @@
+//! Initial ramdisk, an archive bundled with the kernel that is unpacked in a tmpfs mounted as the root
+//! Supported formats: cpio "newc" (with or without checksums) and ustar (with GNU long names and pax paths)
+//! It's built from build/initramfs by `python3 disk_create.py initramfs`, and included with the `initramfs` feature
+use alloc::{
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+use super::{
+    fs_driver::{FsDriver, FsWriteError},
+    path::FilePath,
+    tmpfs::{TmpfsDriver, DEFAULT_MAX_SIZE},
+    vfs::{normalize, Volume},
+};
+
+#[cfg(feature = "initramfs")]
+pub static ARCHIVE: Option<&[u8]> = Some(include_bytes!("../../../build/initramfs.cpio"));
+#[cfg(not(feature = "initramfs"))]
+pub static ARCHIVE: Option<&[u8]> = None;
+
+const CPIO_MAGIC: &[u8] = b"070701";
+/// Same header, but the check field holds a sum of the bytes of the file
+const CPIO_CRC_MAGIC: &[u8] = b"070702";
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_TRAILER: &str = "TRAILER!!!";
+const TAR_BLOCK_SIZE: usize = 512;
+/// At offset 257 of the headers, followed by "\0" for POSIX ustar, or by " " for old GNU tar
+const TAR_MAGIC: &[u8] = b"ustar";
+
+/// Mode bits of cpio headers, see `man 7 inode`
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFLNK: u32 = 0o120_000;
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    UnknownFormat,
+    /// Offset of the header in the archive
+    InvalidHeader(usize),
+    /// An entry goes past the end of the archive
+    Truncated,
+    Write(String, FsWriteError),
+}
+
+#[derive(Debug, Clone)]
+enum EntryKind<'a> {
+    File(&'a [u8]),
+    Dir,
+    Symlink(String),
+    /// Path in the archive of the file it's a link to
+    HardLink(String),
+}
+
+#[derive(Debug, Clone)]
+struct ArchiveEntry<'a> {
+    /// Normalized absolute path
+    path: String,
+    kind: EntryKind<'a>,
+    /// Unix time
+    mtime: u64,
+}
+
+/// Bytes from start to start + len, or Truncated
+fn slice(data: &[u8], start: usize, len: usize) -> Result<&[u8], ArchiveError> {
+    data.get(start..start.checked_add(len).ok_or(ArchiveError::Truncated)?)
+        .ok_or(ArchiveError::Truncated)
+}
+
+/// Names are stored relative to the root of the archive, i.e. "./bin/sh" or "bin/sh"
+fn entry_path(name: &str, offset: usize) -> Result<String, ArchiveError> {
+    normalize(&format!("/{name}")).or(Err(ArchiveError::InvalidHeader(offset)))
+}
+
+fn parse_cpio(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let mut entries = Vec::new();
+    // Files with hard links only have their content in the last entry with their inode
+    let mut inodes: HashMap<u32, Vec<usize>> = HashMap::new();
+    let mut offset = 0;
+    loop {
+        let header = slice(data, offset, CPIO_HEADER_SIZE)?;
+        if !header.starts_with(CPIO_MAGIC) && !header.starts_with(CPIO_CRC_MAGIC) {
+            return Err(ArchiveError::InvalidHeader(offset));
+        }
+        // 13 fields of 8 hexadecimal digits after the magic
+        let field = |idx: usize| {
+            let raw = &header[6 + idx * 8..14 + idx * 8];
+            core::str::from_utf8(raw)
+                .ok()
+                .and_then(|raw| u32::from_str_radix(raw, 16).ok())
+                .ok_or(ArchiveError::InvalidHeader(offset))
+        };
+        let (inode, mode, links, mtime, size, name_size) =
+            (field(0)?, field(1)?, field(4)?, field(5)?, field(6)? as usize, field(11)? as usize);
+        let name = slice(data, offset + CPIO_HEADER_SIZE, name_size)?;
+        let name = core::str::from_utf8(name.strip_suffix(&[0]).unwrap_or(name))
+            .or(Err(ArchiveError::InvalidHeader(offset)))?;
+        if name == CPIO_TRAILER {
+            break;
+        }
+        // The name and the content are padded to 4 bytes
+        let content_start = (offset + CPIO_HEADER_SIZE + name_size).next_multiple_of(4);
+        let content = slice(data, content_start, size)?;
+        let kind = match mode & S_IFMT {
+            S_IFDIR => Some(EntryKind::Dir),
+            S_IFREG => Some(EntryKind::File(content)),
+            S_IFLNK => Some(EntryKind::Symlink(String::from_utf8_lossy(content).to_string())),
+            _ => {
+                log::warn!("Skipping special file {} in the initramfs", name);
+                None
+            }
+        };
+        if let Some(kind) = kind {
+            if links > 1 && matches!(kind, EntryKind::File(_)) {
+                inodes.entry(inode).or_default().push(entries.len());
+            }
+            entries.push(ArchiveEntry {
+                path: entry_path(name, offset)?,
+                kind,
+                mtime: u64::from(mtime),
+            });
+        }
+        offset = (content_start + size).next_multiple_of(4);
+    }
+    for idxs in inodes.values() {
+        let Some(&content_idx) = idxs
+            .iter()
+            .find(|&&idx| matches!(entries[idx].kind, EntryKind::File(content) if !content.is_empty()))
+        else {
+            continue;
+        };
+        let target = entries[content_idx].path.clone();
+        for &idx in idxs {
+            if idx != content_idx {
+                entries[idx].kind = EntryKind::HardLink(target.clone());
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Reads a NUL terminated (or not if it fills the field) string field of a tar header
+fn tar_str(raw: &[u8]) -> &str {
+    let len = raw.iter().position(|&byte| byte == 0).unwrap_or(raw.len());
+    core::str::from_utf8(&raw[..len]).unwrap_or("")
+}
+
+fn tar_octal(raw: &[u8], offset: usize) -> Result<u64, ArchiveError> {
+    let raw = tar_str(raw).trim_matches(' ');
+    if raw.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(raw, 8).or(Err(ArchiveError::InvalidHeader(offset)))
+}
+
+/// Records of pax extended headers are "[len] [key]=[value]\n"
+fn pax_records(content: &[u8]) -> HashMap<String, String> {
+    let mut records = HashMap::new();
+    let mut rest = content;
+    while let Some(space) = rest.iter().position(|&byte| byte == b' ') {
+        let Some(len) = core::str::from_utf8(&rest[..space])
+            .ok()
+            .and_then(|len| len.parse::<usize>().ok())
+            .filter(|&len| len > space && len <= rest.len())
+        else {
+            break;
+        };
+        let record = String::from_utf8_lossy(&rest[space + 1..len]);
+        if let Some((key, value)) = record.trim_end_matches('\n').split_once('=') {
+            records.insert(key.to_string(), value.to_string());
+        }
+        rest = &rest[len..];
+    }
+    records
+}
+
+fn parse_tar(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    let mut entries = Vec::new();
+    // Set by GNU long name ('L', 'K') and pax ('x') entries for the next entry
+    let mut next_path = None;
+    let mut next_link = None;
+    let mut offset = 0;
+    while offset + TAR_BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + TAR_BLOCK_SIZE];
+        if header.iter().all(|&byte| byte == 0) {
+            // End of archive
+            break;
+        }
+        if !header[257..].starts_with(TAR_MAGIC) {
+            return Err(ArchiveError::InvalidHeader(offset));
+        }
+        // Sum of the bytes of the header, with the checksum field counted as spaces
+        let checksum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(idx, &byte)| if (148..156).contains(&idx) { u64::from(b' ') } else { u64::from(byte) })
+            .sum();
+        if checksum != tar_octal(&header[148..156], offset)? {
+            return Err(ArchiveError::InvalidHeader(offset));
+        }
+        let size = tar_octal(&header[124..136], offset)? as usize;
+        let mtime = tar_octal(&header[136..148], offset)?;
+        let content = slice(data, offset + TAR_BLOCK_SIZE, size)?;
+        let mut name = tar_str(&header[0..100]).to_string();
+        // Only POSIX ustar has a prefix, old GNU tar stores other things there
+        let prefix = tar_str(&header[345..500]);
+        if header[257..263] == *b"ustar\0" && !prefix.is_empty() {
+            name = format!("{prefix}/{name}");
+        }
+        let link = tar_str(&header[157..257]).to_string();
+        let type_flag = header[156];
+        offset += TAR_BLOCK_SIZE + size.next_multiple_of(TAR_BLOCK_SIZE);
+        let kind = match type_flag {
+            b'L' => {
+                next_path = Some(tar_str(content).to_string());
+                continue;
+            }
+            b'K' => {
+                next_link = Some(tar_str(content).to_string());
+                continue;
+            }
+            b'x' => {
+                let mut records = pax_records(content);
+                next_path = records.remove("path").or(next_path);
+                next_link = records.remove("linkpath").or(next_link);
+                continue;
+            }
+            b'g' => continue,
+            b'0' | b'\0' | b'7' => EntryKind::File(content),
+            b'5' => EntryKind::Dir,
+            b'2' => EntryKind::Symlink(next_link.take().unwrap_or(link)),
+            b'1' => EntryKind::HardLink(entry_path(&next_link.take().unwrap_or(link), offset)?),
+            _ => {
+                log::warn!("Skipping special file {} in the initramfs", name);
+                next_path = None;
+                next_link = None;
+                continue;
+            }
+        };
+        let name = next_path.take().unwrap_or(name);
+        entries.push(ArchiveEntry {
+            path: entry_path(&name, offset)?,
+            kind,
+            mtime,
+        });
+    }
+    Ok(entries)
+}
+
+fn parse(data: &[u8]) -> Result<Vec<ArchiveEntry>, ArchiveError> {
+    if data.starts_with(CPIO_MAGIC) || data.starts_with(CPIO_CRC_MAGIC) {
+        parse_cpio(data)
+    } else if data.len() >= TAR_BLOCK_SIZE && data[257..].starts_with(TAR_MAGIC) {
+        parse_tar(data)
+    } else {
+        Err(ArchiveError::UnknownFormat)
+    }
+}
+
+/// Creates the dirs leading to path that aren't in the archive
+fn create_parents(driver: &mut TmpfsDriver, path: &FilePath) -> Result<(), FsWriteError> {
+    let parent = path.parent();
+    if parent.name().is_empty() || driver.entry(&parent).is_some() {
+        return Ok(());
+    }
+    create_parents(driver, &parent)?;
+    driver.create_dir(&parent)
+}
+
+/// Unpacks a cpio or tar archive in a new tmpfs, which has `DEFAULT_MAX_SIZE` bytes free after the content of the archive
+pub fn unpack(data: &[u8], volume: Volume) -> Result<TmpfsDriver, ArchiveError> {
+    let entries = parse(data)?;
+    let mut driver = TmpfsDriver::new(volume.clone(), usize::MAX);
+    // In cpio archives, the content of hard links can be after them
+    let (links, others): (Vec<&ArchiveEntry>, Vec<&ArchiveEntry>) = entries
+        .iter()
+        .partition(|entry| matches!(entry.kind, EntryKind::HardLink(_)));
+    for entry in others.into_iter().chain(links) {
+        if entry.path == "/" {
+            continue;
+        }
+        let path = FilePath::new(entry.path.clone(), volume.clone());
+        let result = create_parents(&mut driver, &path).and_then(|()| match &entry.kind {
+            EntryKind::File(content) => driver.write_file(&path, content),
+            EntryKind::Dir => match driver.create_dir(&path) {
+                // The dir was created for a file inside of it
+                Err(FsWriteError::AlreadyExists) => Ok(()),
+                result => result,
+            },
+            EntryKind::HardLink(target) => {
+                let target = FilePath::new(target.clone(), volume.clone());
+                let mut content = alloc::vec![0; driver.file_len(&target)? as usize];
+                driver.read_at(&target, 0, &mut content)?;
+                driver.write_file(&path, &content)
+            }
+            EntryKind::Symlink(target) => {
+                log::warn!("Skipping symlink {} -> {} in the initramfs", entry.path, target);
+                Ok(())
+            }
+        });
+        result.map_err(|err| ArchiveError::Write(entry.path.clone(), err))?;
+    }
+    // Parents are modified while their content is unpacked, so times are set at the end
+    for entry in &entries {
+        driver.set_modified(&FilePath::new(entry.path.clone(), volume.clone()), entry.mtime);
+    }
+    driver.set_max_size(driver.usage().0 + DEFAULT_MAX_SIZE);
+    Ok(driver)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+
+    const MTIME: u32 = 1_700_000_000;
+
+    /// Newc header and content of an entry, padded to 4 bytes
+    fn cpio_entry(inode: u32, mode: u32, links: u32, name: &str, content: &[u8]) -> Vec<u8> {
+        let fields = [inode, mode, 0, 0, links, MTIME, content.len() as u32, 0, 0, 0, 0, name.len() as u32 + 1, 0];
+        let mut entry = CPIO_MAGIC.to_vec();
+        for field in fields {
+            entry.extend_from_slice(format!("{field:08x}").as_bytes());
+        }
+        entry.extend_from_slice(name.as_bytes());
+        entry.push(0);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry.extend_from_slice(content);
+        entry.resize(entry.len().next_multiple_of(4), 0);
+        entry
+    }
+    fn cpio(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.extend(cpio_entry(0, 0, 1, CPIO_TRAILER, &[]));
+        archive
+    }
+    /// Ustar header and content of an entry, padded to the block size
+    fn tar_entry(name: &str, type_flag: u8, link: &str, content: &[u8]) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", content.len()).as_bytes());
+        header[136..147].copy_from_slice(format!("{MTIME:011o}").as_bytes());
+        header[156] = type_flag;
+        header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[148..156].fill(b' ');
+        let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+        header[148..155].copy_from_slice(format!("{checksum:06o}\0").as_bytes());
+        header.extend_from_slice(content);
+        header.resize(header.len().next_multiple_of(TAR_BLOCK_SIZE), 0);
+        header
+    }
+    fn tar(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+        archive.resize(archive.len() + 2 * TAR_BLOCK_SIZE, 0);
+        archive
+    }
+    fn content(driver: &TmpfsDriver, path: &str) -> Vec<u8> {
+        driver
+            .read_file(&FilePath::new(path.to_string(), Volume::Memory(0)))
+            .unwrap()
+            .content
+    }
+
+    #[test_case]
+    fn cpio_is_unpacked() {
+        let archive = cpio(&[
+            cpio_entry(1, S_IFDIR | 0o755, 2, ".", &[]),
+            cpio_entry(2, S_IFDIR | 0o755, 2, "bin", &[]),
+            cpio_entry(3, S_IFREG | 0o755, 1, "bin/sh", b"echo"),
+            // The parent dir isn't in the archive
+            cpio_entry(4, S_IFREG | 0o644, 1, "./etc/motd", b"hello"),
+        ]);
+        let driver = unpack(&archive, Volume::Memory(0)).unwrap();
+        assert_eq!(content(&driver, "/bin/sh"), b"echo");
+        assert_eq!(content(&driver, "/etc/motd"), b"hello");
+        let sh = driver.stat(&FilePath::new("/bin/sh".to_string(), Volume::Memory(0))).unwrap();
+        assert_eq!(sh.modified, u64::from(MTIME));
+        assert_eq!(driver.usage(), (9, 9 + DEFAULT_MAX_SIZE));
+    }
+
+    #[test_case]
+    fn cpio_hard_links_share_the_last_content() {
+        let archive = cpio(&[
+            cpio_entry(5, S_IFREG | 0o644, 2, "a", &[]),
+            cpio_entry(5, S_IFREG | 0o644, 2, "b", b"data"),
+        ]);
+        let driver = unpack(&archive, Volume::Memory(0)).unwrap();
+        assert_eq!(content(&driver, "/a"), b"data");
+        assert_eq!(content(&driver, "/b"), b"data");
+    }
+
+    #[test_case]
+    fn tar_is_unpacked() {
+        let long_name = format!("dir/{}", "n".repeat(120));
+        let archive = tar(&[
+            tar_entry("dir/", b'5', "", &[]),
+            tar_entry("dir/file", b'0', "", b"content"),
+            tar_entry("././@LongLink", b'L', "", format!("{long_name}\0").as_bytes()),
+            tar_entry("dir/truncated", b'0', "", b"long"),
+            tar_entry("pax", b'x', "", b"21 path=dir/from_pax\n"),
+            tar_entry("dir/ignored", b'0', "", b"pax"),
+            tar_entry("link", b'1', "dir/file", &[]),
+        ]);
+        let driver = unpack(&archive, Volume::Memory(0)).unwrap();
+        assert_eq!(content(&driver, "/dir/file"), b"content");
+        assert_eq!(content(&driver, &format!("/{long_name}")), b"long");
+        assert_eq!(content(&driver, "/dir/from_pax"), b"pax");
+        assert_eq!(content(&driver, "/link"), b"content");
+    }
+
+    #[test_case]
+    fn broken_archives_are_refused() {
+        assert!(matches!(unpack(&[0; 1024], Volume::Memory(0)), Err(ArchiveError::UnknownFormat)));
+        let mut archive = tar(&[tar_entry("file", b'0', "", b"content")]);
+        archive[0] = b'F';
+        assert!(matches!(unpack(&archive, Volume::Memory(0)), Err(ArchiveError::InvalidHeader(0))));
+        let archive = cpio(&[cpio_entry(1, S_IFREG | 0o644, 1, "file", b"content")]);
+        assert!(matches!(unpack(&archive[..120], Volume::Memory(0)), Err(ArchiveError::Truncated)));
+    }
+}
@@ -0,0 +1,676 @@
+use alloc::{
+    boxed::Box,
+    string::{String, ToString},
+    vec,
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+use crate::{
+    disk::driver::{read_from_partition, SECTOR_SIZE},
+    time::days_from_civil,
+};
+
+use super::{
+    fs_driver::{
+        Dir, Entry, EntryKind, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError,
+        Metadata, SoftEntry,
+    },
+    partition::Partition,
+    path::FilePath,
+};
+
+/// Start of a primary or supplementary volume descriptor
+/// Numbers stored in both endians are split in two fields, only the little endian one is used
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct VolumeDescriptor {
+    pub kind: u8,
+    pub identifier: [u8; 5],
+    pub version: u8,
+    /// Only in supplementary descriptors, bit 0 set means the escape sequences aren't registered
+    pub volume_flags: u8,
+    pub system_identifier: [u8; 32],
+    pub volume_identifier: [u8; 32],
+    pub unused: [u8; 8],
+    /// In logical blocks
+    pub volume_space_size: u32,
+    pub volume_space_size_be: u32,
+    /// Only in supplementary descriptors, tells which Joliet level is used
+    pub escape_sequences: [u8; 32],
+    pub volume_set_size: u16,
+    pub volume_set_size_be: u16,
+    pub volume_sequence_number: u16,
+    pub volume_sequence_number_be: u16,
+    pub logical_block_size: u16,
+    pub logical_block_size_be: u16,
+    /// In bytes
+    pub path_table_size: u32,
+    pub path_table_size_be: u32,
+    /// Little endian path table
+    pub l_path_table: u32,
+    pub optional_l_path_table: u32,
+    /// Big endian path table
+    pub m_path_table: u32,
+    pub optional_m_path_table: u32,
+    pub root_record: [u8; 34],
+}
+
+/// Fixed part of a directory record, followed by the name and the system use area
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct DirRecordHeader {
+    pub length: u8,
+    /// In logical blocks, they come before the data of the extent
+    pub ext_attr_length: u8,
+    pub extent: u32,
+    pub extent_be: u32,
+    pub data_length: u32,
+    pub data_length_be: u32,
+    pub date: [u8; 7],
+    pub flags: u8,
+    /// Interleaved files aren't supported
+    pub file_unit_size: u8,
+    pub interleave_gap: u8,
+    pub volume_sequence_number: u16,
+    pub volume_sequence_number_be: u16,
+    pub name_length: u8,
+}
+
+/// How the names of the directory records are encoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameEncoding {
+    /// Upper case d-characters followed by a version, i.e. "README.TXT;1"
+    Iso,
+    /// UCS-2 big endian, from a Joliet supplementary descriptor
+    Joliet,
+    /// Names from the NM entries of the system use area, the ISO ones are used if there is none
+    /// Holds how many bytes of the system use area are skipped, from the SP entry
+    RockRidge(u8),
+}
+
+/// POSIX attributes of a Rock Ridge PX entry
+#[derive(Debug, Clone, Copy)]
+pub struct PosixAttributes {
+    /// Type and permissions, like `st_mode`
+    pub mode: u32,
+    pub links: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+impl PosixAttributes {
+    #[must_use] pub fn is_symlink(&self) -> bool {
+        self.mode & S_IFMT == S_IFLNK
+    }
+}
+
+/// What the Rock Ridge entries of a record say about it
+#[derive(Debug, Default)]
+struct RockRidge {
+    name: Option<String>,
+    attributes: Option<PosixAttributes>,
+    symlink: Option<String>,
+    /// CL entry, the record stands for a directory relocated at this block
+    child_link: Option<u32>,
+    /// RE entry, the directory was relocated and is listed through its CL record
+    relocated: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct IsoSoftEntry {
+    pub path: FilePath,
+    pub is_file: bool,
+    /// First block and length in bytes of each extent, files over 4GiB have several
+    pub extents: Vec<(u32, u32)>,
+    /// In bytes
+    pub size: u64,
+    /// Only with Rock Ridge
+    pub attributes: Option<PosixAttributes>,
+    /// Target of Rock Ridge symbolic links
+    pub symlink: Option<String>,
+    /// Unix time, from the date of the directory record
+    pub recorded: u64,
+}
+
+/// Read only driver for CD images
+/// Logical blocks are usually 2048 bytes, they're read as disk sectors of `SECTOR_SIZE`
+/// <https://wiki.osdev.org/ISO_9660>
+#[derive(Debug)]
+pub struct Iso9660Driver {
+    partition: Partition,
+    /// The primary descriptor, or the Joliet one if the names are read from it
+    descriptor: VolumeDescriptor,
+    encoding: NameEncoding,
+    files: HashMap<FilePath, IsoSoftEntry>,
+}
+impl Iso9660Driver {
+    #[must_use] pub fn new(partition: &Partition) -> Option<Self> {
+        let (primary, joliet) = Self::read_descriptors(partition)?;
+        let mut driver = Self {
+            partition: partition.clone(),
+            descriptor: primary,
+            encoding: NameEncoding::Iso,
+            files: HashMap::new(),
+        };
+        let block_size = u64::from(primary.logical_block_size);
+        if block_size < u64::from(SECTOR_SIZE) || block_size % u64::from(SECTOR_SIZE) != 0 {
+            log::error!("Unsupported ISO 9660 block size {} on {:?}", block_size, partition);
+            return None;
+        }
+        // Rock Ridge extends the primary tree, it's preferred since it also has POSIX attributes
+        let root = driver.root_entry();
+        if let Some(skip) = driver.rock_ridge_skip(&root) {
+            driver.encoding = NameEncoding::RockRidge(skip);
+        } else if let Some(joliet) = joliet {
+            driver.descriptor = joliet;
+            driver.encoding = NameEncoding::Joliet;
+        }
+        driver.index();
+        Some(driver)
+    }
+    /// Finds the primary descriptor, and the Joliet one if there is one
+    fn read_descriptors(partition: &Partition) -> Option<(VolumeDescriptor, Option<VolumeDescriptor>)> {
+        let sectors_per_descriptor = DESCRIPTOR_SIZE / u64::from(SECTOR_SIZE);
+        let mut primary = None;
+        let mut joliet = None;
+        for idx in 0..MAX_VOLUME_DESCRIPTORS {
+            let sector = (FIRST_DESCRIPTOR + idx) * sectors_per_descriptor;
+            if sector + sectors_per_descriptor > partition.2 {
+                return None;
+            }
+            let raw = read_from_partition(partition, sector, sectors_per_descriptor).ok()?;
+            let descriptor: VolumeDescriptor =
+                bytemuck::pod_read_unaligned(&raw[..core::mem::size_of::<VolumeDescriptor>()]);
+            if descriptor.identifier != *ISO_IDENTIFIER {
+                return None;
+            }
+            match descriptor.kind {
+                DESCRIPTOR_PRIMARY if primary.is_none() => primary = Some(descriptor),
+                DESCRIPTOR_SUPPLEMENTARY
+                    if descriptor.volume_flags & 1 == 0
+                        && JOLIET_ESCAPE_SEQUENCES
+                            .iter()
+                            .any(|seq| descriptor.escape_sequences.starts_with(*seq)) =>
+                {
+                    joliet = Some(descriptor);
+                }
+                DESCRIPTOR_TERMINATOR => break,
+                _ => {}
+            }
+        }
+        Some((primary?, joliet))
+    }
+    #[must_use] pub fn block_size(&self) -> u64 {
+        u64::from(self.descriptor.logical_block_size)
+    }
+    /// Name of the volume, without the padding
+    #[must_use] pub fn label(&self) -> String {
+        let raw = self.descriptor.volume_identifier;
+        let label = if self.encoding == NameEncoding::Joliet {
+            decode_ucs2(&raw)
+        } else {
+            String::from_utf8_lossy(&raw).to_string()
+        };
+        label.trim_end_matches([' ', '\0']).to_string()
+    }
+    /// Reads bytes from the partition, starting at a block
+    fn read_bytes(&self, block: u64, offset: u64, len: u64) -> Result<Vec<u8>, FsReadError> {
+        let sector_size = u64::from(SECTOR_SIZE);
+        let start = block * self.block_size() + offset;
+        let first_sector = start / sector_size;
+        let sector_count = (start + len).div_ceil(sector_size) - first_sector;
+        if first_sector + sector_count > self.partition.2 {
+            log::error!("ISO 9660 extent at block {} is outside of the partition", block);
+            return Err(FsReadError::ParsingError);
+        }
+        let data = read_from_partition(&self.partition, first_sector, sector_count)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let skip = (start % sector_size) as usize;
+        Ok(data[skip..skip + len as usize].to_vec())
+    }
+    fn root_entry(&self) -> IsoSoftEntry {
+        let header: DirRecordHeader = bytemuck::pod_read_unaligned(
+            &self.descriptor.root_record[..core::mem::size_of::<DirRecordHeader>()],
+        );
+        IsoSoftEntry {
+            path: FilePath::new("/".to_string(), self.partition.clone()),
+            is_file: false,
+            extents: vec![(header.extent + u32::from(header.ext_attr_length), header.data_length)],
+            size: u64::from(header.data_length),
+            attributes: None,
+            symlink: None,
+            recorded: recording_time(&header.date),
+        }
+    }
+    fn read_extents(&self, entry: &IsoSoftEntry) -> Result<Vec<u8>, FsReadError> {
+        let mut data = Vec::with_capacity(entry.size as usize);
+        for (block, len) in &entry.extents {
+            data.extend(self.read_bytes(u64::from(*block), 0, u64::from(*len))?);
+        }
+        Ok(data)
+    }
+    /// Rock Ridge is used if the "." record of the root directory starts with a SP entry
+    /// Returns how many bytes are skipped at the start of every system use area
+    fn rock_ridge_skip(&self, root: &IsoSoftEntry) -> Option<u8> {
+        let data = self.read_extents(root).ok()?;
+        let (header, _, system_use) = records(&data, self.block_size() as usize).next()?;
+        if header.name_length != 1 || system_use.len() < 7 {
+            return None;
+        }
+        (system_use[..2] == *b"SP" && system_use[4..6] == SP_CHECK_BYTES).then_some(system_use[6])
+    }
+    /// Parses the Rock Ridge entries of a system use area, following the continuation areas
+    fn rock_ridge(&self, mut area: Vec<u8>) -> RockRidge {
+        let mut rock_ridge = RockRidge::default();
+        let mut name_done = false;
+        let mut link_continues = false;
+        for _ in 0..MAX_CONTINUATION_AREAS {
+            let mut continuation = None;
+            let mut offset = 0;
+            while offset + 4 <= area.len() {
+                let len = usize::from(area[offset + 2]);
+                if len < 4 || offset + len > area.len() {
+                    break;
+                }
+                let field = &area[offset..offset + len];
+                match &field[..2] {
+                    b"CE" if len >= 28 => {
+                        continuation = Some((read_u32(field, 4), read_u32(field, 12), read_u32(field, 20)));
+                    }
+                    b"PX" if len >= 36 => {
+                        rock_ridge.attributes = Some(PosixAttributes {
+                            mode: read_u32(field, 4),
+                            links: read_u32(field, 12),
+                            uid: read_u32(field, 20),
+                            gid: read_u32(field, 28),
+                        });
+                    }
+                    // The name can be split in several NM entries, the "." and ".." flags are ignored
+                    b"NM" if len >= 5 && !name_done && field[4] & (NM_CURRENT | NM_PARENT) == 0 => {
+                        let part = String::from_utf8_lossy(&field[5..]);
+                        rock_ridge.name.get_or_insert_with(String::new).push_str(&part);
+                        name_done = field[4] & NM_CONTINUE == 0;
+                    }
+                    b"SL" if len >= 5 => {
+                        let target = rock_ridge.symlink.get_or_insert_with(String::new);
+                        link_continues = push_symlink_components(target, &field[5..], link_continues);
+                    }
+                    b"CL" if len >= 12 => rock_ridge.child_link = Some(read_u32(field, 4)),
+                    b"RE" => rock_ridge.relocated = true,
+                    b"ST" => break,
+                    _ => {}
+                }
+                offset += len;
+            }
+            let Some((block, offset, len)) = continuation else {
+                break;
+            };
+            match self.read_bytes(u64::from(block), u64::from(offset), u64::from(len)) {
+                Ok(next) => area = next,
+                Err(err) => {
+                    log::error!("Couldn't read Rock Ridge continuation area: {:?}", err);
+                    break;
+                }
+            }
+        }
+        rock_ridge
+    }
+    /// Entries of a directory, with the extents of multi extent files merged
+    fn read_dir_entries(&self, dir: &IsoSoftEntry) -> Result<Vec<IsoSoftEntry>, FsReadError> {
+        let data = self.read_extents(dir)?;
+        let mut entries: Vec<IsoSoftEntry> = Vec::new();
+        let mut previous_continues = false;
+        for (header, raw_name, system_use) in records(&data, self.block_size() as usize) {
+            let continues = previous_continues;
+            previous_continues = header.flags & FLAG_MULTI_EXTENT != 0;
+            // "." and ".."
+            if header.name_length == 1 && raw_name[0] <= 1 {
+                continue;
+            }
+            if header.flags & FLAG_ASSOCIATED != 0 || header.file_unit_size != 0 {
+                continue;
+            }
+            let extent = (header.extent + u32::from(header.ext_attr_length), header.data_length);
+            if continues {
+                if let Some(last) = entries.last_mut() {
+                    last.extents.push(extent);
+                    last.size += u64::from(header.data_length);
+                    continue;
+                }
+            }
+            let mut name = match self.encoding {
+                NameEncoding::Joliet => decode_ucs2(raw_name),
+                _ => String::from_utf8_lossy(raw_name).to_string(),
+            };
+            let mut is_file = header.flags & FLAG_DIRECTORY == 0;
+            let mut extents = vec![extent];
+            let mut size = u64::from(header.data_length);
+            let mut attributes = None;
+            let mut symlink = None;
+            if let NameEncoding::RockRidge(skip) = self.encoding {
+                let area = system_use.get(usize::from(skip)..).unwrap_or_default();
+                let rock_ridge = self.rock_ridge(area.to_vec());
+                if rock_ridge.relocated {
+                    continue;
+                }
+                if let Some(child_link) = rock_ridge.child_link {
+                    // The relocated directory's "." record holds its size
+                    let header_size = core::mem::size_of::<DirRecordHeader>();
+                    let Ok(first) = self.read_bytes(u64::from(child_link), 0, header_size as u64) else {
+                        continue;
+                    };
+                    let child: DirRecordHeader = bytemuck::pod_read_unaligned(&first);
+                    is_file = false;
+                    extents = vec![(child_link, child.data_length)];
+                    size = u64::from(child.data_length);
+                }
+                if let Some(rr_name) = rock_ridge.name {
+                    name = rr_name;
+                } else {
+                    name = strip_version(&name);
+                }
+                attributes = rock_ridge.attributes;
+                symlink = rock_ridge
+                    .symlink
+                    .filter(|_| attributes.map_or(true, |attributes| attributes.is_symlink()));
+            } else {
+                name = strip_version(&name);
+            }
+            if name.is_empty() || name.contains(['/', '\0']) || name == "." || name == ".." {
+                log::warn!("Skipping invalid ISO 9660 name {:?} in {}", name, dir.path);
+                continue;
+            }
+            entries.push(IsoSoftEntry {
+                path: dir.path.join_str(name),
+                is_file,
+                extents,
+                size,
+                attributes,
+                symlink,
+                recorded: recording_time(&header.date),
+            });
+        }
+        Ok(entries)
+    }
+    /// Block of each directory, from the little endian path table
+    /// Parents come before their children, except for directories relocated by Rock Ridge
+    fn path_table(&self) -> Result<Vec<u32>, FsReadError> {
+        let table = self.read_bytes(
+            u64::from(self.descriptor.l_path_table),
+            0,
+            u64::from(self.descriptor.path_table_size),
+        )?;
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset + 8 <= table.len() {
+            let name_length = usize::from(table[offset]);
+            if name_length == 0 {
+                break;
+            }
+            blocks.push(read_u32(&table, offset + 2) + u32::from(table[offset + 1]));
+            offset += 8 + name_length + name_length % 2;
+        }
+        Ok(blocks)
+    }
+    /// Indexes every directory of the path table, each one is read once
+    /// Directories are named by the record pointing to them, so a directory can only be read once its parent was
+    fn index(&mut self) {
+        let root = self.root_entry();
+        let mut pending = match self.path_table() {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                log::error!("Couldn't read the ISO 9660 path table: {:?}", err);
+                vec![root.extents[0].0]
+            }
+        };
+        let mut found = HashMap::new();
+        found.insert(root.extents[0].0, root.clone());
+        self.files.insert(root.path.clone(), root);
+        loop {
+            let before = pending.len();
+            let mut idx = 0;
+            while idx < pending.len() {
+                let Some(dir) = found.remove(&pending[idx]) else {
+                    idx += 1;
+                    continue;
+                };
+                pending.swap_remove(idx);
+                let entries = match self.read_dir_entries(&dir) {
+                    Ok(entries) => entries,
+                    Err(err) => {
+                        log::error!("Couldn't read directory {}: {:?}", dir.path, err);
+                        continue;
+                    }
+                };
+                for entry in entries {
+                    if !entry.is_file {
+                        found.insert(entry.extents[0].0, entry.clone());
+                    }
+                    self.files.insert(entry.path.clone(), entry);
+                }
+            }
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+        if !pending.is_empty() {
+            log::warn!("{} directories of the path table aren't in the tree", pending.len());
+        }
+    }
+    fn file_entry(&self, path: &FilePath) -> Result<&IsoSoftEntry, FsReadError> {
+        match self.files.get(path) {
+            Some(entry) if entry.is_file => Ok(entry),
+            _ => Err(FsReadError::EntryNotFound),
+        }
+    }
+}
+
+impl FsDriverInitialiser for Iso9660Driver {
+    fn try_init(partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        Some(Box::new(Self::new(partition)?))
+    }
+}
+
+impl FsDriver for Iso9660Driver {
+    fn as_enum(&self) -> FsDriverEnum {
+        FsDriverEnum::Iso9660
+    }
+    fn partition(&self) -> Option<&Partition> {
+        Some(&self.partition)
+    }
+    fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        if entry.is_file {
+            let mut content = vec![0; entry.size as usize];
+            let size = self.read_at(path, 0, &mut content)?;
+            return Ok(Entry::File(File {
+                path: entry.path.clone(),
+                content,
+                size,
+            }));
+        }
+        let entries: Vec<SoftEntry> = self
+            .read_dir_entries(entry)?
+            .into_iter()
+            .map(|child| SoftEntry {
+                path: child.path,
+                size: child.size as usize,
+            })
+            .collect();
+        Ok(Entry::Dir(Dir {
+            path: entry.path.clone(),
+            size: entries.len(),
+            entries,
+        }))
+    }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        Ok(self.file_entry(path)?.size)
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let entry = self.file_entry(path)?;
+        let end = (offset + buf.len() as u64).min(entry.size);
+        if offset >= end {
+            return Ok(0);
+        }
+        // Only the extents overlapping the range are read
+        let mut extent_start = 0;
+        for (block, len) in &entry.extents {
+            let extent_end = extent_start + u64::from(*len);
+            let start = offset.max(extent_start);
+            let stop = end.min(extent_end);
+            if start < stop {
+                let data = self.read_bytes(u64::from(*block), start - extent_start, stop - start)?;
+                let dst = (start - offset) as usize;
+                buf[dst..dst + data.len()].copy_from_slice(&data);
+            }
+            extent_start = extent_end;
+        }
+        Ok((end - offset) as usize)
+    }
+    fn read_link(&self, path: &FilePath) -> Option<String> {
+        self.files.get(path)?.symlink.clone()
+    }
+    /// Without Rock Ridge there are no permissions, everything is read only
+    /// Only the recording date is stored, it's used for all the times
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        let kind = if entry.symlink.is_some() {
+            EntryKind::Symlink
+        } else if entry.is_file {
+            EntryKind::File
+        } else {
+            EntryKind::Dir
+        };
+        let mut metadata = Metadata::new(kind, entry.size);
+        if let Some(attributes) = entry.attributes {
+            metadata.mode = (attributes.mode & 0o7777) as u16;
+            metadata.uid = attributes.uid;
+            metadata.gid = attributes.gid;
+            metadata.links = attributes.links;
+            metadata.kind = match attributes.mode & S_IFMT {
+                S_IFBLK => EntryKind::BlockDevice,
+                S_IFCHR => EntryKind::CharDevice,
+                _ => kind,
+            };
+        } else {
+            metadata.mode &= !0o222;
+        }
+        metadata.created = entry.recorded;
+        metadata.modified = entry.recorded;
+        metadata.accessed = entry.recorded;
+        Ok(metadata)
+    }
+}
+
+/// Unix time of the date of a directory record: years since 1900, month, day, hour, minute, second
+/// and the offset from GMT in 15 minutes intervals
+fn recording_time(date: &[u8; 7]) -> u64 {
+    if date[1] == 0 {
+        // Not recorded
+        return 0;
+    }
+    let days = days_from_civil(1900 + u64::from(date[0]), u64::from(date[1]), u64::from(date[2]));
+    let local = days * 86400 + u64::from(date[3]) * 3600 + u64::from(date[4]) * 60 + u64::from(date[5]);
+    local.saturating_add_signed(-i64::from(date[6] as i8) * 15 * 60)
+}
+
+/// Directory records of a directory, with their name and system use area
+/// Records don't cross block boundaries, a zero length means the rest of the block is padding
+fn records(data: &[u8], block_size: usize) -> impl Iterator<Item = (DirRecordHeader, &[u8], &[u8])> {
+    let header_size = core::mem::size_of::<DirRecordHeader>();
+    let mut offset = 0;
+    core::iter::from_fn(move || loop {
+        if offset + header_size > data.len() {
+            return None;
+        }
+        let len = usize::from(data[offset]);
+        if len == 0 {
+            offset = (offset / block_size + 1) * block_size;
+            continue;
+        }
+        let header: DirRecordHeader = bytemuck::pod_read_unaligned(&data[offset..offset + header_size]);
+        let name_end = header_size + usize::from(header.name_length);
+        if len < name_end || offset + len > data.len() {
+            return None;
+        }
+        let record = &data[offset..offset + len];
+        offset += len;
+        // The name is padded to an even length
+        let system_use = record.get(name_end + name_end % 2..).unwrap_or_default();
+        return Some((header, &record[header_size..name_end], system_use));
+    })
+}
+/// Appends the components of a SL entry to the symlink target
+/// Returns if the last component continues in the next entry
+fn push_symlink_components(target: &mut String, mut components: &[u8], mut continues: bool) -> bool {
+    while components.len() >= 2 {
+        let flags = components[0];
+        let len = usize::from(components[1]);
+        let Some(content) = components.get(2..2 + len) else {
+            break;
+        };
+        if !continues && !target.is_empty() && !target.ends_with('/') {
+            target.push('/');
+        }
+        if flags & SL_ROOT != 0 {
+            target.clear();
+            target.push('/');
+        } else if flags & SL_CURRENT != 0 {
+            target.push('.');
+        } else if flags & SL_PARENT != 0 {
+            target.push_str("..");
+        } else {
+            target.push_str(&String::from_utf8_lossy(content));
+        }
+        continues = flags & SL_CONTINUE != 0;
+        components = &components[2 + len..];
+    }
+    continues
+}
+/// Removes the ";1" version and the trailing dot of files without extension
+fn strip_version(name: &str) -> String {
+    let name = name.split(';').next().unwrap_or_default();
+    name.strip_suffix('.').unwrap_or(name).to_string()
+}
+fn decode_ucs2(raw: &[u8]) -> String {
+    let units = raw.chunks_exact(2).map(|unit| u16::from_be_bytes([unit[0], unit[1]]));
+    char::decode_utf16(units)
+        .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect()
+}
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+pub const ISO_IDENTIFIER: &[u8; 5] = b"CD001";
+/// The first 16 blocks are the system area, descriptors are always 2048 bytes
+pub const FIRST_DESCRIPTOR: u64 = 16;
+pub const DESCRIPTOR_SIZE: u64 = 2048;
+/// Stops looking for the terminator on corrupted images
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+pub const DESCRIPTOR_PRIMARY: u8 = 1;
+pub const DESCRIPTOR_SUPPLEMENTARY: u8 = 2;
+pub const DESCRIPTOR_TERMINATOR: u8 = 255;
+/// UCS-2 levels 1, 2 and 3
+pub const JOLIET_ESCAPE_SEQUENCES: [&[u8; 3]; 3] = [b"%/@", b"%/C", b"%/E"];
+pub const FLAG_DIRECTORY: u8 = 0x02;
+pub const FLAG_ASSOCIATED: u8 = 0x04;
+/// The next record is the next extent of the same file
+pub const FLAG_MULTI_EXTENT: u8 = 0x80;
+pub const SP_CHECK_BYTES: [u8; 2] = [0xBE, 0xEF];
+/// Stops following CE entries on corrupted images
+const MAX_CONTINUATION_AREAS: usize = 16;
+pub const NM_CONTINUE: u8 = 0x01;
+pub const NM_CURRENT: u8 = 0x02;
+pub const NM_PARENT: u8 = 0x04;
+pub const SL_CONTINUE: u8 = 0x01;
+pub const SL_CURRENT: u8 = 0x02;
+pub const SL_PARENT: u8 = 0x04;
+pub const SL_ROOT: u8 = 0x08;
+pub const S_IFMT: u32 = 0o170_000;
+pub const S_IFLNK: u32 = 0o120_000;
+pub const S_IFBLK: u32 = 0o060_000;
+pub const S_IFCHR: u32 = 0o020_000;
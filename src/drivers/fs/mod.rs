@@ -2,48 +2,130 @@
 pub mod elf;
 pub mod entry;
 pub mod fs_driver;
+pub mod fsck;
+pub mod handle;
+pub mod initramfs;
 pub mod partition;
+pub mod partitioning;
 pub mod path;
 pub mod userland;
+pub mod vfs;
 // Specific fs's
+pub mod devfs;
+pub mod exfat;
 pub mod ext;
 pub mod fat;
+pub mod iso9660;
 pub mod ntfs;
+pub mod procfs;
+pub mod tmpfs;
 
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, format, string::String, vec, vec::Vec};
 use hashbrown::HashMap;
 
 use crate::{
     dbg,
-    disk::{driver::DISK_MANAGER, DiskLoc},
+    disk::{
+        driver::{disk_sector_count, read_from_disk_async, DISK_MANAGER},
+        DiskLoc,
+    },
     fs_driver,
     state::FS_DRIVER,
+    task::{event::Event, yield_now},
 };
 
 use self::{
-    fs_driver::{Entry, FsDriver, FsDriverInitialiser, FsReadError},
+    fs_driver::{
+        Entry, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, FsWriteError, Metadata,
+    },
+    handle::FileHandle,
     partition::{HeaderType, Partition},
     path::FilePath,
+    vfs::{MountTable, Volume},
 };
 
-/// Holds drivers for all of the partitions of all the disks
+/// Sectors read ahead of the probes at the start of each disk, the MBR and the GPT with its entries
+const PARTITION_TABLE_SECTORS: u64 = 34;
+/// Sectors read ahead of the probes at the start of each partition, up to the ISO 9660 volume descriptors
+const SUPERBLOCK_SECTORS: u64 = 72;
+
+/// Set once `init` is done
+static INITIALISED: Event = Event::new();
+
+/// Holds drivers for all of the partitions of all the disks, and for the tmpfs
 pub struct FsDriverManager {
-    pub drivers: HashMap<Partition, Box<dyn FsDriver>>,
+    pub drivers: HashMap<Volume, Box<dyn FsDriver>>,
     pub partitions: HashMap<DiskLoc, Vec<Partition>>,
+    pub mounts: MountTable,
+    /// Id of the next memory volume, see `new_memory_volume`
+    next_memory_id: u32,
 }
 
 impl FsDriverManager {
     pub fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
-        if let Some(driver) = self.drivers.get(&path.partition) {
+        if let Some(driver) = self.drivers.get(&path.volume) {
             driver.read(path)
         } else {
             dbg!(self.drivers);
             Err(FsReadError::EntryNotFound)
         }
     }
+    /// Doesn't follow symlinks, see `resolve_no_follow`
+    pub fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        self.drivers
+            .get(&path.volume)
+            .ok_or(FsReadError::EntryNotFound)?
+            .stat(path)
+    }
+    /// Target of a symlink as it's stored, None if the entry isn't one
+    pub fn read_link(&self, path: &FilePath) -> Option<String> {
+        self.drivers.get(&path.volume)?.read_link(path)
+    }
+    /// Opens a file without reading its content
+    pub fn open(&self, path: &FilePath) -> Result<FileHandle, FsReadError> {
+        self.drivers
+            .get(&path.volume)
+            .ok_or(FsReadError::EntryNotFound)?
+            .file_len(path)?;
+        Ok(FileHandle::new(path.clone()))
+    }
+    pub fn write_file(&mut self, path: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        self.drivers
+            .get_mut(&path.volume)
+            .ok_or(FsWriteError::EntryNotFound)?
+            .write_file(path, content)
+    }
+    pub fn create_dir(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        self.drivers
+            .get_mut(&path.volume)
+            .ok_or(FsWriteError::EntryNotFound)?
+            .create_dir(path)
+    }
+    pub fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        self.drivers
+            .get_mut(&path.volume)
+            .ok_or(FsWriteError::EntryNotFound)?
+            .remove(path)
+    }
+    pub fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        if from.volume != to.volume {
+            return Err(FsWriteError::CrossPartition);
+        }
+        self.drivers
+            .get_mut(&from.volume)
+            .ok_or(FsWriteError::EntryNotFound)?
+            .rename(from, to)
+    }
     #[must_use] pub fn get_partition_from_id(&self, loc: &DiskLoc, part_id: u8) -> Option<&Partition> {
         return self.partitions.get(loc)?.get(part_id as usize)
     }
+    /// Finds a GPT partition by its name
+    #[must_use] pub fn get_partition_from_name(&self, name: &str) -> Option<&Partition> {
+        self.partitions
+            .values()
+            .flatten()
+            .find(|part| part.name() == Some(name))
+    }
     pub async fn new() -> Self {
         let mut self_drivers = HashMap::new();
         let mut self_partitions = HashMap::new();
@@ -54,46 +136,182 @@ impl FsDriverManager {
             .collect::<Vec<DiskLoc>>();
         for loc in locs {
             log::trace!("Fetching filesystem on disk {}", loc);
-            let header_type = partition::read_header_type(&loc);
-            if header_type.is_none() {
-                continue;
-            }
-            let header_type = header_type.unwrap();
-            let partitions = match header_type {
-                HeaderType::GPT(gpt) => gpt,
-                HeaderType::MBR(mbr) => mbr,
+            prefetch(&loc, 0, PARTITION_TABLE_SECTORS).await;
+            let partitions = match partition::read_header_type(&loc) {
+                Some(HeaderType::GPT(gpt)) => gpt,
+                Some(HeaderType::MBR(mbr)) => mbr,
+                // i.e. a CD, the filesystem may cover the whole disk
+                None => partition::whole_disk(&loc).into_iter().collect(),
             };
             self_partitions.insert(loc, partitions);
         }
         for (disk, parts) in &self_partitions {
             for part in parts {
-                let drv = partition::find_and_init_fs_driver_for_part(part);
-                if drv.is_none() {
+                prefetch(disk, part.1, SUPERBLOCK_SECTORS.min(part.2)).await;
+                let Some(mut drv) = partition::find_and_open_fs_driver_for_part(part) else {
                     log::error!("Couldn't init a fs driver on partition {:?}", part);
                     continue;
+                };
+                // The directories are read synchronously, the other tasks run between them
+                loop {
+                    match drv.index_step() {
+                        Ok(true) => yield_now().await,
+                        Ok(false) => {
+                            self_drivers.insert(Volume::Partition(part.clone()), drv);
+                            break;
+                        }
+                        Err(err) => {
+                            log::error!("Couldn't index partition {:?}: {:?}", part, err);
+                            break;
+                        }
+                    }
                 }
-                let drv = drv.unwrap();
-                self_drivers.insert(part.clone(), drv);
             }
         }
 
-        Self {
+        let mut _self = Self {
             drivers: self_drivers,
             partitions: self_partitions,
+            mounts: MountTable::default(),
+            next_memory_id: 0,
+        };
+        _self.mount_all();
+        _self
+    }
+    /// Formats the partition and binds the new driver to it, the mount points of the partition are kept
+    pub fn format(&mut self, partition: &Partition, fs: &FsDriverEnum, label: &str) -> Result<(), FsWriteError> {
+        let driver: Box<dyn FsDriver> = match fs {
+            FsDriverEnum::Fat32 => fat::Fat32Driver::format(partition, label)?,
+            FsDriverEnum::Ext => ext::ExtDriver::format(partition, label)?,
+            _ => return Err(FsWriteError::NotSupported),
+        };
+        self.drivers.insert(Volume::Partition(partition.clone()), driver);
+        Ok(())
+    }
+    /// Checks the filesystem of the partition, see `fsck::check`
+    /// The driver of the partition is initialised again if anything was repaired, as its cached state may be stale
+    pub fn fsck(&mut self, partition: &Partition, repair: bool) -> Result<fsck::FsckReport, FsWriteError> {
+        let report = fsck::check(partition, repair)?;
+        if report.repaired() != 0 {
+            let volume = Volume::Partition(partition.clone());
+            match partition::find_and_init_fs_driver_for_part(partition) {
+                Some(driver) => {
+                    self.drivers.insert(volume, driver);
+                }
+                None => {
+                    self.drivers.remove(&volume);
+                }
+            }
+        }
+        Ok(report)
+    }
+    /// Reads the partition table of a disk again, i.e. after editing it
+    /// Drivers and mount points of the partitions that changed are dropped, new partitions aren't mounted
+    pub fn rescan(&mut self, loc: &DiskLoc) {
+        let partitions = match partition::read_header_type(loc) {
+            Some(HeaderType::GPT(partitions) | HeaderType::MBR(partitions)) => partitions,
+            None => partition::whole_disk(loc).into_iter().collect(),
+        };
+        for old in self.partitions.remove(loc).unwrap_or_default() {
+            if !partitions.contains(&old) {
+                let volume = Volume::Partition(old);
+                self.drivers.remove(&volume);
+                self.mounts.umount_volume(&volume);
+            }
+        }
+        for part in &partitions {
+            let volume = Volume::Partition(part.clone());
+            if self.drivers.contains_key(&volume) {
+                continue;
+            }
+            if let Some(drv) = partition::find_and_init_fs_driver_for_part(part) {
+                self.drivers.insert(volume, drv);
+            }
+        }
+        self.partitions.insert(*loc, partitions);
+    }
+    /// Mounts the initramfs as the root if the kernel has one
+    /// Then every partition with a driver on /mnt/disk[disk idx]p[partition idx]
+    /// Without initramfs, the first one is also mounted as the root, or a tmpfs if there is none
+    /// A tmpfs is always mounted on /tmp, the procfs on /proc and the devfs on /dev
+    fn mount_all(&mut self) {
+        if let Some(archive) = initramfs::ARCHIVE {
+            let volume = self.new_memory_volume();
+            match initramfs::unpack(archive, volume.clone()) {
+                Ok(driver) => {
+                    if let Err(err) = self.mount_driver("/", volume, Box::new(driver)) {
+                        log::error!("Failed mounting initramfs: {:?}", err);
+                    }
+                }
+                Err(err) => log::error!("Failed unpacking initramfs: {:?}", err),
+            }
+        }
+        let has_root = self.mounts.find("/").is_some();
+        let mut locs: Vec<DiskLoc> = self.partitions.keys().copied().collect();
+        locs.sort_by_key(DiskLoc::as_index);
+        let mut to_mount = Vec::new();
+        for loc in locs {
+            for (part_idx, part) in self.partitions[&loc].iter().enumerate() {
+                if self.drivers.contains_key(&Volume::Partition(part.clone())) {
+                    to_mount.push((format!("/mnt/disk{}p{}", loc.as_index(), part_idx), part.clone()));
+                }
+            }
+        }
+        if let (false, Some((_, root))) = (has_root, to_mount.first()) {
+            to_mount.insert(0, ("/".into(), root.clone()));
+        }
+        let mut tmpfs_mounts = vec!["/tmp"];
+        if to_mount.is_empty() && !has_root {
+            tmpfs_mounts.insert(0, "/");
+        }
+        for (path, part) in to_mount {
+            if let Err(err) = self.mount(&path, Volume::Partition(part)) {
+                log::error!("Failed mounting {}: {:?}", path, err);
+            }
+        }
+        for path in tmpfs_mounts {
+            if let Err(err) = self.mount_tmpfs(path, tmpfs::DEFAULT_MAX_SIZE) {
+                log::error!("Failed mounting tmpfs on {}: {:?}", path, err);
+            }
+        }
+        if let Err(err) = self.mount_procfs("/proc") {
+            log::error!("Failed mounting procfs: {:?}", err);
+        }
+        if let Err(err) = self.mount_devfs("/dev") {
+            log::error!("Failed mounting devfs: {:?}", err);
         }
     }
 }
 
+/// Reads the sectors into the disk cache while the other tasks run, the probes then find them there
+/// Errors are left to the probes
+async fn prefetch(loc: &DiskLoc, start_sector: u64, sector_count: u64) {
+    let sector_count = sector_count.min(disk_sector_count(loc).unwrap_or(0).saturating_sub(start_sector));
+    if sector_count != 0 {
+        let _ = read_from_disk_async(loc, start_sector, sector_count).await;
+    }
+}
+
 #[allow(clippy::borrowed_box)]
 #[must_use] pub fn get_fs_driver(loc: &Partition) -> Option<&Box<dyn FsDriver>> {
-    return fs_driver!().drivers.get(loc)
+    return fs_driver!().drivers.get(&Volume::Partition(loc.clone()))
 }
 
 /// Tries to identify the different filesystems on all of the drives, and binds a driver to it if there is a supported driver
 /// Supported fs:
 /// - NTFS
-/// - Fat32
+/// - Fat12/16/32
+/// - exFAT
 /// - Ext2/3/4
+/// - ISO 9660 (only read), with Joliet and Rock Ridge
+/// The initramfs is mounted as the root, a tmpfs on /tmp, the procfs on /proc and the devfs on /dev, see `mount_all`
+/// The disks are read asynchronously, so the other tasks run in the meantime, see `initialised`
 pub async fn init() {
-    unsafe { FS_DRIVER.replace(FsDriverManager::new().await); }
+    let manager = FsDriverManager::new().await;
+    unsafe { FS_DRIVER.replace(manager); }
+    INITIALISED.set();
+}
+/// Resolves once `init` is done, for the tasks that use `fs_driver!` and may run before
+pub async fn initialised() {
+    INITIALISED.wait().await;
 }
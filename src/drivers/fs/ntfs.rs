@@ -1,10 +1,14 @@
 use alloc::{
     format,
     string::{String, ToString},
+    vec,
     vec::Vec,
 };
 use hashbrown::HashMap;
-use ntfs::{Ntfs, NtfsFile, NtfsReadSeek};
+use ntfs::{
+    attribute_value::NtfsAttributeValue, structured_values::NtfsFileAttributeFlags,
+    KnownNtfsFileRecordNumber, Ntfs, NtfsAttributeType, NtfsError, NtfsFile, NtfsReadSeek, NtfsTime,
+};
 
 use crate::{
     bit_manipulation::all_zeroes,
@@ -15,7 +19,7 @@ use crate::{
 };
 
 use super::{
-    fs_driver::{Entry, File, FsDriver, FsDriverInitialiser, FsReadError},
+    fs_driver::{Entry, EntryKind, File, FsDriver, FsDriverInitialiser, FsReadError, Metadata},
     partition::Partition,
     path::FilePath,
 };
@@ -25,7 +29,16 @@ pub struct NTFSDriver {
     partition: Partition,
     ntfs: ntfs::Ntfs,
     io: DiskReader,
-    files: HashMap<FilePath, Entry>,
+    files: HashMap<FilePath, NtfsEntry>,
+}
+
+/// Files only keep where they are, their content is read when needed
+#[derive(Debug, Clone)]
+enum NtfsEntry {
+    File { record_number: u64, size: u64 },
+    Dir { record_number: u64, dir: Dir },
+    /// Symbolic links and junctions
+    Symlink { record_number: u64, target: String },
 }
 
 impl FsDriverInitialiser for NTFSDriver {
@@ -57,71 +70,93 @@ impl NTFSDriver {
         reader: &'a mut DiskReader,
         ntfs: &Ntfs,
         dir: NtfsFile<'a>,
-    ) -> Option<HashMap<FilePath, Entry>> {
+    ) -> Option<HashMap<FilePath, NtfsEntry>> {
         let mut parsed_entries = HashMap::new();
         let mut entries_idx = dir.directory_index(reader).ok()?;
         let mut entries = entries_idx.entries();
-        while let Some(Ok(mut entry)) = entries.next(reader) {
+        while let Some(Ok(entry)) = entries.next(reader) {
             let file_name = entry.key().unwrap().unwrap();
             // println!("{}", file_name.name());
             if let Ok(name) = file_name.name().to_string() {
                 if name.starts_with('$') {
                     // Skip if starts with $
-                    if let Some(Ok(_entry)) = entries.next(reader) {
-                        entry = _entry;
-                        continue;
-                    } else {
-                        break;
-                    }
+                    continue;
                 }
+                let path = FilePath::new(format!("{}/{}", prefix, name), partition.clone());
                 if let Ok(file) = entry.to_file(ntfs, reader) {
-                    let parsed_entry = if file.is_directory() {
-                        let a = Self::walk_dir(
-                            partition,
-                            file_name.name().to_string_lossy().as_str(),
-                            reader,
-                            ntfs,
-                            file.clone(),
-                        )?;
+                    // Junctions are directories, they aren't walked
+                    let parsed_entry = if let Some(target) = Self::link_target(&file, reader, path.path()) {
+                        NtfsEntry::Symlink {
+                            record_number: file.file_record_number(),
+                            target,
+                        }
+                    } else if file.is_directory() {
+                        let a = Self::walk_dir(partition, path.path(), reader, ntfs, file.clone())?;
                         let mut soft_entries = Vec::new();
-                        for (path, soft_entry) in &a {
-                            soft_entries.push(SoftEntry {
-                                path: path.clone(),
-                                size: 0,
-                            });
+                        for (sub_path, soft_entry) in &a {
+                            if sub_path.parent() == path {
+                                soft_entries.push(SoftEntry {
+                                    path: sub_path.clone(),
+                                    size: 0,
+                                });
+                            }
                         }
                         parsed_entries.extend(a);
-                        Entry::Dir(Dir {
-                            path: FilePath::new(
-                                format!("{}/{}", prefix, file_name.name()),
-                                partition.clone(),
-                            ),
-                            entries: soft_entries,
-                            size: file.data_size() as usize,
-                        })
-                    } else if let Some(Ok(b)) =
-                        file.data(reader, file_name.name().to_string_lossy().as_str())
-                    {
-                        let v = b.to_attribute().unwrap();
-                        let mut buf = Vec::new();
-                        v.value(reader).unwrap().read(reader, &mut buf).unwrap();
-                        Entry::File(File {
-                            path: FilePath::new(
-                                format!("{}/{}", prefix, file_name.name()),
-                                partition.clone(),
-                            ),
-                            content: buf,
-                            size: file.data_size() as usize,
-                        })
+                        NtfsEntry::Dir {
+                            record_number: file.file_record_number(),
+                            dir: Dir {
+                                path: path.clone(),
+                                size: soft_entries.len(),
+                                entries: soft_entries,
+                            },
+                        }
+                    } else if let Some(size) = Self::data_len(&file, reader) {
+                        NtfsEntry::File {
+                            record_number: file.file_record_number(),
+                            size,
+                        }
                     } else {
                         continue;
                     };
-                    parsed_entries.insert(FilePath::new(name, partition.clone()), parsed_entry);
+                    parsed_entries.insert(path, parsed_entry);
                 }
             }
         }
         Some(parsed_entries)
     }
+    /// Size of the unnamed $DATA attribute of a file, which holds its content
+    fn data_len(file: &NtfsFile, reader: &mut DiskReader) -> Option<u64> {
+        let item = file.data(reader, "")?.ok()?;
+        let attribute = item.to_attribute().ok()?;
+        Some(attribute.value_length())
+    }
+    /// Target of a symbolic link or a junction, from the reparse point of the file
+    fn link_target(file: &NtfsFile, reader: &mut DiskReader, path: &str) -> Option<String> {
+        let info = file.info().ok()?;
+        if !info.file_attributes().contains(NtfsFileAttributeFlags::REPARSE_POINT) {
+            return None;
+        }
+        let mut attributes = file.attributes();
+        while let Some(item) = attributes.next(reader) {
+            let item = item.ok()?;
+            let attribute = item.to_attribute().ok()?;
+            if attribute.ty().ok()? != NtfsAttributeType::ReparsePoint {
+                continue;
+            }
+            let mut value = attribute.value(reader).ok()?;
+            let mut data = vec![0; value.len() as usize];
+            let read = read_value(&mut value, reader, &mut data).ok()?;
+            return parse_reparse_point(&data[..read], path);
+        }
+        None
+    }
+    /// A new reader, so that files can be read without borrowing the driver mutably
+    fn reader(&self) -> DiskReader {
+        DiskReader {
+            partition: self.partition.clone(),
+            pos: 0,
+        }
+    }
 }
 
 impl FsDriver for NTFSDriver {
@@ -129,23 +164,167 @@ impl FsDriver for NTFSDriver {
         &self,
         path: &FilePath,
     ) -> Result<super::fs_driver::Entry, super::fs_driver::FsReadError> {
-        dbg!(self.files);
-        Ok(self
-            .files
-            .get(path)
-            .ok_or(FsReadError::EntryNotFound)?
-            .clone())
+        match self.files.get(path).ok_or(FsReadError::EntryNotFound)? {
+            NtfsEntry::File { size, .. } => {
+                let mut content = vec![0; *size as usize];
+                let read = self.read_at(path, 0, &mut content)?;
+                content.truncate(read);
+                Ok(Entry::File(File {
+                    path: path.clone(),
+                    size: content.len(),
+                    content,
+                }))
+            }
+            NtfsEntry::Dir { dir, .. } => Ok(Entry::Dir(dir.clone())),
+            // Read like a file containing the target, the VFS follows it with `read_link`
+            NtfsEntry::Symlink { target, .. } => Ok(Entry::File(File {
+                path: path.clone(),
+                content: target.clone().into_bytes(),
+                size: target.len(),
+            })),
+        }
+    }
+    fn read_link(&self, path: &FilePath) -> Option<String> {
+        match self.files.get(path)? {
+            NtfsEntry::Symlink { target, .. } => Some(target.clone()),
+            _ => None,
+        }
+    }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        match self.files.get(path) {
+            Some(NtfsEntry::File { size, .. }) => Ok(*size),
+            _ => Err(FsReadError::EntryNotFound),
+        }
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let Some(NtfsEntry::File { record_number, .. }) = self.files.get(path) else {
+            return Err(FsReadError::EntryNotFound);
+        };
+        let mut reader = self.reader();
+        let file = self
+            .ntfs
+            .file(&mut reader, *record_number)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let item = file
+            .data(&mut reader, "")
+            .ok_or(FsReadError::ParsingError)?
+            .or(Err(FsReadError::ParsingError))?;
+        let attribute = item.to_attribute().or(Err(FsReadError::ParsingError))?;
+        let mut value = attribute
+            .value(&mut reader)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        if offset >= value.len() {
+            return Ok(0);
+        }
+        value
+            .seek(&mut reader, binrw::io::SeekFrom::Start(offset))
+            .or(Err(FsReadError::ReadingDiskError))?;
+        read_value(&mut value, &mut reader, buf).or(Err(FsReadError::ReadingDiskError))
+    }
+    /// From the $STANDARD_INFORMATION attribute, the permissions are ACLs so only read only is shown
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        let (record_number, mut metadata) = match self.files.get(path) {
+            Some(NtfsEntry::File { record_number, size }) => {
+                (*record_number, Metadata::new(EntryKind::File, *size))
+            }
+            Some(NtfsEntry::Dir { record_number, dir }) => {
+                (*record_number, Metadata::new(EntryKind::Dir, dir.size as u64))
+            }
+            Some(NtfsEntry::Symlink { record_number, target }) => {
+                (*record_number, Metadata::new(EntryKind::Symlink, target.len() as u64))
+            }
+            // The root directory isn't indexed
+            None if path.name().is_empty() => (
+                KnownNtfsFileRecordNumber::RootDirectory as u64,
+                Metadata::new(EntryKind::Dir, 0),
+            ),
+            None => return Err(FsReadError::EntryNotFound),
+        };
+        let mut reader = self.reader();
+        let file = self
+            .ntfs
+            .file(&mut reader, record_number)
+            .or(Err(FsReadError::ReadingDiskError))?;
+        let info = file.info().or(Err(FsReadError::ParsingError))?;
+        if info.file_attributes().contains(NtfsFileAttributeFlags::READ_ONLY) {
+            metadata.mode &= !0o222;
+        }
+        metadata.created = unix_time(info.creation_time());
+        metadata.modified = unix_time(info.modification_time());
+        metadata.accessed = unix_time(info.access_time());
+        metadata.links = u32::from(file.hard_link_count());
+        Ok(metadata)
     }
 
     fn as_enum(&self) -> super::fs_driver::FsDriverEnum {
         super::fs_driver::FsDriverEnum::NTFS
     }
 
-    fn partition(&self) -> &super::partition::Partition {
-        &self.partition
+    fn partition(&self) -> Option<&super::partition::Partition> {
+        Some(&self.partition)
     }
 }
 
+/// Reads from the current position of the value until buf is full or the value ends
+fn read_value(
+    value: &mut NtfsAttributeValue<'_, '_>,
+    reader: &mut DiskReader,
+    buf: &mut [u8],
+) -> Result<usize, NtfsError> {
+    // A read can stop at the end of a data run, so we loop until buf is full
+    let mut read = 0;
+    while read < buf.len() {
+        let n = value.read(reader, &mut buf[read..])?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+    Ok(read)
+}
+
+/// Target of the reparse data of a symbolic link or a junction, other reparse points (i.e. deduplicated files) are None
+/// Absolute targets (i.e. \??\C:\Users) are made relative to the link, the volume isn't necessarily mounted on /
+/// <https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-fscc/b41f1cbf-10df-4a47-98d4-1c52a833d913>
+fn parse_reparse_point(data: &[u8], path: &str) -> Option<String> {
+    let u16_at = |offset: usize| data.get(offset..offset + 2).map(|b| usize::from(u16::from_le_bytes([b[0], b[1]])));
+    let tag = u32::from_le_bytes(data.get(0..4)?.try_into().ok()?);
+    // The names come after the flags for symbolic links
+    let (names_start, relative) = match tag {
+        IO_REPARSE_TAG_SYMLINK => (20, data.get(16)? & SYMLINK_FLAG_RELATIVE != 0),
+        IO_REPARSE_TAG_MOUNT_POINT => (16, false),
+        _ => return None,
+    };
+    let name_start = names_start + u16_at(8)?;
+    let raw_name = data.get(name_start..name_start + u16_at(10)?)?;
+    let units: Vec<u16> = raw_name
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    let target = String::from_utf16_lossy(&units).replace('\\', "/");
+    if relative {
+        return Some(target);
+    }
+    let mut target = target.trim_start_matches("/??/");
+    if target.as_bytes().get(1) == Some(&b':') {
+        // The drive is assumed to be this volume
+        target = &target[2..];
+    }
+    let depth = path.matches('/').count().saturating_sub(1);
+    Some(format!("{}{}", "../".repeat(depth), target.trim_start_matches('/')))
+}
+const IO_REPARSE_TAG_SYMLINK: u32 = 0xA000_000C;
+/// Junctions
+const IO_REPARSE_TAG_MOUNT_POINT: u32 = 0xA000_0003;
+const SYMLINK_FLAG_RELATIVE: u8 = 1;
+
+/// NTFS times are in 100ns since 1601
+fn unix_time(time: NtfsTime) -> u64 {
+    (time.nt_timestamp() / 10_000_000).saturating_sub(NTFS_TO_UNIX_EPOCH)
+}
+/// Seconds between 1601-01-01 and 1970-01-01
+const NTFS_TO_UNIX_EPOCH: u64 = 11_644_473_600;
+
 #[derive(Debug)]
 pub struct DiskReader {
     partition: Partition,
@@ -1,15 +1,24 @@
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{
+    boxed::Box,
+    collections::BTreeSet,
+    string::{String, ToString},
+    vec::Vec,
+};
 
 use crate::{
     dbg,
-    disk::{driver::read_from_disk, DiskLoc},
+    disk::{
+        cache::SectorIo,
+        driver::{disk_sector_count, CachedDisks, SECTOR_SIZE},
+        DiskLoc,
+    },
     fs_driver,
 };
 
 use super::{fat::Fat32Driver, fs_driver::FsDriver, get_fs_driver};
 
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct MBRPartition {
     pub drive_attribute: u8, // Drive attributes (bit 7 set = active or bootable)
     pub chs_addr: [u8; 3],   // CHS Address of partition start
@@ -19,26 +28,182 @@ pub struct MBRPartition {
     pub sector_count: u32,     // Number of sectors in partition
 }
 #[repr(C, packed)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct GPTPartition {
-    pub part_type_guid: [u8; 16], // zero means unused entry
-    pub unique_guid: [u8; 16],
+    pub part_type_guid: Guid, // zero means unused entry
+    pub unique_guid: Guid,
     pub start_lba: u64,
     pub end_lba: u64,
     pub attributes: u64,
     pub name: [u8; 72],
 }
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-/// Holds a disk loc, the start offset of the partition & size
-pub struct Partition(pub DiskLoc, pub u64, pub u64);
+/// Holds a disk loc, the first sector of the partition, its size in sectors, and what the partition table says about it
+pub struct Partition(pub DiskLoc, pub u64, pub u64, pub PartitionKind);
 impl Partition {
+    /// First sector after the partition
+    #[must_use] pub fn end(&self) -> u64 {
+        self.1 + self.2
+    }
     #[must_use] pub fn from_idx(loc: &DiskLoc, part_id: u8) -> Option<&Self> {
         unsafe { return fs_driver!().get_partition_from_id(loc, part_id) }
     }
+    /// Label of GPT partitions
+    #[must_use] pub fn name(&self) -> Option<&str> {
+        match &self.3 {
+            PartitionKind::Gpt { name, .. } => Some(name),
+            PartitionKind::Mbr { .. } | PartitionKind::Whole => None,
+        }
+    }
+    #[must_use] pub fn type_guid(&self) -> Option<&Guid> {
+        match &self.3 {
+            PartitionKind::Gpt { type_guid, .. } => Some(type_guid),
+            PartitionKind::Mbr { .. } | PartitionKind::Whole => None,
+        }
+    }
+    #[must_use] pub fn unique_guid(&self) -> Option<&Guid> {
+        match &self.3 {
+            PartitionKind::Gpt { unique_guid, .. } => Some(unique_guid),
+            PartitionKind::Mbr { .. } | PartitionKind::Whole => None,
+        }
+    }
+    /// System id of MBR partitions
+    #[must_use] pub fn mbr_type(&self) -> Option<u8> {
+        match &self.3 {
+            PartitionKind::Gpt { .. } | PartitionKind::Whole => None,
+            PartitionKind::Mbr { partition_type } => Some(*partition_type),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum PartitionKind {
+    Gpt {
+        name: String,
+        type_guid: Guid,
+        unique_guid: Guid,
+    },
+    /// Primary or logical partition
+    Mbr { partition_type: u8 },
+    /// The disk has no partition table, the filesystem covers all of it (i.e. CDs)
+    Whole,
+}
+
+/// Stored in mixed endian, the first 3 fields are little endian
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, bytemuck::Pod, bytemuck::Zeroable)]
+#[repr(transparent)]
+pub struct Guid(pub [u8; 16]);
+impl Guid {
+    #[must_use] pub fn is_zero(&self) -> bool {
+        self.0.iter().all(|x| *x == 0)
+    }
+    /// Name of the partition type if it's a known one
+    #[must_use] pub fn type_name(&self) -> Option<&'static str> {
+        let guid = self.to_string();
+        KNOWN_PARTITION_TYPES
+            .iter()
+            .find(|(known, _)| *known == guid)
+            .map(|(_, name)| *name)
+    }
+    /// Type GUID of a known partition type, the name is case insensitive and spaces can be replaced by '_'
+    #[must_use] pub fn from_type_name(name: &str) -> Option<Self> {
+        let name = name.replace('_', " ");
+        KNOWN_PARTITION_TYPES
+            .iter()
+            .find(|(_, known)| known.eq_ignore_ascii_case(&name))
+            .and_then(|(guid, _)| guid.parse().ok())
+    }
+    /// Random (version 4) GUID
+    #[must_use] pub fn random() -> Self {
+        let mut guid = [0u8; 16];
+        guid[..8].copy_from_slice(&crate::drivers::rand::rand().to_le_bytes());
+        guid[8..].copy_from_slice(&crate::drivers::rand::rand().to_le_bytes());
+        guid[7] = (guid[7] & 0x0F) | 0x40;
+        guid[8] = (guid[8] & 0x3F) | 0x80;
+        Self(guid)
+    }
+}
+/// Parses the text form, i.e. C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+impl core::str::FromStr for Guid {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let groups: Vec<&str> = s.split('-').collect();
+        if groups.iter().map(|group| group.len()).ne([8, 4, 4, 4, 12]) {
+            return Err(());
+        }
+        let hex: String = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or(())?, 16).map_err(|_| ())?;
+        }
+        // The first 3 groups are stored in little endian
+        bytes[..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Ok(Self(bytes))
+    }
+}
+impl core::fmt::Display for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let g = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            u32::from_le_bytes([g[0], g[1], g[2], g[3]]),
+            u16::from_le_bytes([g[4], g[5]]),
+            u16::from_le_bytes([g[6], g[7]]),
+            g[8],
+            g[9]
+        )?;
+        for byte in &g[10..] {
+            write!(f, "{byte:02X}")?;
+        }
+        Ok(())
+    }
+}
+impl core::fmt::Debug for Guid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
 }
+const KNOWN_PARTITION_TYPES: [(&str, &str); 12] = [
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "Linux filesystem"),
+    ("4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709", "Linux root (x86-64)"),
+    ("933AC7E1-2EB4-4F13-B844-0E14E2AEF915", "Linux home"),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    ("A19D880F-05FC-4D3B-A006-743F0F84911E", "Linux RAID"),
+    ("EBD0A0A2-B9E5-4433-87C0-68B6B72699C7", "Microsoft basic data"),
+    ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft reserved"),
+    ("DE94BBA4-06D1-4D40-A16A-BFD50179D6AC", "Windows recovery"),
+    ("48465300-0000-11AA-AA11-00306543ECAC", "Apple HFS+"),
+];
 
-pub type GPTHeader = ();
-//TODO Implement proper GPT/MBR parsing and checksuming & all
+/// <https://uefi.org/specs/UEFI/2.10/05_GUID_Partition_Table_Format.html#gpt-header>
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct GPTHeader {
+    pub signature: [u8; 8],
+    pub revision: u32,
+    /// The CRC covers this many bytes
+    pub header_size: u32,
+    /// Computed with this field set to 0
+    pub header_crc32: u32,
+    pub reserved: u32,
+    pub my_lba: u64,
+    /// The other header, the backup one is on the last sector of the disk
+    pub alternate_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entries_lba: u64,
+    pub partition_entries_count: u32,
+    pub partition_entry_size: u32,
+    pub partition_entries_crc32: u32,
+}
+/// Above this the header is considered corrupted, the spec requires at least 128 but no tool uses more
+const MAX_GPT_ENTRIES: u32 = 1024;
 #[derive(Debug)]
 pub enum HeaderType {
     GPT(Vec<Partition>),
@@ -46,63 +211,202 @@ pub enum HeaderType {
 }
 
 pub const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+/// Partition type of the single MBR partition covering a GPT disk
+pub const PROTECTIVE_MBR_TYPE: u8 = 0xEE;
+/// CHS, LBA and Linux extended partitions, they contain a chain of EBRs describing logical partitions
+pub const EXTENDED_MBR_TYPES: [u8; 3] = [0x05, 0x0F, 0x85];
+/// Stops looping on corrupted EBR chains
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+pub const MBR_ENTRIES_OFFSET: usize = 446;
 pub const GPT_SIGNATURE: [u8; 8] = [69, 70, 73, 32, 80, 65, 82, 84];
 
+/// Reads and checks the GPT header at lba and its partition entries
+fn read_gpt(io: &mut impl SectorIo, disk: &DiskLoc, lba: u64) -> Result<(GPTHeader, Vec<u8>), &'static str> {
+    let sector = io.read_sectors(disk, lba, 1).or(Err("can't read header"))?;
+    let header: GPTHeader =
+        bytemuck::pod_read_unaligned(&sector[..core::mem::size_of::<GPTHeader>()]);
+    if header.signature != GPT_SIGNATURE {
+        return Err("invalid signature");
+    }
+    let header_size = header.header_size as usize;
+    if header_size < core::mem::size_of::<GPTHeader>() || header_size > sector.len() {
+        return Err("invalid header size");
+    }
+    let mut raw_header = sector[..header_size].to_vec();
+    raw_header[16..20].fill(0);
+    if crc32(&raw_header) != header.header_crc32 {
+        return Err("header CRC mismatch");
+    }
+    if header.my_lba != lba {
+        return Err("header isn't at its own LBA");
+    }
+    let entry_size = header.partition_entry_size as usize;
+    if entry_size < core::mem::size_of::<GPTPartition>()
+        || entry_size % 8 != 0
+        || header.partition_entries_count > MAX_GPT_ENTRIES
+    {
+        return Err("invalid partition entries layout");
+    }
+    let entries_len = entry_size * header.partition_entries_count as usize;
+    let mut entries = io.read_sectors(
+        disk,
+        header.partition_entries_lba,
+        entries_len.div_ceil(usize::from(SECTOR_SIZE)) as u64,
+    )
+    .or(Err("can't read partition entries"))?;
+    entries.truncate(entries_len);
+    if crc32(&entries) != header.partition_entries_crc32 {
+        return Err("partition entries CRC mismatch");
+    }
+    Ok((header, entries))
+}
+
+/// Uses the backup header and entries if the primary ones are corrupted
+pub(super) fn load_gpt(disk: &DiskLoc) -> Option<(GPTHeader, Vec<u8>)> {
+    load_gpt_from(&mut CachedDisks, disk, disk_sector_count(disk).unwrap_or(0))
+}
+fn load_gpt_from(io: &mut impl SectorIo, disk: &DiskLoc, sector_count: u64) -> Option<(GPTHeader, Vec<u8>)> {
+    match read_gpt(io, disk, 1) {
+        Ok(gpt) => Some(gpt),
+        Err(err) => {
+            log::warn!("Invalid primary GPT on {:?} ({}), trying the backup one", disk, err);
+            let last_lba = sector_count.checked_sub(1)?;
+            read_gpt(io, disk, last_lba)
+                .map_err(|err| log::error!("Invalid backup GPT on {:?} ({})", disk, err))
+                .ok()
+        }
+    }
+}
+fn parse_gpt(io: &mut impl SectorIo, disk: &DiskLoc, sector_count: u64) -> Option<Vec<Partition>> {
+    let (header, entries) = load_gpt_from(io, disk, sector_count)?;
+    let mut partitions = Vec::new();
+    for raw in entries.chunks_exact(header.partition_entry_size as usize) {
+        let entry: GPTPartition =
+            bytemuck::pod_read_unaligned(&raw[..core::mem::size_of::<GPTPartition>()]);
+        // Unused entries can be anywhere in the array
+        if entry.part_type_guid.is_zero() {
+            continue;
+        }
+        let name_units = entry
+            .name
+            .chunks_exact(2)
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .take_while(|unit| *unit != 0);
+        let name = char::decode_utf16(name_units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect();
+        // The end LBA is inclusive
+        partitions.push(Partition(
+            *disk,
+            entry.start_lba,
+            (entry.end_lba + 1).saturating_sub(entry.start_lba),
+            PartitionKind::Gpt {
+                name,
+                type_guid: entry.part_type_guid,
+                unique_guid: entry.unique_guid,
+            },
+        ));
+    }
+    Some(partitions)
+}
+
+/// CRC32 (IEEE), used by GPT
+pub(super) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 0 {
+                crc >> 1
+            } else {
+                (crc >> 1) ^ 0xEDB8_8320
+            };
+        }
+    }
+    !crc
+}
+
+/// Used entries of a MBR or an EBR
+/// <https://wiki.osdev.org/MBR_(x86)#MBR_Format>
+pub(super) fn mbr_entries(sector: &[u8]) -> Vec<MBRPartition> {
+    sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * 16]
+        .chunks_exact(16)
+        .map(bytemuck::pod_read_unaligned::<MBRPartition>)
+        .filter(|entry| entry.partition_type != 0 && entry.sector_count != 0)
+        .collect()
+}
+/// `base` is the sector the entry's LBA is relative to
+fn mbr_partition(disk: &DiskLoc, base: u64, entry: &MBRPartition) -> Partition {
+    Partition(
+        *disk,
+        base + u64::from(entry.lba_start),
+        u64::from(entry.sector_count),
+        PartitionKind::Mbr {
+            partition_type: entry.partition_type,
+        },
+    )
+}
+/// Logical partitions of an extended partition
+/// Each EBR holds a logical partition (relative to the EBR) and the next EBR (relative to the extended partition)
+/// <https://en.wikipedia.org/wiki/Extended_boot_record>
+fn parse_ebr_chain(io: &mut impl SectorIo, disk: &DiskLoc, extended_start: u64) -> Vec<Partition> {
+    let mut partitions = Vec::new();
+    let mut ebr_lba = extended_start;
+    let mut visited = BTreeSet::new();
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        if !visited.insert(ebr_lba) {
+            log::error!("EBR chain loops back to {} on {:?}", ebr_lba, disk);
+            break;
+        }
+        let Ok(sector) = io.read_sectors(disk, ebr_lba, 1) else {
+            log::error!("Can't read EBR at {} on {:?}", ebr_lba, disk);
+            break;
+        };
+        if sector[sector.len() - MBR_SIGNATURE.len()..] != MBR_SIGNATURE {
+            log::error!("Invalid EBR signature at {} on {:?}", ebr_lba, disk);
+            break;
+        }
+        let mut next = None;
+        for entry in mbr_entries(&sector) {
+            if EXTENDED_MBR_TYPES.contains(&entry.partition_type) {
+                next = Some(extended_start + u64::from(entry.lba_start));
+            } else {
+                partitions.push(mbr_partition(disk, ebr_lba, &entry));
+            }
+        }
+        match next {
+            Some(next) => ebr_lba = next,
+            None => break,
+        }
+    }
+    partitions
+}
+
 #[must_use] pub fn read_header_type(disk: &DiskLoc) -> Option<HeaderType> {
-    // Check GPT
-    //TODO Remove all of this nesting
-    if let Ok(sec_sector) = read_from_disk(disk, 1, 1) {
+    parse_header_type(&mut CachedDisks, disk, disk_sector_count(disk).unwrap_or(0))
+}
+fn parse_header_type(io: &mut impl SectorIo, disk: &DiskLoc, sector_count: u64) -> Option<HeaderType> {
+    // Check GPT, on the primary header or on the backup one
+    if let Ok(sec_sector) = io.read_sectors(disk, 1, 1) {
         if sec_sector[0..GPT_SIGNATURE.len()] == GPT_SIGNATURE {
-            let mut partitions = Vec::new();
-            for sector in 2..33 {
-                let raw_partitions = read_from_disk(disk, sector, 1);
-                if raw_partitions.is_err() {
-                    break;
-                }
-                let raw_partitions = raw_partitions.unwrap();
-                for part_num in 0..4 {
-                    // 128 bytes per partition, and 1 sector = 512 bytes
-                    let partition = unsafe {
-                        &*raw_partitions[128 * part_num..].as_ptr().cast::<GPTPartition>()
-                    };
-                    // Check if only zeroes, if so done reading ?
-                    if partition.part_type_guid.into_iter().all(|x| x == 0) {
-                        break;
-                    }
-                    let start_lba = partition.start_lba;
-                    let end_lba = partition.end_lba;
-                    partitions.push(Partition(
-                        *disk,
-                        partition.start_lba,
-                        partition.end_lba,
-                    ));
-                }
-            }
-            return Some(HeaderType::GPT(partitions));
+            return parse_gpt(io, disk, sector_count).map(HeaderType::GPT);
         }
     }
     // Check MBR
-    if let Ok(first_sector) = read_from_disk(disk, 0, 1) {
+    if let Ok(first_sector) = io.read_sectors(disk, 0, 1) {
         if first_sector[first_sector.len() - MBR_SIGNATURE.len()..] == MBR_SIGNATURE {
-            // https://wiki.osdev.org/MBR_(x86)#MBR_Format
+            let entries = mbr_entries(&first_sector);
+            // Protective MBR, the primary GPT header is unreadable so the backup one is used
+            if entries.iter().any(|entry| entry.partition_type == PROTECTIVE_MBR_TYPE) {
+                return parse_gpt(io, disk, sector_count).map(HeaderType::GPT);
+            }
             let mut partitions = Vec::new();
-            for part_num in 0..4 {
-                let mbr_part = unsafe {
-                    &*first_sector[446 + (16 * part_num)..].as_ptr().cast::<MBRPartition>()
-                };
-                if first_sector[446 + (16 * part_num)..446 + (16 * part_num) + 16]
-                    .iter()
-                    .all(|x| *x == 0)
-                {
-                    continue;
+            for entry in entries {
+                if EXTENDED_MBR_TYPES.contains(&entry.partition_type) {
+                    partitions.extend(parse_ebr_chain(io, disk, u64::from(entry.lba_start)));
+                } else {
+                    partitions.push(mbr_partition(disk, 0, &entry));
                 }
-                let lba_start = mbr_part.lba_start;
-                let sector_count = mbr_part.sector_count;
-                partitions.push(Partition(
-                    *disk,
-                    u64::from(mbr_part.lba_start),
-                    u64::from(mbr_part.sector_count),
-                ));
             }
             return Some(HeaderType::MBR(partitions));
         }
@@ -111,11 +415,19 @@ pub const GPT_SIGNATURE: [u8; 8] = [69, 70, 73, 32, 80, 65, 82, 84];
     log::warn!("No MBR/GPT on disk at {:?}", disk);
     None
 }
+/// Partition covering a disk without partition table
+#[must_use] pub fn whole_disk(disk: &DiskLoc) -> Option<Partition> {
+    let sector_count = disk_sector_count(disk).ok()?;
+    (sector_count > 0).then_some(Partition(*disk, 0, sector_count, PartitionKind::Whole))
+}
 pub struct _FsDriverWrapper<'a>(pub &'a Partition);
 impl _FsDriverWrapper<'_> {
     #[must_use] pub fn try_init_drv<T: FsDriver>(&self) -> Option<Box<T>> {
         T::try_init(self.0)
     }
+    #[must_use] pub fn try_open_drv<T: FsDriver>(&self) -> Option<Box<T>> {
+        T::try_open(self.0)
+    }
 }
 
 macro_rules! fs_driver_init {
@@ -125,13 +437,25 @@ macro_rules! fs_driver_init {
 }
 
 #[must_use] pub fn find_and_init_fs_driver_for_part(part: &Partition) -> Option<Box<dyn FsDriver>> {
-    if let Some(drv) = _FsDriverWrapper(part).try_init_drv::<Fat32Driver>() {
+    let mut drv = find_and_open_fs_driver_for_part(part)?;
+    while drv.index_step().ok()? {}
+    Some(drv)
+}
+/// Finds the driver of the partition without indexing its directories, see `FsDriverInitialiser::index_step`
+#[must_use] pub fn find_and_open_fs_driver_for_part(part: &Partition) -> Option<Box<dyn FsDriver>> {
+    if let Some(drv) = _FsDriverWrapper(part).try_open_drv::<Fat32Driver>() {
+        return Some(drv);
+    }
+    if let Some(drv) = _FsDriverWrapper(part).try_open_drv::<super::exfat::ExFatDriver>() {
         return Some(drv);
     }
-    if let Some(drv) = _FsDriverWrapper(part).try_init_drv::<super::ext::ExtDriver>() {
+    if let Some(drv) = _FsDriverWrapper(part).try_open_drv::<super::ext::ExtDriver>() {
         return Some(drv);
     }
-    if let Some(drv) = _FsDriverWrapper(part).try_init_drv::<super::ntfs::NTFSDriver>() {
+    if let Some(drv) = _FsDriverWrapper(part).try_open_drv::<super::ntfs::NTFSDriver>() {
+        return Some(drv);
+    }
+    if let Some(drv) = _FsDriverWrapper(part).try_open_drv::<super::iso9660::Iso9660Driver>() {
         return Some(drv);
     }
     None
@@ -155,3 +479,193 @@ macro_rules! fs_driver_init {
 // );
 // crate::dbg!(part_id);
 // let files = Self::read_dirs_structure(&fat_info, &partition, &part_id.as_path().unwrap()).unwrap();
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::disk::DiskError;
+
+    const DISK: DiskLoc = DiskLoc::NVMe(0);
+    const DISK_SECTORS: u64 = 128;
+    /// The entries take a single sector
+    const GPT_ENTRIES: u32 = 4;
+
+    struct MemDisk(Vec<u8>);
+    impl MemDisk {
+        fn new() -> Self {
+            Self(vec![0; DISK_SECTORS as usize * usize::from(SECTOR_SIZE)])
+        }
+        fn write(&mut self, lba: u64, data: &[u8]) {
+            let start = lba as usize * usize::from(SECTOR_SIZE);
+            self.0[start..start + data.len()].copy_from_slice(data);
+        }
+        /// Flips a byte, so the CRC covering it doesn't match anymore
+        fn corrupt(&mut self, lba: u64, offset: usize) {
+            self.0[lba as usize * usize::from(SECTOR_SIZE) + offset] ^= 0xFF;
+        }
+    }
+    impl SectorIo for MemDisk {
+        fn read_sectors(&mut self, _loc: &DiskLoc, start_sector: u64, sector_count: u64) -> Result<Vec<u8>, DiskError> {
+            let start = start_sector as usize * usize::from(SECTOR_SIZE);
+            let end = start + sector_count as usize * usize::from(SECTOR_SIZE);
+            self.0.get(start..end).map(<[u8]>::to_vec).ok_or(DiskError::SectorTooBig)
+        }
+        fn write_sectors(&mut self, _loc: &DiskLoc, start_sector: u64, content: &[u8]) -> Result<(), DiskError> {
+            self.write(start_sector, content);
+            Ok(())
+        }
+    }
+
+    /// A single partition from sector 10 to 19
+    fn gpt_entries(name: &str) -> Vec<u8> {
+        let mut entry: GPTPartition = bytemuck::Zeroable::zeroed();
+        entry.part_type_guid = Guid([1; 16]);
+        entry.unique_guid = Guid([2; 16]);
+        entry.start_lba = 10;
+        entry.end_lba = 19;
+        for (unit, chunk) in name.encode_utf16().zip(entry.name.chunks_exact_mut(2)) {
+            chunk.copy_from_slice(&unit.to_le_bytes());
+        }
+        let mut entries = vec![0; GPT_ENTRIES as usize * core::mem::size_of::<GPTPartition>()];
+        entries[..core::mem::size_of::<GPTPartition>()].copy_from_slice(bytemuck::bytes_of(&entry));
+        entries
+    }
+    /// Writes a header at `lba` and the entries it points to
+    fn write_gpt(disk: &mut MemDisk, lba: u64, entries_lba: u64, entries: &[u8]) {
+        let mut header = GPTHeader {
+            signature: GPT_SIGNATURE,
+            revision: 0x0001_0000,
+            header_size: core::mem::size_of::<GPTHeader>() as u32,
+            header_crc32: 0,
+            reserved: 0,
+            my_lba: lba,
+            alternate_lba: if lba == 1 { DISK_SECTORS - 1 } else { 1 },
+            first_usable_lba: 3,
+            last_usable_lba: DISK_SECTORS - 3,
+            disk_guid: Guid([3; 16]),
+            partition_entries_lba: entries_lba,
+            partition_entries_count: GPT_ENTRIES,
+            partition_entry_size: core::mem::size_of::<GPTPartition>() as u32,
+            partition_entries_crc32: crc32(entries),
+        };
+        header.header_crc32 = crc32(bytemuck::bytes_of(&header));
+        disk.write(lba, bytemuck::bytes_of(&header));
+        disk.write(entries_lba, entries);
+    }
+    /// The primary and backup partitions have different names, to know which one was read
+    fn gpt_disk() -> MemDisk {
+        let mut disk = MemDisk::new();
+        write_gpt(&mut disk, 1, 2, &gpt_entries("primary"));
+        write_gpt(&mut disk, DISK_SECTORS - 1, DISK_SECTORS - 2, &gpt_entries("backup"));
+        disk
+    }
+    fn gpt_names(disk: &mut MemDisk) -> Option<Vec<String>> {
+        match parse_header_type(disk, &DISK, DISK_SECTORS)? {
+            HeaderType::GPT(partitions) => Some(partitions.iter().map(|part| part.name().unwrap().to_string()).collect()),
+            HeaderType::MBR(_) => None,
+        }
+    }
+
+    #[test_case]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test_case]
+    fn primary_gpt_is_used() {
+        let mut disk = gpt_disk();
+        let Some(HeaderType::GPT(partitions)) = parse_header_type(&mut disk, &DISK, DISK_SECTORS) else {
+            panic!("no GPT found");
+        };
+        assert_eq!(partitions.len(), 1);
+        assert_eq!((partitions[0].1, partitions[0].2), (10, 10));
+        assert_eq!(partitions[0].name(), Some("primary"));
+    }
+
+    #[test_case]
+    fn corrupted_primary_header_falls_back_to_backup() {
+        let mut disk = gpt_disk();
+        // In the disk GUID, the signature is still there
+        disk.corrupt(1, 60);
+        assert_eq!(gpt_names(&mut disk), Some(vec!["backup".to_string()]));
+    }
+
+    #[test_case]
+    fn corrupted_primary_entries_fall_back_to_backup() {
+        let mut disk = gpt_disk();
+        disk.corrupt(2, 0);
+        assert_eq!(gpt_names(&mut disk), Some(vec!["backup".to_string()]));
+    }
+
+    #[test_case]
+    fn corrupted_primary_and_backup_are_refused() {
+        let mut disk = gpt_disk();
+        disk.corrupt(1, 60);
+        disk.corrupt(DISK_SECTORS - 1, 60);
+        assert_eq!(gpt_names(&mut disk), None);
+    }
+
+    /// MBR or EBR holding the entries, as (type, first sector, sector count)
+    fn mbr_sector(entries: &[(u8, u32, u32)]) -> Vec<u8> {
+        let mut sector = vec![0; usize::from(SECTOR_SIZE)];
+        for (i, (partition_type, lba_start, sector_count)) in entries.iter().enumerate() {
+            let entry = MBRPartition {
+                drive_attribute: 0,
+                chs_addr: [0; 3],
+                partition_type: *partition_type,
+                chs_end_addr: [0; 3],
+                lba_start: *lba_start,
+                sector_count: *sector_count,
+            };
+            let offset = MBR_ENTRIES_OFFSET + i * 16;
+            sector[offset..offset + 16].copy_from_slice(bytemuck::bytes_of(&entry));
+        }
+        let len = sector.len();
+        sector[len - MBR_SIGNATURE.len()..].copy_from_slice(&MBR_SIGNATURE);
+        sector
+    }
+    /// A primary partition, and an extended one from sector 20 with 2 logical partitions
+    fn mbr_disk() -> MemDisk {
+        let mut disk = MemDisk::new();
+        disk.write(0, &mbr_sector(&[(0x83, 2, 8), (0x05, 20, 60)]));
+        // The logical partition is relative to its EBR, the next EBR to the extended partition
+        disk.write(20, &mbr_sector(&[(0x83, 2, 10), (0x05, 20, 20)]));
+        disk.write(40, &mbr_sector(&[(0x0C, 4, 10)]));
+        disk
+    }
+    fn mbr_partitions(disk: &mut MemDisk) -> Vec<(u64, u64, u8)> {
+        let Some(HeaderType::MBR(partitions)) = parse_header_type(disk, &DISK, DISK_SECTORS) else {
+            panic!("no MBR found");
+        };
+        partitions
+            .iter()
+            .map(|part| match part.3 {
+                PartitionKind::Mbr { partition_type } => (part.1, part.2, partition_type),
+                _ => panic!("not a MBR partition"),
+            })
+            .collect()
+    }
+
+    #[test_case]
+    fn ebr_chain_is_followed() {
+        let mut disk = mbr_disk();
+        assert_eq!(mbr_partitions(&mut disk), vec![(2, 8, 0x83), (22, 10, 0x83), (44, 10, 0x0C)]);
+    }
+
+    #[test_case]
+    fn looping_ebr_chain_stops() {
+        let mut disk = mbr_disk();
+        // The last EBR points back to the first one
+        disk.write(40, &mbr_sector(&[(0x0C, 4, 10), (0x05, 0, 20)]));
+        assert_eq!(mbr_partitions(&mut disk), vec![(2, 8, 0x83), (22, 10, 0x83), (44, 10, 0x0C)]);
+    }
+
+    #[test_case]
+    fn broken_ebr_keeps_the_previous_partitions() {
+        let mut disk = mbr_disk();
+        disk.write(40, &[0; 512]);
+        assert_eq!(mbr_partitions(&mut disk), vec![(2, 8, 0x83), (22, 10, 0x83)]);
+    }
+}
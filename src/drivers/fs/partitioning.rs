@@ -0,0 +1,353 @@
+//! Creates and edits GPT / MBR partition tables
+//! Changes are only seen by the filesystem drivers after `FsDriverManager::rescan`
+use alloc::{vec, vec::Vec};
+
+use crate::disk::{
+    driver::{disk_sector_count, read_from_disk, sync_disk, write_to_disk, SECTOR_SIZE},
+    DiskError, DiskLoc,
+};
+
+use super::partition::{
+    crc32, load_gpt, mbr_entries, GPTHeader, GPTPartition, Guid, MBRPartition, Partition,
+    PartitionKind, EXTENDED_MBR_TYPES, GPT_SIGNATURE, MBR_ENTRIES_OFFSET, MBR_SIGNATURE,
+    PROTECTIVE_MBR_TYPE,
+};
+
+/// What most tools use, the entries take 32 sectors
+const GPT_ENTRIES_COUNT: u32 = 128;
+const GPT_REVISION: u32 = 0x0001_0000;
+/// Tells the BIOS to use the LBA fields of the MBR entry
+const MBR_CHS_UNUSED: [u8; 3] = [0xFE, 0xFF, 0xFF];
+
+#[derive(Debug)]
+pub enum PartitioningError {
+    Disk(DiskError),
+    /// The disk has no valid GPT or MBR
+    NoPartitionTable,
+    /// i.e. a GPT partition on a MBR disk
+    WrongPartitionTable,
+    TableFull,
+    /// Outside of the usable sectors of the disk, or empty
+    InvalidRange,
+    Overlap,
+    /// Logical MBR partitions can't be edited for now
+    NotFound,
+}
+impl From<DiskError> for PartitioningError {
+    fn from(value: DiskError) -> Self {
+        Self::Disk(value)
+    }
+}
+
+/// Writes an empty GPT, with a protective MBR and the backup header, erasing the previous partition table
+pub fn init_gpt(disk: &DiskLoc) -> Result<(), PartitioningError> {
+    let sector_count = disk_sector_count(disk)?;
+    let entries_len = GPT_ENTRIES_COUNT as usize * core::mem::size_of::<GPTPartition>();
+    // The GPT is written first, so a disk too small for it or a failed write leaves the old MBR in place
+    write_gpt(
+        disk,
+        Guid::random(),
+        core::mem::size_of::<GPTPartition>() as u32,
+        &vec![0; entries_len],
+    )?;
+    write_protective_mbr(disk, sector_count)
+}
+/// Writes an empty MBR, keeping the boot code, and erases the GPT headers if there were any
+pub fn init_mbr(disk: &DiskLoc) -> Result<(), PartitioningError> {
+    let sector_count = disk_sector_count(disk)?;
+    if sector_count == 0 {
+        return Err(PartitioningError::InvalidRange);
+    }
+    let mut mbr = read_from_disk(disk, 0, 1)?;
+    mbr[MBR_ENTRIES_OFFSET..].fill(0);
+    let len = mbr.len();
+    mbr[len - MBR_SIGNATURE.len()..].copy_from_slice(&MBR_SIGNATURE);
+    write_through(disk, 0, &mbr)?;
+    for lba in [1, sector_count - 1] {
+        if read_from_disk(disk, lba, 1)?.starts_with(&GPT_SIGNATURE) {
+            write_through(disk, lba, &vec![0; usize::from(SECTOR_SIZE)])?;
+        }
+    }
+    Ok(())
+}
+
+/// Adds the partition to the table of its disk, its kind must match the table
+/// On GPT a zero unique GUID is replaced by a random one
+pub fn add_partition(partition: &Partition) -> Result<(), PartitioningError> {
+    match &partition.3 {
+        PartitionKind::Gpt {
+            name,
+            type_guid,
+            unique_guid,
+        } => {
+            let mut gpt = Gpt::load(&partition.0)?;
+            gpt.check_range(partition.1, partition.2, None)?;
+            let slot = gpt
+                .entries()
+                .position(|entry| entry.part_type_guid.is_zero())
+                .ok_or(PartitioningError::TableFull)?;
+            let mut name_units = [0u8; 72];
+            for (unit, chunk) in name.encode_utf16().zip(name_units.chunks_exact_mut(2)) {
+                chunk.copy_from_slice(&unit.to_le_bytes());
+            }
+            let entry = GPTPartition {
+                part_type_guid: *type_guid,
+                unique_guid: if unique_guid.is_zero() { Guid::random() } else { *unique_guid },
+                start_lba: partition.1,
+                end_lba: partition.end() - 1,
+                attributes: 0,
+                name: name_units,
+            };
+            gpt.set_entry(slot, &entry);
+            gpt.write(&partition.0)
+        }
+        PartitionKind::Mbr { partition_type } => {
+            let mut mbr = Mbr::load(&partition.0)?;
+            mbr.check_range(partition.1, partition.2, None)?;
+            let slot = mbr
+                .entries
+                .iter()
+                .position(|entry| entry.partition_type == 0)
+                .ok_or(PartitioningError::TableFull)?;
+            mbr.entries[slot] = MBRPartition {
+                drive_attribute: 0,
+                chs_addr: MBR_CHS_UNUSED,
+                partition_type: *partition_type,
+                chs_end_addr: MBR_CHS_UNUSED,
+                // Safe casts, check_range made sure they fit
+                lba_start: partition.1 as u32,
+                sector_count: partition.2 as u32,
+            };
+            mbr.write(&partition.0)
+        }
+        PartitionKind::Whole => Err(PartitioningError::NoPartitionTable),
+    }
+}
+pub fn delete_partition(partition: &Partition) -> Result<(), PartitioningError> {
+    match &partition.3 {
+        PartitionKind::Gpt { .. } => {
+            let mut gpt = Gpt::load(&partition.0)?;
+            let slot = gpt.find(partition)?;
+            gpt.set_entry(slot, &bytemuck::Zeroable::zeroed());
+            gpt.write(&partition.0)
+        }
+        PartitionKind::Mbr { .. } => {
+            let mut mbr = Mbr::load(&partition.0)?;
+            let slot = mbr.find(partition)?;
+            mbr.entries[slot] = bytemuck::Zeroable::zeroed();
+            mbr.write(&partition.0)
+        }
+        PartitionKind::Whole => Err(PartitioningError::NoPartitionTable),
+    }
+}
+/// Only changes the partition table, the filesystem on the partition isn't resized
+pub fn resize_partition(partition: &Partition, sector_count: u64) -> Result<(), PartitioningError> {
+    match &partition.3 {
+        PartitionKind::Gpt { .. } => {
+            let mut gpt = Gpt::load(&partition.0)?;
+            let slot = gpt.find(partition)?;
+            gpt.check_range(partition.1, sector_count, Some(slot))?;
+            let mut entry = gpt.entry(slot);
+            entry.end_lba = partition.1 + sector_count - 1;
+            gpt.set_entry(slot, &entry);
+            gpt.write(&partition.0)
+        }
+        PartitionKind::Mbr { .. } => {
+            let mut mbr = Mbr::load(&partition.0)?;
+            let slot = mbr.find(partition)?;
+            mbr.check_range(partition.1, sector_count, Some(slot))?;
+            // Safe cast, check_range made sure it fits
+            mbr.entries[slot].sector_count = sector_count as u32;
+            mbr.write(&partition.0)
+        }
+        PartitionKind::Whole => Err(PartitioningError::NoPartitionTable),
+    }
+}
+
+/// Partition table being edited
+struct Gpt {
+    header: GPTHeader,
+    entries: Vec<u8>,
+}
+impl Gpt {
+    fn load(disk: &DiskLoc) -> Result<Self, PartitioningError> {
+        let (header, entries) = load_gpt(disk).ok_or(PartitioningError::NoPartitionTable)?;
+        Ok(Self { header, entries })
+    }
+    fn entry_size(&self) -> usize {
+        self.header.partition_entry_size as usize
+    }
+    fn entry(&self, slot: usize) -> GPTPartition {
+        let start = slot * self.entry_size();
+        bytemuck::pod_read_unaligned(&self.entries[start..start + core::mem::size_of::<GPTPartition>()])
+    }
+    fn entries(&self) -> impl Iterator<Item = GPTPartition> + '_ {
+        (0..self.header.partition_entries_count as usize).map(|slot| self.entry(slot))
+    }
+    fn set_entry(&mut self, slot: usize, entry: &GPTPartition) {
+        let entry_size = self.entry_size();
+        let start = slot * entry_size;
+        self.entries[start..start + entry_size].fill(0);
+        self.entries[start..start + core::mem::size_of::<GPTPartition>()]
+            .copy_from_slice(bytemuck::bytes_of(entry));
+    }
+    fn find(&self, partition: &Partition) -> Result<usize, PartitioningError> {
+        self.entries()
+            .position(|entry| !entry.part_type_guid.is_zero() && entry.start_lba == partition.1)
+            .ok_or(PartitioningError::NotFound)
+    }
+    /// `ignored` is the slot of the partition being resized
+    fn check_range(&self, start: u64, sector_count: u64, ignored: Option<usize>) -> Result<(), PartitioningError> {
+        let first_usable = self.header.first_usable_lba;
+        let last_usable = self.header.last_usable_lba;
+        if sector_count == 0 || start < first_usable || start + sector_count - 1 > last_usable {
+            return Err(PartitioningError::InvalidRange);
+        }
+        for (slot, entry) in self.entries().enumerate() {
+            if Some(slot) == ignored || entry.part_type_guid.is_zero() {
+                continue;
+            }
+            if start <= entry.end_lba && entry.start_lba < start + sector_count {
+                return Err(PartitioningError::Overlap);
+            }
+        }
+        Ok(())
+    }
+    fn write(&self, disk: &DiskLoc) -> Result<(), PartitioningError> {
+        write_gpt(disk, self.header.disk_guid, self.header.partition_entry_size, &self.entries)
+    }
+}
+
+/// Primary entries of a MBR being edited, empty entries included
+struct Mbr {
+    sector: Vec<u8>,
+    entries: [MBRPartition; 4],
+    sector_count: u64,
+}
+impl Mbr {
+    fn load(disk: &DiskLoc) -> Result<Self, PartitioningError> {
+        let sector = read_from_disk(disk, 0, 1)?;
+        if sector[sector.len() - MBR_SIGNATURE.len()..] != MBR_SIGNATURE {
+            return Err(PartitioningError::NoPartitionTable);
+        }
+        if mbr_entries(&sector)
+            .iter()
+            .any(|entry| entry.partition_type == PROTECTIVE_MBR_TYPE)
+        {
+            return Err(PartitioningError::WrongPartitionTable);
+        }
+        let mut entries: [MBRPartition; 4] = bytemuck::Zeroable::zeroed();
+        for (entry, raw) in entries
+            .iter_mut()
+            .zip(sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * 16].chunks_exact(16))
+        {
+            *entry = bytemuck::pod_read_unaligned(raw);
+        }
+        Ok(Self {
+            sector,
+            entries,
+            sector_count: disk_sector_count(disk)?,
+        })
+    }
+    /// Logical partitions aren't in the primary entries so they aren't found
+    fn find(&self, partition: &Partition) -> Result<usize, PartitioningError> {
+        self.entries
+            .iter()
+            .position(|entry| {
+                entry.partition_type != 0
+                    && !EXTENDED_MBR_TYPES.contains(&entry.partition_type)
+                    && u64::from(entry.lba_start) == partition.1
+            })
+            .ok_or(PartitioningError::NotFound)
+    }
+    fn check_range(&self, start: u64, sector_count: u64, ignored: Option<usize>) -> Result<(), PartitioningError> {
+        let end = start + sector_count;
+        if sector_count == 0 || start == 0 || end > self.sector_count || end > u64::from(u32::MAX) {
+            return Err(PartitioningError::InvalidRange);
+        }
+        for (slot, entry) in self.entries.iter().enumerate() {
+            if Some(slot) == ignored || entry.partition_type == 0 {
+                continue;
+            }
+            let entry_start = u64::from(entry.lba_start);
+            if start < entry_start + u64::from(entry.sector_count) && entry_start < end {
+                return Err(PartitioningError::Overlap);
+            }
+        }
+        Ok(())
+    }
+    fn write(&mut self, disk: &DiskLoc) -> Result<(), PartitioningError> {
+        for (raw, entry) in self.sector[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 4 * 16]
+            .chunks_exact_mut(16)
+            .zip(&self.entries)
+        {
+            raw.copy_from_slice(bytemuck::bytes_of(entry));
+        }
+        write_through(disk, 0, &self.sector)
+    }
+}
+
+/// A single partition covering the whole disk, so GPT unaware tools don't think it's empty
+fn write_protective_mbr(disk: &DiskLoc, sector_count: u64) -> Result<(), PartitioningError> {
+    let mut mbr = read_from_disk(disk, 0, 1)?;
+    mbr[MBR_ENTRIES_OFFSET..].fill(0);
+    let entry = MBRPartition {
+        drive_attribute: 0,
+        chs_addr: [0, 2, 0],
+        partition_type: PROTECTIVE_MBR_TYPE,
+        chs_end_addr: MBR_CHS_UNUSED,
+        lba_start: 1,
+        sector_count: u32::try_from(sector_count - 1).unwrap_or(u32::MAX),
+    };
+    mbr[MBR_ENTRIES_OFFSET..MBR_ENTRIES_OFFSET + 16].copy_from_slice(bytemuck::bytes_of(&entry));
+    let len = mbr.len();
+    mbr[len - MBR_SIGNATURE.len()..].copy_from_slice(&MBR_SIGNATURE);
+    write_through(disk, 0, &mbr)
+}
+
+/// The cache writes sectors back sorted, so the writes that must be done in order are synced one by one
+fn write_through(disk: &DiskLoc, lba: u64, content: &[u8]) -> Result<(), PartitioningError> {
+    write_to_disk(disk, lba, content)?;
+    Ok(sync_disk(disk)?)
+}
+
+/// Writes the primary header and entries at the start of the disk, and the backup ones at its end
+fn write_gpt(disk: &DiskLoc, disk_guid: Guid, entry_size: u32, entries: &[u8]) -> Result<(), PartitioningError> {
+    let sector_size = u64::from(SECTOR_SIZE);
+    let sector_count = disk_sector_count(disk)?;
+    let entries_sectors = (entries.len() as u64).div_ceil(sector_size);
+    // MBR, 2 headers and 2 copies of the entries
+    if sector_count <= 3 + 2 * entries_sectors {
+        return Err(PartitioningError::InvalidRange);
+    }
+    let last_lba = sector_count - 1;
+    let mut header = GPTHeader {
+        signature: GPT_SIGNATURE,
+        revision: GPT_REVISION,
+        header_size: core::mem::size_of::<GPTHeader>() as u32,
+        header_crc32: 0,
+        reserved: 0,
+        my_lba: 1,
+        alternate_lba: last_lba,
+        first_usable_lba: 2 + entries_sectors,
+        last_usable_lba: last_lba - 1 - entries_sectors,
+        disk_guid,
+        partition_entries_lba: 2,
+        partition_entries_count: (entries.len() / entry_size as usize) as u32,
+        partition_entry_size: entry_size,
+        partition_entries_crc32: crc32(entries),
+    };
+    let mut backup = header;
+    backup.my_lba = last_lba;
+    backup.alternate_lba = 1;
+    backup.partition_entries_lba = last_lba - entries_sectors;
+    // The backup is written first, so the previous primary GPT stays valid if writing it fails
+    for header in [&mut backup, &mut header] {
+        header.header_crc32 = crc32(bytemuck::bytes_of(header));
+        let mut sector = vec![0; usize::from(SECTOR_SIZE)];
+        sector[..core::mem::size_of::<GPTHeader>()].copy_from_slice(bytemuck::bytes_of(header));
+        write_through(disk, header.partition_entries_lba, entries)?;
+        write_through(disk, header.my_lba, &sector)?;
+    }
+    Ok(())
+}
@@ -6,7 +6,7 @@ use alloc::{
 
 use crate::{disk::DiskLoc, fs_driver};
 
-use super::{partition::Partition, userland::FatAttributes};
+use super::{userland::FatAttributes, vfs::Volume};
 
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Debug)]
 pub enum FileSystemError {
@@ -17,16 +17,16 @@ pub enum FileSystemError {
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Hash, Debug)]
 pub struct FilePath {
     raw_path: String,
-    pub partition: Partition,
+    pub volume: Volume,
 }
 impl FilePath {
-    #[must_use] pub fn new(mut full_path: String, partition: Partition) -> Self {
+    #[must_use] pub fn new(mut full_path: String, volume: impl Into<Volume>) -> Self {
         if !full_path.starts_with('/') {
             full_path.insert(0, '/');
         }
         Self {
             raw_path: full_path.replace('\u{ffff}', ""),
-            partition,
+            volume: volume.into(),
         }
     }
     #[allow(clippy::len_without_is_empty)]
@@ -43,23 +43,23 @@ impl FilePath {
     }
     /// Creates a new filepath poiting to parent
     #[must_use] pub fn parent(&self) -> FilePath {
-        let splitted: Vec<&str> = self.raw_path.split('/').collect();
+        let splitted: Vec<&str> = self.raw_path.trim_end_matches('/').split('/').collect();
         FilePath::new(
-            splitted[0..splitted.len() - 2].join("/"),
-            self.partition.clone(),
+            splitted[0..splitted.len().saturating_sub(1)].join("/"),
+            self.volume.clone(),
         )
     }
     #[must_use] pub fn path(&self) -> &String {
         &self.raw_path
     }
-    // Both paths must be on same partition !
+    // Both paths must be on same volume !
     #[must_use] pub fn join(&self, other_path: FilePath) -> FilePath {
         let mut path = self.raw_path.clone();
-        assert_eq!(self.partition, other_path.partition);
+        assert_eq!(self.volume, other_path.volume);
         path.push_str(other_path.path());
         Self::new(
             path.replace("//", "/").replace('\\', "/"),
-            self.partition.clone(),
+            self.volume.clone(),
         )
     }
     //TODO Return new or mutate self ?
@@ -67,11 +67,15 @@ impl FilePath {
         let path = &format!("{}/{}", self.path(), other_path);
         Self::new(
             path.replace("//", "/").replace('\\', "/"),
-            self.partition.clone(),
+            self.volume.clone(),
         )
     }
-    #[must_use] pub fn disk_loc(&self) -> DiskLoc {
-        self.partition.0
+    /// None for files that aren't on a disk
+    #[must_use] pub fn disk_loc(&self) -> Option<DiskLoc> {
+        match &self.volume {
+            Volume::Partition(partition) => Some(partition.0),
+            Volume::Memory(_) => None,
+        }
     }
     #[must_use] pub fn name(&self) -> &str {
         return self.raw_path.split('/').last().unwrap()
@@ -0,0 +1,243 @@
+//! Synthetic filesystem mounted on /proc, its files are generated from the kernel state when they're read
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::{fmt::Write, sync::atomic::Ordering};
+
+use raw_cpuid::CpuId;
+
+use crate::{
+    acpi::tables::madt::ApicRecord,
+    interrupts::hardware::{InterruptIndex, IRQ_COUNTS},
+    memory::allocator::{heap_used, HEAP_SIZE},
+};
+
+use super::{
+    fs_driver::{Dir, Entry, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError, SoftEntry},
+    partition::{Partition, PartitionKind},
+    path::FilePath,
+    vfs::Volume,
+};
+
+/// Name and generator of each file, they're all at the root
+const FILES: &[(&str, fn() -> String)] = &[
+    ("meminfo", meminfo),
+    ("cpuinfo", cpuinfo),
+    #[cfg(feature = "pci-ids")]
+    ("pci", pci),
+    ("partitions", partitions),
+    ("uptime", uptime),
+    ("interrupts", interrupts),
+    ("tasks", tasks),
+];
+
+/// Physical memory from the frame allocator, and the kernel heap, in Kb
+fn meminfo() -> String {
+    let (frames, used_frames) = unsafe { crate::state::MEM_HANDLER.as_ref() }
+        .map_or((0, 0), |handler| handler.frame_allocator.usage());
+    let heap_used = heap_used();
+    format!(
+        "MemTotal: {} kB\nMemUsed: {} kB\nMemFree: {} kB\nHeapTotal: {} kB\nHeapUsed: {} kB\nHeapFree: {} kB\n",
+        frames * 4,
+        used_frames * 4,
+        (frames - used_frames) * 4,
+        HEAP_SIZE / 1024,
+        heap_used / 1024,
+        HEAP_SIZE.saturating_sub(heap_used) / 1024,
+    )
+}
+
+/// CPUID of the current core, and the cores listed in the MADT
+fn cpuinfo() -> String {
+    let cpuid = CpuId::new();
+    let mut info = String::new();
+    if let Some(vendor) = cpuid.get_vendor_info() {
+        writeln!(info, "vendor: {}", vendor.as_str()).unwrap();
+    }
+    if let Some(brand) = cpuid.get_processor_brand_string() {
+        writeln!(info, "brand: {}", brand.as_str().trim()).unwrap();
+    }
+    if let Some(features) = cpuid.get_feature_info() {
+        writeln!(
+            info,
+            "family: {} model: {} stepping: {}",
+            features.family_id(),
+            features.model_id(),
+            features.stepping_id()
+        )
+        .unwrap();
+    }
+    // Virtual machines usually report 0
+    if let Some(freq) = cpuid
+        .get_processor_frequency_info()
+        .filter(|freq| freq.processor_base_frequency() != 0)
+    {
+        writeln!(info, "frequency: {} MHz", freq.processor_base_frequency()).unwrap();
+    }
+    let Some(tables) = (unsafe { crate::state::DESCRIPTOR_TABLES.as_ref() }) else {
+        return info;
+    };
+    writeln!(info, "cores: {}", tables.num_core()).unwrap();
+    let mut core_idx = 0;
+    for record in &tables.madt.fields {
+        if let ApicRecord::ProcLocalAPIC(core) = record {
+            let flags = core.flags;
+            let state = if flags & 1 != 0 {
+                "enabled"
+            } else if flags & 2 != 0 {
+                "online capable"
+            } else {
+                "disabled"
+            };
+            writeln!(
+                info,
+                "core {}: processor id {} apic id {} {}",
+                core_idx, core.acpi_proc_id, core.apic_id, state
+            )
+            .unwrap();
+            core_idx += 1;
+        }
+    }
+    info
+}
+
+/// Same as lspci --class
+#[cfg(feature = "pci-ids")]
+fn pci() -> String {
+    let Some(manager) = (unsafe { crate::drivers::pci::MANAGER.as_ref() }) else {
+        return String::new();
+    };
+    let mut devices: Vec<_> = manager.values().collect();
+    devices.sort_by_key(|device| {
+        let loc = device.location();
+        (loc.bus(), loc.slot(), loc.function())
+    });
+    devices
+        .iter()
+        .map(|device| format!("{} - {}\n", device, device.display_classes()))
+        .collect()
+}
+
+/// One line per partition: name, first sector, sector count, type and filesystem
+fn partitions() -> String {
+    let Some(manager) = (unsafe { crate::state::FS_DRIVER.as_ref() }) else {
+        return String::new();
+    };
+    let mut locs: Vec<_> = manager.partitions.keys().collect();
+    locs.sort_by_key(|loc| loc.as_index());
+    let mut info = String::new();
+    for loc in locs {
+        for (part_idx, part) in manager.partitions[loc].iter().enumerate() {
+            let kind = match &part.3 {
+                PartitionKind::Gpt { type_guid, .. } => type_guid
+                    .type_name()
+                    .map_or_else(|| type_guid.to_string(), ToString::to_string),
+                PartitionKind::Mbr { partition_type } => format!("{partition_type:#04x}"),
+                PartitionKind::Whole => "whole disk".to_string(),
+            };
+            let fs = manager
+                .drivers
+                .get(&Volume::Partition(part.clone()))
+                .map_or_else(|| "-".to_string(), |driver| driver.as_enum().to_string());
+            writeln!(
+                info,
+                "disk{}p{} {} {} {} {}",
+                loc.as_index(),
+                part_idx,
+                part.1,
+                part.2,
+                kind,
+                fs
+            )
+            .unwrap();
+        }
+    }
+    info
+}
+
+/// Seconds since the PIT was initialised
+fn uptime() -> String {
+    let millis = crate::time::elapsed_millis();
+    format!("{}.{:03}\n", millis / 1000, millis % 1000)
+}
+
+/// Amount of times each IRQ was handled
+fn interrupts() -> String {
+    (0..IRQ_COUNTS.len() as u8)
+        .filter_map(|irq| InterruptIndex::from_num_pic(irq).map(|idx| (irq, idx)))
+        .map(|(irq, idx)| {
+            format!("{:>2}: {:>10} {:?}\n", irq, IRQ_COUNTS[irq as usize].load(Ordering::Relaxed), idx)
+        })
+        .collect()
+}
+
+/// Ids of the tasks that are running on the executor
+fn tasks() -> String {
+    crate::task::running_tasks()
+        .iter()
+        .map(|id| format!("{id}\n"))
+        .collect()
+}
+
+#[derive(Debug)]
+pub struct ProcfsDriver {
+    volume: Volume,
+}
+impl ProcfsDriver {
+    #[must_use] pub fn new(volume: Volume) -> Self {
+        Self { volume }
+    }
+    fn generate(path: &FilePath) -> Result<String, FsReadError> {
+        let name = path.path().trim_start_matches('/');
+        FILES
+            .iter()
+            .find(|(file_name, _)| *file_name == name)
+            .map(|(_, generator)| generator())
+            .ok_or(FsReadError::EntryNotFound)
+    }
+}
+
+impl FsDriverInitialiser for ProcfsDriver {
+    /// Procfs isn't stored on partitions, see `FsDriverManager::mount_procfs`
+    fn try_init(_partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl FsDriver for ProcfsDriver {
+    fn as_enum(&self) -> FsDriverEnum {
+        FsDriverEnum::Procfs
+    }
+    fn partition(&self) -> Option<&Partition> {
+        None
+    }
+    fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
+        if path.path() == "/" {
+            // The size of the files isn't known until they're generated
+            let entries: Vec<SoftEntry> = FILES
+                .iter()
+                .map(|(name, _)| SoftEntry {
+                    path: FilePath::new(format!("/{name}"), self.volume.clone()),
+                    size: 0,
+                })
+                .collect();
+            return Ok(Entry::Dir(Dir {
+                path: path.clone(),
+                size: entries.len(),
+                entries,
+            }));
+        }
+        let content = Self::generate(path)?.into_bytes();
+        Ok(Entry::File(File {
+            path: path.clone(),
+            size: content.len(),
+            content,
+        }))
+    }
+}
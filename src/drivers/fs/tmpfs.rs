@@ -0,0 +1,351 @@
+//! Filesystem stored in memory, its content is lost when it's unmounted
+use alloc::{
+    boxed::Box,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+use hashbrown::HashMap;
+
+use crate::time::unix_time;
+
+use super::{
+    fs_driver::{
+        Dir, Entry, EntryKind, File, FsDriver, FsDriverEnum, FsDriverInitialiser, FsReadError,
+        FsWriteError, Metadata, SoftEntry,
+    },
+    partition::Partition,
+    path::FilePath,
+    vfs::Volume,
+};
+
+#[derive(Debug, Clone)]
+pub enum TmpfsNode {
+    File(Vec<u8>),
+    /// Names of the entries of the directory
+    Dir(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct TmpfsEntry {
+    pub node: TmpfsNode,
+    /// Unix times
+    pub created: u64,
+    /// Changes when a file is written, or when entries are added to or removed from a directory
+    pub modified: u64,
+}
+impl TmpfsEntry {
+    fn new(node: TmpfsNode) -> Self {
+        let now = unix_time();
+        Self {
+            node,
+            created: now,
+            modified: now,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct TmpfsDriver {
+    volume: Volume,
+    files: HashMap<FilePath, TmpfsEntry>,
+    /// In bytes, only the content of the files is counted
+    max_size: usize,
+    used: usize,
+}
+impl TmpfsDriver {
+    #[must_use] pub fn new(volume: Volume, max_size: usize) -> Self {
+        let mut files = HashMap::new();
+        files.insert(
+            FilePath::new("/".to_string(), volume.clone()),
+            TmpfsEntry::new(TmpfsNode::Dir(Vec::new())),
+        );
+        Self {
+            volume,
+            files,
+            max_size,
+            used: 0,
+        }
+    }
+    /// Bytes used by the content of the files, and the maximum
+    #[must_use] pub fn usage(&self) -> (usize, usize) {
+        (self.used, self.max_size)
+    }
+    #[must_use] pub fn entry(&self, path: &FilePath) -> Option<&TmpfsEntry> {
+        self.files.get(path)
+    }
+    /// Files that are already stored are kept even if they go over the new maximum
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+    /// i.e. to keep the times of the files of an archive, does nothing if the entry doesn't exist
+    pub fn set_modified(&mut self, path: &FilePath, time: u64) {
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.modified = time;
+        }
+    }
+    /// Names of the entries of a directory
+    fn dir_mut(&mut self, path: &FilePath) -> Result<&mut Vec<String>, FsWriteError> {
+        let entry = self.files.get_mut(path).ok_or(FsWriteError::ParentNotFound)?;
+        entry.modified = unix_time();
+        match &mut entry.node {
+            TmpfsNode::Dir(names) => Ok(names),
+            TmpfsNode::File(_) => Err(FsWriteError::NotADir),
+        }
+    }
+    fn file_mut(&mut self, path: &FilePath) -> Result<&mut Vec<u8>, FsWriteError> {
+        let entry = self.files.get_mut(path).ok_or(FsWriteError::EntryNotFound)?;
+        entry.modified = unix_time();
+        match &mut entry.node {
+            TmpfsNode::File(content) => Ok(content),
+            TmpfsNode::Dir(_) => Err(FsWriteError::IsADir),
+        }
+    }
+    /// Checks that the content of a file can go from old_len to new_len bytes
+    fn reserve(&mut self, old_len: usize, new_len: usize) -> Result<(), FsWriteError> {
+        let used = self.used - old_len + new_len;
+        if new_len > old_len && used > self.max_size {
+            return Err(FsWriteError::NoSpaceLeft);
+        }
+        self.used = used;
+        Ok(())
+    }
+    /// Adds a new entry to its parent directory
+    fn insert(&mut self, path: &FilePath, node: TmpfsNode) -> Result<(), FsWriteError> {
+        check_name(path.name())?;
+        if self.files.contains_key(path) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        self.dir_mut(&path.parent())?.push(path.name().to_string());
+        self.files.insert(path.clone(), TmpfsEntry::new(node));
+        Ok(())
+    }
+}
+
+impl FsDriverInitialiser for TmpfsDriver {
+    /// A tmpfs isn't stored on partitions, see `FsDriverManager::mount_tmpfs`
+    fn try_init(_partition: &Partition) -> Option<Box<Self>>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+impl FsDriver for TmpfsDriver {
+    fn as_enum(&self) -> FsDriverEnum {
+        FsDriverEnum::Tmpfs
+    }
+    fn partition(&self) -> Option<&Partition> {
+        None
+    }
+    fn read(&self, path: &FilePath) -> Result<Entry, FsReadError> {
+        match &self.files.get(path).ok_or(FsReadError::EntryNotFound)?.node {
+            TmpfsNode::File(content) => Ok(Entry::File(File {
+                path: path.clone(),
+                content: content.clone(),
+                size: content.len(),
+            })),
+            TmpfsNode::Dir(names) => {
+                let entries: Vec<SoftEntry> = names
+                    .iter()
+                    .map(|name| {
+                        let child = path.join_str(name.clone());
+                        let size = match self.files.get(&child).map(|entry| &entry.node) {
+                            Some(TmpfsNode::File(content)) => content.len(),
+                            _ => 0,
+                        };
+                        SoftEntry { path: child, size }
+                    })
+                    .collect();
+                Ok(Entry::Dir(Dir {
+                    path: path.clone(),
+                    size: entries.len(),
+                    entries,
+                }))
+            }
+        }
+    }
+    /// Accesses aren't tracked, the access time is the modification time
+    fn stat(&self, path: &FilePath) -> Result<Metadata, FsReadError> {
+        let entry = self.files.get(path).ok_or(FsReadError::EntryNotFound)?;
+        let mut metadata = match &entry.node {
+            TmpfsNode::File(content) => Metadata::new(EntryKind::File, content.len() as u64),
+            TmpfsNode::Dir(names) => Metadata::new(EntryKind::Dir, names.len() as u64),
+        };
+        metadata.created = entry.created;
+        metadata.modified = entry.modified;
+        metadata.accessed = entry.modified;
+        Ok(metadata)
+    }
+    fn file_len(&self, path: &FilePath) -> Result<u64, FsReadError> {
+        match &self.files.get(path).ok_or(FsReadError::EntryNotFound)?.node {
+            TmpfsNode::File(content) => Ok(content.len() as u64),
+            TmpfsNode::Dir(_) => Err(FsReadError::EntryNotFound),
+        }
+    }
+    fn read_at(&self, path: &FilePath, offset: u64, buf: &mut [u8]) -> Result<usize, FsReadError> {
+        let TmpfsNode::File(content) = &self.files.get(path).ok_or(FsReadError::EntryNotFound)?.node else {
+            return Err(FsReadError::EntryNotFound);
+        };
+        let start = (offset as usize).min(content.len());
+        let read = buf.len().min(content.len() - start);
+        buf[..read].copy_from_slice(&content[start..start + read]);
+        Ok(read)
+    }
+    fn write_at(&mut self, path: &FilePath, offset: u64, data: &[u8]) -> Result<usize, FsWriteError> {
+        let end = offset as usize + data.len();
+        let old_len = self.file_mut(path)?.len();
+        self.reserve(old_len, old_len.max(end))?;
+        let content = self.file_mut(path)?;
+        if content.len() < end {
+            content.resize(end, 0);
+        }
+        content[offset as usize..end].copy_from_slice(data);
+        Ok(data.len())
+    }
+    fn write_file(&mut self, filepath: &FilePath, content: &[u8]) -> Result<(), FsWriteError> {
+        let old_len = match self.files.get(filepath).map(|entry| &entry.node) {
+            Some(TmpfsNode::File(old)) => old.len(),
+            Some(TmpfsNode::Dir(_)) => return Err(FsWriteError::IsADir),
+            None => {
+                self.reserve(0, content.len())?;
+                if let Err(err) = self.insert(filepath, TmpfsNode::File(content.to_vec())) {
+                    self.used -= content.len();
+                    return Err(err);
+                }
+                return Ok(());
+            }
+        };
+        self.reserve(old_len, content.len())?;
+        *self.file_mut(filepath)? = content.to_vec();
+        Ok(())
+    }
+    fn create_dir(&mut self, dirpath: &FilePath) -> Result<(), FsWriteError> {
+        self.insert(dirpath, TmpfsNode::Dir(Vec::new()))
+    }
+    fn remove(&mut self, path: &FilePath) -> Result<(), FsWriteError> {
+        if path.name().is_empty() {
+            // Can't remove root
+            return Err(FsWriteError::InvalidName);
+        }
+        match &self.files.get(path).ok_or(FsWriteError::EntryNotFound)?.node {
+            TmpfsNode::Dir(names) if !names.is_empty() => return Err(FsWriteError::DirNotEmpty),
+            TmpfsNode::Dir(_) => {}
+            TmpfsNode::File(content) => self.used -= content.len(),
+        }
+        self.files.remove(path);
+        self.dir_mut(&path.parent())?.retain(|name| name != path.name());
+        Ok(())
+    }
+    fn rename(&mut self, from: &FilePath, to: &FilePath) -> Result<(), FsWriteError> {
+        check_name(to.name())?;
+        if from.name().is_empty() || to.path().starts_with(&format!("{}/", from.path())) {
+            // Root can't be moved, and a dir can't be moved in itself
+            return Err(FsWriteError::InvalidName);
+        }
+        if !self.files.contains_key(from) {
+            return Err(FsWriteError::EntryNotFound);
+        }
+        if self.files.contains_key(to) {
+            return Err(FsWriteError::AlreadyExists);
+        }
+        self.dir_mut(&to.parent())?.push(to.name().to_string());
+        self.dir_mut(&from.parent())?.retain(|name| name != from.name());
+        // Move the entry and all of its children
+        let old_prefix = format!("{}/", from.path());
+        let moved: Vec<FilePath> = self
+            .files
+            .keys()
+            .filter(|path| *path == from || path.path().starts_with(&old_prefix))
+            .cloned()
+            .collect();
+        for old_path in moved {
+            // Safe unwrap, key comes from the map
+            let entry = self.files.remove(&old_path).unwrap();
+            let new_path = FilePath::new(
+                format!("{}{}", to.path(), &old_path.path()[from.path().len()..]),
+                self.volume.clone(),
+            );
+            self.files.insert(new_path, entry);
+        }
+        Ok(())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsWriteError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('\0') {
+        return Err(FsWriteError::InvalidName);
+    }
+    Ok(())
+}
+
+/// Used for the tmpfs mounted at boot, the kernel heap is small
+pub const DEFAULT_MAX_SIZE: usize = 256 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX_SIZE: usize = 10;
+
+    fn tmpfs() -> TmpfsDriver {
+        TmpfsDriver::new(Volume::Memory(0), MAX_SIZE)
+    }
+    fn path(path: &str) -> FilePath {
+        FilePath::new(path.to_string(), Volume::Memory(0))
+    }
+
+    #[test_case]
+    fn writes_over_max_size_are_refused() {
+        let mut tmpfs = tmpfs();
+        tmpfs.write_file(&path("/a"), &[1; 6]).unwrap();
+        assert!(matches!(tmpfs.write_file(&path("/b"), &[2; 5]), Err(FsWriteError::NoSpaceLeft)));
+        assert!(tmpfs.entry(&path("/b")).is_none());
+        assert_eq!(tmpfs.usage(), (6, MAX_SIZE));
+    }
+
+    #[test_case]
+    fn rewritten_file_only_counts_its_new_size() {
+        let mut tmpfs = tmpfs();
+        tmpfs.write_file(&path("/a"), &[1; 6]).unwrap();
+        tmpfs.write_file(&path("/a"), &[1; MAX_SIZE]).unwrap();
+        assert_eq!(tmpfs.usage(), (MAX_SIZE, MAX_SIZE));
+        tmpfs.write_file(&path("/a"), &[1; 4]).unwrap();
+        assert_eq!(tmpfs.usage(), (4, MAX_SIZE));
+    }
+
+    #[test_case]
+    fn write_at_past_max_size_is_refused() {
+        let mut tmpfs = tmpfs();
+        tmpfs.write_file(&path("/a"), &[1; 6]).unwrap();
+        assert!(matches!(tmpfs.write_at(&path("/a"), 8, &[2; 3]), Err(FsWriteError::NoSpaceLeft)));
+        assert_eq!(tmpfs.file_len(&path("/a")).unwrap(), 6);
+        assert_eq!(tmpfs.write_at(&path("/a"), 6, &[2; 4]).unwrap(), 4);
+        assert_eq!(tmpfs.usage(), (MAX_SIZE, MAX_SIZE));
+    }
+
+    #[test_case]
+    fn removed_and_failed_files_free_their_space() {
+        let mut tmpfs = tmpfs();
+        tmpfs.write_file(&path("/a"), &[1; MAX_SIZE]).unwrap();
+        tmpfs.remove(&path("/a")).unwrap();
+        assert_eq!(tmpfs.usage(), (0, MAX_SIZE));
+        assert!(matches!(
+            tmpfs.write_file(&path("/missing/b"), &[2; 5]),
+            Err(FsWriteError::ParentNotFound)
+        ));
+        assert_eq!(tmpfs.usage(), (0, MAX_SIZE));
+    }
+
+    #[test_case]
+    fn lowered_max_size_keeps_the_files() {
+        let mut tmpfs = tmpfs();
+        tmpfs.write_file(&path("/a"), &[1; 6]).unwrap();
+        tmpfs.set_max_size(4);
+        assert_eq!(tmpfs.file_len(&path("/a")).unwrap(), 6);
+        // Shrinking a file is allowed even over the maximum, growing it isn't
+        tmpfs.write_file(&path("/a"), &[1; 5]).unwrap();
+        assert!(matches!(tmpfs.write_file(&path("/a"), &[1; 6]), Err(FsWriteError::NoSpaceLeft)));
+    }
+}
@@ -3,34 +3,45 @@ use alloc::{
     vec::Vec,
 };
 
-use super::path::FilePath;
+use super::{
+    fat::{ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_HIDDEN, ATTR_READ_ONLY, ATTR_SYSTEM},
+    path::FilePath,
+};
 
 #[derive(Default, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 pub struct FatPermissions(pub u8);
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct FatGroup {
     pub group_name: String,
-    pub id: u16,
-    pub derived_groups: Vec<u16>,
+    pub id: u32,
+    pub derived_groups: Vec<u32>,
 }
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub struct FatUser {
     pub username: String,
-    pub id: u16,
-    pub groups: Vec<u16>,
+    pub id: u32,
+    pub groups: Vec<u32>,
 }
-#[must_use] pub fn get_group(id: u16) -> FatGroup {
+/// There is no user database yet, 0 is root and the others are named by their id
+#[must_use] pub fn get_group(id: u32) -> FatGroup {
     FatGroup {
-        group_name: "default".to_string(),
+        group_name: name_of(id),
         id,
         derived_groups: Vec::new(),
     }
 }
-#[must_use] pub fn get_user(id: u16) -> FatUser {
+#[must_use] pub fn get_user(id: u32) -> FatUser {
     FatUser {
-        username: "Sxmourai".to_string(),
+        username: name_of(id),
         id,
-        groups: alloc::vec![1],
+        groups: alloc::vec![id],
+    }
+}
+fn name_of(id: u32) -> String {
+    if id == 0 {
+        "root".to_string()
+    } else {
+        id.to_string()
     }
 }
 #[derive(PartialEq, Eq, Hash, Debug, Clone)]
@@ -39,7 +50,7 @@ pub enum FatPerson {
     User(FatUser),
 }
 impl FatPerson {
-    #[must_use] pub fn new(group: bool, id: u16) -> Self {
+    #[must_use] pub fn new(group: bool, id: u32) -> Self {
         if group {
             Self::Group(get_group(id))
         } else {
@@ -47,15 +58,26 @@ impl FatPerson {
         }
     }
 }
-#[derive(Default, Debug, Clone)] // Proper debug for attributes (flags in self.flags)
-pub struct FatAttributes {
-    // flags: u16,
-    // permissions: HashMap<FatPerson, FatPermissions>,
-}
+/// Attribute byte of a FAT directory entry
+#[derive(Default, Debug, Clone, Copy)]
+pub struct FatAttributes(pub u8);
 impl FatAttributes {
-    // pub fn permissions(&self, group: &FatPerson) -> Option<&FatPermissions> {
-    //     self.permissions.get(group)
-    // }
+    #[must_use] pub fn read_only(&self) -> bool {
+        self.0 & ATTR_READ_ONLY != 0
+    }
+    #[must_use] pub fn hidden(&self) -> bool {
+        self.0 & ATTR_HIDDEN != 0
+    }
+    #[must_use] pub fn system(&self) -> bool {
+        self.0 & ATTR_SYSTEM != 0
+    }
+    #[must_use] pub fn is_dir(&self) -> bool {
+        self.0 & ATTR_DIRECTORY != 0
+    }
+    /// Set when the file is written, for backup tools
+    #[must_use] pub fn archive(&self) -> bool {
+        self.0 & ATTR_ARCHIVE != 0
+    }
 }
 pub trait Fat32Element: core::fmt::Debug {
     fn path(&self) -> &FilePath;
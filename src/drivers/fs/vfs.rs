@@ -0,0 +1,341 @@
+//! Virtual filesystem, maps Unix absolute paths to the volumes mounted on them
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    format,
+    string::{String, ToString},
+    vec::Vec,
+};
+
+use super::{
+    devfs::DevfsDriver,
+    fs_driver::FsDriver, partition::Partition, path::FilePath, procfs::ProcfsDriver,
+    tmpfs::TmpfsDriver, FsDriverManager,
+};
+
+/// Following more symlinks than this while resolving a path is considered a loop
+const MAX_SYMLINK_FOLLOWS: usize = 40;
+
+#[derive(Debug)]
+pub enum VfsError {
+    InvalidPath,
+    NotMounted,
+    AlreadyMounted,
+    /// The volume doesn't have a filesystem driver
+    DriverNotFound,
+    SymlinkLoop,
+}
+
+/// What a filesystem is stored on
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Volume {
+    Partition(Partition),
+    /// A filesystem that isn't stored on a disk (tmpfs, procfs, devfs), they're numbered from 0 in creation order
+    Memory(u32),
+}
+impl From<Partition> for Volume {
+    fn from(value: Partition) -> Self {
+        Self::Partition(value)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Mount {
+    pub path: String,
+    pub volume: Volume,
+}
+
+#[derive(Debug, Default)]
+pub struct MountTable {
+    /// Sorted by path length, longest first, so the first match is the deepest mount point
+    mounts: Vec<Mount>,
+}
+impl MountTable {
+    pub fn mount(&mut self, path: &str, volume: Volume) -> Result<(), VfsError> {
+        let path = normalize(path)?;
+        if self.mounts.iter().any(|mount| mount.path == path) {
+            return Err(VfsError::AlreadyMounted);
+        }
+        let idx = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path.len() < path.len())
+            .unwrap_or(self.mounts.len());
+        self.mounts.insert(idx, Mount { path, volume });
+        Ok(())
+    }
+    pub fn umount(&mut self, path: &str) -> Result<Mount, VfsError> {
+        let path = normalize(path)?;
+        let idx = self
+            .mounts
+            .iter()
+            .position(|mount| mount.path == path)
+            .ok_or(VfsError::NotMounted)?;
+        Ok(self.mounts.remove(idx))
+    }
+    /// Removes all the mount points of the volume
+    pub fn umount_volume(&mut self, volume: &Volume) {
+        self.mounts.retain(|mount| mount.volume != *volume);
+    }
+    #[must_use] pub fn mounts(&self) -> &[Mount] {
+        &self.mounts
+    }
+    /// Finds the deepest mount point containing the (normalized) path
+    #[must_use] pub fn find(&self, path: &str) -> Option<&Mount> {
+        self.mounts
+            .iter()
+            .find(|mount| is_inside(path, &mount.path))
+    }
+    /// Names of the mount points (or the directories leading to them) directly inside dir
+    #[must_use] pub fn children(&self, dir: &str) -> Vec<String> {
+        let mut children = Vec::new();
+        for mount in &self.mounts {
+            if mount.path == dir || !is_inside(&mount.path, dir) {
+                continue;
+            }
+            let rest = mount.path[dir.len()..].trim_start_matches('/');
+            // Safe unwrap, split always returns at least one element
+            let child = rest.split('/').next().unwrap().to_string();
+            if !children.contains(&child) {
+                children.push(child);
+            }
+        }
+        children
+    }
+}
+
+/// True if path is mount_point or is inside of it
+fn is_inside(path: &str, mount_point: &str) -> bool {
+    mount_point == "/"
+        || path == mount_point
+        || (path.starts_with(mount_point) && path.as_bytes()[mount_point.len()] == b'/')
+}
+
+/// Resolves `.`, `..` and repeated slashes of an absolute path
+pub fn normalize(path: &str) -> Result<String, VfsError> {
+    if !path.starts_with('/') {
+        return Err(VfsError::InvalidPath);
+    }
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => {}
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    Ok(format!("/{}", components.join("/")))
+}
+
+impl FsDriverManager {
+    pub fn mount(&mut self, path: &str, volume: Volume) -> Result<(), VfsError> {
+        if !self.drivers.contains_key(&volume) {
+            return Err(VfsError::DriverNotFound);
+        }
+        self.mounts.mount(path, volume)
+    }
+    /// Volume for a new tmpfs, the ids of the unmounted ones aren't reused
+    pub fn new_memory_volume(&mut self) -> Volume {
+        let id = self.next_memory_id;
+        self.next_memory_id += 1;
+        Volume::Memory(id)
+    }
+    /// Binds the driver to the volume and mounts it, the driver is dropped if mounting fails
+    pub fn mount_driver(&mut self, path: &str, volume: Volume, driver: Box<dyn FsDriver>) -> Result<(), VfsError> {
+        self.drivers.insert(volume.clone(), driver);
+        if let Err(err) = self.mount(path, volume.clone()) {
+            self.drivers.remove(&volume);
+            return Err(err);
+        }
+        Ok(())
+    }
+    /// Creates an empty tmpfs and mounts it, max size is in bytes
+    pub fn mount_tmpfs(&mut self, path: &str, max_size: usize) -> Result<(), VfsError> {
+        let volume = self.new_memory_volume();
+        let driver = Box::new(TmpfsDriver::new(volume.clone(), max_size));
+        self.mount_driver(path, volume, driver)
+    }
+    /// Mounts the files generated from the kernel state, usually on /proc
+    pub fn mount_procfs(&mut self, path: &str) -> Result<(), VfsError> {
+        let volume = self.new_memory_volume();
+        let driver = Box::new(ProcfsDriver::new(volume.clone()));
+        self.mount_driver(path, volume, driver)
+    }
+    /// Mounts the device files, usually on /dev
+    pub fn mount_devfs(&mut self, path: &str) -> Result<(), VfsError> {
+        let volume = self.new_memory_volume();
+        let driver = Box::new(DevfsDriver::new(volume.clone()));
+        self.mount_driver(path, volume, driver)
+    }
+    /// The content of a tmpfs is dropped when its last mount point is removed
+    pub fn umount(&mut self, path: &str) -> Result<Mount, VfsError> {
+        let mount = self.mounts.umount(path)?;
+        if matches!(mount.volume, Volume::Memory(_))
+            && !self.mounts.mounts().iter().any(|other| other.volume == mount.volume)
+        {
+            self.drivers.remove(&mount.volume);
+        }
+        Ok(mount)
+    }
+    /// Maps a normalized path to the path on the mounted partition, without following symlinks
+    fn to_file_path(&self, path: &str) -> Result<FilePath, VfsError> {
+        let mount = self.mounts.find(path).ok_or(VfsError::NotMounted)?;
+        let inner = if mount.path == "/" {
+            path
+        } else {
+            &path[mount.path.len()..]
+        };
+        Ok(FilePath::new(inner.to_string(), mount.volume.clone()))
+    }
+    /// Resolves an absolute path to the partition it's on, following symlinks
+    pub fn resolve(&self, path: &str) -> Result<FilePath, VfsError> {
+        self.resolve_inner(path, true)
+    }
+    /// Same as `resolve`, but if the last component is a symlink the link itself is returned
+    /// Used to remove or rename links
+    pub fn resolve_no_follow(&self, path: &str) -> Result<FilePath, VfsError> {
+        self.resolve_inner(path, false)
+    }
+    /// Walks the path one component at a time, so that the .. after a symlink goes to the parent of its target
+    fn resolve_inner(&self, path: &str, follow_last: bool) -> Result<FilePath, VfsError> {
+        if !path.starts_with('/') {
+            return Err(VfsError::InvalidPath);
+        }
+        let mut components: VecDeque<String> = path
+            .split('/')
+            .filter(|component| !component.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        let mut resolved = String::new();
+        let mut follows = 0;
+        while let Some(component) = components.pop_front() {
+            match component.as_str() {
+                "." => continue,
+                ".." => {
+                    // Symlink targets can contain ..
+                    let parent_len = resolved.rfind('/').unwrap_or(0);
+                    resolved.truncate(parent_len);
+                    continue;
+                }
+                _ => {}
+            }
+            let candidate = format!("{resolved}/{component}");
+            if !follow_last && components.is_empty() {
+                resolved = candidate;
+                break;
+            }
+            let target = self
+                .to_file_path(&candidate)
+                .ok()
+                .and_then(|file_path| self.read_link(&file_path));
+            let Some(target) = target else {
+                resolved = candidate;
+                continue;
+            };
+            follows += 1;
+            if follows > MAX_SYMLINK_FOLLOWS {
+                return Err(VfsError::SymlinkLoop);
+            }
+            if target.starts_with('/') {
+                resolved.clear();
+            }
+            for target_component in target.split('/').filter(|c| !c.is_empty()).rev() {
+                components.push_front(target_component.to_string());
+            }
+        }
+        if resolved.is_empty() {
+            resolved.push('/');
+        }
+        self.to_file_path(&resolved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use hashbrown::HashMap;
+
+    use super::*;
+    use crate::fs::fs_driver::{Entry, FsDriverEnum, FsDriverInitialiser, FsReadError};
+
+    /// Only holds symlinks, as (path on the volume, target)
+    #[derive(Debug)]
+    struct LinkFs(Vec<(&'static str, &'static str)>);
+    impl FsDriverInitialiser for LinkFs {
+        fn try_init(_partition: &Partition) -> Option<Box<Self>> {
+            None
+        }
+    }
+    impl FsDriver for LinkFs {
+        fn read(&self, _path: &FilePath) -> Result<Entry, FsReadError> {
+            Err(FsReadError::EntryNotFound)
+        }
+        fn read_link(&self, path: &FilePath) -> Option<String> {
+            self.0
+                .iter()
+                .find(|(link, _)| FilePath::new(link.to_string(), path.volume.clone()) == *path)
+                .map(|(_, target)| target.to_string())
+        }
+        fn as_enum(&self) -> FsDriverEnum {
+            FsDriverEnum::Tmpfs
+        }
+        fn partition(&self) -> Option<&Partition> {
+            None
+        }
+    }
+
+    fn manager() -> FsDriverManager {
+        FsDriverManager {
+            drivers: HashMap::new(),
+            partitions: HashMap::new(),
+            mounts: MountTable::default(),
+            next_memory_id: 0,
+        }
+    }
+    /// Manager with the links mounted on /
+    fn links(links: Vec<(&'static str, &'static str)>) -> FsDriverManager {
+        let mut manager = manager();
+        let volume = manager.new_memory_volume();
+        manager.mount_driver("/", volume, Box::new(LinkFs(links))).unwrap();
+        manager
+    }
+    fn memory_path(path: &str, id: u32) -> FilePath {
+        FilePath::new(path.to_string(), Volume::Memory(id))
+    }
+
+    #[test_case]
+    fn normalize_resolves_dots_and_slashes() {
+        assert_eq!(normalize("/a/./b//c/../d/").unwrap(), "/a/b/d");
+        assert_eq!(normalize("/../..").unwrap(), "/");
+        assert!(matches!(normalize("a/b"), Err(VfsError::InvalidPath)));
+    }
+
+    #[test_case]
+    fn deepest_mount_is_used() {
+        let mut manager = manager();
+        manager.mount_tmpfs("/", 1024).unwrap();
+        manager.mount_tmpfs("/mnt", 1024).unwrap();
+        assert_eq!(manager.resolve("/mnt/file").unwrap(), memory_path("/file", 1));
+        assert_eq!(manager.resolve("/mnt").unwrap(), memory_path("/", 1));
+        assert_eq!(manager.resolve("/mnt2/file").unwrap(), memory_path("/mnt2/file", 0));
+        assert!(matches!(manager.mount_tmpfs("/mnt/", 1024), Err(VfsError::AlreadyMounted)));
+    }
+
+    #[test_case]
+    fn symlinks_are_followed() {
+        let manager = links(vec![("/abs", "/dir/target"), ("/rel", "dir/sub")]);
+        assert_eq!(manager.resolve("/abs/file").unwrap(), memory_path("/dir/target/file", 0));
+        // The .. goes to the parent of the target, not back to /
+        assert_eq!(manager.resolve("/rel/../file").unwrap(), memory_path("/dir/file", 0));
+        assert_eq!(manager.resolve_no_follow("/abs").unwrap(), memory_path("/abs", 0));
+    }
+
+    #[test_case]
+    fn symlink_loop_is_refused() {
+        let manager = links(vec![("/a", "/b"), ("/b", "a")]);
+        assert!(matches!(manager.resolve("/a"), Err(VfsError::SymlinkLoop)));
+        assert_eq!(manager.resolve_no_follow("/a").unwrap(), memory_path("/a", 0));
+    }
+}
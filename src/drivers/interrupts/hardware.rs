@@ -1,6 +1,7 @@
 use crate::{dbg, drivers::time, ps2, serial_print, sync::TimeOutRwLock};
 
 use alloc::boxed::Box;
+use core::sync::atomic::AtomicU64;
 use pic8259::ChainedPics;
 use spin::Mutex;
 use x86_64::{
@@ -19,6 +20,9 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Amount of times each IRQ was handled, indexed by `InterruptIndex`
+pub static IRQ_COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
 /// TODO Make a handler for all interrupts and setup a Vec for all interrupts, then we can bind at runtime some functions to be called when this interrupt occurs
 /// i.e. when a keyboard interrupt occurs, 3 functions are called, one for the kernel, one that will be passed to userland
 pub fn setup_hardware_interrupts(idt: &mut InterruptDescriptorTable) {
@@ -79,6 +83,8 @@ macro_rules! interrupt_handler {
         pub extern "x86-interrupt" fn _int(
             stack_frame: x86_64::structures::idt::InterruptStackFrame,
         ) {
+            $crate::interrupts::hardware::IRQ_COUNTS[$idx as usize]
+                .fetch_add(1, core::sync::atomic::Ordering::Relaxed);
             #[allow(clippy::redundant_closure_call)]
             $f(stack_frame);
             $crate::interrupts::hardware::notify_end_of_interrupt($idx);
@@ -39,6 +39,18 @@ impl FixedSizeBlockAllocator {
                 .init(&mut *(heap_start as *mut u8), heap_size);
         }
     }
+    /// Bytes allocated on the heap, the free blocks kept in the lists aren't counted
+    #[must_use] pub fn used(&self) -> usize {
+        let mut cached = 0;
+        for (head, block_size) in self.list_heads.iter().zip(BLOCK_SIZES) {
+            let mut node = head.as_deref();
+            while let Some(current) = node {
+                cached += block_size;
+                node = current.next.as_deref();
+            }
+        }
+        self.fallback_allocator.used().saturating_sub(cached)
+    }
     /// Allocates using the fallback allocator.
     fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.fallback_allocator.allocate_first_fit(layout) {
@@ -51,6 +51,11 @@ pub fn init_heap(mem_handler: &mut MemoryHandler) -> Result<(), MapToError<Size4
     Ok(())
 }
 
+/// Bytes used on the heap, out of `HEAP_SIZE`
+#[must_use] pub fn heap_used() -> usize {
+    ALLOCATOR.lock().used()
+}
+
 /// A wrapper around `spin::Mutex` to permit trait implementations.
 pub struct Locked<A> {
     inner: spin::Mutex<A>,
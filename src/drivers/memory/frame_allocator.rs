@@ -27,6 +27,16 @@ impl BootInfoFrameAllocator {
             next: 0,
         }
     }
+    /// Amount of usable frames in the memory map, and amount of them that were allocated
+    #[must_use] pub fn usage(&self) -> (usize, usize) {
+        let total = self
+            .memory_map
+            .iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| ((r.range.end_addr() - r.range.start_addr()) / 4096) as usize)
+            .sum();
+        (total, self.next.min(total))
+    }
     /// Returns an iterator over the usable frames specified in the memory map.
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
         // get usable regions from memory map
@@ -138,8 +138,8 @@ pub fn get_drivers() -> Vec<Driver> {
         // #[cfg(feature = "smp")]
         // ("multiprocessing (SMP)", super::smp::init),
         make_driver!(Userland, async { super::userland::go_ring3() }),
-        make_driver!(Shell, crate::shell::Shell::default().run_with_command("help".to_string()))
-        // make_driver!(Shell, crate::shell::Shell::default().run_with_command("exec 10/userland".to_string()))
+        make_driver!(Shell, crate::shell::Shell::default().run_init())
+        // make_driver!(Shell, crate::shell::Shell::default().run_with_command("exec /mnt/disk1p0/userland".to_string()))
         // make_driver!(Random, async{super::rand::init()}),
         // ("Network", super::network::init),
         // Don't need to init mouse driver cuz we don't have a use for it currently
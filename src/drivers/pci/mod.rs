@@ -511,7 +511,8 @@ impl RawPciDevice {
             return Err("BAR index must be between 0 and 5 inclusive");
         };
         if bar.get_bit(0) {
-            let base = bar.get_bits(2..);
+            // The 2 lowest bits aren't part of the address
+            let base = *bar.set_bits(0..2, 0);
             Ok(PciMemoryBase::IOSpace(base))
         } else {
             // Check bits [2:1] of the bar to determine address length (64-bit or 32-bit)
@@ -0,0 +1,74 @@
+//! A flag a task can sleep on until an interrupt handler or another task sets it
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+use spin::Mutex;
+
+/// Only one task waits on an event at a time, the last one to poll it is the one woken
+#[derive(Debug)]
+pub struct Event {
+    set: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Event {
+    #[must_use] pub const fn new() -> Self {
+        Self {
+            set: AtomicBool::new(false),
+            waker: Mutex::new(None),
+        }
+    }
+    #[must_use] pub fn is_set(&self) -> bool {
+        self.set.load(Ordering::Acquire)
+    }
+    pub fn reset(&self) {
+        self.set.store(false, Ordering::Release);
+    }
+    /// Can be called from an interrupt handler, as the waker is only locked with interrupts disabled
+    pub fn set(&self) {
+        self.set.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+    /// Resolves once the event is set, it stays set until `reset`
+    #[must_use] pub fn wait(&self) -> EventFuture<'_> {
+        EventFuture { event: self }
+    }
+    /// Same as `wait`, but also resolves once `done` returns true
+    /// For when someone else can do what the task waits for, the event may have been reset since
+    pub async fn wait_until(&self, done: impl Fn() -> bool) {
+        poll_fn(|cx| self.poll_wait(cx, &done)).await;
+    }
+    fn poll_wait(&self, cx: &mut Context<'_>, done: &dyn Fn() -> bool) -> Poll<()> {
+        if self.is_set() || done() {
+            return Poll::Ready(());
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            self.waker.lock().replace(cx.waker().clone());
+        });
+        // It might have been set before the waker was there
+        if self.is_set() || done() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+pub struct EventFuture<'a> {
+    event: &'a Event,
+}
+impl Future for EventFuture<'_> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        self.event.poll_wait(cx, &|| false)
+    }
+}
@@ -1,6 +1,6 @@
 use crate::dbg;
 
-use super::{Task, TaskId};
+use super::{Task, TaskId, RUNNING_TASKS};
 use alloc::task::Wake;
 use alloc::{collections::BTreeMap, sync::Arc};
 use core::task::Waker;
@@ -30,6 +30,7 @@ impl Executor {
     pub fn spawn(&mut self, task: Task) {
         let task_id = task.id;
         assert!(self.tasks.insert(task.id, task).is_none(), "task with same ID already in tasks");
+        RUNNING_TASKS.lock().insert(task_id);
         self.task_queue.push(task_id).expect("queue full");
     }
     pub fn run(&mut self) -> ! {
@@ -66,6 +67,7 @@ impl Executor {
                     // task done -> remove it and its cached waker
                     self.tasks.remove(&task_id);
                     self.waker_cache.remove(&task_id);
+                    RUNNING_TASKS.lock().remove(&task_id);
                 }
                 Poll::Pending => {}
             }
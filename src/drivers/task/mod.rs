@@ -1,8 +1,12 @@
-use alloc::boxed::Box;
+use alloc::{boxed::Box, collections::BTreeSet, vec::Vec};
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::task::{Context, Poll};
-use core::{future::Future, pin::Pin};
+use core::{
+    future::{poll_fn, Future},
+    pin::Pin,
+};
 
+pub mod event;
 pub mod executor;
 pub mod keyboard;
 pub mod simple_executor;
@@ -16,6 +20,26 @@ impl TaskId {
     }
 }
 
+/// Ids of the tasks spawned on the executor that aren't finished
+static RUNNING_TASKS: spin::Mutex<BTreeSet<TaskId>> = spin::Mutex::new(BTreeSet::new());
+#[must_use] pub fn running_tasks() -> Vec<u64> {
+    RUNNING_TASKS.lock().iter().map(|id| id.0).collect()
+}
+
+/// Lets the other ready tasks run before the task continues
+pub async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await;
+}
+
 pub struct Task {
     id: TaskId,
     future: Pin<Box<dyn Future<Output = ()>>>,
@@ -1,4 +1,8 @@
-use core::{fmt::Display, time::Duration};
+use core::{
+    fmt::Display,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
 
 use spin::Mutex;
 use x86_64::instructions::{hlt, port::Port};
@@ -6,6 +10,8 @@ use x86_64::instructions::{hlt, port::Port};
 use crate::serial_println;
 
 static ELAPSED_TICKS_SINCE_BOOT: Mutex<usize> = Mutex::new(0);
+/// Unix time read when booting, 0 until `init`
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
 // static DATE: Mutex<usize> = Mutex::new(0);
 
 pub struct Date {
@@ -20,6 +26,18 @@ impl Date {
     // pub fn as_ticks(&self) -> usize {
     //     // (self.seconds as usize*100)+(self.minutes as usize*100*60)+(self.hours as usize*100*3600)+(self.days as usize*100*3600*24)+(self.months as usize*100*3600*24*30)+(self.years as usize*100*3600*24*365)
     // }
+    /// Seconds since 1970, the century register isn't read so years are after 2000
+    #[must_use] pub fn as_unix_time(&self) -> u64 {
+        let days = super::days_from_civil(
+            2000 + u64::from(self.years),
+            u64::from(self.months),
+            u64::from(self.days),
+        );
+        days * 86400
+            + u64::from(self.hours) * 3600
+            + u64::from(self.minutes) * 60
+            + u64::from(self.seconds)
+    }
 }
 impl Display for Date {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -97,8 +115,12 @@ pub fn init() {
             years,
         };
         log::trace!("Current time is {}", date);
+        BOOT_TIME.store(date.as_unix_time(), Ordering::Relaxed);
     });
 }
+#[must_use] pub fn boot_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed)
+}
 
 pub fn sleep(_time: Duration) {
     loop {
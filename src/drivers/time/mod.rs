@@ -10,6 +10,35 @@ pub fn init() {
     pit::init();
 }
 
+/// Seconds since the Unix epoch, from the CMOS time at boot and the PIT ticks since then
+#[must_use] pub fn unix_time() -> u64 {
+    cmos::boot_time() + pit::elapsed_millis() / 1000
+}
+
+/// Days since 1970-01-01 of a date, month and day start at 1, dates before 1970 give 0
+/// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+#[must_use] pub fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    let month = month.clamp(1, 12);
+    let year = year - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day.max(1) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146_097 + day_of_era).saturating_sub(719_468)
+}
+/// Year, month and day of a day since 1970-01-01, the opposite of `days_from_civil`
+#[must_use] pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    (year_of_era + era * 400 + u64::from(month <= 2), month, day)
+}
+
 pub fn try_udelay(micros: u16) -> Result<(), TimerError> {
     set(micros)?;
     wait_for_timeout()
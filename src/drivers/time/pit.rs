@@ -23,6 +23,11 @@ pub fn irq() {
     }
 }
 
+/// Milliseconds since the PIT was initialised
+#[must_use] pub fn elapsed_millis() -> u64 {
+    unsafe { PIT_CONTROLLER.get() }
+        .map_or(0, |c| (c.elapsed_ticks * 1000 / u128::from(SELECTED_HZ)) as u64)
+}
 /// Creates a new entry in ticks and returns it's id
 pub fn register_wait() -> Option<usize> {
     unsafe{PIT_CONTROLLER.get_mut().map(|c| {
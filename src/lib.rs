@@ -38,6 +38,16 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     test::panic_handler(info)
 }
 
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+/// Only the heap is set up, the drivers are spawned on the executor which doesn't run
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static bootloader::BootInfo) -> ! {
+    let _executor = boot::boot(boot_info);
+    test_main();
+    test::end()
+}
+
 extern crate alloc; // Lib which stores some useful structs on the heap / smart pointers from stdlib like Vec, String, Box...
 extern crate bitfield;
 extern crate bootloader; // The bootloader crate, usefull for boot_info, paging and other stuff
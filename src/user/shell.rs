@@ -18,6 +18,10 @@ use crate::{
 
 use super::prompt::{input, COMMANDS_HISTORY, COMMANDS_INDEX};
 
+/// Amount of bytes read at once when printing a file
+#[cfg(feature = "fs")]
+const READ_CHUNK_SIZE: usize = 4096;
+
 #[command("lsdisk", "Lists plugged disks with size & slot")]
 fn lsdisk(_args: String) -> Result<(), String> {
     #[cfg(feature = "fs")]
@@ -25,15 +29,28 @@ fn lsdisk(_args: String) -> Result<(), String> {
     #[cfg(feature = "fs")]
     for (loc, disk) in &disk_manager!().disks {
         println!("- {:?} {:?}", disk.loc, disk.drv);
-        let partitions = drvs.partitions.get(loc).ok_or("Partition not found".to_string())?;
-        // If the partition start is 1 we know it's MBR because on GPT the first 33 sectors are reserved !
-        let start_lba = partitions.first().map_or(0, |x| x.1);
-        if start_lba == 1 {
-            println!("--MBR--");
-        }
+        let Some(partitions) = drvs.partitions.get(loc) else {
+            println!("No partition table\n");
+            continue;
+        };
         for part in partitions {
-            print!("|-> {}Kb ({} - {})", (part.2 - part.1) / 2, part.1, part.2);
-            if let Some(drv) = drvs.drivers.get(part) {
+            print!("|-> {}Kb ({} - {})", part.2 / 2, part.1, part.end());
+            if part.3 == crate::fs::partition::PartitionKind::Whole {
+                print!(" whole disk");
+            }
+            if let Some(partition_type) = part.mbr_type() {
+                print!(" type {:#04x}", partition_type);
+            }
+            if let Some(name) = part.name() {
+                print!(" \"{}\"", name);
+            }
+            if let Some(type_guid) = part.type_guid() {
+                match type_guid.type_name() {
+                    Some(type_name) => print!(" {}", type_name),
+                    None => print!(" {}", type_guid),
+                }
+            }
+            if let Some(drv) = drvs.drivers.get(&crate::fs::vfs::Volume::Partition(part.clone())) {
                 print!(" {}", drv.as_enum());
             }
             println!();
@@ -79,7 +96,7 @@ fn read_sector(raw_args: String) -> Result<(), String> {
         .parse()
         .map_err(|e| format!("Failed to parse end: {e}"))?;
 
-    let sectors = read_from_disk(&DiskLoc(channel, drive), start, end)
+    let sectors = read_from_disk(&DiskLoc::Ata(channel, drive), start, end)
         .or(Err("The sector migth be too big !".to_string()))?;
     let sectors = if raw_args.contains("num") {
         let mut nums = String::new();
@@ -137,51 +154,364 @@ fn write_sector(raw_args: String) -> Result<(), String> {
             bytes.push(c as u8);
         }
     }
-    write_to_disk(&DiskLoc(channel, drive), start, &bytes).unwrap();
+    write_to_disk(&DiskLoc::Ata(channel, drive), start, &bytes).unwrap();
     println!("Done");
     Ok(())
 }
 
-/// n = `DriveLoc`
-/// p = Partition id
-/// [n][p]/[path]
+#[command("sync", "Writes the cached disk writes to the disks")]
+fn sync(_args: String) -> Result<(), String> {
+    disk_manager!()
+        .sync()
+        .map_err(|e| format!("Failed syncing disks: {e:?}"))
+}
+
+#[command("cache", "Shows the disk cache counters (cache | cache size [sectors] | cache drop [disk idx])")]
+fn cache(raw_args: String) -> Result<(), String> {
+    let mut args = raw_args.split(' ').filter(|arg| !arg.is_empty());
+    // The guard is kept, `disk_manager!` only locks for a single expression
+    let mut guard = unsafe { crate::disk::driver::DISK_MANAGER.lock() };
+    let manager = guard.as_mut().unwrap();
+    match args.next() {
+        None => {
+            let cache = &manager.cache;
+            let stats = cache.stats;
+            let accesses = (stats.hits + stats.misses).max(1);
+            println!(
+                "{}/{} sectors cached, {} dirty",
+                cache.len(),
+                cache.capacity(),
+                cache.dirty()
+            );
+            println!(
+                "Hits: {} Misses: {} ({}% hit rate)",
+                stats.hits,
+                stats.misses,
+                stats.hits * 100 / accesses
+            );
+            println!("Evictions: {} Write backs: {}", stats.evictions, stats.write_backs);
+        }
+        Some("size") => {
+            let capacity = args
+                .next()
+                .ok_or("Please specify the capacity in sectors !".to_string())?
+                .parse()
+                .map_err(|e| format!("Failed to parse capacity: {e}"))?;
+            manager.set_cache_capacity(capacity);
+        }
+        Some("drop") => {
+            let loc = args
+                .next()
+                .and_then(|idx| DiskLoc::from_idx(idx.parse().ok()?))
+                .ok_or("Please specify a valid disk index !".to_string())?;
+            manager
+                .invalidate(&loc)
+                .map_err(|e| format!("Failed dropping cache: {e:?}"))?;
+        }
+        Some(_) => return Err("Invalid argument ! size/drop".to_string()),
+    }
+    Ok(())
+}
+
+#[command("eject", "Opens the tray of a CD drive, or closes it and reads the new disc (eject [disk idx] | eject [disk idx] close)")]
+fn eject(raw_args: String) -> Result<(), String> {
+    let mut args = raw_args.split(' ').filter(|arg| !arg.is_empty());
+    let loc = args
+        .next()
+        .and_then(|idx| DiskLoc::from_idx(idx.parse().ok()?))
+        .ok_or("Please specify a valid disk index !".to_string())?;
+    let load = match args.next() {
+        None => false,
+        Some("close") => true,
+        Some(_) => return Err("Invalid argument ! close".to_string()),
+    };
+    {
+        let mut ata_drv = unsafe { ata::ATA_DRIVER.as_mut() }
+            .ok_or("No IDE controller !".to_string())?
+            .write_with_timeout();
+        ata_drv
+            .eject(&loc, load)
+            .map_err(|e| format!("Failed ejecting: {e:?}"))?;
+    }
+    // The cached sectors and the partitions are from the previous disc
+    disk_manager!().cache.discard(&loc);
+    #[cfg(feature = "fs")]
+    crate::fs_driver!().rescan(&loc);
+    Ok(())
+}
+
+/// Absolute path, resolved through the mount table (i.e. /mnt/disk0p1/userland)
+#[cfg(feature = "fs")]
+fn parse_path(path: &str) -> Result<crate::fs::path::FilePath, String> {
+    crate::fs_driver!()
+        .resolve(path)
+        .map_err(|e| format!("Invalid path {path}: {e:?}"))
+}
+/// Doesn't follow the last component if it's a symlink, to act on the link itself
 #[cfg(feature = "fs")]
-fn parse_path(path: &str) -> Option<crate::fs::path::FilePath> {
-    let mut chars = path.chars();
-    let loc_idx = chars.next()?.to_string().parse::<u8>().ok()?;
-    let loc = DiskLoc::from_idx(loc_idx)?;
-    let part_idx = chars.next()?.to_string().parse::<u8>().ok()?;
-    let part = crate::fs::partition::Partition::from_idx(&loc, part_idx)?;
-    Some(crate::fs::path::FilePath::new(
-        path[2..].to_string(),
-        part.clone(),
-    ))
+fn parse_path_no_follow(path: &str) -> Result<crate::fs::path::FilePath, String> {
+    crate::fs_driver!()
+        .resolve_no_follow(path)
+        .map_err(|e| format!("Invalid path {path}: {e:?}"))
+}
+
+#[command("mount", "Mounts a partition on a path, lists mount points without args (mount [disk idx] [partition idx] [path] | mount label [GPT name] [path] | mount tmpfs [path] [max size in Kb] | mount procfs|devfs [path])")]
+fn mount(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let fs_driver = crate::fs_driver!();
+        if raw_args.trim().is_empty() {
+            for mount in fs_driver.mounts.mounts() {
+                println!("{:?} on {}", mount.volume, mount.path);
+            }
+            return Ok(());
+        }
+        let mut args = raw_args.split(' ');
+        let first_arg = args.next().ok_or("Please specify disk index !".to_string())?;
+        if first_arg == "tmpfs" {
+            let path = args.next().ok_or("Please specify mount point !".to_string())?;
+            let max_size = match args.next() {
+                Some(size) => size
+                    .parse::<usize>()
+                    .map_err(|e| format!("Failed to parse max size: {e}"))?
+                    * 1024,
+                None => crate::fs::tmpfs::DEFAULT_MAX_SIZE,
+            };
+            return fs_driver
+                .mount_tmpfs(path, max_size)
+                .map_err(|e| format!("Failed mounting: {e:?}"));
+        }
+        if first_arg == "procfs" || first_arg == "devfs" {
+            let path = args.next().ok_or("Please specify mount point !".to_string())?;
+            let mounted = if first_arg == "procfs" {
+                fs_driver.mount_procfs(path)
+            } else {
+                fs_driver.mount_devfs(path)
+            };
+            return mounted.map_err(|e| format!("Failed mounting: {e:?}"));
+        }
+        let part = if first_arg == "label" {
+            let name = args.next().ok_or("Please specify partition name !".to_string())?;
+            fs_driver
+                .get_partition_from_name(name)
+                .ok_or("Partition not found".to_string())?
+                .clone()
+        } else {
+            let loc_idx = first_arg
+                .parse::<u8>()
+                .or(Err("Please specify disk index !".to_string()))?;
+            let loc = DiskLoc::from_idx(loc_idx).ok_or("Invalid disk index".to_string())?;
+            let part_idx = args
+                .next()
+                .and_then(|idx| idx.parse::<u8>().ok())
+                .ok_or("Please specify partition index !".to_string())?;
+            fs_driver
+                .get_partition_from_id(&loc, part_idx)
+                .ok_or("Partition not found".to_string())?
+                .clone()
+        };
+        let path = args.next().ok_or("Please specify mount point !".to_string())?;
+        fs_driver
+            .mount(path, part.into())
+            .map_err(|e| format!("Failed mounting: {e:?}"))?;
+    }
+    Ok(())
+}
+
+#[command("umount", "Unmounts the partition, tmpfs, procfs or devfs mounted on a path, the content of a tmpfs is lost")]
+fn umount(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        crate::fs_driver!()
+            .umount(raw_args.trim())
+            .map_err(|e| format!("Failed unmounting: {e:?}"))?;
+    }
+    Ok(())
+}
+
+#[command("part", "Edits partition tables (part gpt|mbr [disk idx] | part add [disk idx] [start] [sectors] [type] [name] | part del [disk idx] [partition idx] | part resize [disk idx] [partition idx] [sectors])")]
+fn part(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        use crate::fs::{
+            partition::{read_header_type, Guid, HeaderType, Partition, PartitionKind},
+            partitioning,
+        };
+        fn parse_u64<'a>(args: &mut impl Iterator<Item = &'a str>, name: &str) -> Result<u64, String> {
+            args.next()
+                .ok_or(format!("Please specify {name} !"))?
+                .parse::<u64>()
+                .map_err(|e| format!("Failed to parse {name}: {e}"))
+        }
+        let mut args = raw_args.split(' ').filter(|arg| !arg.is_empty());
+        let action = args.next().ok_or("Please specify an action !".to_string())?;
+        let loc = args
+            .next()
+            .and_then(|idx| idx.parse::<u8>().ok())
+            .and_then(DiskLoc::from_idx)
+            .ok_or("Please specify a valid disk index !".to_string())?;
+        let res = match action {
+            "gpt" => partitioning::init_gpt(&loc),
+            "mbr" => partitioning::init_mbr(&loc),
+            "add" => {
+                let start = parse_u64(&mut args, "start")?;
+                let sector_count = parse_u64(&mut args, "sectors")?;
+                let part_type = args.next();
+                let kind = match read_header_type(&loc) {
+                    Some(HeaderType::GPT(_)) => {
+                        let type_guid = match part_type {
+                            Some(part_type) => part_type
+                                .parse()
+                                .ok()
+                                .or_else(|| Guid::from_type_name(part_type))
+                                .ok_or(format!("Unknown partition type {part_type}"))?,
+                            // Safe unwrap, it's one of the known types
+                            None => Guid::from_type_name("Linux filesystem").unwrap(),
+                        };
+                        PartitionKind::Gpt {
+                            name: args.collect::<Vec<&str>>().join(" "),
+                            type_guid,
+                            unique_guid: Guid::default(),
+                        }
+                    }
+                    Some(HeaderType::MBR(_)) => PartitionKind::Mbr {
+                        partition_type: u8::from_str_radix(part_type.unwrap_or("83"), 16)
+                            .map_err(|e| format!("Failed to parse partition type: {e}"))?,
+                    },
+                    None => return Err("No partition table on disk, use part gpt|mbr first".to_string()),
+                };
+                partitioning::add_partition(&Partition(loc, start, sector_count, kind))
+            }
+            "del" | "resize" => {
+                let part_idx = parse_u64(&mut args, "partition index")?;
+                let sector_count = if action == "resize" { Some(parse_u64(&mut args, "sectors")?) } else { None };
+                let part = crate::fs_driver!()
+                    .get_partition_from_id(&loc, part_idx as u8)
+                    .ok_or("Partition not found".to_string())?
+                    .clone();
+                match sector_count {
+                    Some(sector_count) => partitioning::resize_partition(&part, sector_count),
+                    None => partitioning::delete_partition(&part),
+                }
+            }
+            _ => return Err(format!("Unknown action {action}")),
+        };
+        res.map_err(|e| format!("Failed editing partition table: {e:?}"))?;
+        crate::fs_driver!().rescan(&loc);
+    }
+    Ok(())
+}
+
+#[command("mkfs", "Formats a partition (mkfs fat32|ext2 [disk idx] [partition idx] [label])")]
+fn mkfs(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        use crate::fs::fs_driver::FsDriverEnum;
+        let mut args = raw_args.split(' ').filter(|arg| !arg.is_empty());
+        let fs = match args.next() {
+            Some("fat32") => FsDriverEnum::Fat32,
+            Some("ext2") => FsDriverEnum::Ext,
+            _ => return Err("Please specify the filesystem (fat32/ext2) !".to_string()),
+        };
+        let loc = args
+            .next()
+            .and_then(|idx| idx.parse::<u8>().ok())
+            .and_then(DiskLoc::from_idx)
+            .ok_or("Please specify a valid disk index !".to_string())?;
+        let part_idx = args
+            .next()
+            .and_then(|idx| idx.parse::<u8>().ok())
+            .ok_or("Please specify partition index !".to_string())?;
+        let fs_driver = crate::fs_driver!();
+        let part = fs_driver
+            .get_partition_from_id(&loc, part_idx)
+            .ok_or("Partition not found".to_string())?
+            .clone();
+        fs_driver
+            .format(&part, &fs, args.next().unwrap_or(""))
+            .map_err(|e| format!("Failed formatting partition: {e:?}"))?;
+    }
+    Ok(())
+}
+
+#[command("fsck", "Checks a FAT or ext2 partition, -r also repairs it (fsck [-r] [disk idx] [partition idx])")]
+fn fsck(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let mut args = raw_args.split(' ').filter(|arg| !arg.is_empty()).peekable();
+        let repair = args.next_if_eq(&"-r").is_some();
+        let loc = args
+            .next()
+            .and_then(|idx| idx.parse::<u8>().ok())
+            .and_then(DiskLoc::from_idx)
+            .ok_or("Please specify a valid disk index !".to_string())?;
+        let part_idx = args
+            .next()
+            .and_then(|idx| idx.parse::<u8>().ok())
+            .ok_or("Please specify partition index !".to_string())?;
+        let fs_driver = crate::fs_driver!();
+        let part = fs_driver
+            .get_partition_from_id(&loc, part_idx)
+            .ok_or("Partition not found".to_string())?
+            .clone();
+        let report = fs_driver
+            .fsck(&part, repair)
+            .map_err(|e| format!("Failed checking partition: {e:?}"))?;
+        for (problem, repaired) in &report.problems {
+            println!("{}{}", problem, if *repaired { " (repaired)" } else { "" });
+        }
+        if report.is_clean() {
+            println!("No problem found");
+        } else {
+            println!("{} problems found, {} repaired", report.problems.len(), report.repaired());
+        }
+    }
+    Ok(())
 }
 
 #[command("exec", "Tries to execute a file from disk")]
 fn exec(raw_args: String) -> Result<(), String> {
     #[cfg(feature = "fs")] // Cheat for now because #[command] doesn't support #[cfg]
     if true {
-        use crate::fs::fs_driver::Entry;
         let mut args = raw_args.split(' ');
-        let path = parse_path(args.next().ok_or("Please specify path !".to_string())?)
-            .ok_or("Not found !".to_string())?;
-        let fs_driver = crate::fs_driver!();
-        let entry = fs_driver.read(&path).or(Err("Error reading file ! Maybe specified path couldn't be found".to_string()))?;
-        match entry {
-            Entry::File(mut f) => {
-                if crate::fs::elf::execute(&f.content).is_err() {
-                    return Err("Failed executing file".to_string());
-                }
-            }
-            Entry::Dir(mut d) => {
-                return Err("Entry is a dir !".to_string());
-            }
+        let path = parse_path(args.next().ok_or("Please specify path !".to_string())?)?;
+        let file = crate::fs_driver!().open(&path).or(Err("Error opening file ! Maybe specified path couldn't be found or is a dir".to_string()))?;
+        if crate::fs::elf::execute(&file).is_err() {
+            return Err("Failed executing file".to_string());
         }
     }
     Ok(())
 }
 
+#[command("sh", "Runs each line of a file as a command, lines starting with # are ignored")]
+fn sh(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        use crate::fs::fs_driver::Entry;
+        let path = parse_path(raw_args.trim())?;
+        let Ok(Entry::File(file)) = crate::fs_driver!().read(&path) else {
+            return Err("Error reading script ! Maybe specified path couldn't be found or is a dir".to_string());
+        };
+        let script = String::from_utf8(file.content).or(Err("Script isn't valid UTF-8".to_string()))?;
+        Shell::default().inner.run_script(&script);
+    }
+    Ok(())
+}
+
+/// Command ran when the shell starts, /init is ran if there is one (i.e. in the initramfs)
+fn init_command() -> String {
+    #[cfg(feature = "fs")]
+    if let Ok(file) = parse_path("/init").and_then(|path| {
+        crate::fs_driver!().open(&path).map_err(|e| format!("{e:?}"))
+    }) {
+        let mut magic = [0; 4];
+        let is_elf = matches!(file.read_at(0, &mut magic), Ok(4)) && magic == crate::fs::elf::ELF_MAGIC;
+        file.close();
+        return if is_elf { "exec /init" } else { "sh /init" }.to_string();
+    }
+    "help".to_string()
+}
+
 #[command("panic", "Creates a kernel panic for testing")]
 fn panic(raw_args: String) -> Result<(), String> {
     panic!("{}", raw_args)
@@ -195,66 +525,200 @@ fn read(raw_args: String) -> Result<(), String> {
     #[cfg(feature = "fs")]
     let mut args = raw_args.split(' ');
     #[cfg(feature = "fs")]
-    let path = parse_path(args.next().unwrap_or("0"));
+    let raw_path = crate::fs::vfs::normalize(args.next().unwrap_or("/"))
+        .map_err(|e| format!("Invalid path: {e:?}"))?;
     #[cfg(feature = "fs")]
-    if path.is_none() {
-        println!("Invalid path");
-        return Ok(());
+    let fs_driver = crate::fs_driver!();
+    // Mount points aren't necessarily dirs of the parent filesystem
+    #[cfg(feature = "fs")]
+    let mount_points = fs_driver.mounts.children(&raw_path);
+    #[cfg(feature = "fs")]
+    for mount_point in &mount_points {
+        println!("- {}/", mount_point);
     }
     #[cfg(feature = "fs")]
-    let path = path.unwrap();
+    let path = parse_path(&raw_path);
     #[cfg(feature = "fs")]
-    let fs_driver = crate::fs_driver!();
+    if let Ok(mut file) = path.clone().and_then(|path| {
+        fs_driver.open(&path).map_err(|e| format!("{e:?}"))
+    }) {
+        // Files are printed by chunks, so that big ones don't fill the heap
+        let mut buf = alloc::vec![0; READ_CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut buf).map_err(|e| format!("Failed reading file: {e:?}"))?;
+            if read == 0 {
+                break;
+            }
+            print!("{}", String::from_utf8_lossy(&buf[..read]));
+        }
+        println!();
+        file.close();
+    } else if let Ok(Entry::Dir(d)) = path.and_then(|path| {
+        fs_driver
+            .read(&path)
+            .map_err(|e| format!("{e:?}"))
+    }) {
+        for sub in d.entries {
+            println!("- {} ({}Kb)", sub.path.name(), sub.size);
+        }
+    } else if mount_points.is_empty() {
+        println!("Error reading file ! Maybe specified path couldn't be found");
+    }
+    Ok(())
+}
+
+#[command("ls", "Lists a dir, -l also shows the type, permissions, links, owner, size and modification time (ls [-l] [path])")]
+fn ls(raw_args: String) -> Result<(), String> {
     #[cfg(feature = "fs")]
-    if let Ok(entry) = fs_driver.read(&path) {
-        match entry {
-            Entry::File(mut f) => {
-                println!("{}", String::from_utf8_lossy(&f.content));
+    if true {
+        use crate::fs::fs_driver::Entry;
+        let mut long = false;
+        let mut raw_path = "/";
+        for arg in raw_args.split(' ').filter(|arg| !arg.is_empty()) {
+            if arg == "-l" {
+                long = true;
+            } else {
+                raw_path = arg;
             }
-            Entry::Dir(mut d) => {
-                for sub in d.entries {
-                    println!("- {} ({}Kb)", sub.path, sub.size);
+        }
+        let raw_path = crate::fs::vfs::normalize(raw_path).map_err(|e| format!("Invalid path: {e:?}"))?;
+        let fs_driver = crate::fs_driver!();
+        // Mount points aren't necessarily dirs of the parent filesystem
+        let mut names = fs_driver.mounts.children(&raw_path);
+        match parse_path(&raw_path).and_then(|path| fs_driver.read(&path).map_err(|e| format!("{e:?}"))) {
+            Ok(Entry::Dir(dir)) => names.extend(dir.entries.iter().map(|entry| entry.path.name().to_string())),
+            Ok(Entry::File(_)) => {
+                // Like ls, a file is listed by itself
+                let name = raw_path.rsplit('/').next().unwrap_or_default().to_string();
+                if long {
+                    return print_long(&raw_path, &name);
                 }
+                println!("{name}");
+                return Ok(());
             }
+            Err(e) if names.is_empty() => return Err(format!("Error reading dir: {e}")),
+            Err(_) => {}
+        }
+        names.sort();
+        names.dedup();
+        for name in names {
+            if long {
+                print_long(&format!("{}/{}", raw_path.trim_end_matches('/'), name), &name)?;
+            } else {
+                println!("{name}");
+            }
+        }
+    }
+    Ok(())
+}
+/// One line of ls -l, symlinks aren't followed
+#[cfg(feature = "fs")]
+fn print_long(raw_path: &str, name: &str) -> Result<(), String> {
+    use crate::fs::{
+        fs_driver::EntryKind,
+        userland::{get_group, get_user},
+    };
+    let path = parse_path_no_follow(raw_path)?;
+    let fs_driver = crate::fs_driver!();
+    let metadata = fs_driver
+        .stat(&path)
+        .map_err(|e| format!("Failed reading metadata of {raw_path}: {e:?}"))?;
+    let (year, month, day) = crate::time::civil_from_days(metadata.modified / 86400);
+    let seconds = metadata.modified % 86400;
+    let mut line = format!(
+        "{} {:>3} {:<8} {:<8} {:>10} {}-{:02}-{:02} {:02}:{:02} {}",
+        metadata.mode_string(),
+        metadata.links,
+        get_user(metadata.uid).username,
+        get_group(metadata.gid).group_name,
+        metadata.size,
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        name
+    );
+    if metadata.kind == EntryKind::Symlink {
+        if let Some(target) = fs_driver.read_link(&path) {
+            line.push_str(" -> ");
+            line.push_str(&target);
         }
-    } else {
-        println!("Error reading file ! Maybe specified path couldn't be found");
     }
+    println!("{line}");
     Ok(())
 }
 
-// #[command("write", "Writes a file to disk")]
-// fn write(args: String) -> Result<(), String> {
-//     // TODO Refactor input/output for PROPER error handling
-//     let mut args = args.split(" ");
-//     let entry_type = args.next().unwrap();
-//     let path = parse_path(args.next().unwrap()).unwrap();
-//     let mut fs_driver = unsafe{fs_driver!()};
-//     if let Some(_entry) = fs_driver.get_entry(&path) {
-//         println!("File already exists !");
-//         return Ok(());
-//     }
-//     let content = args.collect::<String>();
-//     match entry_type {
-//         "dir" => {
-//             if !content.is_empty() {
-//                 println!("Useless to specify content, created a empty dir");
-//             }
-//             fs_driver.write_dir(path).unwrap()
-//         }
-//         "file" => {
-//             if content.is_empty() {
-//                 println!("Created a empty file");
-//                 return Ok(());
-//             }
-//             fs_driver.write_file(path, content).unwrap()
-//         }
-//         _ => {
-//             println!("Invalid entry type ! dir/file")
-//         }
-//     };
-//     Ok(())
-// }
+#[command("readlink", "Prints the target of a symbolic link (readlink [path])")]
+fn readlink(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let path = parse_path_no_follow(raw_args.trim())?;
+        let target = crate::fs_driver!()
+            .read_link(&path)
+            .ok_or("Not a symbolic link".to_string())?;
+        println!("{target}");
+    }
+    Ok(())
+}
+
+#[command("write", "Writes a file or creates a dir on disk (write file [path] [content] | write dir [path])")]
+fn write(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let mut args = raw_args.split(' ');
+        let entry_type = args.next().ok_or("Please specify entry type (dir/file)".to_string())?;
+        let path = parse_path(args.next().ok_or("Please specify path !".to_string())?)?;
+        let content = args.collect::<Vec<&str>>().join(" ");
+        let fs_driver = crate::fs_driver!();
+        match entry_type {
+            "dir" => {
+                if !content.is_empty() {
+                    println!("Useless to specify content, created a empty dir");
+                }
+                fs_driver
+                    .create_dir(&path)
+                    .map_err(|e| format!("Failed creating dir: {e:?}"))?;
+            }
+            "file" => {
+                if content.is_empty() {
+                    println!("Created a empty file");
+                }
+                fs_driver
+                    .write_file(&path, content.as_bytes())
+                    .map_err(|e| format!("Failed writing file: {e:?}"))?;
+            }
+            _ => return Err("Invalid entry type ! dir/file".to_string()),
+        };
+    }
+    Ok(())
+}
+
+#[command("rm", "Removes a file or an empty dir from disk")]
+fn rm(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let path = parse_path_no_follow(raw_args.trim())?;
+        crate::fs_driver!()
+            .remove(&path)
+            .map_err(|e| format!("Failed removing entry: {e:?}"))?;
+    }
+    Ok(())
+}
+
+#[command("mv", "Moves a file or a dir on the same partition (mv [from] [to])")]
+fn mv(raw_args: String) -> Result<(), String> {
+    #[cfg(feature = "fs")]
+    if true {
+        let mut args = raw_args.split(' ');
+        let from = parse_path_no_follow(args.next().ok_or("Please specify source path !".to_string())?)?;
+        let to = parse_path_no_follow(args.next().ok_or("Please specify destination path !".to_string())?)?;
+        crate::fs_driver!()
+            .rename(&from, &to)
+            .map_err(|e| format!("Failed moving entry: {e:?}"))?;
+    }
+    Ok(())
+}
 
 #[command("dump_disk", "Dumps disk to serial output (QEMU ONLY)")]
 fn dump_disk(args: String) -> Result<(), String> {
@@ -280,7 +744,7 @@ fn dump_disk(args: String) -> Result<(), String> {
         _ => return Err("Wrong drive: Master//0 or Slave//1".to_string()),
     };
     let mut i = 0;
-    let loc = DiskLoc(channel, drive);
+    let loc = DiskLoc::Ata(channel, drive);
     loop {
         let sectors = read_from_disk(&loc, i, 1);
         if sectors.is_err() {
@@ -441,6 +905,15 @@ impl CommandRunner {
             self.run_command(cmd);
         }
     }
+    /// Runs each line as a command, empty lines and comments (#) are skipped
+    pub fn run_script(&mut self, script: &str) {
+        for line in script.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.run_command(line.to_string());
+        }
+    }
     pub fn run_command(&mut self, cmd: String) {
         let mut command = Vec::new();
         for char in cmd.bytes() {
@@ -503,6 +976,13 @@ impl Shell {
         self.inner.run_command(cmd);
         self.inner.run();
     }
+    /// Runs /init if there is one, otherwise prints the help
+    /// Waits for the filesystems first, as the shell never yields once it runs
+    pub async fn run_init(self) {
+        #[cfg(feature = "fs")]
+        crate::fs::initialised().await;
+        self.run_with_command(init_command()).await;
+    }
 }
 impl Default for Shell {
     fn default() -> Self {